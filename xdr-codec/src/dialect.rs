@@ -0,0 +1,108 @@
+//! Nonstandard encodings
+//!
+//! Some peers don't quite follow RFC4506: they fill padding with something other than zero bytes,
+//! or align 64-bit values to 8 bytes as their C structures do. A `Dialect` describes those
+//! differences, and applies to everything packed or unpacked on the current thread within
+//! `Dialect::scope()` (or a `Dialect::pack()` or `Dialect::unpack()` call).
+//!
+//! The dialect is applied by this crate's `Pack` and `Unpack` implementations and helpers, so it
+//! covers generated code as well as hand-written code built from them. Alignment is measured from
+//! the start of the scope, so a scope should cover exactly one message.
+//!
+//! This module needs the `dialect` feature; without it, encoding is always standard and doesn't
+//! pay for the bookkeeping.
+use std::cell::Cell;
+
+use super::{Read, Result, Unpack, Write, Pack};
+
+/// Differences from standard XDR. The default is the standard encoding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Dialect {
+    /// Byte value padding is filled with. Unpacking skips padding whatever it contains.
+    pub pad_byte: u8,
+    /// Align each hyper (`i64` and `u64`) to 8 bytes from the start of the scope, with 4 bytes of
+    /// padding before it where needed.
+    pub align_hyper: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct State {
+    dialect: Dialect,
+    // Bytes packed or unpacked so far in the scope
+    offset: usize,
+}
+
+thread_local! {
+    static STATE: Cell<State> = Cell::new(State::default());
+}
+
+impl Dialect {
+    /// Run `f` with this dialect applied to everything it packs and unpacks on this thread.
+    ///
+    /// Offsets for alignment start from zero, and the previous dialect is restored afterwards.
+    pub fn scope<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        struct Restore(State);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                STATE.with(|s| s.set(self.0));
+            }
+        }
+
+        let state = State { dialect: *self, offset: 0 };
+        let _restore = Restore(STATE.with(|s| s.replace(state)));
+
+        f()
+    }
+
+    /// Pack `val` into `out` in this dialect, returning its size.
+    pub fn pack<Out: Write, T: Pack<Out> + ?Sized>(&self, val: &T, out: &mut Out) -> Result<usize> {
+        self.scope(|| val.pack(out))
+    }
+
+    /// Unpack a `T` from `input` in this dialect.
+    pub fn unpack<In: Read, T: Unpack<In>>(&self, input: &mut In) -> Result<T> {
+        self.scope(|| super::unpack(input))
+    }
+}
+
+/// Account for `n` bytes packed or unpacked.
+#[inline]
+pub(crate) fn advance(n: usize) {
+    STATE.with(|s| {
+        let mut state = s.get();
+        state.offset = state.offset.wrapping_add(n);
+        s.set(state);
+    })
+}
+
+/// The byte padding is filled with.
+#[inline]
+pub(crate) fn pad_byte() -> u8 {
+    STATE.with(|s| s.get()).dialect.pad_byte
+}
+
+/// Bytes of padding due before a hyper at the current offset.
+#[inline]
+pub(crate) fn hyper_gap() -> usize {
+    let state = STATE.with(|s| s.get());
+    if state.dialect.align_hyper { (8 - state.offset % 8) % 8 } else { 0 }
+}
+
+/// Whether hypers are aligned, so that their encoding may be longer than 8 bytes.
+#[inline]
+pub(crate) fn aligns_hyper() -> bool {
+    STATE.with(|s| s.get()).dialect.align_hyper
+}
+
+/// Run `f`, then put the offset back as it was, as for packing something only to measure it.
+#[inline]
+pub(crate) fn measure<R>(f: impl FnOnce() -> R) -> R {
+    let offset = STATE.with(|s| s.get()).offset;
+    let res = f();
+    STATE.with(|s| s.set(State { offset, ..s.get() }));
+    res
+}
@@ -1,9 +1,11 @@
 //#![allow(deprecated)]
 
-pub type Result<T, E = Error> = std::result::Result<T, E>;
+pub type Result<T, E = Error> = core::result::Result<T, E>;
 
-use std::io::Error as IOError;
-use std::string::FromUtf8Error;
+use crate::io::Error as IOError;
+use alloc::string::FromUtf8Error;
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, vec, vec::Vec};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -19,10 +21,22 @@ pub enum Error {
     InvalidNamedCase{name: &'static str, value: i32},
     #[error("enum '{name}' - invalid value: {value} (0x{value:X})")]
     InvalidNamedEnum{name: &'static str, value: i32},
+    #[error("'{name}' - length {len} exceeds bound {max}")]
+    LengthExceeded{name: &'static str, len: usize, max: usize},
+    #[error("decoding limit {limit} exceeded: {value} > {max}")]
+    LimitExceeded{limit: &'static str, value: usize, max: usize},
+    #[error("short read: {missing} bytes missing")]
+    ShortRead{missing: usize},
+    #[error("invalid timestamp: {seconds}s + {nanos}ns")]
+    InvalidTimestamp{seconds: i64, nanos: u32},
     #[error("IO Error: {0}")]
     IOError(IOError),
     #[error("Invalid utf8: {0}")]
     InvalidUtf8(FromUtf8Error),
+    /// Decoding failed part way through a message; see `Error::in_field`. The error it failed
+    /// with is its `source()`.
+    #[error("decoding '{}' after {consumed} bytes", .path.join("."))]
+    Partial{path: Vec<&'static str>, consumed: usize, source: Box<Error>},
 }
 
 impl From<IOError> for Error {
@@ -60,7 +74,61 @@ impl Error {
         Error::InvalidNamedEnum{name, value}
     }
 
-    #[cfg(test)]
+    pub fn length_exceeded(name: &'static str, len: usize, max: usize) -> Error {
+        Error::LengthExceeded{name, len, max}
+    }
+
+    pub fn limit_exceeded(limit: &'static str, value: usize, max: usize) -> Error {
+        Error::LimitExceeded{limit, value, max}
+    }
+
+    pub fn short_read(missing: usize) -> Error {
+        Error::ShortRead{missing}
+    }
+
+    pub fn invalid_timestamp(seconds: i64, nanos: u32) -> Error {
+        Error::InvalidTimestamp{seconds, nanos}
+    }
+
+    /// Attribute this error to decoding `field`, which started after `consumed` bytes of the
+    /// enclosing value. Applied at each level of nesting, this builds up the path to the field
+    /// which failed and the total bytes consumed before it.
+    pub fn in_field(self, field: &'static str, consumed: usize) -> Error {
+        match self {
+            Error::Partial{mut path, consumed: inner, source} => {
+                path.insert(0, field);
+                Error::Partial{path, consumed: consumed + inner, source}
+            }
+            err => Error::Partial{path: vec![field], consumed, source: Box::new(err)},
+        }
+    }
+
+    /// Bytes consumed by successfully decoded fields before the failure, if known. Any partial
+    /// read of the failing field itself isn't included.
+    pub fn consumed(&self) -> Option<usize> {
+        match self {
+            Error::Partial{consumed, ..} => Some(*consumed),
+            _ => None,
+        }
+    }
+
+    /// Path of field names to where decoding failed, outermost first; empty if not known.
+    pub fn field_path(&self) -> &[&'static str] {
+        match self {
+            Error::Partial{path, ..} => path,
+            _ => &[],
+        }
+    }
+
+    /// The underlying error, without any partial decode state.
+    pub fn innermost(&self) -> &Error {
+        match self {
+            Error::Partial{source, ..} => source,
+            err => err,
+        }
+    }
+
+    #[cfg(all(test, feature = "std"))]
     #[allow(deprecated)]
     pub(crate) fn is_invalid_enum(&self) -> bool {
         matches!(self, Error::InvalidEnum{..} | Error::InvalidNamedEnum{..})
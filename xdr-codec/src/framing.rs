@@ -0,0 +1,238 @@
+//! Message framing
+//!
+//! A `Framing` splits a bytestream into whole messages. `RecordMarking` is the RFC1831 scheme
+//! (see the `record` module for streaming access to it), and `LengthPrefixed` is the simpler
+//! scheme of a big-endian `u32` byte count before each message, which some protocols use instead.
+//!
+//! Code which needs to handle either can be generic over `Framing`.
+//!
+//! A `FrameTransform`, such as compression or encryption, can be layered onto any framing with
+//! `Framing::with_transform`, so that it's applied to each whole message.
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::record::LAST_REC;
+
+/// A way of delimiting messages in a bytestream.
+pub trait Framing {
+    /// Read one whole frame. Returns `None` on a clean end of stream between frames; the stream
+    /// ending part way through a frame is an `UnexpectedEof` error.
+    fn read_frame<R: Read>(&self, reader: &mut R) -> io::Result<Option<Vec<u8>>>;
+
+    /// Write `frame` as one whole frame.
+    fn write_frame<W: Write>(&self, writer: &mut W, frame: &[u8]) -> io::Result<()>;
+
+    /// Iterate over the frames in `reader`.
+    fn frames<R: Read>(self, reader: R) -> Frames<R, Self>
+    where
+        Self: Sized,
+    {
+        Frames { reader, framing: self, done: false }
+    }
+
+    /// Apply `transform` to each frame's contents: encoding before writing, and decoding after
+    /// reading. Transforms added later are applied first when writing, so
+    /// `.with_transform(encrypt).with_transform(compress)` compresses then encrypts.
+    ///
+    /// Any `max_len` applies to the frame as it is on the wire, not its decoded contents.
+    fn with_transform<T: FrameTransform>(self, transform: T) -> Transformed<Self, T>
+    where
+        Self: Sized,
+    {
+        Transformed { framing: self, transform }
+    }
+}
+
+/// A transformation of whole frames, such as compression or encryption.
+///
+/// The methods take `&self` like those of `Framing`; a transform which needs per-frame state,
+/// such as a nonce counter, can keep it in a `Cell` or similar.
+pub trait FrameTransform {
+    /// Transform a frame's contents for writing.
+    fn encode(&self, frame: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Reverse `encode` on a frame which has been read. Malformed input should be an
+    /// `InvalidData` error.
+    fn decode(&self, frame: Vec<u8>) -> io::Result<Vec<u8>>;
+}
+
+impl<T: FrameTransform + ?Sized> FrameTransform for &T {
+    fn encode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        (**self).encode(frame)
+    }
+
+    fn decode(&self, frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        (**self).decode(frame)
+    }
+}
+
+impl<T: FrameTransform + ?Sized> FrameTransform for Box<T> {
+    fn encode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        (**self).encode(frame)
+    }
+
+    fn decode(&self, frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        (**self).decode(frame)
+    }
+}
+
+/// A framing with a transform applied to each frame; see `Framing::with_transform`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transformed<F, T> {
+    pub framing: F,
+    pub transform: T,
+}
+
+impl<F: Framing, T: FrameTransform> Framing for Transformed<F, T> {
+    fn read_frame<R: Read>(&self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        match self.framing.read_frame(reader)? {
+            None => Ok(None),
+            Some(frame) => self.transform.decode(frame).map(Some),
+        }
+    }
+
+    fn write_frame<W: Write>(&self, writer: &mut W, frame: &[u8]) -> io::Result<()> {
+        let frame = self.transform.encode(frame)?;
+        self.framing.write_frame(writer, &frame)
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Header of the next frame or fragment, or None at end of stream.
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut hdr = [0; 4];
+    let mut got = 0;
+
+    while got < hdr.len() {
+        match reader.read(&mut hdr[got..]) {
+            Ok(0) if got == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => got += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    (&hdr[..]).read_u32::<BigEndian>().map(Some)
+}
+
+// Append exactly `len` bytes to `buf`.
+fn read_body<R: Read>(reader: &mut R, len: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+    let got = reader.take(len as u64).read_to_end(buf)?;
+    if got < len {
+        Err(io::ErrorKind::UnexpectedEof.into())
+    } else {
+        Ok(())
+    }
+}
+
+fn check_len(len: usize, max_len: Option<usize>) -> io::Result<()> {
+    match max_len {
+        Some(max) if len > max => Err(invalid(format!("frame length {} exceeds limit {}", len, max))),
+        _ => Ok(()),
+    }
+}
+
+/// Each frame is a big-endian `u32` length followed by that many bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LengthPrefixed {
+    /// Largest frame to accept when reading.
+    pub max_len: Option<usize>,
+}
+
+impl Framing for LengthPrefixed {
+    fn read_frame<R: Read>(&self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let len = match read_header(reader)? {
+            None => return Ok(None),
+            Some(len) => len as usize,
+        };
+        check_len(len, self.max_len)?;
+
+        let mut buf = Vec::new();
+        read_body(reader, len, &mut buf)?;
+        Ok(Some(buf))
+    }
+
+    fn write_frame<W: Write>(&self, writer: &mut W, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > u32::MAX as usize {
+            return Err(invalid(format!("frame length {} too large", frame.len())));
+        }
+        writer.write_u32::<BigEndian>(frame.len() as u32)?;
+        writer.write_all(frame)
+    }
+}
+
+/// RFC1831 record marking: each record is one or more fragments, each with a `u32` header
+/// holding its length and an end-of-record flag.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecordMarking {
+    /// Largest whole record to accept when reading.
+    pub max_len: Option<usize>,
+}
+
+impl Framing for RecordMarking {
+    fn read_frame<R: Read>(&self, reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = Vec::new();
+        let mut first = true;
+
+        loop {
+            let hdr = match read_header(reader)? {
+                None if first => return Ok(None),
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Some(hdr) => hdr,
+            };
+            first = false;
+
+            let len = (hdr & !LAST_REC) as usize;
+            check_len(buf.len() + len, self.max_len)?;
+            read_body(reader, len, &mut buf)?;
+
+            if hdr & LAST_REC != 0 {
+                return Ok(Some(buf));
+            }
+        }
+    }
+
+    fn write_frame<W: Write>(&self, writer: &mut W, frame: &[u8]) -> io::Result<()> {
+        let maxfrag = !LAST_REC as usize;
+        let mut chunks = frame.chunks(maxfrag).peekable();
+
+        if chunks.peek().is_none() {
+            return writer.write_u32::<BigEndian>(LAST_REC);
+        }
+        while let Some(chunk) = chunks.next() {
+            let eor = if chunks.peek().is_none() { LAST_REC } else { 0 };
+            writer.write_u32::<BigEndian>(chunk.len() as u32 | eor)?;
+            writer.write_all(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over frames; see `Framing::frames`.
+///
+/// It ends after the first error.
+#[derive(Debug)]
+pub struct Frames<R, F> {
+    reader: R,
+    framing: F,
+    done: bool,
+}
+
+impl<R: Read, F: Framing> Iterator for Frames<R, F> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let res = self.framing.read_frame(&mut self.reader).transpose();
+        self.done = !matches!(res, Some(Ok(_)));
+        res
+    }
+}
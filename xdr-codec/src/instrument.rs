@@ -0,0 +1,128 @@
+//! Decode instrumentation
+//!
+//! Code generated by xdrgen with its `instrument` feature reports every type it unpacks here,
+//! along with the number of bytes consumed and how deeply nested it was. Install a
+//! `DecodeObserver` with `observe()` to see those reports, for example to export metrics about
+//! protocol traffic. `DecodeStats` is a ready-made observer which counts them.
+//!
+//! Observers are per-thread, and only apply for the duration of the `observe()` call. With no
+//! observer installed the cost is a thread-local lookup per decoded value.
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Receives reports of decoded values.
+pub trait DecodeObserver {
+    /// A value of type `type_name` was decoded from `bytes` bytes of input. `depth` is the
+    /// number of instrumented values it is nested within, so `0` is an outermost message.
+    fn decoded(&self, type_name: &'static str, bytes: usize, depth: usize);
+}
+
+thread_local! {
+    static OBSERVER: RefCell<Option<Arc<dyn DecodeObserver>>> = const { RefCell::new(None) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Run `f` with `observer` receiving reports of everything it decodes on this thread.
+///
+/// Any previously installed observer is restored afterwards.
+pub fn observe<F, R>(observer: Arc<dyn DecodeObserver>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    struct Restore(Option<Arc<dyn DecodeObserver>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            OBSERVER.with(|o| *o.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(OBSERVER.with(|o| o.borrow_mut().replace(observer)));
+
+    f()
+}
+
+/// Tracks one value being decoded; used by generated code.
+///
+/// Create it with `enter()` before decoding any of the value, and call `done()` once it has been
+/// decoded successfully. Dropping it without calling `done()` (ie, on error) reports nothing.
+#[derive(Debug)]
+pub struct DecodeSpan {
+    type_name: &'static str,
+}
+
+impl DecodeSpan {
+    #[inline]
+    pub fn enter(type_name: &'static str) -> DecodeSpan {
+        DEPTH.with(|d| d.set(d.get() + 1));
+        DecodeSpan { type_name }
+    }
+
+    /// Report the decoded value, passing it through.
+    #[inline]
+    pub fn done<T>(self, (val, sz): (T, usize)) -> (T, usize) {
+        let depth = DEPTH.with(|d| d.get()) - 1;
+        OBSERVER.with(|o| {
+            if let Some(observer) = &*o.borrow() {
+                observer.decoded(self.type_name, sz, depth)
+            }
+        });
+        (val, sz)
+    }
+}
+
+impl Drop for DecodeSpan {
+    #[inline]
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+/// Counts of decoded messages, bytes and types.
+///
+/// A message is an outermost decoded value; `bytes` only counts those, so that nested values
+/// aren't counted more than once.
+#[derive(Debug, Default)]
+pub struct DecodeStats {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+    types: Mutex<HashMap<&'static str, usize>>,
+}
+
+impl DecodeStats {
+    pub fn new() -> DecodeStats {
+        Default::default()
+    }
+
+    /// Number of outermost values decoded.
+    pub fn messages(&self) -> usize {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    /// Total size of outermost values decoded.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of values decoded for each type, at any depth.
+    pub fn type_counts(&self) -> HashMap<&'static str, usize> {
+        self.types.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl DecodeObserver for DecodeStats {
+    fn decoded(&self, type_name: &'static str, bytes: usize, depth: usize) {
+        if depth == 0 {
+            self.messages.fetch_add(1, Ordering::Relaxed);
+            self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        *self
+            .types
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(type_name)
+            .or_insert(0) += 1;
+    }
+}
@@ -0,0 +1,221 @@
+//! Byte streams
+//!
+//! With the `std` feature, `Read` and `Write` are `std::io`'s, so values can be packed into and
+//! unpacked from anything which writes or reads bytes. Without it, for `no_std` targets, they're
+//! traits of the same names with just what packing and unpacking need, implemented for byte
+//! slices and `Vec<u8>`.
+#[cfg(feature = "std")]
+pub use std::io::{sink, Error, ErrorKind, Read, Sink, Write};
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(not(feature = "std"))]
+use core::{cmp::min, fmt};
+
+/// What went wrong reading or writing.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The input ended before the value did.
+    UnexpectedEof,
+    /// The output had no room for the value.
+    WriteZero,
+    /// The operation was interrupted, and can be retried.
+    Interrupted,
+    Other,
+}
+
+/// An error reading or writing.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+#[cfg(not(feature = "std"))]
+impl Error {
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error::new(kind)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self.kind {
+            ErrorKind::UnexpectedEof => "unexpected end of input",
+            ErrorKind::WriteZero => "no room for output",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::Other => "other error",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl core::error::Error for Error {}
+
+/// A source of bytes to unpack from.
+#[cfg(not(feature = "std"))]
+pub trait Read {
+    /// Read some bytes into `buf`, returning how many; 0 at the end of the input.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+
+    /// Fill `buf`, failing with `ErrorKind::UnexpectedEof` if the input ends first.
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            match self.read(buf) {
+                Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof)),
+                Ok(n) => buf = &mut buf[n..],
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A sink for packed bytes.
+#[cfg(not(feature = "std"))]
+pub trait Write {
+    /// Write some of `buf`, returning how many bytes; 0 if there's no more room.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error>;
+
+    /// Write all of `buf`, failing with `ErrorKind::WriteZero` if there isn't room.
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            match self.write(buf) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero)),
+                Ok(n) => buf = &buf[n..],
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reading consumes the front of the slice.
+#[cfg(not(feature = "std"))]
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = min(buf.len(), self.len());
+        let (head, rest) = self.split_at(n);
+        buf[..n].copy_from_slice(head);
+        *self = rest;
+        Ok(n)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<R: Read + ?Sized> Read for &mut R {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        (**self).read(buf)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+/// Writing fills the front of the slice, which is left as the space remaining.
+#[cfg(not(feature = "std"))]
+impl Write for &mut [u8] {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let n = min(buf.len(), self.len());
+        let (head, rest) = core::mem::take(self).split_at_mut(n);
+        head.copy_from_slice(&buf[..n]);
+        *self = rest;
+        Ok(n)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<W: Write + ?Sized> Write for &mut W {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        (**self).write(buf)
+    }
+}
+
+/// Output which discards everything written to it; see `sink()`.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sink(());
+
+/// Output which discards everything written to it.
+#[cfg(not(feature = "std"))]
+pub fn sink() -> Sink {
+    Sink(())
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for Sink {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        Ok(buf.len())
+    }
+}
+
+// Read up to `n` bytes onto the end of `buf`, returning how many there were before the input
+// ended.
+#[cfg(feature = "std")]
+pub(crate) fn read_up_to<In: Read>(input: &mut In, n: usize, buf: &mut Vec<u8>) -> Result<usize, Error> {
+    input.take(n as u64).read_to_end(buf)
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn read_up_to<In: Read>(input: &mut In, n: usize, buf: &mut Vec<u8>) -> Result<usize, Error> {
+    let mut chunk = [0; 256];
+    let mut got = 0;
+    while got < n {
+        match input.read(&mut chunk[..min(n - got, 256)]) {
+            Ok(0) => break,
+            Ok(r) => {
+                buf.extend_from_slice(&chunk[..r]);
+                got += r;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(got)
+}
+
+// Skip up to `n` bytes, returning how many there were before the input ended.
+#[cfg(feature = "std")]
+pub(crate) fn skip<In: Read>(input: &mut In, n: u64) -> Result<u64, Error> {
+    std::io::copy(&mut input.take(n), &mut sink())
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn skip<In: Read>(input: &mut In, n: u64) -> Result<u64, Error> {
+    let mut chunk = [0; 256];
+    let mut got = 0;
+    while got < n {
+        match input.read(&mut chunk[..min(n - got, 256) as usize]) {
+            Ok(0) => break,
+            Ok(r) => got += r as u64,
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(got)
+}
@@ -17,22 +17,125 @@
 //! However, some protocols are mis-specified to use byte arrays (I'm looking at
 //! you, gluster), so the option to support the exists. You can enable byte codec
 //! with the `bytecodec` feature.
+//!
+//! The `maps` feature implements `Pack` and `Unpack` for `BTreeMap` and `HashMap`, encoded as
+//! a flex array of key/value pairs. Note that `HashMap` packs its entries in an unspecified order.
+//!
+//! The `chrono` and `time` features implement them for `chrono::DateTime<Utc>` and
+//! `time::OffsetDateTime`, encoded as a timestamp struct; see the `timestamp` module.
+//!
+//! When decoding untrusted input, use a `Limits` policy to bound string lengths, array sizes,
+//! total allocation and nesting depth.
+//!
+//! The `dialect` feature adds `Dialect`, for peers which fill padding with something other than
+//! zeros or align hypers to 8 bytes.
+//!
+//! Without the default `std` feature, the crate is `no_std`, needing only `alloc`, for embedded
+//! targets. Values are packed into and unpacked from byte slices and `Vec<u8>` with the `Read` and
+//! `Write` traits in `io`. The parts which need the standard library - `Limits`, `Dialect`,
+//! `instrument`, `framing`, `record`, and the `HashMap` codecs - aren't available.
 #![crate_type = "lib"]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
 extern crate byteorder;
 
-pub use std::io::{Read, Write};
-use std::ops::Deref;
-use std::cmp::min;
-use std::borrow::{Borrow, Cow};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-
+pub mod io;
+pub use io::{Read, Write};
+use core::ops::Deref;
+use core::cmp::min;
+use core::borrow::Borrow;
+use core::iter::FromIterator;
+use alloc::borrow::Cow;
+#[cfg(not(feature = "std"))]
+use alloc::{borrow::ToOwned, boxed::Box, string::String, vec, vec::Vec};
+#[cfg(feature = "maps")]
+use alloc::collections::BTreeMap;
+#[cfg(all(feature = "maps", feature = "std"))]
+use std::collections::HashMap;
+#[cfg(all(feature = "maps", feature = "std"))]
+use std::hash::{BuildHasher, Hash};
+
+#[cfg(feature = "std")]
+pub mod framing;
+#[cfg(feature = "std")]
+pub mod instrument;
+#[cfg(feature = "dialect")]
+pub mod dialect;
+#[cfg(feature = "std")]
+pub mod limits;
+#[cfg(feature = "std")]
 pub mod record;
+#[cfg(any(feature = "chrono", feature = "time"))]
+pub mod timestamp;
+
+#[cfg(feature = "std")]
+pub use limits::{Limits, LimitsBuilder};
+#[cfg(feature = "dialect")]
+pub use dialect::Dialect;
+
+// Standard XDR, without the `dialect` feature
+#[cfg(not(feature = "dialect"))]
+mod dialect {
+    #[inline]
+    pub(crate) fn advance(_n: usize) {}
+
+    #[inline]
+    pub(crate) fn pad_byte() -> u8 {
+        0
+    }
+
+    #[inline]
+    pub(crate) fn hyper_gap() -> usize {
+        0
+    }
+
+    #[inline]
+    pub(crate) fn aligns_hyper() -> bool {
+        false
+    }
+
+    #[inline]
+    pub(crate) fn measure<R>(f: impl FnOnce() -> R) -> R {
+        f()
+    }
+}
+
+// No `Limits` without the standard library to keep them per thread, so only bounds in the
+// specification apply
+#[cfg(not(feature = "std"))]
+mod limits {
+    use super::Result;
+
+    pub(crate) struct Nested;
+
+    #[inline]
+    pub(crate) fn string(_len: usize) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    #[allow(clippy::extra_unused_type_parameters)]
+    pub(crate) fn flex<T>(_elems: usize) -> Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    #[allow(clippy::extra_unused_type_parameters)]
+    pub(crate) fn flex_room<T>() -> usize {
+        usize::MAX
+    }
+
+    #[inline]
+    pub(crate) fn nest() -> Result<Nested> {
+        Ok(Nested)
+    }
+}
 
 mod error;
 pub use error::{Error, Result};
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod test;
 
 static PADDING: [u8; 4] = [0; 4];
@@ -46,6 +149,44 @@ pub fn padding(sz: usize) -> &'static [u8] {
     &PADDING[..(4 - (sz % 4)) % 4]
 }
 
+// `n` bytes packed or unpacked, accounted for alignment in the current `Dialect`
+#[inline]
+fn counted(n: usize) -> usize {
+    dialect::advance(n);
+    n
+}
+
+// Write `n` bytes of padding, filled as the current `Dialect` says
+fn write_padding<Out: Write>(out: &mut Out, n: usize) -> Result<usize> {
+    if n > 0 {
+        out.write_all(&[dialect::pad_byte(); 8][..n])?;
+    }
+    Ok(counted(n))
+}
+
+// Skip `n` bytes of padding, whatever they contain
+fn skip_padding<In: Read>(input: &mut In, n: usize) -> Result<usize> {
+    if n > 0 {
+        input.read_exact(&mut [0; 8][..n])?;
+    }
+    Ok(counted(n))
+}
+
+// Write a big-endian integer or float
+#[inline]
+fn write_be<Out: Write, const N: usize>(out: &mut Out, bytes: [u8; N]) -> Result<usize> {
+    out.write_all(&bytes)?;
+    Ok(counted(N))
+}
+
+// Read a big-endian integer or float
+#[inline]
+fn read_be<In: Read, const N: usize>(input: &mut In) -> Result<([u8; N], usize)> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok((bytes, counted(N)))
+}
+
 /// Wrapper for XDR opaque data.
 ///
 /// In XDR terms, "opaque data" is a plain array of bytes, packed as tightly as possible, and then
@@ -76,9 +217,62 @@ impl<'a> From<&'a [u8]> for Opaque<'a> {
     }
 }
 
+/// An XDR string as the bytes it was sent as.
+///
+/// XDR strings are meant to be ASCII, but nothing checks, and real peers send filenames and the
+/// like in whatever encoding they were written in. `String` can't hold those, so unpacking one
+/// fails; this keeps the bytes as they are, and packs them back unchanged.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct XdrString(pub Vec<u8>);
+
+impl XdrString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// The string, if it's UTF-8.
+    pub fn to_str(&self) -> core::result::Result<&str, core::str::Utf8Error> {
+        core::str::from_utf8(&self.0)
+    }
+
+    /// The string, with anything which isn't UTF-8 replaced by U+FFFD.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl Deref for XdrString {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for XdrString {
+    fn from(v: Vec<u8>) -> Self {
+        XdrString(v)
+    }
+}
+
+impl From<String> for XdrString {
+    fn from(s: String) -> Self {
+        XdrString(s.into_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for XdrString {
+    fn from(s: &'a str) -> Self {
+        XdrString(s.as_bytes().to_vec())
+    }
+}
+
 /// Serialization (packing) helper.
 ///
-/// Helper to serialize any type implementing `Pack` into an implementation of `std::io::Write`.
+/// Helper to serialize any type implementing `Pack` into an implementation of `Write`.
 pub fn pack<Out: Write, T: Pack<Out>>(val: &T, out: &mut Out) -> Result<()> {
     val.pack(out).map(|_| ())
 }
@@ -125,11 +319,13 @@ pub fn pack_opaque_array<Out: Write>(val: &[u8], sz: usize, out: &mut Out) -> Re
     vsz = val.len();
     out.write_all(val)?;
 
-    let p = padding(sz);
-    for _ in val.len()..(sz + p.len()) {
-        out.write_u8(0)?;
+    for _ in val.len()..sz {
+        out.write_all(&[0])?;
         vsz += 1;
     }
+    dialect::advance(vsz);
+
+    vsz += write_padding(out, padding(sz).len())?;
 
     Ok(vsz)
 }
@@ -167,6 +363,15 @@ pub fn pack_opaque_flex<Out: Write>(
     Opaque::borrowed(val).pack(out)
 }
 
+/// Pack raw bytes as they are, without a length or padding.
+///
+/// The counterpart of `unpack_rest`, for data which wasn't decoded, such as an unknown union arm.
+#[inline]
+pub fn pack_rest<Out: Write>(val: &[u8], out: &mut Out) -> Result<usize> {
+    out.write_all(val)?;
+    Ok(counted(val.len()))
+}
+
 /// Pack a string with size limit check.
 #[inline]
 pub fn pack_string<Out: Write>(val: &str, maxsz: Option<usize>, out: &mut Out) -> Result<usize> {
@@ -271,32 +476,47 @@ pub fn unpack_opaque_array<In: Read>(
     input: &mut In,
     bytes: &mut [u8],
     bytesz: usize,
+) -> Result<usize> {
+    try_unpack_opaque_array(input, bytes, bytesz)
+}
+
+/// Unpack a fixed-sized opaque array, reporting short input
+///
+/// As `unpack_opaque_array`, but if the input ends early this fails with `Error::ShortRead`, giving
+/// the number of bytes (including padding) which were missing. Returns the size on the wire,
+/// including padding.
+pub fn try_unpack_opaque_array<In: Read>(
+    input: &mut In,
+    bytes: &mut [u8],
+    bytesz: usize,
 ) -> Result<usize> {
     let sz = min(bytesz, bytes.len());
+    let wiresz = bytesz + padding(bytesz).len();
     let mut rsz = 0;
 
     while rsz < sz {
-        let r = input.read(&mut bytes[rsz..])?;
-        rsz += r;
+        match input.read(&mut bytes[rsz..sz]) {
+            Ok(0) => return Err(Error::short_read(wiresz - rsz)),
+            Ok(r) => rsz += r,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.into()),
+        }
     }
 
     // Fill in excess
-    if sz < bytes.len() {
-        for b in &mut bytes[sz..] {
-            *b = 0;
-        }
+    for b in &mut bytes[sz..] {
+        *b = 0;
     }
 
     // Mop up unused data on the wire and padding
-    let p = padding(bytesz).len();
-    if bytes.len() < bytesz + p {
-        for _ in bytes.len()..(bytesz + p) {
-            let _ = input.read_u8()?;
-            rsz += 1;
-        }
+    let rest = (wiresz - sz) as u64;
+    let skipped = io::skip(input, rest)?;
+    if skipped < rest {
+        return Err(Error::short_read((rest - skipped) as usize));
     }
+    dialect::advance(wiresz);
 
-    Ok(rsz)
+    Ok(wiresz)
 }
 
 /// Unpack a (perhaps) length-limited array
@@ -307,6 +527,8 @@ pub fn unpack_flex<In: Read, T: Unpack<In>>(
     let (elems, mut sz) = Unpack::unpack(input)?;
 
     check_maxsz(maxsz, elems)?;
+    limits::flex::<T>(elems)?;
+    let _nested = limits::nest()?;
 
     // TODO_THINK_ABOUT: One can cause allocation maximum exceeding in case
     // of XDR protocol missmatch (different XDR-files or invalid input data).
@@ -319,11 +541,7 @@ pub fn unpack_flex<In: Read, T: Unpack<In>>(
         sz += esz;
     }
 
-    let p = padding(sz);
-    for _ in 0..p.len() {
-        let _ = input.read_u8()?;
-    }
-    sz += p.len();
+    sz += skip_padding(input, padding(sz).len())?;
 
     Ok((out, sz))
 }
@@ -334,33 +552,125 @@ pub fn unpack_flex<In: Read, T: Unpack<In>>(
 pub fn unpack_opaque_flex<In: Read>(
     input: &mut In,
     maxsz: Option<usize>,
+) -> Result<(Vec<u8>, usize)> {
+    unpack_bytes(input, maxsz, limits::flex::<u8>)
+}
+
+// Opaque data or string bytes, accounted against `Limits` by `limit`
+fn unpack_bytes<In: Read>(
+    input: &mut In,
+    maxsz: Option<usize>,
+    limit: fn(usize) -> Result<()>,
 ) -> Result<(Vec<u8>, usize)> {
     let (elems, mut sz): (usize, _) = Unpack::unpack(input)?;
 
     check_maxsz(maxsz, elems)?;
+    limit(elems)?;
 
     // TODO_THINK_ABOUT: same as unpack_flex
     // let mut out = Vec::with_capacity(elems);
     let mut out = vec![];
 
-    sz += input.take(elems as u64).read_to_end(&mut out)?;
+    sz += counted(io::read_up_to(input, elems, &mut out)?);
 
-    let p = padding(sz);
-    for _ in 0..p.len() {
-        let _ = input.read_u8()?;
-    }
-    sz += p.len();
+    sz += skip_padding(input, padding(sz).len())?;
+
+    Ok((out, sz))
+}
+
+/// Unpack the rest of the input as raw bytes
+///
+/// For data which can't be decoded and doesn't record its length, such as the arm of a union
+/// for a discriminant the spec doesn't know. Everything up to the end of the input is taken, so
+/// this is only right at the end of a message. The bytes count against `Limits` as a flex array,
+/// and no more than one byte past what they allow is read.
+pub fn unpack_rest<In: Read>(input: &mut In) -> Result<(Vec<u8>, usize)> {
+    // One byte more than the limits allow is enough to know they're exceeded, without reading
+    // (and keeping) all of a hostile input
+    let room = limits::flex_room::<u8>();
+    let mut out = vec![];
+    let sz = counted(io::read_up_to(input, room.saturating_add(1), &mut out)?);
+    limits::flex::<u8>(sz)?;
 
     Ok((out, sz))
 }
 
 /// Unpack (perhaps) length-limited string
 pub fn unpack_string<In: Read>(input: &mut In, maxsz: Option<usize>) -> Result<(String, usize)> {
-    let (v, sz) = unpack_opaque_flex(input, maxsz)?;
+    let (v, sz) = unpack_bytes(input, maxsz, limits::string)?;
 
     String::from_utf8(v).map_err(Error::from).map(|s| (s, sz))
 }
 
+/// Unpack (perhaps) length-limited string, whether or not it's UTF-8
+pub fn unpack_xdr_string<In: Read>(input: &mut In, maxsz: Option<usize>) -> Result<(XdrString, usize)> {
+    unpack_bytes(input, maxsz, limits::string).map(|(v, sz)| (XdrString(v), sz))
+}
+
+/// Pack a map as a dynamically sized array of key/value pairs, with size limit check.
+///
+/// This is the encoding of an XDR `struct entry { key; value; } entries<>;` declaration. Entries
+/// are packed in iteration order using `pack_entry`, which is expected to pack the key followed
+/// by the value.
+pub fn pack_map_with<'a, Out, K, V, I, F>(
+    entries: I,
+    maxsz: Option<usize>,
+    out: &mut Out,
+    mut pack_entry: F,
+) -> Result<usize>
+where
+    Out: Write,
+    K: 'a,
+    V: 'a,
+    I: IntoIterator<Item = (&'a K, &'a V)>,
+    I::IntoIter: ExactSizeIterator,
+    F: FnMut(&'a K, &'a V, &mut Out) -> Result<usize>,
+{
+    let entries = entries.into_iter();
+
+    check_maxsz(maxsz, entries.len())?;
+    check_maxsz(u32::MAX as usize, entries.len())?;
+
+    let mut sz = entries.len().pack(out)?;
+    for (k, v) in entries {
+        sz += pack_entry(k, v, out)?;
+    }
+
+    Ok(sz)
+}
+
+/// Unpack a (perhaps) length-limited array of key/value pairs into a map.
+///
+/// The counterpart of `pack_map_with`; `unpack_entry` decodes a key followed by a value. If a key
+/// appears more than once, which value is kept depends on the map type's `FromIterator`
+/// implementation; for the standard maps it is the last one.
+pub fn unpack_map_with<In, K, V, M, F>(
+    input: &mut In,
+    maxsz: Option<usize>,
+    mut unpack_entry: F,
+) -> Result<(M, usize)>
+where
+    In: Read,
+    M: FromIterator<(K, V)>,
+    F: FnMut(&mut In) -> Result<((K, V), usize)>,
+{
+    let (elems, mut sz): (usize, _) = Unpack::unpack(input)?;
+
+    check_maxsz(maxsz, elems)?;
+    limits::flex::<(K, V)>(elems)?;
+    let _nested = limits::nest()?;
+
+    let map = (0..elems)
+        .map(|_| {
+            let (entry, esz) = unpack_entry(input)?;
+            sz += esz;
+            Ok(entry)
+        })
+        .collect::<Result<M>>()?;
+
+    Ok((map, sz))
+}
+
 /// Basic packing trait.
 ///
 /// This trait is used to implement XDR packing any Rust type into a
@@ -375,13 +685,64 @@ pub trait Pack<Out: Write> {
     fn pack(&self, out: &mut Out) -> Result<usize>;
 }
 
+/// The size of a value's encoding, such as to size a buffer or fill in a length-prefixed
+/// envelope before packing.
+///
+/// By default, the value is packed into a sink which discards the bytes, so this fails just as
+/// packing would. Types whose encoding always has the same length can say so instead; xdrgen's
+/// generated types with a `PACKED_SIZE` do. In a `Dialect` scope, the size is that of packing the
+/// value at the current offset, which is left as it was.
+pub trait PackedSize: Pack<io::Sink> {
+    fn packed_size(&self) -> Result<usize> {
+        measure(self)
+    }
+}
+
+/// The size of `val`'s encoding, measured by packing it into a sink, as `PackedSize` does by
+/// default.
+#[inline]
+pub fn measure<T: Pack<io::Sink> + ?Sized>(val: &T) -> Result<usize> {
+    dialect::measure(|| val.pack(&mut io::sink()))
+}
+
+/// Whether a type's encoding on this thread is its standard length, and so the `PACKED_SIZE` of a
+/// fixed-length type. It isn't in a `Dialect` scope which aligns hypers.
+#[inline]
+pub fn standard_sizes() -> bool {
+    !dialect::aligns_hyper()
+}
+
+macro_rules! packed_size_measured {
+    ($($ty:ty),*) => {
+        $(
+            impl PackedSize for $ty {}
+        )*
+    };
+}
+
+packed_size_measured!(u32, i32, u64, i64, bool, (), usize, str, XdrString);
+#[cfg(feature = "bytecodec")]
+packed_size_measured!(u8, i8);
+#[cfg(feature = "float")]
+packed_size_measured!(f32, f64);
+
+impl<'a> PackedSize for Opaque<'a> {}
+impl<T: Pack<io::Sink>> PackedSize for [T] {}
+impl<T: Pack<io::Sink>> PackedSize for Vec<T> {}
+impl<T: Pack<io::Sink>> PackedSize for Option<T> {}
+impl<T: Pack<io::Sink> + ?Sized> PackedSize for Box<T> {}
+impl<T: Pack<io::Sink> + ?Sized> PackedSize for &T {}
+impl<'a, T: 'a + Pack<io::Sink> + ToOwned<Owned = T>> PackedSize for Cow<'a, T> {}
+#[cfg(feature = "maps")]
+impl<K: Pack<io::Sink>, V: Pack<io::Sink>> PackedSize for BTreeMap<K, V> {}
+#[cfg(all(feature = "maps", feature = "std"))]
+impl<K: Pack<io::Sink>, V: Pack<io::Sink>, S> PackedSize for HashMap<K, V, S> {}
+
 #[cfg(feature = "bytecodec")]
 impl<Out: Write> Pack<Out> for u8 {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        out.write_u32::<BigEndian>(*self as u32)
-            .map_err(Error::from)
-            .map(|_| 4)
+        write_be(out, (*self as u32).to_be_bytes())
     }
 }
 
@@ -389,63 +750,53 @@ impl<Out: Write> Pack<Out> for u8 {
 impl<Out: Write> Pack<Out> for i8 {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        out.write_i32::<BigEndian>(*self as i32)
-            .map_err(Error::from)
-            .map(|_| 4)
+        write_be(out, (*self as i32).to_be_bytes())
     }
 }
 
 impl<Out: Write> Pack<Out> for u32 {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        out.write_u32::<BigEndian>(*self).map_err(Error::from).map(
-            |_| 4,
-        )
+        write_be(out, self.to_be_bytes())
     }
 }
 
 impl<Out: Write> Pack<Out> for i32 {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        out.write_i32::<BigEndian>(*self).map_err(Error::from).map(
-            |_| 4,
-        )
+        write_be(out, self.to_be_bytes())
     }
 }
 
 impl<Out: Write> Pack<Out> for u64 {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        out.write_u64::<BigEndian>(*self).map_err(Error::from).map(
-            |_| 8,
-        )
+        let gap = write_padding(out, dialect::hyper_gap())?;
+        Ok(gap + write_be(out, self.to_be_bytes())?)
     }
 }
 
 impl<Out: Write> Pack<Out> for i64 {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        out.write_i64::<BigEndian>(*self).map_err(Error::from).map(
-            |_| 8,
-        )
+        let gap = write_padding(out, dialect::hyper_gap())?;
+        Ok(gap + write_be(out, self.to_be_bytes())?)
     }
 }
 
+#[cfg(feature = "float")]
 impl<Out: Write> Pack<Out> for f32 {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        out.write_f32::<BigEndian>(*self).map_err(Error::from).map(
-            |_| 4,
-        )
+        write_be(out, self.to_be_bytes())
     }
 }
 
+#[cfg(feature = "float")]
 impl<Out: Write> Pack<Out> for f64 {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        out.write_f64::<BigEndian>(*self).map_err(Error::from).map(
-            |_| 8,
-        )
+        write_be(out, self.to_be_bytes())
     }
 }
 
@@ -470,20 +821,18 @@ impl<Out: Write> Pack<Out> for usize {
     }
 }
 
+/// Slices pack as flex arrays, with a count.
 impl<Out: Write, T: Pack<Out>> Pack<Out> for [T] {
     fn pack(&self, out: &mut Out) -> Result<usize> {
         let len = self.len();
+        check_maxsz(u32::MAX as usize, len)?;
 
         let mut sz = len.pack(out)?;
         for it in self {
             sz += it.pack(out)?;
         }
 
-        let p = padding(sz);
-        if p.len() > 0 {
-            out.write_all(p)?;
-            sz += p.len();
-        }
+        sz += write_padding(out, padding(sz).len())?;
 
         Ok(sz)
     }
@@ -492,9 +841,7 @@ impl<Out: Write, T: Pack<Out>> Pack<Out> for [T] {
 impl<Out: Write, T: Pack<Out>> Pack<Out> for Vec<T> {
     #[inline]
     fn pack(&self, out: &mut Out) -> Result<usize> {
-        check_maxsz(u32::max_value() as usize, self.len())?;
-
-        (&self[..]).pack(out)
+        self[..].pack(out)
     }
 }
 
@@ -503,18 +850,14 @@ impl<'a, Out: Write> Pack<Out> for Opaque<'a> {
         let mut sz;
         let data: &[u8] = self.0.borrow();
 
-        check_maxsz(u32::max_value() as usize, data.len())?;
+        check_maxsz(u32::MAX as usize, data.len())?;
 
         sz = data.len().pack(out)?;
 
         out.write_all(data)?;
-        sz += data.len();
+        sz += counted(data.len());
 
-        let p = padding(sz);
-        if p.len() > 0 {
-            out.write_all(p)?;
-            sz += p.len();
-        }
+        sz += write_padding(out, padding(sz).len())?;
 
         Ok(sz)
     }
@@ -527,6 +870,13 @@ impl<Out: Write> Pack<Out> for str {
     }
 }
 
+impl<Out: Write> Pack<Out> for XdrString {
+    #[inline]
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        Opaque::borrowed(&self.0).pack(out)
+    }
+}
+
 impl<Out: Write, T: Pack<Out>> Pack<Out> for Option<T> {
     fn pack(&self, out: &mut Out) -> Result<usize> {
         match self {
@@ -539,13 +889,21 @@ impl<Out: Write, T: Pack<Out>> Pack<Out> for Option<T> {
     }
 }
 
-impl<Out: Write, T: Pack<Out>> Pack<Out> for Box<T> {
+impl<Out: Write, T: Pack<Out> + ?Sized> Pack<Out> for Box<T> {
     fn pack(&self, out: &mut Out) -> Result<usize> {
         let t: &T = self.borrow();
         t.pack(out)
     }
 }
 
+/// Pack borrowed data, such as `&str` or `&[T]`, without copying it into an owned type.
+impl<Out: Write, T: Pack<Out> + ?Sized> Pack<Out> for &T {
+    #[inline]
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        (**self).pack(out)
+    }
+}
+
 impl<'a, Out: Write, T> Pack<Out> for Cow<'a, T>
 where
     T: 'a + Pack<Out> + ToOwned<Owned = T>,
@@ -556,6 +914,125 @@ where
     }
 }
 
+#[cfg(feature = "maps")]
+impl<Out: Write, K: Pack<Out>, V: Pack<Out>> Pack<Out> for BTreeMap<K, V> {
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_map_with(self, None, out, |k, v, out| Ok(k.pack(out)? + v.pack(out)?))
+    }
+}
+
+#[cfg(all(feature = "maps", feature = "std"))]
+impl<Out: Write, K: Pack<Out>, V: Pack<Out>, S> Pack<Out> for HashMap<K, V, S> {
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_map_with(self, None, out, |k, v, out| Ok(k.pack(out)? + v.pack(out)?))
+    }
+}
+
+/// Validation of a value against its XDR specification.
+///
+/// `Pack` checks constraints such as flex array bounds as it reaches them, by which time it may
+/// have written part of the value. `Validate` checks the whole value up front, so that `TryPack`
+/// can fail without writing anything. xdrgen generates implementations with its `try_pack`
+/// feature.
+pub trait Validate {
+    fn validate(&self) -> Result<()>;
+}
+
+/// Packing which validates the value first.
+///
+/// This is implemented for everything which implements both `Pack` and `Validate`.
+pub trait TryPack<Out: Write>: Pack<Out> + Validate {
+    fn try_pack(&self, out: &mut Out) -> Result<usize> {
+        self.validate()?;
+        self.pack(out)
+    }
+}
+
+impl<Out: Write, T: Pack<Out> + Validate + ?Sized> TryPack<Out> for T {}
+
+/// Check the length of the flex array `name` against its bound, if any.
+#[inline]
+pub fn validate_len(name: &'static str, len: usize, maxsz: Option<usize>) -> Result<()> {
+    match maxsz {
+        Some(max) if len > max => Err(Error::length_exceeded(name, len, max)),
+        _ => Ok(()),
+    }
+}
+
+macro_rules! validate_always {
+    ($($ty:ty),*) => {
+        $(
+            impl Validate for $ty {
+                #[inline]
+                fn validate(&self) -> Result<()> {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+validate_always!(u8, i8, u32, i32, u64, i64, bool, (), String, str);
+#[cfg(feature = "float")]
+validate_always!(f32, f64);
+
+impl<'a> Validate for Opaque<'a> {
+    #[inline]
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Validate> Validate for [T] {
+    fn validate(&self) -> Result<()> {
+        self.iter().try_for_each(Validate::validate)
+    }
+}
+
+impl<T: Validate, const N: usize> Validate for [T; N] {
+    fn validate(&self) -> Result<()> {
+        self[..].validate()
+    }
+}
+
+impl<T: Validate> Validate for Vec<T> {
+    fn validate(&self) -> Result<()> {
+        self[..].validate()
+    }
+}
+
+impl<T: Validate> Validate for Option<T> {
+    fn validate(&self) -> Result<()> {
+        self.as_ref().map_or(Ok(()), Validate::validate)
+    }
+}
+
+impl<T: Validate + ?Sized> Validate for Box<T> {
+    fn validate(&self) -> Result<()> {
+        (**self).validate()
+    }
+}
+
+impl<T: Validate + ?Sized> Validate for &T {
+    fn validate(&self) -> Result<()> {
+        (**self).validate()
+    }
+}
+
+#[cfg(feature = "maps")]
+impl<K: Validate, V: Validate> Validate for BTreeMap<K, V> {
+    fn validate(&self) -> Result<()> {
+        self.iter().try_for_each(|(k, v)| k.validate().and_then(|_| v.validate()))
+    }
+}
+
+#[cfg(all(feature = "maps", feature = "std"))]
+impl<K: Validate, V: Validate, S> Validate for HashMap<K, V, S> {
+    fn validate(&self) -> Result<()> {
+        self.iter().try_for_each(|(k, v)| k.validate().and_then(|_| v.validate()))
+    }
+}
+
 /// Deserialization (unpacking) helper function
 ///
 /// This function will read encoded bytes from `input` (a `Read`
@@ -567,6 +1044,19 @@ pub fn unpack<In: Read, T: Unpack<In>>(input: &mut In) -> Result<T> {
     T::unpack(input).map(|(v, _)| v)
 }
 
+/// Unpack one field of a larger value, attributing any error to `field`; used by generated code.
+///
+/// `consumed` is the number of bytes of the enclosing value already decoded. The error records
+/// the field path and total bytes consumed, so that the caller can report or skip a corrupt
+/// message; see `Error::in_field`.
+#[inline]
+pub fn unpack_field<T, F>(field: &'static str, consumed: usize, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    f().map_err(|err| err.in_field(field, consumed))
+}
+
 /// Basic unpacking trait
 ///
 /// This trait is used to unpack a type from an XDR encoded byte
@@ -583,11 +1073,7 @@ pub trait Unpack<In: Read>: Sized {
 impl<In: Read> Unpack<In> for u8 {
     #[inline]
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        input.read_u32::<BigEndian>().map_err(Error::from).map(
-            |v| {
-                (v as u8, 4)
-            },
-        )
+        read_be(input).map(|(v, sz)| (u32::from_be_bytes(v) as u8, sz))
     }
 }
 
@@ -595,63 +1081,51 @@ impl<In: Read> Unpack<In> for u8 {
 impl<In: Read> Unpack<In> for i8 {
     #[inline]
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        input.read_i32::<BigEndian>().map_err(Error::from).map(
-            |v| {
-                (v as i8, 4)
-            },
-        )
+        read_be(input).map(|(v, sz)| (i32::from_be_bytes(v) as i8, sz))
     }
 }
 
 impl<In: Read> Unpack<In> for u32 {
     #[inline]
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        input.read_u32::<BigEndian>().map_err(Error::from).map(
-            |v| (v, 4),
-        )
+        read_be(input).map(|(v, sz)| (u32::from_be_bytes(v), sz))
     }
 }
 
 impl<In: Read> Unpack<In> for i32 {
     #[inline]
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        input.read_i32::<BigEndian>().map_err(Error::from).map(
-            |v| (v, 4),
-        )
+        read_be(input).map(|(v, sz)| (i32::from_be_bytes(v), sz))
     }
 }
 
 impl<In: Read> Unpack<In> for u64 {
     #[inline]
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        input.read_u64::<BigEndian>().map_err(Error::from).map(
-            |v| (v, 8),
-        )
+        let gap = skip_padding(input, dialect::hyper_gap())?;
+        read_be(input).map(|(v, sz)| (u64::from_be_bytes(v), gap + sz))
     }
 }
 
 impl<In: Read> Unpack<In> for i64 {
     #[inline]
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        input.read_i64::<BigEndian>().map_err(Error::from).map(
-            |v| (v, 8),
-        )
+        let gap = skip_padding(input, dialect::hyper_gap())?;
+        read_be(input).map(|(v, sz)| (i64::from_be_bytes(v), gap + sz))
     }
 }
 
+#[cfg(feature = "float")]
 impl<In: Read> Unpack<In> for f32 {
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        input.read_f32::<BigEndian>().map_err(Error::from).map(
-            |v| (v, 4),
-        )
+        read_be(input).map(|(v, sz)| (f32::from_be_bytes(v), sz))
     }
 }
 
+#[cfg(feature = "float")]
 impl<In: Read> Unpack<In> for f64 {
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        input.read_f64::<BigEndian>().map_err(Error::from).map(
-            |v| (v, 8),
-        )
+        read_be(input).map(|(v, sz)| (f64::from_be_bytes(v), sz))
     }
 }
 
@@ -688,22 +1162,24 @@ impl<In: Read, T: Unpack<In>> Unpack<In> for Vec<T> {
 
 impl<In: Read> Unpack<In> for String {
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
-        let (v, sz) = unpack_opaque_flex(input, None)?;
-        String::from_utf8(v).map_err(Error::from).map(|s| (s, sz))
+        unpack_string(input, None)
+    }
+}
+
+impl<In: Read> Unpack<In> for XdrString {
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        unpack_xdr_string(input, None)
     }
 }
 
 impl<'a, In: Read> Unpack<In> for Opaque<'a> {
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
         let (len, mut sz) = usize::unpack(input)?;
+        limits::flex::<u8>(len)?;
         let mut v = Vec::new();
-        sz += input.by_ref().take(len as u64).read_to_end(&mut v)?;
+        sz += counted(io::read_up_to(input, len, &mut v)?);
 
-        let p = padding(sz);
-        for _ in 0..p.len() {
-            let _ = input.read_u8()?;
-            sz += 1;
-        }
+        sz += skip_padding(input, padding(sz).len())?;
 
         Ok((Opaque(Cow::Owned(v)), sz))
     }
@@ -712,6 +1188,7 @@ impl<'a, In: Read> Unpack<In> for Opaque<'a> {
 impl<In: Read, T: Unpack<In>> Unpack<In> for Option<T> {
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
         let (have, mut sz) = Unpack::unpack(input)?;
+        let _nested = limits::nest()?;
         let ret = if have {
             let (v, osz) = Unpack::unpack(input)?;
             sz += osz;
@@ -725,6 +1202,7 @@ impl<In: Read, T: Unpack<In>> Unpack<In> for Option<T> {
 
 impl<In: Read, T: Unpack<In>> Unpack<In> for Box<T> {
     fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        let _nested = limits::nest()?;
         let (b, sz) = Unpack::unpack(input)?;
         Ok((Box::new(b), sz))
     }
@@ -739,3 +1217,30 @@ where
         Ok((Cow::Owned(b), sz))
     }
 }
+
+#[cfg(feature = "maps")]
+impl<In: Read, K: Unpack<In> + Ord, V: Unpack<In>> Unpack<In> for BTreeMap<K, V> {
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        unpack_map_with(input, None, unpack_entry)
+    }
+}
+
+#[cfg(all(feature = "maps", feature = "std"))]
+impl<In, K, V, S> Unpack<In> for HashMap<K, V, S>
+where
+    In: Read,
+    K: Unpack<In> + Eq + Hash,
+    V: Unpack<In>,
+    S: BuildHasher + Default,
+{
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        unpack_map_with(input, None, unpack_entry)
+    }
+}
+
+#[cfg(feature = "maps")]
+fn unpack_entry<In: Read, K: Unpack<In>, V: Unpack<In>>(input: &mut In) -> Result<((K, V), usize)> {
+    let (k, ksz) = Unpack::unpack(input)?;
+    let (v, vsz) = Unpack::unpack(input)?;
+    Ok(((k, v), ksz + vsz))
+}
@@ -0,0 +1,170 @@
+//! Decoding safety limits
+//!
+//! `Limits` collects the limits which protect a decoder from hostile or mismatched input into one
+//! policy. A policy applies to everything decoded on the current thread within `Limits::scope()`
+//! (or a `Limits::unpack()` call), on top of any bounds in the XDR specification itself.
+//!
+//! The limits are enforced by this crate's `Unpack` implementations and `unpack_*` helpers, so
+//! they cover generated code as well as hand-written code built from them.
+use std::cell::Cell;
+use std::mem::size_of;
+
+use super::{Error, Read, Result, Unpack};
+
+/// Policy for decoding untrusted input. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Limits {
+    /// Longest string, in bytes.
+    pub max_string: Option<usize>,
+    /// Most elements in a flex array, including opaque data.
+    pub max_flex_elems: Option<usize>,
+    /// Most memory allocated for strings and arrays over the whole decode, in bytes. This is an
+    /// estimate based on element sizes, not counting any heap data the elements themselves own.
+    pub max_total_alloc: Option<usize>,
+    /// Deepest nesting of optional, boxed and flex array values; these are what recursive types
+    /// are built from.
+    pub max_depth: Option<usize>,
+}
+
+/// Builder for `Limits`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LimitsBuilder(Limits);
+
+impl LimitsBuilder {
+    pub fn max_string(mut self, max: usize) -> Self {
+        self.0.max_string = Some(max);
+        self
+    }
+
+    pub fn max_flex_elems(mut self, max: usize) -> Self {
+        self.0.max_flex_elems = Some(max);
+        self
+    }
+
+    pub fn max_total_alloc(mut self, max: usize) -> Self {
+        self.0.max_total_alloc = Some(max);
+        self
+    }
+
+    pub fn max_depth(mut self, max: usize) -> Self {
+        self.0.max_depth = Some(max);
+        self
+    }
+
+    pub fn build(self) -> Limits {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct State {
+    limits: Limits,
+    alloc: usize,
+    depth: usize,
+}
+
+thread_local! {
+    static STATE: Cell<State> = Cell::new(State::default());
+}
+
+impl Limits {
+    pub fn builder() -> LimitsBuilder {
+        LimitsBuilder::default()
+    }
+
+    /// Run `f` with these limits applied to everything it decodes on this thread.
+    ///
+    /// The allocation and depth accounting starts afresh, and the previous policy is restored
+    /// afterwards.
+    pub fn scope<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        struct Restore(State);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                STATE.with(|s| s.set(self.0));
+            }
+        }
+
+        let state = State { limits: *self, ..State::default() };
+        let _restore = Restore(STATE.with(|s| s.replace(state)));
+
+        f()
+    }
+
+    /// Unpack a `T` from `input` with these limits.
+    pub fn unpack<In: Read, T: Unpack<In>>(&self, input: &mut In) -> Result<T> {
+        self.scope(|| super::unpack(input))
+    }
+}
+
+fn check(limit: &'static str, value: usize, max: Option<usize>) -> Result<()> {
+    match max {
+        Some(max) if value > max => Err(Error::limit_exceeded(limit, value, max)),
+        _ => Ok(()),
+    }
+}
+
+/// Account for a string of `len` bytes about to be decoded.
+pub(crate) fn string(len: usize) -> Result<()> {
+    let limits = STATE.with(|s| s.get()).limits;
+    check("max_string", len, limits.max_string)?;
+    alloc(len)
+}
+
+/// Account for a flex array of `elems` `T`s about to be decoded.
+pub(crate) fn flex<T>(elems: usize) -> Result<()> {
+    let limits = STATE.with(|s| s.get()).limits;
+    check("max_flex_elems", elems, limits.max_flex_elems)?;
+    alloc(elems.saturating_mul(size_of::<T>()))
+}
+
+/// The most `T`s a flex array could have without exceeding the limits, to read no more than
+/// that of input which doesn't give its length.
+pub(crate) fn flex_room<T>() -> usize {
+    let state = STATE.with(|s| s.get());
+    let elems = state.limits.max_flex_elems.unwrap_or(usize::MAX);
+    let alloc = match state.limits.max_total_alloc {
+        Some(max) => max.saturating_sub(state.alloc) / size_of::<T>().max(1),
+        None => usize::MAX,
+    };
+    elems.min(alloc)
+}
+
+fn alloc(bytes: usize) -> Result<()> {
+    STATE.with(|s| {
+        let mut state = s.get();
+        state.alloc = state.alloc.saturating_add(bytes);
+        check("max_total_alloc", state.alloc, state.limits.max_total_alloc)?;
+
+        s.set(state);
+        Ok(())
+    })
+}
+
+/// Tracks one level of nesting; see `nest()`.
+pub(crate) struct Nested(());
+
+impl Drop for Nested {
+    fn drop(&mut self) {
+        STATE.with(|s| {
+            let mut state = s.get();
+            state.depth -= 1;
+            s.set(state);
+        })
+    }
+}
+
+/// Enter a nested value, which lasts until the returned guard is dropped.
+pub(crate) fn nest() -> Result<Nested> {
+    STATE.with(|s| {
+        let mut state = s.get();
+        state.depth += 1;
+        check("max_depth", state.depth, state.limits.max_depth)?;
+
+        s.set(state);
+        Ok(Nested(()))
+    })
+}
@@ -17,7 +17,7 @@ use std::cmp::min;
 
 use super::{Error, pack, unpack};
 
-const LAST_REC: u32 = 1u32 << 31;
+pub(crate) const LAST_REC: u32 = 1u32 << 31;
 
 fn mapioerr(xdrerr: Error) -> io::Error {
     match xdrerr {
@@ -2,9 +2,10 @@
 #![cfg_attr(rustfmt, rustfmt_skip)]
 
 use std::io::Cursor;
-use super::{Error, Pack, Unpack, Opaque,
+use super::{Error, Pack, PackedSize, Unpack, Opaque, XdrString, Validate, TryPack,
             pack_flex, pack_opaque_flex, pack_string, pack_array, pack_opaque_array,
-            unpack_array, unpack_opaque_array, unpack_string, unpack_flex, unpack_opaque_flex};
+            unpack_array, unpack_opaque_array, try_unpack_opaque_array, unpack_string, unpack_xdr_string, unpack_flex, unpack_opaque_flex,
+            pack_map_with, unpack_map_with, pack_rest, unpack_rest, validate_len, unpack_field, Read, Result};
 
 
 #[cfg(feature = "bytecodec")]
@@ -486,6 +487,18 @@ fn bounded_opaque_flex() {
     }
 }
 
+#[test]
+fn rest() {
+    let mut out = Cursor::new(Vec::new());
+
+    assert_eq!(7u32.pack(&mut out).unwrap() + pack_rest(&[1, 2, 3], &mut out).unwrap(), 7);
+
+    let mut input = Cursor::new(out.into_inner());
+    assert_eq!(u32::unpack(&mut input).unwrap(), (7, 4));
+    assert_eq!(unpack_rest(&mut input).unwrap(), (vec![1, 2, 3], 3));
+    assert_eq!(unpack_rest(&mut input).unwrap(), (vec![], 0));
+}
+
 #[test]
 fn bounded_string() {
     let mut out = Cursor::new(Vec::new());
@@ -508,6 +521,42 @@ fn bounded_string() {
     }
 }
 
+#[test]
+fn xdr_string() {
+    // Latin-1, which `String` can't hold
+    let v = vec![0x00, 0x00, 0x00, 0x05, 0x63, 0x61, 0x66, 0xe9, 0x21, 0x00, 0x00, 0x00];
+    assert!(unpack_string(&mut Cursor::new(v.clone()), None).is_err());
+
+    let (s, sz) = unpack_xdr_string(&mut Cursor::new(v.clone()), Some(5)).unwrap();
+    assert_eq!((s.as_bytes(), sz), (&b"caf\xe9!"[..], 12));
+    assert!(s.to_str().is_err());
+    assert_eq!(s.to_string_lossy(), "caf\u{fffd}!");
+
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(s.pack(&mut out).unwrap(), 12);
+    assert_eq!(out.into_inner(), v);
+
+    match unpack_xdr_string(&mut Cursor::new(v), Some(4)) {
+        Result::Err(Error::InvalidLen{..}) => (),
+        e => panic!("Unexpected {:?}", e),
+    }
+    assert_eq!(XdrString::from("foo").to_str(), Ok("foo"));
+}
+
+#[test]
+fn packed_size() {
+    assert_eq!(7u32.packed_size().unwrap(), 4);
+    assert_eq!(String::from("foo!").packed_size().unwrap(), 8);
+    assert_eq!(vec![1u64, 2, 3].packed_size().unwrap(), 28);
+    assert_eq!(Some(Opaque::borrowed(b"abcde")).packed_size().unwrap(), 16);
+    assert_eq!(Vec::<u32>::new().packed_size().unwrap(), 4);
+
+    let v = (vec![0x11u32, 0x22], String::from("x"));
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!((v.0.pack(&mut out).unwrap() + v.1.pack(&mut out).unwrap()),
+               v.0.packed_size().unwrap() + v.1.packed_size().unwrap());
+}
+
 #[test]
 fn basic_array() {
     {
@@ -710,6 +759,32 @@ fn basic_opaque_array() {
     }
 }
 
+#[test]
+fn short_opaque_array() {
+    // 5 bytes + 3 padding on the wire
+    let mut b = [0u8; 5];
+
+    let mut input = Cursor::new(vec![0x11u8, 0x22]);
+    match try_unpack_opaque_array(&mut input, &mut b[..], 5) {
+        Err(Error::ShortRead { missing: 6 }) => (),
+        res => panic!("bad result {:?}", res),
+    }
+
+    let mut input = Cursor::new(vec![0x11u8, 0x22, 0x33, 0x44, 0x55, 0x00]);
+    match unpack_opaque_array(&mut input, &mut b[..], 5) {
+        Err(Error::ShortRead { missing: 2 }) => (),
+        res => panic!("bad result {:?}", res),
+    }
+
+    // Discarded excess still has to be there
+    let mut small = [0u8; 2];
+    let mut input = Cursor::new(vec![0x11u8, 0x22, 0x33]);
+    match try_unpack_opaque_array(&mut input, &mut small[..], 5) {
+        Err(Error::ShortRead { missing: 5 }) => (),
+        res => panic!("bad result {:?}", res),
+    }
+}
+
 #[test]
 fn basic_option() {
     let mut out = Cursor::new(Vec::new());
@@ -737,3 +812,251 @@ fn basic_option() {
         res => panic!("bad result {:?}", res),
     }
 }
+
+#[test]
+fn basic_map() {
+    use std::collections::BTreeMap;
+
+    let map: BTreeMap<u32, bool> = vec![(2, true), (1, false)].into_iter().collect();
+    let pack_entry = |k: &u32, v: &bool, out: &mut Cursor<Vec<u8>>| Ok(k.pack(out)? + v.pack(out)?);
+    let unpack_entry = |input: &mut Cursor<Vec<u8>>| {
+        let (k, ksz): (u32, _) = Unpack::unpack(input)?;
+        let (v, vsz): (bool, _) = Unpack::unpack(input)?;
+        Ok(((k, v), ksz + vsz))
+    };
+
+    {
+        let mut out = Cursor::new(Vec::new());
+
+        assert_eq!(pack_map_with(&map, None, &mut out, pack_entry).unwrap(), 2*8 + 4);
+
+        let v = out.into_inner();
+
+        assert_eq!(v, vec![0x00, 0x00, 0x00, 0x02,
+                           0x00, 0x00, 0x00, 0x01,  0x00, 0x00, 0x00, 0x00,
+                           0x00, 0x00, 0x00, 0x02,  0x00, 0x00, 0x00, 0x01]);
+
+        let mut input = Cursor::new(v.clone());
+        assert_eq!(unpack_map_with(&mut input, Some(2), unpack_entry).unwrap(), (map.clone(), 2*8 + 4));
+
+        let mut input = Cursor::new(v);
+        match unpack_map_with::<_, u32, bool, BTreeMap<_, _>, _>(&mut input, Some(1), unpack_entry) {
+            Err(Error::InvalidLen { len: 1 }) => (),
+            res => panic!("bad result {:?}", res),
+        }
+    }
+
+    {
+        let mut out = Cursor::new(Vec::new());
+
+        match pack_map_with(&map, Some(1), &mut out, pack_entry) {
+            Err(Error::InvalidLen { len: 1 }) => (),
+            res => panic!("bad result {:?}", res),
+        }
+    }
+}
+
+#[cfg(feature = "maps")]
+#[test]
+fn map_impls() {
+    use std::collections::{BTreeMap, HashMap};
+
+    let map: BTreeMap<u32, i64> = vec![(3, -3), (1, 1)].into_iter().collect();
+    let mut out = Cursor::new(Vec::new());
+
+    assert_eq!(map.pack(&mut out).unwrap(), 4 + 2*(4 + 8));
+
+    let v = out.into_inner();
+
+    let mut input = Cursor::new(v.clone());
+    assert_eq!(Unpack::unpack(&mut input).unwrap(), (map.clone(), v.len()));
+
+    let mut input = Cursor::new(v.clone());
+    let (hash, sz): (HashMap<u32, i64>, _) = Unpack::unpack(&mut input).unwrap();
+    assert_eq!(sz, v.len());
+    assert_eq!(hash, map.clone().into_iter().collect());
+
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(hash.pack(&mut out).unwrap(), v.len());
+}
+
+#[test]
+fn try_pack() {
+    // Shaped like xdrgen's generated impls
+    struct Named { name: String, vals: Vec<Option<u32>> }
+
+    impl<Out: super::Write> Pack<Out> for Named {
+        fn pack(&self, out: &mut Out) -> super::Result<usize> {
+            Ok(pack_string(&self.name, None, out)? + pack_flex(&self.vals, Some(2), out)?)
+        }
+    }
+
+    impl Validate for Named {
+        fn validate(&self) -> super::Result<()> {
+            validate_len("Named.name", self.name.len(), None)?;
+            validate_len("Named.vals", self.vals.len(), Some(2))?;
+            self.vals.validate()
+        }
+    }
+
+    let ok = Named { name: "a".into(), vals: vec![Some(1), None] };
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(ok.try_pack(&mut out).unwrap(), 8 + 4 + 8 + 4);
+
+    let long = Named { name: "a".into(), vals: vec![None; 3] };
+    let mut out = Cursor::new(Vec::new());
+    match long.try_pack(&mut out) {
+        Err(Error::LengthExceeded { name: "Named.vals", len: 3, max: 2 }) => (),
+        res => panic!("bad result {:?}", res),
+    }
+    assert!(out.into_inner().is_empty());
+
+    // plain pack gets part way before failing
+    let mut out = Cursor::new(Vec::new());
+    assert!(long.pack(&mut out).is_err());
+    assert_eq!(out.into_inner().len(), 8);
+}
+
+#[test]
+fn borrowed_pack() {
+    let owned = (vec![1u32, 2, 3], String::from("abc"));
+    let mut expect = Cursor::new(Vec::new());
+    owned.0.pack(&mut expect).unwrap();
+    Opaque::borrowed(owned.1.as_bytes()).pack(&mut expect).unwrap();
+    let expect = expect.into_inner();
+
+    let ints: &[u32] = &[1, 2, 3];
+    let s: &str = "abc";
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(ints.pack(&mut out).unwrap() + s.pack(&mut out).unwrap(), expect.len());
+    assert_eq!(out.into_inner(), expect);
+
+    // References to references, and containers of references
+    let mut out = Cursor::new(Vec::new());
+    let refs: Vec<&u32> = ints.iter().collect();
+    assert_eq!((&&ints).pack(&mut out).unwrap() + (&refs).pack(&mut out).unwrap(), 32);
+    let v = out.into_inner();
+    assert_eq!(v[..16], v[16..]);
+}
+
+#[test]
+fn partial_decode_state() {
+    // Shaped like xdrgen's output with the decode_context feature
+    #[derive(Debug)]
+    struct Inner {
+        a: u32,
+        b: String,
+    }
+    #[derive(Debug)]
+    struct Outer {
+        x: u32,
+        inner: Inner,
+    }
+
+    impl<In: Read> Unpack<In> for Inner {
+        fn unpack(input: &mut In) -> Result<(Inner, usize)> {
+            let mut sz = 0;
+            Ok((
+                Inner {
+                    a: { let (v, fsz) = unpack_field("a", sz, || u32::unpack(input))?; sz += fsz; v },
+                    b: { let (v, fsz) = unpack_field("b", sz, || unpack_string(input, Some(4)))?; sz += fsz; v },
+                },
+                sz,
+            ))
+        }
+    }
+    impl<In: Read> Unpack<In> for Outer {
+        fn unpack(input: &mut In) -> Result<(Outer, usize)> {
+            let mut sz = 0;
+            Ok((
+                Outer {
+                    x: { let (v, fsz) = unpack_field("x", sz, || u32::unpack(input))?; sz += fsz; v },
+                    inner: { let (v, fsz) = unpack_field("inner", sz, || Inner::unpack(input))?; sz += fsz; v },
+                },
+                sz,
+            ))
+        }
+    }
+
+    let ok = vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, b'z', 0, 0, 0];
+    let (v, sz) = Outer::unpack(&mut Cursor::new(&ok)).unwrap();
+    assert_eq!((v.x, v.inner.a, v.inner.b.as_str(), sz), (1, 2, "z", 16));
+
+    // String too long
+    let bad = vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 9];
+    let err = Outer::unpack(&mut Cursor::new(&bad)).unwrap_err();
+    assert_eq!(err.field_path(), &["inner", "b"]);
+    assert_eq!(err.consumed(), Some(8));
+    match err.innermost() {
+        Error::InvalidLen { .. } => (),
+        err => panic!("bad cause {:?}", err),
+    }
+    assert_eq!(err.to_string(), "decoding 'inner.b' after 8 bytes");
+    let source = std::error::Error::source(&err).map(ToString::to_string);
+    assert_eq!(source.as_deref(), Some("invalid array len: 4 (0x4)"));
+
+    // Truncated
+    let err = Outer::unpack(&mut Cursor::new(&bad[..6])).unwrap_err();
+    assert_eq!(err.field_path(), &["inner", "a"]);
+    assert_eq!(err.consumed(), Some(4));
+    assert!(matches!(err.innermost(), Error::IOError(_)));
+
+    // Plain errors have no state
+    let err = u32::unpack(&mut Cursor::new(&bad[..2])).unwrap_err();
+    assert_eq!((err.field_path(), err.consumed()), (&[][..], None));
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn chrono_timestamp() {
+    use super::timestamp::ChronoDateTime;
+
+    let time = ChronoDateTime::from_timestamp(-2, 500).unwrap();
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(time.pack(&mut out).unwrap(), 12);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0, 0, 0x01, 0xf4]);
+    assert_eq!(ChronoDateTime::unpack(&mut Cursor::new(v)).unwrap(), (time, 12));
+
+    // A leap second is packed as the end of the second before
+    let leap = ChronoDateTime::from_timestamp(59, 1_000_000_123).unwrap();
+    let mut out = Cursor::new(Vec::new());
+    leap.pack(&mut out).unwrap();
+    assert_eq!(ChronoDateTime::unpack(&mut Cursor::new(out.into_inner())).unwrap().0,
+               ChronoDateTime::from_timestamp(59, 999_999_999).unwrap());
+
+    let bad = vec![0, 0, 0, 0, 0, 0, 0, 1, 0x3b, 0x9a, 0xca, 0x00];
+    match ChronoDateTime::unpack(&mut Cursor::new(bad)) {
+        Err(Error::InvalidTimestamp{seconds: 1, nanos: 1_000_000_000}) => (),
+        res => panic!("unexpected {:?}", res),
+    }
+    let huge = vec![0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0];
+    assert!(ChronoDateTime::unpack(&mut Cursor::new(huge)).is_err());
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn time_timestamp() {
+    use super::timestamp::TimeDateTime;
+
+    let time = TimeDateTime::from_unix_timestamp_nanos(-1_999_999_500).unwrap();
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(time.pack(&mut out).unwrap(), 12);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0, 0, 0x01, 0xf4]);
+    assert_eq!(TimeDateTime::unpack(&mut Cursor::new(v)).unwrap(), (time, 12));
+
+    // Only the instant is encoded
+    let offset = time.to_offset(time::UtcOffset::from_hms(3, 0, 0).unwrap());
+    let mut out = Cursor::new(Vec::new());
+    offset.pack(&mut out).unwrap();
+    assert_eq!(TimeDateTime::unpack(&mut Cursor::new(out.into_inner())).unwrap().0, time);
+
+    let huge = vec![0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0];
+    match TimeDateTime::unpack(&mut Cursor::new(huge)) {
+        Err(Error::InvalidTimestamp{seconds: i64::MAX, nanos: 0}) => (),
+        res => panic!("unexpected {:?}", res),
+    }
+}
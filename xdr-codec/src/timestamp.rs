@@ -0,0 +1,105 @@
+//! Date and time codecs
+//!
+//! With the `chrono` or `time` feature, `chrono::DateTime<Utc>` or `time::OffsetDateTime`
+//! implement `Pack` and `Unpack` with the encoding of
+//!
+//! ```text
+//! struct timestamp {
+//!     hyper seconds;          /* since 1970-01-01T00:00:00Z */
+//!     unsigned int nanos;     /* less than 1000000000 */
+//! };
+//! ```
+//!
+//! xdrgen generates such a struct as one of the aliases here when its definition is annotated
+//! `@chrono` or `@time`, so specs can keep their timestamp type while the generated code uses the
+//! date and time types applications already have.
+//!
+//! Decoding a timestamp which can't be represented, or with too many nanoseconds, fails with
+//! `Error::InvalidTimestamp`.
+
+use super::{Error, Pack, PackedSize, Read, Result, Unpack, Validate, Write};
+
+const NANOS_PER_SEC: u32 = 1_000_000_000;
+
+fn pack_timestamp<Out: Write>(seconds: i64, nanos: u32, out: &mut Out) -> Result<usize> {
+    Ok(seconds.pack(out)? + nanos.pack(out)?)
+}
+
+fn unpack_timestamp<In: Read>(input: &mut In) -> Result<((i64, u32), usize)> {
+    let (seconds, ssz) = i64::unpack(input)?;
+    let (nanos, nsz) = u32::unpack(input)?;
+    if nanos >= NANOS_PER_SEC {
+        return Err(Error::invalid_timestamp(seconds, nanos));
+    }
+    Ok(((seconds, nanos), ssz + nsz))
+}
+
+/// `chrono::DateTime<Utc>`, as generated for `@chrono` timestamps.
+#[cfg(feature = "chrono")]
+pub type ChronoDateTime = chrono::DateTime<chrono::Utc>;
+
+/// `time::OffsetDateTime`, as generated for `@time` timestamps.
+#[cfg(feature = "time")]
+pub type TimeDateTime = time::OffsetDateTime;
+
+/// A leap second, which chrono represents with extra nanoseconds, is packed as the last
+/// nanosecond of the second before.
+#[cfg(feature = "chrono")]
+impl<Out: Write> Pack<Out> for ChronoDateTime {
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_timestamp(self.timestamp(), self.timestamp_subsec_nanos().min(NANOS_PER_SEC - 1), out)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<In: Read> Unpack<In> for ChronoDateTime {
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        let ((seconds, nanos), sz) = unpack_timestamp(input)?;
+        match ChronoDateTime::from_timestamp(seconds, nanos) {
+            Some(time) => Ok((time, sz)),
+            None => Err(Error::invalid_timestamp(seconds, nanos)),
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl PackedSize for ChronoDateTime {}
+
+#[cfg(feature = "chrono")]
+impl Validate for ChronoDateTime {
+    #[inline]
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The offset isn't encoded; it's unpacked as UTC.
+#[cfg(feature = "time")]
+impl<Out: Write> Pack<Out> for TimeDateTime {
+    fn pack(&self, out: &mut Out) -> Result<usize> {
+        pack_timestamp(self.unix_timestamp(), self.nanosecond(), out)
+    }
+}
+
+#[cfg(feature = "time")]
+impl<In: Read> Unpack<In> for TimeDateTime {
+    fn unpack(input: &mut In) -> Result<(Self, usize)> {
+        let ((seconds, nanos), sz) = unpack_timestamp(input)?;
+        let total = i128::from(seconds) * i128::from(NANOS_PER_SEC) + i128::from(nanos);
+        match TimeDateTime::from_unix_timestamp_nanos(total) {
+            Ok(time) => Ok((time, sz)),
+            Err(_) => Err(Error::invalid_timestamp(seconds, nanos)),
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+impl PackedSize for TimeDateTime {}
+
+#[cfg(feature = "time")]
+impl Validate for TimeDateTime {
+    #[inline]
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg(feature = "std")]
+
 extern crate quickcheck;
 extern crate xdr_codec;
 
@@ -1,3 +1,5 @@
+#![cfg(feature = "std")]
+
 extern crate xdr_codec;
 extern crate quickcheck;
 
@@ -103,6 +105,7 @@ fn quickcheck_pack_iu64() {
 }
 
 #[test]
+#[cfg(feature = "float")]
 fn quickcheck_pack_float() {
     quickcheck_pack_t::<f32>();
     quickcheck_pack_t::<f64>();
@@ -122,6 +125,7 @@ fn quickcheck_codec_iu64() {
 }
 
 #[test]
+#[cfg(feature = "float")]
 fn quickcheck_codec_float() {
     quickcheck_codec_t::<f32>();
     quickcheck_codec_t::<f64>();
@@ -141,6 +145,7 @@ fn quickcheck_short_unpack_iu64() {
 }
 
 #[test]
+#[cfg(feature = "float")]
 fn quickcheck_short_unpack_float() {
     quickcheck_short_unpack_t::<f32>();
     quickcheck_short_unpack_t::<f64>();
@@ -0,0 +1,93 @@
+// Don't rustfmt in here to avoid trashing vec![] formatting
+#![cfg_attr(rustfmt, rustfmt_skip)]
+#![cfg(feature = "dialect")]
+
+extern crate xdr_codec;
+
+use std::io::Cursor;
+
+use xdr_codec::{pack_opaque_array, standard_sizes, Dialect, Opaque, Pack, PackedSize, Result, Unpack};
+
+const LEGACY: Dialect = Dialect { pad_byte: 0xff, align_hyper: true };
+
+// A `u32` then a `u64`, as a struct with those fields packs them
+fn pack_pair(out: &mut Cursor<Vec<u8>>) -> Result<usize> {
+    Ok(7u32.pack(out)? + 9u64.pack(out)?)
+}
+
+fn unpack_pair(input: &mut Cursor<Vec<u8>>) -> Result<((u32, u64), usize)> {
+    let (a, asz) = u32::unpack(input)?;
+    let (b, bsz) = u64::unpack(input)?;
+    Ok(((a, b), asz + bsz))
+}
+
+#[test]
+fn pad_byte() {
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(LEGACY.pack(&Opaque::borrowed(b"abc"), &mut out).unwrap(), 8);
+    assert_eq!(out.get_ref(), &vec![0, 0, 0, 3, b'a', b'b', b'c', 0xff]);
+
+    let v = out.into_inner();
+    assert_eq!(LEGACY.unpack::<_, Opaque>(&mut Cursor::new(v.clone())).unwrap().0.as_ref(), b"abc");
+    // Padding is skipped whatever it contains
+    assert_eq!(Opaque::unpack(&mut Cursor::new(v)).unwrap().0.0.as_ref(), b"abc");
+
+    // Filling out a short fixed array is data, not padding
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(LEGACY.scope(|| pack_opaque_array(b"a", 3, &mut out)).unwrap(), 4);
+    assert_eq!(out.into_inner(), vec![b'a', 0, 0, 0xff]);
+
+    // Standard outside the scope
+    let mut out = Cursor::new(Vec::new());
+    Opaque::borrowed(b"abc").pack(&mut out).unwrap();
+    assert_eq!(out.into_inner(), vec![0, 0, 0, 3, b'a', b'b', b'c', 0]);
+}
+
+#[test]
+fn align_hyper() {
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(LEGACY.scope(|| pack_pair(&mut out)).unwrap(), 16);
+
+    let v = out.into_inner();
+    assert_eq!(v, vec![0, 0, 0, 7, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 9]);
+    assert_eq!(LEGACY.scope(|| unpack_pair(&mut Cursor::new(v))).unwrap(), ((7, 9), 16));
+
+    // Already aligned at the start of the scope, and each scope starts afresh
+    for _ in 0..2 {
+        let mut out = Cursor::new(Vec::new());
+        assert_eq!(LEGACY.pack(&9i64, &mut out).unwrap(), 8);
+        assert_eq!(out.into_inner(), vec![0, 0, 0, 0, 0, 0, 0, 9]);
+    }
+
+    // Flex arrays count their length, then align each element
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(LEGACY.pack(&vec![1u64, 2], &mut out).unwrap(), 24);
+    let v = out.into_inner();
+    assert_eq!(&v[..8], &[0, 0, 0, 2, 0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(LEGACY.unpack::<_, Vec<u64>>(&mut Cursor::new(v)).unwrap(), vec![1, 2]);
+
+    // The standard encoding is unaligned
+    let mut out = Cursor::new(Vec::new());
+    assert_eq!(Dialect::default().scope(|| pack_pair(&mut out)).unwrap(), 12);
+    assert_eq!(pack_pair(&mut Cursor::new(Vec::new())).unwrap(), 12);
+}
+
+#[test]
+fn packed_size() {
+    assert_eq!(LEGACY.scope(|| 9u64.packed_size()).unwrap(), 8);
+
+    // Measured at the current offset, which is left as it was
+    let mut out = Cursor::new(Vec::new());
+    let sz = LEGACY.scope(|| -> Result<_> {
+        let sz = 7u32.pack(&mut out)?;
+        assert_eq!(9u64.packed_size()?, 12);
+        Ok(sz + 9u64.pack(&mut out)?)
+    }).unwrap();
+    assert_eq!(sz, 16);
+    assert_eq!(out.into_inner().len(), 16);
+
+    // Fixed sizes only hold where hypers aren't aligned
+    assert!(standard_sizes());
+    assert!(Dialect { pad_byte: 0xff, align_hyper: false }.scope(standard_sizes));
+    assert!(!LEGACY.scope(standard_sizes));
+}
@@ -0,0 +1,126 @@
+// Don't rustfmt in here to avoid trashing vec![] formatting
+#![cfg_attr(rustfmt, rustfmt_skip)]
+#![cfg(feature = "std")]
+
+extern crate xdr_codec;
+
+use std::io::{self, Cursor, ErrorKind};
+
+use xdr_codec::framing::{FrameTransform, Framing, LengthPrefixed, RecordMarking};
+use xdr_codec::record::XdrRecordReader;
+
+#[test]
+fn length_prefixed() {
+    let framing = LengthPrefixed::default();
+    let mut out = Vec::new();
+
+    framing.write_frame(&mut out, &[1, 2, 3]).unwrap();
+    framing.write_frame(&mut out, &[]).unwrap();
+    assert_eq!(out, vec![0, 0, 0, 3, 1, 2, 3, 0, 0, 0, 0]);
+
+    let frames: Vec<_> = framing.frames(Cursor::new(&out)).map(Result::unwrap).collect();
+    assert_eq!(frames, vec![vec![1, 2, 3], vec![]]);
+}
+
+#[test]
+fn length_prefixed_errors() {
+    // Truncated body
+    let mut frames = LengthPrefixed::default().frames(Cursor::new(vec![0, 0, 0, 3, 1, 2]));
+    assert_eq!(frames.next().unwrap().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert!(frames.next().is_none());
+
+    // Truncated header
+    let res = LengthPrefixed::default().read_frame(&mut Cursor::new(vec![0, 0]));
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+    // Over limit
+    let framing = LengthPrefixed { max_len: Some(2) };
+    let res = framing.read_frame(&mut Cursor::new(vec![0, 0, 0, 3, 1, 2, 3]));
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn record_marking() {
+    let framing = RecordMarking::default();
+    let mut out = Vec::new();
+
+    framing.write_frame(&mut out, &[1, 2, 3]).unwrap();
+    framing.write_frame(&mut out, &[]).unwrap();
+    assert_eq!(out, vec![128, 0, 0, 3, 1, 2, 3, 128, 0, 0, 0]);
+
+    // Agrees with the record module
+    let records: Vec<_> = XdrRecordReader::new(Cursor::new(&out)).into_iter().map(Result::unwrap).collect();
+    assert_eq!(records, vec![vec![1, 2, 3], vec![]]);
+
+    // Multiple fragments
+    let input = vec![0, 0, 0, 2, 1, 2, 128, 0, 0, 1, 3];
+    let frames: Vec<_> = framing.frames(Cursor::new(input.clone())).map(Result::unwrap).collect();
+    assert_eq!(frames, vec![vec![1, 2, 3]]);
+
+    let res = RecordMarking { max_len: Some(2) }.read_frame(&mut Cursor::new(input));
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidData);
+
+    // Unterminated record
+    let res = framing.read_frame(&mut Cursor::new(vec![0, 0, 0, 2, 1, 2]));
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+// Stands in for encryption
+struct Xor(u8);
+
+impl FrameTransform for Xor {
+    fn encode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(frame.iter().map(|b| b ^ self.0).collect())
+    }
+
+    fn decode(&self, frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        self.encode(&frame)
+    }
+}
+
+// Stands in for compression: a count followed by the repeated byte
+struct Repeat;
+
+impl FrameTransform for Repeat {
+    fn encode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        match frame {
+            [] => Ok(vec![]),
+            [b, ..] if frame.len() < 256 && frame.iter().all(|c| c == b) => Ok(vec![frame.len() as u8, *b]),
+            _ => Err(io::Error::new(ErrorKind::InvalidInput, "not compressible")),
+        }
+    }
+
+    fn decode(&self, frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        match frame[..] {
+            [] => Ok(vec![]),
+            [n, b] => Ok(vec![b; n as usize]),
+            _ => Err(io::Error::new(ErrorKind::InvalidData, "bad frame")),
+        }
+    }
+}
+
+#[test]
+fn transformed() {
+    let framing = || LengthPrefixed::default().with_transform(Xor(0xff)).with_transform(Repeat);
+    let mut out = Vec::new();
+
+    framing().write_frame(&mut out, &[7; 5]).unwrap();
+    framing().write_frame(&mut out, &[]).unwrap();
+    // Compressed then encrypted
+    assert_eq!(out, vec![0, 0, 0, 2, 0xfa, 0xf8, 0, 0, 0, 0]);
+
+    let frames: Vec<_> = framing().frames(Cursor::new(&out)).map(Result::unwrap).collect();
+    assert_eq!(frames, vec![vec![7; 5], vec![]]);
+
+    // Untransformed view of the same stream
+    let raw: Vec<_> = LengthPrefixed::default().with_transform(Xor(0xff)).frames(Cursor::new(&out)).map(Result::unwrap).collect();
+    assert_eq!(raw, vec![vec![5, 7], vec![]]);
+
+    // Errors from either direction
+    let res = framing().write_frame(&mut Vec::new(), &[1, 2]);
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let mut frames = RecordMarking::default().with_transform(&Repeat).frames(Cursor::new(vec![128, 0, 0, 1, 9]));
+    assert_eq!(frames.next().unwrap().unwrap_err().kind(), ErrorKind::InvalidData);
+    assert!(frames.next().is_none());
+}
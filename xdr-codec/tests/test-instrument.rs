@@ -0,0 +1,57 @@
+#![cfg(feature = "std")]
+
+extern crate xdr_codec;
+
+use std::io::Cursor;
+use std::sync::Arc;
+
+use xdr_codec::instrument::{observe, DecodeSpan, DecodeStats};
+use xdr_codec::{Read, Result, Unpack};
+
+// Shaped like xdrgen's instrumented output
+#[derive(Debug, PartialEq)]
+struct Inner(u32);
+
+#[derive(Debug, PartialEq)]
+struct Outer(Inner, Inner);
+
+impl<In: Read> Unpack<In> for Inner {
+    fn unpack(input: &mut In) -> Result<(Inner, usize)> {
+        let span = DecodeSpan::enter("Inner");
+        let (v, sz) = u32::unpack(input)?;
+        Ok(span.done((Inner(v), sz)))
+    }
+}
+
+impl<In: Read> Unpack<In> for Outer {
+    fn unpack(input: &mut In) -> Result<(Outer, usize)> {
+        let span = DecodeSpan::enter("Outer");
+        let (a, asz) = Inner::unpack(input)?;
+        let (b, bsz) = Inner::unpack(input)?;
+        Ok(span.done((Outer(a, b), asz + bsz)))
+    }
+}
+
+#[test]
+fn decode_stats() {
+    let stats = Arc::new(DecodeStats::new());
+    let input = vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5];
+    let mut cur = Cursor::new(input);
+
+    observe(stats.clone(), || {
+        assert_eq!(Outer::unpack(&mut cur).unwrap().0, Outer(Inner(1), Inner(2)));
+        assert_eq!(Outer::unpack(&mut cur).unwrap().0, Outer(Inner(3), Inner(4)));
+        // Truncated: not reported
+        assert!(Outer::unpack(&mut cur).is_err());
+    });
+
+    // Not observed
+    assert!(Inner::unpack(&mut Cursor::new(vec![0, 0, 0, 6])).is_ok());
+
+    assert_eq!(stats.messages(), 2);
+    assert_eq!(stats.bytes(), 16);
+
+    let types = stats.type_counts();
+    assert_eq!(types.get("Outer"), Some(&2));
+    assert_eq!(types.get("Inner"), Some(&5));
+}
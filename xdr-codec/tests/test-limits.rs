@@ -0,0 +1,106 @@
+// Don't rustfmt in here to avoid trashing vec![] formatting
+#![cfg_attr(rustfmt, rustfmt_skip)]
+#![cfg(feature = "std")]
+
+extern crate xdr_codec;
+
+use std::io::Cursor;
+
+use xdr_codec::{unpack, unpack_rest, Error, Limits, Pack, Unpack};
+
+fn packed<T: Pack<Cursor<Vec<u8>>> + ?Sized>(val: &T) -> Vec<u8> {
+    let mut out = Cursor::new(Vec::new());
+    val.pack(&mut out).unwrap();
+    out.into_inner()
+}
+
+fn limit_err<T: std::fmt::Debug>(res: xdr_codec::Result<T>) -> &'static str {
+    match res {
+        Err(Error::LimitExceeded { limit, .. }) => limit,
+        res => panic!("bad result {:?}", res),
+    }
+}
+
+#[test]
+fn builder() {
+    let limits = Limits::builder().max_string(10).max_depth(3).build();
+
+    assert_eq!(limits, Limits { max_string: Some(10), max_depth: Some(3), ..Limits::default() });
+}
+
+#[test]
+fn strings_and_flex() {
+    let limits = Limits::builder().max_string(3).max_flex_elems(2).build();
+
+    let s = packed("abcd");
+    assert_eq!(limit_err(limits.unpack::<_, String>(&mut Cursor::new(&s))), "max_string");
+    // Not applied outside the scope
+    assert_eq!(unpack::<_, String>(&mut Cursor::new(&s)).unwrap(), "abcd");
+    // Strings aren't flex arrays
+    assert!(Limits::builder().max_flex_elems(1).build().unpack::<_, String>(&mut Cursor::new(&s)).is_ok());
+
+    let v = packed(&vec![1u32, 2, 3]);
+    assert_eq!(limit_err(limits.unpack::<_, Vec<u32>>(&mut Cursor::new(&v))), "max_flex_elems");
+    assert_eq!(limits.unpack::<_, Vec<u32>>(&mut Cursor::new(&packed(&vec![1u32, 2]))).unwrap(), vec![1, 2]);
+}
+
+#[test]
+fn total_alloc() {
+    let limits = Limits::builder().max_total_alloc(16).build();
+
+    // 3 * 4 bytes, then 2 * 4 more
+    let mut v = packed(&vec![1u32, 2, 3]);
+    v.extend(packed(&vec![4u32, 5]));
+    let mut input = Cursor::new(&v);
+    limits.scope(|| {
+        assert!(unpack::<_, Vec<u32>>(&mut input).is_ok());
+        assert_eq!(limit_err(unpack::<_, Vec<u32>>(&mut input)), "max_total_alloc");
+    });
+
+    // Accounting is per scope
+    let v = packed(&vec![1u32, 2, 3]);
+    assert!(limits.unpack::<_, Vec<u32>>(&mut Cursor::new(&v)).is_ok());
+    assert!(limits.unpack::<_, Vec<u32>>(&mut Cursor::new(&v)).is_ok());
+}
+
+#[test]
+fn depth() {
+    #[derive(Debug, PartialEq)]
+    struct List(Option<Box<List>>);
+
+    impl<In: xdr_codec::Read> Unpack<In> for List {
+        fn unpack(input: &mut In) -> xdr_codec::Result<(List, usize)> {
+            let (next, sz) = Unpack::unpack(input)?;
+            Ok((List(next), sz))
+        }
+    }
+
+    // List of 4: 3 Some, then None
+    let input = vec![0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0];
+
+    // Each link is an Option and a Box
+    let res = Limits::builder().max_depth(5).build().unpack::<_, List>(&mut Cursor::new(&input));
+    assert_eq!(limit_err(res), "max_depth");
+
+    let res = Limits::builder().max_depth(7).build().unpack::<_, List>(&mut Cursor::new(&input));
+    assert!(res.is_ok());
+}
+
+#[test]
+fn rest() {
+    let input = vec![0u8; 1 << 20];
+
+    // No more is read than shows the limit is exceeded
+    let mut cur = Cursor::new(&input);
+    let limits = Limits::builder().max_flex_elems(16).build();
+    assert_eq!(limit_err(limits.scope(|| unpack_rest(&mut cur))), "max_flex_elems");
+    assert_eq!(cur.position(), 17);
+
+    let mut cur = Cursor::new(&input);
+    let limits = Limits::builder().max_total_alloc(1024).build();
+    assert_eq!(limit_err(limits.scope(|| unpack_rest(&mut cur))), "max_total_alloc");
+    assert_eq!(cur.position(), 1025);
+
+    let mut cur = Cursor::new(&input[..16]);
+    assert_eq!(Limits::builder().max_flex_elems(16).build().scope(|| unpack_rest(&mut cur)).unwrap().1, 16);
+}
@@ -0,0 +1,47 @@
+// Don't rustfmt in here to avoid trashing vec![] formatting
+#![cfg_attr(rustfmt, rustfmt_skip)]
+#![cfg(not(feature = "std"))]
+
+extern crate xdr_codec;
+
+use xdr_codec::io::{self, ErrorKind};
+use xdr_codec::{pack, unpack, Error, Opaque, PackedSize, Unpack, Write, XdrString};
+
+#[test]
+fn slices() {
+    let mut out = Vec::new();
+    pack(&7u32, &mut out).unwrap();
+    pack(&"abcde", &mut out).unwrap();
+    assert_eq!(out, vec![0, 0, 0, 7, 0, 0, 0, 5, b'a', b'b', b'c', b'd', b'e', 0, 0, 0]);
+
+    let mut input = &out[..];
+    assert_eq!(unpack::<_, u32>(&mut input).unwrap(), 7);
+    assert_eq!(String::unpack(&mut input).unwrap(), (String::from("abcde"), 12));
+    assert!(input.is_empty());
+
+    // Into a fixed buffer, as far as it goes
+    let mut buf = [0u8; 12];
+    let mut space = &mut buf[..];
+    pack(&Opaque::borrowed(b"ab"), &mut space).unwrap();
+    match pack(&9u64, &mut space) {
+        Err(Error::IOError(err)) => assert_eq!(err.kind(), ErrorKind::WriteZero),
+        e => panic!("Unexpected {:?}", e),
+    }
+
+    assert_eq!(XdrString::from("xyz").packed_size().unwrap(), 8);
+    assert_eq!(io::sink().write_all(b"abc").map_err(|e| e.kind()), Ok(()));
+}
+
+#[test]
+fn short_input() {
+    match unpack::<_, u64>(&mut &[0u8, 0, 0, 1][..]) {
+        Err(Error::IOError(err)) => assert_eq!(err.kind(), ErrorKind::UnexpectedEof),
+        e => panic!("Unexpected {:?}", e),
+    }
+
+    let mut bytes = [0u8; 4];
+    match xdr_codec::try_unpack_opaque_array(&mut &[1u8, 2, 3][..], &mut bytes, 3) {
+        Err(Error::ShortRead{missing}) => assert_eq!(missing, 1),
+        e => panic!("Unexpected {:?}", e),
+    }
+}
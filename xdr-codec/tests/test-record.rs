@@ -1,5 +1,6 @@
 // Don't rustfmt in here to avoid trashing vec![] formatting
 #![cfg_attr(rustfmt, rustfmt_skip)]
+#![cfg(feature = "std")]
 
 extern crate xdr_codec;
 
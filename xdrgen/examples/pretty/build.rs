@@ -23,8 +23,9 @@ fn main() {
             }
         ),
     });
-    let _simple_output = xdrgen::generate_pretty(&(input.clone() + &xdr_header), &GenerateOptions{rust_header, tagging: tagging.clone(), ..Default::default()}).unwrap();
-    let output = xdrgen::generate_pretty(&input, &GenerateOptions{rust_header, xdr_header, tagging, ..Default::default()}).unwrap();
+    let generate = xdrgen::GenerateOptions{rust_header, ..Default::default()};
+    let _simple_output = xdrgen::generate_pretty(&(input.clone() + &xdr_header), &GenerateOptions{generate: generate.clone(), tagging: tagging.clone(), ..Default::default()}).unwrap();
+    let output = xdrgen::generate_pretty(&input, &GenerateOptions{generate, xdr_header, tagging, ..Default::default()}).unwrap();
     std::fs::create_dir_all("generated").unwrap();
     std::fs::write("generated/pretty_xdr.rs", output).unwrap();
 }
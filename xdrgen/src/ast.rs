@@ -0,0 +1,48 @@
+//! The parsed form of a specification
+//!
+//! For tools which work with specifications rather than generating code from them, such as
+//! linters, documentation generators and converters to other IDLs. `parse` gives the definitions
+//! of a specification as written, before the generator lifts out anonymous types or resolves
+//! names; `parse_with_spans` gives where each was written too, and `parse_with_comments` the
+//! comments the grammar otherwise discards.
+//!
+//! Text saved by other platforms' tools, with a byte order mark or CR line endings, should be
+//! passed through `normalize_source` first, as the generator does.
+
+pub use crate::spec::{
+    normalize_source, BinOp, Comment, CommentKind, Decl, Defn, Derives, EnumDefn, EnumWidth, MapKind, Procedure, SpecComment,
+    SpecPart, SpecSpan, Spanned, Specification, Type, UnionCase, Value, Version,
+};
+use crate::{spec, Result};
+
+/// Parse a specification into its definitions, in the order they're written.
+///
+/// Preprocessor directives and `%` passthrough lines are skipped, as rpcgen's are by cpp, and
+/// `#include`s aren't followed. A syntax error is an `Error::Parse`, with where it was found.
+pub fn parse(input: &str) -> Result<Vec<Defn>> {
+    spec::specification(input)
+}
+
+/// Parse a specification, with where each definition and each part of one was written.
+pub fn parse_with_spans(input: &str) -> Result<Vec<Spanned>> {
+    spec::specification_spans(input)
+}
+
+/// Parse a specification, keeping its comments alongside the definitions.
+pub fn parse_with_comments(input: &str) -> Result<Specification> {
+    spec::specification_with_comments(input)
+}
+
+#[test]
+fn test_parse() {
+    let defns = parse("const N = 2;\nstruct pair { int v[N]; };\n").unwrap();
+    assert_eq!(defns, [
+        Defn::Const("N".to_string(), Value::Const(2)),
+        Defn::Typespec("pair".to_string(), Type::Struct(vec![
+            Decl::Named("v".to_string(), Type::Array(Box::new(Type::Int), Value::Ident("N".to_string())), None, None),
+        ])),
+    ]);
+    assert_eq!(defns[1].name(), "pair");
+
+    assert!(matches!(parse("struct pair { int v }"), Err(crate::Error::Parse(_))));
+}
@@ -0,0 +1,76 @@
+//! rustc-style rendering of generation errors, for build scripts.
+
+use std::fmt::Write;
+
+use crate::error::{Error, Location};
+
+fn location(err: &Error) -> Option<Location> {
+    err.parse_error().map(|perr| perr.location)
+}
+
+/// Render `err` as rustc-style diagnostics against `source`, which was read from `path`.
+///
+/// Each underlying error gets its own `error:` block. Errors with a location show the offending
+/// line of the source with a marker under the column.
+pub(crate) fn render_error(path: &str, source: &str, err: &Error) -> String {
+    let mut out = String::new();
+
+    for err in err.errors() {
+        let _ = writeln!(out, "error: {}", err);
+
+        match location(err) {
+            None => {
+                let _ = writeln!(out, "  --> {}", path);
+            }
+            Some(loc) => {
+                let line = source.lines().nth(loc.line - 1).unwrap_or("");
+                let lineno = loc.line.to_string();
+                let pad = " ".repeat(lineno.len());
+
+                let _ = writeln!(out, "{}--> {}:{}", pad, path, loc);
+                let _ = writeln!(out, "{} |", pad);
+                let _ = writeln!(out, "{} | {}", lineno, line);
+                let _ = writeln!(out, "{} | {}^", pad, " ".repeat(loc.column - 1));
+            }
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Render a non-fatal issue as a `cargo:warning=` line for a build script to print.
+pub(crate) fn cargo_warning(path: &str, warning: &str) -> String {
+    format!("cargo:warning={}: {}", path, warning)
+}
+
+#[test]
+fn test_render_parse_error() {
+    let source = "const A = 1;\n  struct foo { int a; }\n";
+    let err = crate::spec::specification(source).unwrap_err();
+
+    assert_eq!(
+        render_error("foo.x", source, &err),
+        "error: 2:3: expected definition or end of input, found \"struct foo { int a; }\"\n \
+          --> foo.x:2:3\n  \
+          |\n\
+          2 |   struct foo { int a; }\n  \
+          |   ^\n\n"
+    );
+}
+
+#[test]
+fn test_render_multiple() {
+    let source = "enum E { A }; union u switch (int x) { case A: void; }; union v switch (E x) { case 1: void; };";
+    let err = crate::generate_with_options(
+        "foo.x",
+        std::io::Cursor::new(source.as_bytes()),
+        Vec::new(),
+        &Default::default(),
+    ).unwrap_err();
+    let out = render_error("foo.x", source, &err);
+
+    assert_eq!(err.errors().len(), 2);
+    assert_eq!(out.matches("error: incompat selector").count(), 2);
+    assert_eq!(out.matches("  --> foo.x\n").count(), 2);
+}
@@ -1,4 +1,6 @@
+use std::fmt;
 use std::io::Error as IOError;
+use std::path::PathBuf;
 
 use crate::spec::{Decl, Value, Type};
 
@@ -8,20 +10,218 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub enum Error {
     #[error("can't have unnamed type: {0:?}")]
     UnnamedType(Type),
-    #[error("parsing error: {0}")]
-    Parse(String),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
     #[error("IO Error: {0}")]
-    IOError(IOError),
+    IOError(#[from] IOError),
     #[error("incompat selector {selector:?} case {value:?}")]
-    IncompatSelector{selector: Decl, value: Value},
+    IncompatSelector{selector: Box<Decl>, value: Value},
+    #[error("enum {name} member {member} has unknown value {value:?}")]
+    UnknownEnumValue{name: String, member: String, value: Value},
+    #[error("const {name} has unknown value {value:?}")]
+    UnknownConstValue{name: String, value: Value},
+    #[error("enum {name} member {member} is {value}, which doesn't fit in an int; annotate the enum @hyper for 64-bit values")]
+    EnumValueRange{name: String, member: String, value: i64},
     #[error("discriminant value {value:?} unknown")]
     DiscriminantValueUnknown{value: Value},
     #[error("unimplemented type: {ty:?}")]
     UnimplementedType{ty: Type},
+    #[error("{option} can't be used with the {feature} feature: {problem}")]
+    FeatureConflict{option: String, feature: String, problem: String},
+    #[error("floating point type {ty:?} needs the float feature")]
+    FloatDisabled{ty: Type},
+    #[error("fixed array {ty:?}[{bound:?}] has {size} elements, which must be from 1 to 4294967295")]
+    InvalidArraySize{ty: Type, bound: Value, size: i64},
+    #[error("map entry must be a struct with a key and a value field: {ty:?}")]
+    InvalidMapEntry{ty: Type},
+    #[error("{ty:?} is a @hashmap, which needs std; use @map for no_std code")]
+    NoStdHashMap{ty: Type},
+    #[error(
+        "{defn} uses undefined constant {name}{}",
+        suggestion.as_ref().map_or(String::new(), |known| format!("; did you mean {}?", known))
+    )]
+    UndefinedConstant{defn: String, name: String, suggestion: Option<String>},
+    #[error("no definition named {name}")]
+    UnknownDefinition{name: String},
+    #[error("in {}: {source}", path.display())]
+    Include{path: PathBuf, source: Box<Error>},
+    #[error("included files must be within {}", root.display())]
+    IncludeOutsideRoot{root: PathBuf},
+    #[error("#include isn't allowed here")]
+    IncludeNotAllowed,
+    #[error("invalid message {name}: {problem}")]
+    InvalidMessage{name: String, problem: String},
+    #[error("invalid annotation on {name}: {problem}")]
+    InvalidAnnotation{name: String, problem: String},
+    #[error("can't name the anonymous type of field {field} {name}, which is already defined")]
+    AnonymousTypeName{field: String, name: String},
+    #[error("program {program} defines {name} more than once")]
+    DuplicateProgramName{program: String, name: String},
+    #[error("{name} starts with __xdr_, which is reserved for generated code")]
+    ReservedName{name: String},
+    #[error("{first} and {second} are both {rust} in Rust; rename one")]
+    NameClash{first: String, second: String, rust: String},
+    #[error("flattening namespace {namespace} defines {name} again")]
+    NamespaceClash{namespace: String, name: String},
+    #[error("generated helper {ty}::{method} clashes with a hand-written method; set a helper prefix")]
+    HelperClash{ty: String, method: String},
+    #[error("invalid primitive mapping for {xdr_type}: {problem}")]
+    InvalidPrimitive{xdr_type: String, problem: String},
+    #[error("invalid extra derive {derive} for {name}: {problem}")]
+    InvalidDerive{name: String, derive: String, problem: String},
+    #[error("can't rename {name} to {rust_name}, which isn't an identifier")]
+    InvalidRename{name: String, rust_name: String},
+    #[error("module import {path} isn't a path to use")]
+    InvalidImport{path: String},
+    #[error("invalid definition name pattern {pattern}: {problem}")]
+    InvalidPattern{pattern: String, problem: String},
+    #[error("invalid lint config line {line}: {problem}")]
+    InvalidLintConfig{line: usize, problem: String},
+    #[error(transparent)]
+    Multiple(MultipleErrors),
 }
 
-impl From<IOError> for Error {
-    fn from(err: IOError) -> Self {
-        Self::IOError(err)
+/// Several independent errors from one generation run, in definition order.
+///
+/// Always has at least two entries; its `source()` is the first.
+#[derive(Debug)]
+pub struct MultipleErrors(pub Vec<Error>);
+
+impl fmt::Display for MultipleErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} errors, first: {}", self.0.len(), self.0[0])
+    }
+}
+
+impl std::error::Error for MultipleErrors {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0[0])
+    }
+}
+
+impl Error {
+    /// Combine the errors from independent definitions, if there are any.
+    pub(crate) fn from_many(mut errors: Vec<Error>) -> Option<Error> {
+        match errors.len() {
+            0 => None,
+            1 => errors.pop(),
+            _ => Some(Error::Multiple(MultipleErrors(errors))),
+        }
+    }
+
+    /// All the individual errors this represents.
+    pub fn errors(&self) -> Vec<&Error> {
+        match self {
+            Error::Multiple(MultipleErrors(errs)) => errs.iter().flat_map(Error::errors).collect(),
+            err => vec![err],
+        }
+    }
+
+    /// Structured parse failure, if this error came from the parser.
+    pub fn parse_error(&self) -> Option<&ParseError> {
+        match self {
+            Error::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Position within a specification source.
+///
+/// `offset` is in bytes from the start of the input; `line` and `column` are 1-based, with
+/// `column` counted in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub(crate) fn from_offset(source: &str, offset: usize) -> Location {
+        let offset = offset.min(source.len());
+        let prefix = String::from_utf8_lossy(&source.as_bytes()[..offset]);
+        let line = prefix.matches('\n').count() + 1;
+        let column = prefix.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+
+        Location { offset, line, column }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// What the parser was looking for when it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Expected {
+    /// A specific keyword (`struct`, `case`, ...)
+    Keyword,
+    /// An identifier which isn't a keyword
+    Identifier,
+    /// A numeric literal
+    Number,
+    /// Punctuation or other fixed token
+    Token,
+    /// A definition, or the end of the specification
+    Definition,
+    /// The input ended part way through a construct
+    MoreInput,
+    /// Less deeply nested brackets or expressions than the parser allows
+    ShallowerNesting,
+    /// A well-formed preprocessor directive, which matches the conditionals around it
+    Directive,
+    /// `#endif` to close a conditional
+    Endif,
+    /// Anything else the grammar may reject
+    Other,
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Expected::Keyword => "keyword",
+            Expected::Identifier => "identifier",
+            Expected::Number => "number",
+            Expected::Token => "token",
+            Expected::Definition => "definition or end of input",
+            Expected::MoreInput => "more input",
+            Expected::ShallowerNesting => "shallower nesting",
+            Expected::Directive => "valid preprocessor directive",
+            Expected::Endif => "#endif",
+            Expected::Other => "valid syntax",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Structured parse failure.
+///
+/// `found` is a short excerpt of the input at `location`, up to the end of that line.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("{location}: expected {expected}, found {found:?}")]
+pub struct ParseError {
+    pub expected: Expected,
+    pub location: Location,
+    pub found: String,
+}
+
+impl ParseError {
+    const MAX_FOUND: usize = 40;
+
+    pub(crate) fn new(source: &str, offset: usize, expected: Expected) -> ParseError {
+        let location = Location::from_offset(source, offset);
+        let rest = String::from_utf8_lossy(&source.as_bytes()[location.offset..]);
+        let found = rest
+            .lines()
+            .next()
+            .unwrap_or("")
+            .chars()
+            .take(Self::MAX_FOUND)
+            .collect();
+
+        ParseError { expected, location, found }
     }
 }
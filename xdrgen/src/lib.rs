@@ -25,18 +25,49 @@ extern crate nom;
 #[macro_use]
 extern crate bitflags;
 
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
-use std::fmt::Display;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
 mod spec;
-use spec::{Emit, Emitpack, Symtab, SymDef};
+pub mod ast;
+use spec::{Emit, Emitpack};
+pub use spec::{constant_expr, Symtab, SymDef, TypeGraph, Derives, RustItem, specification_source, normalize_source, parse_fuzz, preprocess, rpcgen_quirks, Codecs, Lint, LintConfig, LintLevel, LintRule, SpecSource, TypeAliases, Primitive, Primitives, FieldDiff, Namespaces, Naming, KeywordEscape, Visibility, VariantPrefixes, BoolLabels};
 
 mod error;
-pub use self::error::{Result, Error};
+pub use self::error::{Result, Error, MultipleErrors, ParseError, Expected, Location};
 
+mod diagnostic;
+
+mod names;
+use names::NamePatterns;
+
+mod serve;
+pub use serve::{serve, serve_with_options, ServeOptions};
+#[cfg(unix)]
+pub use serve::serve_unix;
+
+mod source_map;
+pub use source_map::{ItemKind, SourceMap, SourceMapItem};
+
+mod validate;
+pub use validate::{validate_all, FileReport, Report};
+
+#[cfg(feature = "pretty")]
+mod mode_diff;
+#[cfg(feature = "pretty")]
+pub use mode_diff::{diff_modes, ItemDiff};
+#[cfg(feature = "dialect")]
+pub use xdr_codec::Dialect;
+
+/// Whether a line of generated code seems to define or implement something for one of
+/// `exclude_defs`, going by its text.
+///
+/// Generation used to exclude definitions this way, but it takes names for prefixes of others and
+/// misses typedefs; definitions are now left out before code is generated for them.
+#[deprecated(since = "0.9.0", note = "exclude_defs are left out before generating code")]
 pub fn exclude_definition_line(line: &str, exclude_defs: &[&str]) -> bool {
     exclude_defs.iter().fold(false, |acc, v| {
         acc || line.contains(&format!("const {}", v))
@@ -48,74 +79,477 @@ pub fn exclude_definition_line(line: &str, exclude_defs: &[&str]) -> bool {
 
 /// Generate Rust code from an RFC4506 XDR specification
 ///
-/// `infile` is simply a string used in error messages; it may be empty. `input` is a read stream of
-/// the specification, and `output` is where the generated code is sent.
+/// `infile` is the specification's file name, used in error messages; it may be empty. `input` is a read stream of the
+/// specification, and `output` is where the generated code is sent.
 /// `exclude_defs` is list of not generated type definitions.
+///
+/// Superseded by `generate_with_options`, which takes the rest of the generator's options too.
+#[deprecated(since = "0.9.0", note = "use generate_with_options")]
 pub fn generate<In, Out>(
     infile: &str,
-    mut input: In,
-    mut output: Out,
+    input: In,
+    output: Out,
     exclude_defs: &[&str],
 ) -> Result<()>
 where
     In: Read,
     Out: Write,
 {
-    let mut source = String::new();
+    generate_with_options(infile, input, output, &GenerateOptions { exclude_defs, ..Default::default() }).map(|_| ())
+}
+
+/// The generator's options
+///
+/// These are the options for `generate_with_options`, and are shared by `compile_with_options`
+/// and `generate_pretty`, whose own options embed them.
+#[derive(Debug, Clone, Default)]
+pub struct GenerateOptions<'a> {
+    /// Rust code added after the generated file's banner, before the generated items
+    pub rust_header: &'a str,
+    /// Wrap the generated code in a module of this name, with `use xdr_codec;` and
+    /// `module_imports`, so it can be `include!`d as it is. The module has the items'
+    /// `visibility`, and they're used through it, as `<module>::<item>`.
+    pub module: Option<&'a str>,
+    /// Paths to `use` in the `module`, such as `std::io` or `crate::types::*`. It's an error for
+    /// one not to be a path `use` takes.
+    pub module_imports: &'a [&'a str],
+    /// Definitions not to generate, by name or by pattern such as `nfs3_*` or `/^nfs3_/`
+    pub exclude_defs: &'a [&'a str],
+    /// Definitions to generate only the codec impls of, for types kept by hand in Rust. Enums
+    /// also get the `TryFrom` and `From` conversions from and to their wire value, which
+    /// unpacking uses.
+    pub impls_only: &'a [&'a str],
+    /// Only generate these definitions, and with `only_with_deps` those they depend on, so the
+    /// output stands alone. Like `exclude_defs`, they may be given by pattern, and each must
+    /// match some definition.
+    pub only: Option<&'a [&'a str]>,
+    pub only_with_deps: bool,
+    /// Types which the spec uses without defining; the Sun RPC spellings if not set.
+    pub type_aliases: Option<&'a TypeAliases>,
+    /// Custom Rust types for some XDR types, with the code to pack and unpack them; see
+    /// `Primitives`
+    pub primitives: Option<&'a Primitives>,
+    /// Which codec impls to emit for definitions without a `@pack_only` or `@unpack_only`
+    /// annotation
+    pub codecs: Codecs,
+    /// Prefix for generated inherent helpers such as unions' `new_<case>` constructors, for
+    /// types which also have hand-written methods of those names
+    pub helper_prefix: &'a str,
+    /// Only give generated types these of the derives they could have; see
+    /// `Symtab::set_derives`. All of them if not set.
+    pub derives: Option<Derives>,
+    /// Further derives for some generated types, by name; see `Symtab::set_extra_derives`
+    pub extra_derives: Option<fn(&str) -> Vec<String>>,
+    /// Further attributes for some generated items, by name and kind; see
+    /// `Symtab::set_item_attrs`
+    pub item_attrs: Option<fn(&str, RustItem) -> Vec<proc_macro2::TokenStream>>,
+    /// Rust names for some of the spec's names; see `Symtab::set_rename`
+    pub rename: Option<&'a HashMap<String, String>>,
+    /// How the spec's other names are spelled in Rust
+    pub naming: Naming,
+    /// How names which are Rust keywords are escaped; see `Symtab::set_keyword_escape`
+    pub keyword_escape: KeywordEscape,
+    /// Which prefix is left off enums' members to name their variants
+    pub variant_prefixes: VariantPrefixes,
+    /// Whether namespace blocks become Rust modules, or are flattened into the top level
+    pub namespaces: Namespaces,
+    /// Generate enums as newtypes which keep values the spec doesn't know; see
+    /// `Symtab::set_lossless_enums`
+    pub lossless_enums: bool,
+    /// Give unions without a `default` arm an `Unknown` variant for discriminants they don't
+    /// know; see `Symtab::set_unknown_union_arms`
+    pub unknown_union_arms: bool,
+    /// How the case labels of unions switching on a `bool` are read; see
+    /// `Symtab::set_bool_labels`
+    pub bool_labels: BoolLabels,
+    /// Generate unions which are only a `bool` and, when it's `TRUE`, a value as `Option`s of
+    /// the value; see `optional_unions`
+    pub optional_unions: bool,
+    /// Generate code for `no_std` crates, using `core` and `alloc`; see `Symtab::set_no_std`
+    pub no_std: bool,
+    /// Unpack fixed arrays without `unsafe`; see `Symtab::set_safe_arrays`
+    pub safe_arrays: bool,
+    /// Box union arms estimated to take more than this many bytes; see
+    /// `Symtab::set_box_large_arms`
+    pub box_large_arms: Option<u64>,
+    /// Generate only the types, without codec impls or anything else needing xdr-codec; see
+    /// `Symtab::set_types_only`
+    pub types_only: bool,
+    /// Derive traits for types with fixed arrays longer than 32 elements; see
+    /// `Symtab::set_large_array_derives`
+    pub large_array_derives: bool,
+    /// Visibility of generated items and fields, rather than `pub`; see `Symtab::set_visibility`
+    pub visibility: Visibility,
+    /// Where files named by `#include "file"` directives are found, usually the spec's own
+    /// directory. If not set, they're ignored, as rpcgen leaves them to the C compiler. They must
+    /// be relative paths without `..`.
+    pub include_dir: Option<&'a Path>,
+    /// Directory included files must be within, if not `include_dir`, for specifications from
+    /// untrusted sources
+    pub include_root: Option<&'a Path>,
+    /// Evaluate conditionals and `#define`s, starting with these definitions, as cpp would; see
+    /// `preprocess`
+    pub defines: Option<&'a BTreeMap<String, String>>,
+    /// Tolerate rpcgen's quirks, such as stray semicolons, `long int` and `typedef`s of names
+    /// which are already defined, with a warning for each; see `rpcgen_quirks`
+    pub quirks: bool,
+    /// Also map the lines of the generated code to the lines of the specification each item
+    /// was generated from, so tools can take a reader of the code to the definition behind it.
+    /// `generate_with_options` returns the `SourceMap` in `Generated`, and
+    /// `compile_with_options` writes it as JSON next to the generated file; `generate_pretty`
+    /// doesn't make one.
+    pub source_map: bool,
+    /// Emit `XDR_DIALECT`, so the generated types can be packed and unpacked for peers which pad
+    /// or align nonstandardly with `XDR_DIALECT.pack()` and `XDR_DIALECT.unpack()`
+    #[cfg(feature = "dialect")]
+    pub dialect: Option<xdr_codec::Dialect>,
+}
+
+/// What `generate_with_options` found out while generating code, besides the code itself
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Generated {
+    /// The definitions code was generated for, in specification order, with type aliases
+    /// resolved and anonymous types lifted out. Build scripts can drive their own passes over
+    /// them, such as generating extra impls, without parsing the input again.
+    pub defns: Vec<ast::Defn>,
+    /// Where each generated item came from, with `GenerateOptions::source_map`
+    pub source_map: Option<SourceMap>,
+    /// Non-fatal problems with the specification, such as selectors with cases missing
+    pub warnings: Vec<String>,
+}
+
+/// Generate Rust code from an RFC4506 XDR specification, with options
+///
+/// `infile` is the specification's file name, used in error messages; it may be empty. `input` is
+/// a read stream of the specification, and `output` is where the generated code is sent.
+///
+/// The specification is normalized first; see `normalize_source`. Bytes which aren't UTF-8 are
+/// replaced, which is lossy, so comments in a legacy code page have U+FFFD in their docs; each
+/// line with some is logged as a warning.
+pub fn generate_with_options<In, Out>(infile: &str, input: In, output: Out, options: &GenerateOptions) -> Result<Generated>
+where
+    In: Read,
+    Out: Write,
+{
+    let source = spec::read_source(input)?;
 
-    input.read_to_string(&mut source)?;
+    generate_source(infile, &source, output, options, &directives(options))
+}
+
+// The definitions to generate for `only`, and with `with_deps` those they depend on. Names the
+// spec doesn't define are an error either way.
+fn only_defs(defns: &[ast::Defn], only: &[&str], with_deps: bool) -> Result<HashSet<String>> {
+    let only = names::expand(only, &defns.iter().map(ast::Defn::name).collect::<Vec<_>>())?;
+    let needed = spec::dependencies(defns, &only)?;
+    Ok(match with_deps {
+        true => needed.into_iter().map(String::from).collect(),
+        false => only.iter().map(|name| name.to_string()).collect(),
+    })
+}
 
-    let defns = spec::specification(&source)?;
+/// Cut an XDR specification down to some of its definitions
+///
+/// The definitions named in `roots`, and everything they depend on, are copied from `input` to
+/// `output` as they were written, along with their comments and the file's header. The result is
+/// a standalone specification, for example for a bug report or a service which only needs part of
+/// a larger protocol.
+pub fn minimize<In, Out>(input: In, mut output: Out, roots: &[&str]) -> Result<()>
+where
+    In: Read,
+    Out: Write,
+{
+    let source = spec::read_source(input)?;
+
+    let spec = spec::specification_source(&source)?;
+    let defns: Vec<_> = spec.defns.iter().map(|(defn, _)| defn.clone()).collect();
+    let keep: HashSet<&str> = spec::dependencies(&defns, roots)?.into_iter().collect();
+
+    output.write_all(spec.preamble.as_bytes())?;
+    for (defn, text) in &spec.defns {
+        if keep.contains(defn.name()) {
+            output.write_all(text.as_bytes())?;
+        }
+    }
+    writeln!(output)?;
+
+    Ok(())
+}
+
+/// Fingerprint of an XDR specification's wire definition
+///
+/// This is a SHA-256 hash of the definitions `generate` would emit code for, in a canonical form
+/// which ignores comments, layout and the order of definitions. Peers built from specifications
+/// with the same fingerprint agree on the encoding. With the `fingerprint_const` feature, the
+/// generated code includes it as `PROTOCOL_FINGERPRINT`.
+pub fn spec_fingerprint<In: Read>(input: In) -> Result<[u8; 32]> {
+    let source = spec::read_source(input)?;
+
+    let spec_source = spec::specification_source(&source)?;
+    let mut defns: Vec<_> = spec_source.defns.iter().map(|(defn, _)| defn.clone()).collect();
+    TypeAliases::default().resolve(&mut defns);
+    defns.extend(spec::message_envelope(&spec_source)?);
+
+    Ok(spec::fingerprint(&defns))
+}
+
+/// Check an XDR specification against house style rules
+///
+/// Returns the rules it breaks, at the levels set by `config`, in definition order.
+pub fn lint<In: Read>(input: In, config: &LintConfig) -> Result<Vec<Lint>> {
+    let source = spec::read_source(input)?;
+
+    Ok(spec::lint(&spec::specification(&source)?, config))
+}
+
+/// Decode the same data with two versions of an XDR specification, and compare the results
+///
+/// `data` is decoded as the type named `ty` in both `old` and `new`, and each primitive value is
+/// identified by its path from `ty`, such as `msg.items[2].name`. Returns the values which differ,
+/// with an `error: ...` value where decoding stopped, and a difference in how many bytes are left
+/// over. An empty result means both versions read the data the same way.
+pub fn decode_diff<Old, New>(old: Old, new: New, ty: &str, data: &[u8]) -> Result<Vec<FieldDiff>>
+where
+    Old: Read,
+    New: Read,
+{
+    let old = decode_symtab(old)?;
+    let new = decode_symtab(new)?;
+
+    Ok(spec::diff(&spec::decode(&old, ty, data), &spec::decode(&new, ty, data)))
+}
+
+// The symbols of a spec, with the standard type aliases resolved, for decoding data with it.
+fn decode_symtab<In: Read>(input: In) -> Result<Symtab<()>> {
+    let source = spec::read_source(input)?;
+
+    let mut defns = spec::specification(&source)?;
+    TypeAliases::default().resolve(&mut defns);
+
+    let mut xdr = Symtab::new();
+    xdr.update_consts(&defns, &());
+    match Error::from_many(xdr.errors()) {
+        Some(err) => Err(err),
+        None => Ok(xdr),
+    }
+}
+
+// How directives in a spec are handled with `options`
+fn directives<'a>(options: &GenerateOptions<'a>) -> spec::Directives<'a> {
+    spec::Directives {
+        include_dir: options.include_dir,
+        include_root: options.include_root,
+        defines: options.defines,
+        quirks: options.quirks,
+    }
+}
+
+// Apply the options which decide how code is generated from the symbols in `xdr`.
+fn configure<M>(xdr: &mut Symtab<M>, options: &GenerateOptions) -> Result<()> {
+    xdr.set_helper_prefix(options.helper_prefix);
+    if let Some(primitives) = options.primitives {
+        xdr.set_primitives(primitives);
+    }
+    if let Some(derives) = options.derives {
+        xdr.set_derives(derives);
+    }
+    if let Some(extra) = options.extra_derives {
+        xdr.set_extra_derives(extra);
+    }
+    if let Some(attrs) = options.item_attrs {
+        xdr.set_item_attrs(attrs);
+    }
+    if let Some(rename) = options.rename {
+        xdr.set_rename(rename)?;
+    }
+    xdr.set_naming(options.naming);
+    xdr.set_keyword_escape(options.keyword_escape);
+    xdr.set_variant_prefixes(options.variant_prefixes.clone());
+    xdr.set_lossless_enums(options.lossless_enums);
+    xdr.set_unknown_union_arms(options.unknown_union_arms);
+    xdr.set_bool_labels(options.bool_labels);
+    xdr.set_no_std(options.no_std);
+    xdr.set_safe_arrays(options.safe_arrays);
+    xdr.set_box_large_arms(options.box_large_arms);
+    xdr.set_types_only(options.types_only);
+    // Every type derives serde's or schemars' traits with these, which large arrays don't have
+    if options.large_array_derives {
+        let feature = if cfg!(feature = "derive_serde") {
+            Some("derive_serde")
+        } else if cfg!(feature = "derive_json_schema") {
+            Some("derive_json_schema")
+        } else {
+            None
+        };
+        if let Some(feature) = feature {
+            return Err(Error::FeatureConflict {
+                option: "large_array_derives".into(),
+                feature: feature.into(),
+                problem: "its traits aren't implemented for arrays longer than 32".into(),
+            });
+        }
+    }
+    xdr.set_large_array_derives(options.large_array_derives);
+    xdr.set_visibility(options.visibility);
+    // They're pasted into the generated module as they are
+    if let Some(path) = options.module_imports.iter().find(|path| !spec::is_use_tree(path)) {
+        return Err(Error::InvalidImport { path: path.to_string() });
+    }
+    Ok(())
+}
+
+// Generate from an in-memory spec. Errors from independent definitions are all collected, rather
+// than stopping at the first.
+fn generate_source<Out: Write>(
+    infile: &str,
+    source: &str,
+    mut output: Out,
+    options: &GenerateOptions,
+    directives: &spec::Directives,
+) -> Result<Generated> {
+    let source = &match directives.defines {
+        Some(defines) => std::borrow::Cow::Owned(spec::preprocess(source, defines)?),
+        None => std::borrow::Cow::Borrowed(source),
+    };
+    // Warns once, for the main source; its definitions are parsed from the cleaned-up text
+    let source = &match directives.quirks {
+        true => spec::rpcgen_quirks(source),
+        false => std::borrow::Cow::Borrowed(&**source),
+    };
+    let mut defns = spec::specification_with_directives(source, directives)?;
+    // Only the definitions kept, if set, though all of them are still used to resolve names
+    let keep = options.only.map(|only| only_defs(&defns, only, options.only_with_deps)).transpose()?;
+    match options.type_aliases {
+        Some(aliases) => aliases.resolve(&mut defns),
+        None => TypeAliases::default().resolve(&mut defns),
+    }
+    let flattened = match options.namespaces {
+        Namespaces::Modules => HashMap::new(),
+        Namespaces::Flatten => spec::flatten_namespaces(&mut defns)?,
+    };
+    if options.optional_unions {
+        spec::optional_unions(&mut defns);
+    }
+    let mut owners = spec::lift_anonymous(&mut defns)?;
+    // Definitions moved out of namespaces go with the namespace, as do types lifted from them
+    for owner in owners.values_mut() {
+        if let Some(ns) = flattened.get(owner) {
+            *owner = ns.clone();
+        }
+    }
+    owners.extend(flattened);
+    let spec_source = spec::specification_source(source)?;
+    defns.extend(spec::message_envelope(&spec_source)?);
+    // Of the wire definitions, before they're given Rust types
+    #[cfg(feature="fingerprint_const")]
+    let fingerprint = spec::fingerprint(&defns);
+    spec::timestamp_annotations(&spec_source, &mut defns)?;
     let mut xdr = Symtab::new();
+    configure(&mut xdr, options)?;
     xdr.update_consts(&defns, &());
+    // Declared where the code is included, which its contents are generated a module deeper than
+    let module = options.module.map(|name| {
+        let (vis, ident) = (xdr.vis(), spec::quote_ident(name));
+        quote!(#vis mod #ident)
+    });
+    if module.is_some() {
+        xdr = xdr.in_module();
+    }
+    // Lifted anonymous types go with the definition they were declared in
+    let owner = |name: &String| owners.get(name).cloned().unwrap_or_else(|| name.clone());
+    let excluded = NamePatterns::new(options.exclude_defs)?;
+    let impls_only = NamePatterns::new(options.impls_only)?;
+    let kept = |name: &String| {
+        let owner = owner(name);
+        !excluded.matches(&owner) && keep.as_ref().is_none_or(|keep| keep.contains(&owner))
+    };
+    let defined = |name: &String| kept(name) && !impls_only.matches(&owner(name));
+    let annotated = spec::codec_annotations(&spec_source)?;
+    let codecs = |name: &String| match options.types_only {
+        true => Codecs::None,
+        false => annotated.get(&owner(name)).copied().unwrap_or(options.codecs),
+    };
+    let docs = spec::leading_docs(&spec_source)?;
+    let (sections, section_order) = spec::section_modules(&spec_source)?;
+    let section = |name: &String| sections.get(&owner(name));
+    // Items in section modules are generated a module deeper
+    let in_section = xdr.in_module();
+    let symtab = |name: &String| if section(name).is_some() { &in_section } else { &xdr };
 
+    let mut errors = xdr.errors();
     let res: Vec<_> = {
         let consts = xdr
             .constants()
             .map(SymDef::map_value)
-            .filter_map(|(c, &(v, ref scope))| {
-                if scope.is_none() {
-                    Some(spec::Const(c.clone(), v))
-                } else {
-                    None
-                }
-            })
-            .map(|c| c.define(&xdr));
+            .filter(spec::is_local)
+            .filter_map(|entry| spec::const_item(symtab(entry.1.1.as_ref().unwrap_or(entry.0)), entry))
+            .filter(|(owner, _)| kept(owner))
+            .map(|(owner, item)| Ok((owner, ItemKind::Const, item)));
 
         let typespecs = xdr
             .typespecs()
             .map(SymDef::map_value)
+            .filter(spec::is_local)
+            .filter(|(n, _)| defined(n))
             .map(|(n, ty)| spec::Typespec(n.clone(), ty.clone()))
-            .map(|c| c.define(&xdr));
+            .map(|c| c.define(symtab(&c.0)).map(|item| (c.0.clone(), ItemKind::Type, spec::with_doc(docs.get(&c.0), item))));
 
         let typesyns = xdr
             .typesyns()
             .map(SymDef::map_value)
+            .filter(spec::is_local)
+            .filter(|(n, _)| defined(n))
             .map(|(n, ty)| spec::Typesyn(n.clone(), ty.clone()))
-            .map(|c| c.define(&xdr));
+            .map(|c| c.define(symtab(&c.0)).map(|item| (c.0.clone(), ItemKind::Type, spec::with_doc(docs.get(&c.0), item))));
 
         let packers = xdr
             .typespecs()
             .map(SymDef::map_value)
+            .filter(spec::is_local)
+            .filter(|(n, _)| kept(n) && codecs(n).pack())
             .map(|(n, ty)| spec::Typespec(n.clone(), ty.clone()))
-            .filter_map(|c| c.pack(&xdr).transpose());
+            .filter_map(|c| c.pack(&xdr).transpose().map(|res| res.map(|item| (c.0, ItemKind::Pack, item))));
 
         let unpackers = xdr
             .typespecs()
             .map(SymDef::map_value)
+            .filter(spec::is_local)
+            .filter(|(n, _)| kept(n) && codecs(n).unpack())
             .map(|(n, ty)| spec::Typespec(n.clone(), ty.clone()))
-            .filter_map(|c| c.unpack(&xdr).transpose());
+            .filter_map(|c| c.unpack(&xdr).transpose().map(|res| res.map(|item| (c.0, ItemKind::Unpack, item))));
+
+        // Which the types' definitions would have had
+        let conversions = xdr
+            .typespecs()
+            .map(SymDef::map_value)
+            .filter(spec::is_local)
+            .filter(|(n, _)| kept(n) && !defined(n) && codecs(n).unpack())
+            .map(|(n, ty)| spec::Typespec(n.clone(), ty.clone()))
+            .filter_map(|c| c.conversions(&xdr).map(|item| Ok((c.0, ItemKind::Unpack, item))));
+
+        let namespaces = defns.iter().filter_map(|defn| match defn {
+            spec::Defn::Namespace(name, defns) if kept(name) => {
+                Some(spec::namespace_module(name, defns, symtab(name), &(), codecs(name)).map(|item| (name.clone(), ItemKind::Module, item)))
+            }
+            _ => None,
+        });
 
         consts
             .chain(typespecs)
             .chain(typesyns)
             .chain(packers)
             .chain(unpackers)
-            .collect::<Result<Vec<_>>>()?
+            .chain(conversions)
+            .chain(namespaces)
+            .filter_map(|r| r.map_err(|e| errors.push(e)).ok())
+            .collect()
     };
 
-    let _ = writeln!(
-        output,
+    if let Some(err) = Error::from_many(errors) {
+        return Err(err);
+    }
+
+    let header = format!(
         r#"
 // GENERATED CODE
 //
@@ -125,31 +559,115 @@ where
 "#,
         infile
     );
+    let _ = writeln!(output, "{}", header);
+    // Lines written so far
+    let mut lineno = header.matches('\n').count() + 1;
+    if let Some(module) = &module {
+        let _ = writeln!(output, "{} {{\n", module);
+        lineno += 2;
+    }
+    if !options.rust_header.is_empty() {
+        let _ = writeln!(output, "{}\n", options.rust_header.trim_end());
+        lineno += options.rust_header.trim_end().matches('\n').count() + 2;
+    }
 
-    for it in res {
-        let line = it.to_string();
-        if !exclude_definition_line(&line, exclude_defs) {
-            let _ = writeln!(output, "{}\n", line);
+    // Items at the top level, then each section's in its module, with the lines around them
+    let (top, in_sections): (Vec<_>, Vec<_>) = res.into_iter().partition(|(name, ..)| section(name).is_none());
+    let mut chunks = Vec::new();
+    if module.is_some() {
+        let xdr_codec = (!options.types_only).then(|| quote!(#[allow(unused_imports)] use xdr_codec;).to_string());
+        let imports = options.module_imports.iter().map(|path| format!("use {};", path));
+        let imports: Vec<_> = xdr_codec.into_iter().chain(imports).collect();
+        if !imports.is_empty() {
+            chunks.push((None, imports.join("\n")));
         }
     }
+    chunks.extend(section_order.iter().map(|module| (None, spec::section_import(module).to_string())));
+    chunks.extend(top.into_iter().map(|(name, kind, it)| (Some((name, kind)), it.to_string())));
+    for module in &section_order {
+        let (vis, ident) = (xdr.vis(), spec::quote_ident(module));
+        chunks.push((None, format!("{} {{", quote!(#vis mod #ident))));
+        chunks.push((None, quote!(#[allow(unused_imports)] use super::*;).to_string()));
+        chunks.extend(
+            in_sections
+                .iter()
+                .filter(|(name, ..)| section(name) == Some(module))
+                .map(|(name, kind, it)| (Some((name.clone(), *kind)), it.to_string())),
+        );
+        chunks.push((None, "}".to_string()));
+    }
 
-    Ok(())
+    let spec_lines: HashMap<&str, _> = spec_source.defn_lines().into_iter().map(|(defn, lines)| (defn.name(), lines)).collect();
+    let mut source_map = SourceMap { spec_file: infile.to_string(), items: Vec::new() };
+    for (item, line) in chunks {
+        let _ = writeln!(output, "{}\n", line);
+        let generated = lineno + 1..=lineno + 1 + line.matches('\n').count();
+        lineno = generated.end() + 1;
+        if let Some((name, kind)) = item {
+            if let Some(spec) = spec_lines.get(owner(&name).as_str()) {
+                source_map.items.push(SourceMapItem { name, kind, generated, spec: spec.clone() });
+            }
+        }
+    }
+
+    #[cfg(feature="consts_module")]
+    {
+        let consts = xdr
+            .constants()
+            .filter(spec::is_local)
+            .filter(|(name, def)| kept(def.value.1.as_ref().unwrap_or(name)));
+        let _ = writeln!(output, "{}\n", spec::consts_module(&xdr, consts.map(SymDef::map_value)));
+    }
+
+    #[cfg(feature="fingerprint_const")]
+    {
+        let _ = writeln!(output, "{}\n", spec::fingerprint_const(&xdr, &fingerprint));
+    }
+
+    #[cfg(feature="dialect")]
+    if let Some(dialect) = &options.dialect {
+        let _ = writeln!(output, "{}\n", spec::dialect_const(&xdr, dialect));
+    }
+
+    if module.is_some() {
+        let _ = writeln!(output, "}}");
+    }
+
+    Ok(Generated { defns, source_map: options.source_map.then_some(source_map), warnings: xdr.warnings() })
 }
 
 #[cfg(feature = "pretty")]
 pub mod pretty {
-    use std::collections::BTreeMap;
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
     use proc_macro2::{TokenStream, Ident};
 
-    use crate::spec::{Defn, quote_ident, SymDef};
+    use crate::spec::{Defn, NameKind, SymDef, Symtab};
 
+    /// Options for `generate_pretty`.
     #[derive(Default)]
     pub struct GenerateOptions<'a> {
+        /// The options shared with the other generators. `rust_header` goes before the generated
+        /// items, and the `module` also wraps the Rust header and trailer.
+        pub generate: crate::GenerateOptions<'a>,
+        /// Used as `generate.rust_header` when that's empty.
+        #[deprecated(since = "0.9.0", note = "use generate.rust_header")]
         pub rust_header: &'a str,
+        /// Used as `generate.exclude_defs` when that's empty.
+        #[deprecated(since = "0.9.0", note = "use generate.exclude_defs")]
         pub exclude_defs: &'a [&'a str],
+        /// Further header fragments, each added after `rust_header` in order.
+        pub rust_headers: &'a [&'a str],
+        /// Rust code added after all the generated items.
+        pub rust_trailer: &'a str,
         pub tagging: Option<ConstTaggingOptions>,
         pub xdr_header: &'a str,
+        /// Emit definitions in the order they appear in the spec, rather than grouped
+        /// alphabetically.
+        pub spec_order: bool,
+        /// Emit each type's Pack/Unpack impls straight after its definition, rather than after
+        /// all the definitions.
+        pub impls_with_defns: bool,
     }
 
     #[derive(Clone)]
@@ -160,16 +678,16 @@ pub mod pretty {
     }
 
     impl ConstTaggingOptions {
-        pub(super) fn tagged_types<'a>(&'a self, input: &'a [Defn], exclude_defs: &[&str]) -> BTreeMap<&str, TokenStream> {
+        pub(super) fn tagged_types<'a, M>(&'a self, input: &'a [Defn], exclude_defs: &[&str], symtab: &Symtab<M>) -> BTreeMap<&'a str, TokenStream> {
             let mut result = BTreeMap::new();
             let mut tag = None;
             for def in input {
                 match (def, &tag) {
                     (Defn::Const(name, _), _) if !exclude_defs.contains(&name.as_str()) => if (self.const_filter)(name) {
-                        tag = Some((name.as_str(), quote_ident(name)));
+                        tag = Some((name.as_str(), symtab.ident(name, NameKind::Const)));
                     },
                     (Defn::Typespec(name, _), Some(tag))  if !exclude_defs.contains(&name.as_str()) && (self.ty_filter)(name.as_str(), tag.0) => {
-                        result.insert(name.as_str(), (self.quote)(&quote_ident(name), &tag.1));
+                        result.insert(name.as_str(), (self.quote)(&symtab.ident(name, NameKind::Type), &tag.1));
                     },
                     _ => {}
                 }
@@ -178,15 +696,97 @@ pub mod pretty {
         }
     }
 
+    /// Position of each definition within the spec, for emitting in declaration order. Constants
+    /// a definition introduces, like an RPC program's numbers, go with it.
+    pub(super) fn defn_order(defns: &[Defn]) -> HashMap<String, usize> {
+        defns
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, defn)| defn.scope_names().into_iter().chain([defn.name().to_string()]).map(move |name| (name, idx)))
+            .collect()
+    }
+
     pub(super) fn filter_exlude<'a, V>(exclude_defs: &'a [&str]) -> impl 'a + FnMut(&(&String, V)) -> bool {
         move |(name, _): &(&String, V),| {
             !exclude_defs.contains(&name.as_str())
         }
     }
 
-    #[derive(Clone)]
-    pub(super) struct Meta {
-        pub(super) header: bool,
+    /// What the pretty generator annotates each definition in its `Symtab` with.
+    #[derive(Debug, Clone)]
+    pub struct Meta {
+        /// Whether it's from the XDR header, rather than the main input
+        pub header: bool,
+    }
+
+    // The type and method names in the inherent impls among `items`.
+    fn inherent_methods<'a>(items: impl IntoIterator<Item = &'a syn::Item>) -> BTreeSet<(String, String)> {
+        let mut methods = BTreeSet::new();
+        for item in items {
+            let (ty, items) = match item {
+                syn::Item::Impl(imp) if imp.trait_.is_none() => match &*imp.self_ty {
+                    syn::Type::Path(path) => match path.path.segments.last() {
+                        Some(seg) => (seg.ident.to_string(), &imp.items),
+                        None => continue,
+                    },
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            for item in items {
+                if let syn::ImplItem::Method(method) = item {
+                    methods.insert((ty.clone(), method.sig.ident.to_string()));
+                }
+            }
+        }
+        methods
+    }
+
+    // The names consts among `items` define and their `use`s import, or `None` if they glob
+    // import any.
+    pub(super) fn provided_names<'a>(items: impl IntoIterator<Item = &'a syn::Item>) -> Option<HashSet<String>> {
+        fn imported(tree: &syn::UseTree, names: &mut HashSet<String>) -> Option<()> {
+            match tree {
+                syn::UseTree::Path(path) => imported(&path.tree, names),
+                syn::UseTree::Name(name) => {
+                    names.insert(name.ident.to_string());
+                    Some(())
+                }
+                syn::UseTree::Rename(rename) => {
+                    names.insert(rename.rename.to_string());
+                    Some(())
+                }
+                syn::UseTree::Glob(_) => None,
+                syn::UseTree::Group(group) => group.items.iter().try_for_each(|tree| imported(tree, names)),
+            }
+        }
+
+        let mut names = HashSet::new();
+        for item in items {
+            match item {
+                syn::Item::Const(item) => {
+                    names.insert(item.ident.to_string());
+                }
+                syn::Item::Use(item) => imported(&item.tree, &mut names)?,
+                _ => (),
+            }
+        }
+        Some(names)
+    }
+
+    // Generated inherent helpers would be duplicate definitions of hand-written methods of the
+    // same name.
+    pub(super) fn check_helper_clashes<'a>(body: &syn::File, handwritten: impl IntoIterator<Item = &'a syn::Item>) -> crate::Result<()> {
+        let handwritten = inherent_methods(handwritten);
+        let clashes = inherent_methods(&body.items)
+            .into_iter()
+            .filter(|method| handwritten.contains(method))
+            .map(|(ty, method)| crate::Error::HelperClash { ty, method })
+            .collect();
+        match crate::Error::from_many(clashes) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
     pub(super) fn filter_header_out<V>((_, def): &(&String, &SymDef<V, Meta>)) -> bool {
@@ -196,57 +796,188 @@ pub mod pretty {
 
 /// Generate pretty Rust code from an RFC4506 XDR specification
 ///
-/// `input` is a string with XDR specification
-/// `header` is Rust code to prepend before generated output
+/// `input` is a string with XDR specification, which like `xdr_header` is normalized first as
+/// `generate_with_options` does, so a byte order mark or CRLF line endings are fine.
+/// `options.generate` holds the options shared with `generate_with_options`, including the
+/// `rust_header` which goes before the generated items; `rust_headers` and `rust_trailer` add
+/// further Rust code before and after them.
 #[cfg(feature = "pretty")]
 pub fn generate_pretty(input: &str, options: &pretty::GenerateOptions) -> Result<String, anyhow::Error> {
+    generate_pretty_with_defns(input, options).map(|(code, _)| code)
+}
+
+/// Generate pretty Rust code from an RFC4506 XDR specification, returning its definitions
+///
+/// Like `generate_pretty`, but also returns the main input's definitions, as
+/// `Generated::defns` does, so custom tagging passes needn't parse the input again.
+#[cfg(feature = "pretty")]
+pub fn generate_pretty_with_defns(input: &str, options: &pretty::GenerateOptions) -> Result<(String, Vec<ast::Defn>), anyhow::Error> {
+    generate_pretty_all(input, options).map(|(code, defns, _)| (code, defns))
+}
+
+/// Generate pretty Rust code from an RFC4506 XDR specification, returning its symbol table
+///
+/// Like `generate_pretty`, but also returns the `Symtab` the code was generated from, covering
+/// the XDR header and main input, so build scripts can look up resolved constants, enum members
+/// and types, such as to build protocol version tables, without parsing the input again.
+#[cfg(feature = "pretty")]
+pub fn generate_pretty_with_symtab(input: &str, options: &pretty::GenerateOptions) -> Result<(String, Symtab<pretty::Meta>), anyhow::Error> {
+    generate_pretty_all(input, options).map(|(code, _, xdr)| (code, xdr))
+}
+
+#[cfg(feature = "pretty")]
+fn generate_pretty_all(input: &str, options: &pretty::GenerateOptions) -> Result<(String, Vec<ast::Defn>, Symtab<pretty::Meta>), anyhow::Error> {
     use anyhow::Context;
     use proc_macro2::TokenStream;
 
-    let mut file = syn::parse_file(options.rust_header)?;
+    let input: &str = &spec::normalize_source(input.as_bytes());
+    let xdr_header: &str = &spec::normalize_source(options.xdr_header.as_bytes());
+    // The fields since moved to the shared options still work, when those aren't set
+    #[allow(deprecated)]
+    let generate = &crate::GenerateOptions {
+        rust_header: if options.generate.rust_header.is_empty() { options.rust_header } else { options.generate.rust_header },
+        exclude_defs: if options.generate.exclude_defs.is_empty() { options.exclude_defs } else { options.generate.exclude_defs },
+        ..options.generate.clone()
+    };
+    let mut file = syn::parse_file(generate.rust_header)?;
+    for (idx, header) in options.rust_headers.iter().enumerate() {
+        let header = syn::parse_file(header).with_context(|| format!("parse Rust header {}", idx))?;
+        file.attrs.extend(header.attrs);
+        file.items.extend(header.items);
+    }
+    let trailer = syn::parse_file(options.rust_trailer).context("parse Rust trailer")?;
 
-    let xdr_header_defns = if options.xdr_header.is_empty() {
+    let mut xdr_header_defns = if xdr_header.is_empty() {
         vec![]
     } else {
-        spec::specification(options.xdr_header).context("parse XDR header")?
+        spec::specification(xdr_header).context("parse XDR header")?
+    };
+    let header_len = xdr_header_defns.len();
+    let directives = spec::Directives {
+        include_dir: generate.include_dir,
+        include_root: generate.include_root,
+        defines: generate.defines,
+        quirks: generate.quirks,
+    };
+    let input = &match generate.defines {
+        Some(defines) => std::borrow::Cow::Owned(spec::preprocess(input, defines).context("preprocess main XDR input")?),
+        None => std::borrow::Cow::Borrowed(input),
     };
-    let defns = spec::specification(&input).context("parse main XDR input")?;
+    let input = &match generate.quirks {
+        true => spec::rpcgen_quirks(input),
+        false => std::borrow::Cow::Borrowed(&**input),
+    };
+    xdr_header_defns.extend(spec::specification_with_directives(input, &directives).context("parse main XDR input")?);
 
-    let mut tagged_types = options.tagging.as_ref().map(|tagging| tagging.tagged_types(&defns, options.exclude_defs)).unwrap_or_default();
+    // Resolved together, since the header may define names which would otherwise be aliases
+    match generate.type_aliases {
+        Some(aliases) => aliases.resolve(&mut xdr_header_defns),
+        None => TypeAliases::default().resolve(&mut xdr_header_defns),
+    }
+    let mut defns = xdr_header_defns.split_off(header_len);
+    let keep = generate.only.map(|only| only_defs(&defns, only, generate.only_with_deps)).transpose()?;
+    let flattened = match generate.namespaces {
+        Namespaces::Modules => HashMap::new(),
+        Namespaces::Flatten => spec::flatten_namespaces(&mut defns).context("flatten namespaces")?,
+    };
+    if generate.optional_unions {
+        spec::optional_unions(&mut defns);
+    }
+    let mut owners = spec::lift_anonymous(&mut defns).context("lift anonymous types")?;
+    // Definitions moved out of namespaces go with the namespace, as do types lifted from them
+    for owner in owners.values_mut() {
+        if let Some(ns) = flattened.get(owner) {
+            *owner = ns.clone();
+        }
+    }
+    owners.extend(flattened);
+    let spec_source = spec::specification_source(input).context("split definitions")?;
+    defns.extend(spec::message_envelope(&spec_source).context("message envelope")?);
+    // Of the wire definitions, before they're given Rust types
+    #[cfg(feature="fingerprint_const")]
+    let fingerprint = spec::fingerprint(&defns);
+    spec::timestamp_annotations(&spec_source, &mut defns).context("timestamp annotations")?;
+    // Lifted anonymous types go with the definition they were declared in
+    let owner = |name: &String| owners.get(name).cloned().unwrap_or_else(|| name.clone());
+    // Patterns are matched against the definitions here, as the tagging and constant filters want
+    // names
+    let excluded = NamePatterns::new(generate.exclude_defs)?;
+    // Definitions `only` doesn't keep are left out as if excluded
+    let skipped = |name: &str| {
+        let owner = owners.get(name).map_or(name, String::as_str);
+        excluded.matches(name) || excluded.matches(owner) || keep.as_ref().is_some_and(|keep| !keep.contains(owner))
+    };
+    let skipped_defs = defns.iter().map(ast::Defn::name).chain(owners.keys().map(String::as_str)).filter(|name| skipped(name));
+    let exclude_defs: &[&str] = &generate.exclude_defs.iter().copied().chain(skipped_defs).collect::<Vec<_>>();
+    let impls_only = NamePatterns::new(generate.impls_only)?;
+    let defined = |name: &String| !impls_only.matches(&owner(name));
+    let annotated = spec::codec_annotations(&spec_source).context("codec annotations")?;
+    let codecs = |name: &String| match generate.types_only {
+        true => crate::Codecs::None,
+        false => annotated.get(&owner(name)).copied().unwrap_or(generate.codecs),
+    };
+    let docs = spec::leading_docs(&spec_source).context("leading comments")?;
+    let (sections, section_order) = spec::section_modules(&spec_source).context("section modules")?;
+    let section = |name: &String| sections.get(&owner(name));
 
     let mut xdr = Symtab::new();
-    
+    configure(&mut xdr, generate)?;
+    let mut tagged_types = options.tagging.as_ref().map(|tagging| tagging.tagged_types(&defns, exclude_defs, &xdr)).unwrap_or_default();
     xdr.update_consts(&xdr_header_defns, &pretty::Meta{ header: true });
     xdr.update_consts(&defns, &pretty::Meta{ header: false });
+    if let Some(err) = Error::from_many(xdr.errors()) {
+        return Err(err.into());
+    }
+    // Declared where the code is included, which its contents are generated a module deeper than
+    let module = match generate.module {
+        Some(name) => {
+            let vis: syn::Visibility = syn::parse2(xdr.vis())?;
+            let imports = module_imports(generate.types_only, generate.module_imports).context("module imports")?;
+            xdr = xdr.in_module();
+            Some((vis, spec::quote_ident(name), imports))
+        }
+        None => None,
+    };
+
+    // Anything might come from a glob import, so then nothing can be checked
+    if let Some(rust_consts) = pretty::provided_names(file.items.iter().chain(&trailer.items)) {
+        spec::check_size_constants(&defns, &xdr, &rust_consts, |meta: &pretty::Meta| {
+            if meta.header { "the XDR header" } else { "the main input" }
+        })?;
+    }
+    // Items in section modules are generated a module deeper
+    let in_section = xdr.in_module();
+    let symtab = |name: &String| if section(name).is_some() { &in_section } else { &xdr };
 
     let consts = xdr
         .constants()
         .filter(pretty::filter_header_out)
         .map(SymDef::map_value)
-        .filter(pretty::filter_exlude(options.exclude_defs))
-        .filter_map(|(c, &(v, ref scope))| {
-            if scope.is_none() {
-                Some(spec::Const(c.clone(), v))
-            } else {
-                None
-            }
-        })
-        .map(|c| c.define(&xdr));
+        .filter(spec::is_local)
+        .filter(pretty::filter_exlude(exclude_defs))
+        .filter_map(|entry| spec::const_item(symtab(entry.1.1.as_ref().unwrap_or(entry.0)), entry).map(|item| (*entry.0 == item.0, item)))
+        .filter(|(_, (owner, _))| !exclude_defs.contains(&owner.as_str()));
+    // Enum members' constants go after their enum
+    let (consts, member_consts): (Vec<_>, Vec<_>) = consts.partition(|(unscoped, _)| *unscoped);
+    let consts = consts.into_iter().map(|(_, (owner, item))| (owner, Ok(item)));
+    let member_consts = member_consts.into_iter().map(|(_, (owner, item))| (owner, Ok(item)));
 
     let typespecs: Vec<_> = xdr
         .typespecs()
         .filter(pretty::filter_header_out)
         .map(SymDef::map_value)
-        .filter(pretty::filter_exlude(options.exclude_defs))
+        .filter(spec::is_local)
+        .filter(pretty::filter_exlude(exclude_defs))
         .map(|(n, ty)| spec::Typespec(n.clone(), ty.clone()))
         .collect();
     
     let typedefines = typespecs
         .iter()
+        .filter(|c| defined(&c.0))
         .flat_map(|c| {
             [
-                c.define(&xdr),
-                Ok(tagged_types.remove(c.0.as_str()).unwrap_or_default()),
+                (c.0.clone(), c.define(symtab(&c.0)).map(|item| spec::with_doc(docs.get(&c.0), item))),
+                (c.0.clone(), Ok(tagged_types.remove(c.0.as_str()).unwrap_or_default())),
             ]
         });
 
@@ -254,26 +985,91 @@ pub fn generate_pretty(input: &str, options: &pretty::GenerateOptions) -> Result
         .typesyns()
         .filter(pretty::filter_header_out)
         .map(SymDef::map_value)
-        .filter(pretty::filter_exlude(options.exclude_defs))
+        .filter(spec::is_local)
+        .filter(pretty::filter_exlude(exclude_defs))
+        .filter(|(n, _)| defined(n))
         .map(|(n, ty)| spec::Typesyn(n.clone(), ty.clone()))
-        .map(|c| c.define(&xdr));
+        .map(|c| (c.0.clone(), c.define(symtab(&c.0)).map(|item| spec::with_doc(docs.get(&c.0), item))));
+
+    let namespaces = defns.iter().filter_map(|defn| match defn {
+        spec::Defn::Namespace(name, defns) if !exclude_defs.contains(&name.as_str()) => {
+            Some((name.clone(), spec::namespace_module(name, defns, symtab(name), &pretty::Meta{ header: false }, codecs(name))))
+        }
+        _ => None,
+    });
 
     let packers = typespecs
         .iter()
-        .filter_map(|c| c.pack(&xdr).transpose());
+        .filter(|c| codecs(&c.0).pack())
+        .filter_map(|c| c.pack(&xdr).transpose().map(|p| (c.0.clone(), p)));
 
     let unpackers = typespecs
         .iter()
-        .filter_map(|c| c.unpack(&xdr).transpose());
+        .filter(|c| codecs(&c.0).unpack())
+        .filter_map(|c| c.unpack(&xdr).transpose().map(|u| (c.0.clone(), u)));
 
-    let stream = consts
-            .chain(typedefines)
-            .chain(typesyns)
-            .chain(packers)
-            .chain(unpackers)
-            .collect::<Result<TokenStream>>()?;
+    // Which the types' definitions would have had
+    let conversions = typespecs
+        .iter()
+        .filter(|c| !defined(&c.0) && codecs(&c.0).unpack())
+        .filter_map(|c| c.conversions(&xdr).map(|item| (c.0.clone(), Ok(item))));
+
+    let mut defines: Vec<_> = consts.chain(typedefines).chain(member_consts).chain(typesyns).chain(namespaces).collect();
+    let mut impls: Vec<_> = packers.chain(unpackers).chain(conversions).collect();
+
+    if options.spec_order {
+        // Stable sorts, so each type's definition stays ahead of its tag, and its Pack impl
+        // ahead of its Unpack impl.
+        let order = pretty::defn_order(&defns);
+        defines.sort_by_key(|(name, _)| order.get(name.as_str()).copied());
+        impls.sort_by_key(|(name, _)| order.get(name.as_str()).copied());
+    }
+
+    let mut items: Vec<_> = defines.into_iter().chain(impls).collect();
+
+    #[cfg(feature="consts_module")]
+    items.push((String::new(), Ok(spec::consts_module(
+        &xdr,
+        xdr.constants()
+            .filter(pretty::filter_header_out)
+            .filter(spec::is_local)
+            .filter(|(name, def)| !skipped(def.value.1.as_ref().unwrap_or(name)))
+            .map(SymDef::map_value),
+    ))));
+
+    #[cfg(feature="fingerprint_const")]
+    items.push((String::new(), Ok(spec::fingerprint_const(&xdr, &fingerprint))));
+
+    #[cfg(feature="dialect")]
+    if let Some(dialect) = &generate.dialect {
+        items.push((String::new(), Ok(spec::dialect_const(&xdr, dialect))));
+    }
+
+    if options.impls_with_defns {
+        // Stable sort by where each name is first defined pulls impls up to their definitions
+        let first: std::collections::HashMap<String, usize> = items
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(idx, (name, _))| (name.clone(), idx))
+            .collect();
+        items.sort_by_key(|(name, _)| first[name]);
+    }
+
+    // Items at the top level, then each section's in its module
+    let (top, in_sections): (Vec<_>, Vec<_>) = items.into_iter().partition(|(name, _)| section(name).is_none());
+    let mut stream: TokenStream = section_order.iter().map(|module| spec::section_import(module)).collect();
+    stream.extend(top.into_iter().map(|(_, tokens)| tokens).collect::<Result<TokenStream>>()?);
+    let mut sectioned: HashMap<&String, Vec<TokenStream>> = HashMap::new();
+    for (name, tokens) in in_sections {
+        sectioned.entry(section(&name).expect("partitioned")).or_default().push(tokens?);
+    }
+    for module in &section_order {
+        stream.extend(spec::section_module(&xdr, module, sectioned.remove(module).unwrap_or_default()));
+    }
 
     let body: syn::File = syn::parse2(stream)?;
+    pretty::check_helper_clashes(&body, file.items.iter().chain(&trailer.items))?;
 
     // prettyplease treats this as newline
     fn trailing_hardbreak(item: syn::Item) -> [syn::Item; 2] {
@@ -281,10 +1077,75 @@ pub fn generate_pretty(input: &str, options: &pretty::GenerateOptions) -> Result
     }
 
     file.attrs.append(&mut {body.attrs});
+    file.attrs.extend(trailer.attrs);
+    // The module's imports go after the Rust header
+    let header_len = file.items.len();
     file.items.reserve(body.items.len() * 2);
     file.items.extend(body.items.into_iter().map(trailing_hardbreak).flatten());
+    file.items.extend(trailer.items);
+
+    if let Some((vis, ident, imports)) = module {
+        let mut items = std::mem::take(&mut file.items);
+        let hardbreak = (!imports.is_empty()).then(|| syn::Item::Verbatim(TokenStream::new()));
+        items.splice(header_len..header_len, imports.into_iter().chain(hardbreak));
+        file.items = vec![syn::Item::Mod(syn::ItemMod {
+            attrs: std::mem::take(&mut file.attrs),
+            vis,
+            mod_token: Default::default(),
+            ident,
+            content: Some((Default::default(), items)),
+            semi: None,
+        })];
+    }
 
-    Ok(prettyplease::unparse(&file))
+    Ok((prettyplease::unparse(&file), defns, xdr))
+}
+
+// The `use` items at the top of a module the generated code is wrapped in
+#[cfg(feature = "pretty")]
+fn module_imports(types_only: bool, paths: &[&str]) -> syn::Result<Vec<syn::Item>> {
+    let xdr_codec = (!types_only).then(|| syn::parse_quote!(#[allow(unused_imports)] use xdr_codec;));
+    let imports = paths.iter().map(|path| syn::parse_str(&format!("use {};", path)));
+    xdr_codec.map(Ok).into_iter().chain(imports).collect()
+}
+
+/// Line endings for files `compile_with_options` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Newline {
+    /// `\n`, as rustc and rustfmt write
+    #[default]
+    Lf,
+    /// `\r\n`, for Windows tools which expect it
+    CrLf,
+}
+
+impl Newline {
+    fn apply(self, text: Vec<u8>) -> Vec<u8> {
+        match self {
+            Newline::Lf => text,
+            Newline::CrLf => {
+                let mut out = Vec::with_capacity(text.len() + text.len() / 32);
+                for (idx, &byte) in text.iter().enumerate() {
+                    if byte == b'\n' && (idx == 0 || text[idx - 1] != b'\r') {
+                        out.push(b'\r');
+                    }
+                    out.push(byte);
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Options for `compile_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions<'a> {
+    /// How the code is generated. With `source_map`, the `SourceMap` is written as JSON to the
+    /// generated file's name with `.map.json` added.
+    pub generate: GenerateOptions<'a>,
+    /// Where to put the generated file; defaults to `OUT_DIR`, or the current directory
+    pub out_dir: Option<&'a Path>,
+    pub newline: Newline,
 }
 
 /// Simplest possible way to generate Rust code from an XDR specification.
@@ -313,27 +1174,51 @@ pub fn generate_pretty(input: &str, options: &pretty::GenerateOptions) -> Result
 /// own implementations of `Pack` and `Unpack` for them.
 pub fn compile<P>(infile: P, exclude_defs: &[&str]) -> Result<()>
 where
-    P: AsRef<Path> + Display,
+    P: AsRef<Path>,
 {
-    let input = File::open(&infile)?;
-
-    let mut outdir = PathBuf::from(env::var("OUT_DIR").unwrap_or(String::from(".")));
-    let outfile = PathBuf::from(infile.as_ref())
-        .file_stem()
-        .unwrap()
-        .to_owned()
-        .into_string()
-        .unwrap()
-        .replace("-", "_");
-
-    outdir.push(&format!("{}_xdr.rs", outfile));
-
-    let output = File::create(outdir)?;
-
-    generate(
-        infile.as_ref().as_os_str().to_str().unwrap_or("<unknown>"),
-        input,
-        output,
-        exclude_defs,
-    )
+    let generate = GenerateOptions { exclude_defs, ..Default::default() };
+    compile_with_options(infile, &CompileOptions { generate, ..Default::default() })
+}
+
+/// Generate Rust code from an XDR specification into a file, like `compile`, with options
+///
+/// Paths needn't be UTF-8, so `OUT_DIR` and the specification can be anywhere a build can put
+/// them, and `options.newline` can give the file Windows line endings.
+pub fn compile_with_options<P>(infile: P, options: &CompileOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let source = spec::read_source(File::open(&infile)?)?;
+
+    let stem = infile.as_ref().file_stem().unwrap_or_default();
+    // Only UTF-8 names can be respelled; others are used as they are
+    let mut outfile = stem.to_str().map_or_else(|| stem.to_os_string(), |stem| stem.replace('-', "_").into());
+    outfile.push("_xdr.rs");
+    let outdir = match options.out_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from(env::var_os("OUT_DIR").unwrap_or_else(|| ".".into())),
+    };
+
+    let name = infile.as_ref().display().to_string();
+    let mut output = Vec::new();
+
+    let directives = directives(&options.generate);
+    match generate_source(&name, &source, &mut output, &options.generate, &directives) {
+        Ok(generated) => {
+            for warning in generated.warnings {
+                println!("{}", diagnostic::cargo_warning(&name, &warning));
+            }
+            if let Some(source_map) = generated.source_map {
+                let mut mapfile = outfile.clone();
+                mapfile.push(".map.json");
+                File::create(outdir.join(mapfile))?.write_all(&options.newline.apply(source_map.to_json().into_bytes()))?;
+            }
+            File::create(outdir.join(outfile))?.write_all(&options.newline.apply(output))?;
+            Ok(())
+        }
+        Err(err) => {
+            eprint!("{}", diagnostic::render_error(&name, &source, &err));
+            Err(err)
+        }
+    }
 }
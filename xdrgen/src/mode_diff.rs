@@ -0,0 +1,97 @@
+//! Comparing the code `generate` and `generate_pretty` produce for the same specification
+//!
+//! The two share their code generation, but each chooses and arranges the items itself, so a
+//! spec can come out differently from each. Formatting and item order aren't differences.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use quote::ToTokens;
+
+/// An item the two modes of generation disagree on; `None` where one of them has no such item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemDiff {
+    /// What the item is, such as `struct point` or `impl xdr_codec :: Pack < Out > for point`
+    pub item: String,
+    /// The item from `generate`, formatted
+    pub generated: Option<String>,
+    /// The item from `generate_pretty`, formatted
+    pub pretty: Option<String>,
+}
+
+impl fmt::Display for ItemDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let side = |tokens: &Option<String>| tokens.clone().unwrap_or_else(|| "-".to_string());
+        write!(f, "{}:\n--- generate\n{}\n--- pretty\n{}", self.item, side(&self.generated), side(&self.pretty))
+    }
+}
+
+// Each item in `code`, formatted, by what it is; repeats, like a type's inherent impls, are
+// numbered in order.
+fn items(code: &str) -> Result<BTreeMap<String, String>, anyhow::Error> {
+    let file = syn::parse_file(code)?;
+    let mut items = BTreeMap::new();
+
+    for item in file.items {
+        let key = match &item {
+            syn::Item::Const(item) => format!("const {}", item.ident),
+            syn::Item::Struct(item) => format!("struct {}", item.ident),
+            syn::Item::Enum(item) => format!("enum {}", item.ident),
+            syn::Item::Type(item) => format!("type {}", item.ident),
+            syn::Item::Mod(item) => format!("mod {}", item.ident),
+            syn::Item::Fn(item) => format!("fn {}", item.sig.ident),
+            syn::Item::Impl(item) => match &item.trait_ {
+                Some((_, path, _)) => format!("impl {} for {}", path.to_token_stream(), item.self_ty.to_token_stream()),
+                None => format!("impl {}", item.self_ty.to_token_stream()),
+            },
+            item => item.to_token_stream().to_string(),
+        };
+        let key = (1..)
+            .map(|n| if n == 1 { key.clone() } else { format!("{} #{}", key, n) })
+            .find(|key| !items.contains_key(key))
+            .unwrap();
+        // Both formatted the same way, as prettyplease adds commas and braces to some tokens
+        let item = prettyplease::unparse(&syn::File { shebang: None, attrs: Vec::new(), items: vec![item] });
+        items.insert(key, item);
+    }
+
+    Ok(items)
+}
+
+/// Compare the items `generate` and `generate_pretty` produce from `input` with their default
+/// options, returning those which differ, by what they are.
+///
+/// An empty result means switching from one to the other won't change the generated code, other
+/// than its formatting and order.
+pub fn diff_modes(input: &str) -> Result<Vec<ItemDiff>, anyhow::Error> {
+    let mut generated = Vec::new();
+    crate::generate_with_options("", input.as_bytes(), &mut generated, &Default::default())?;
+    let generated = items(&String::from_utf8(generated)?)?;
+
+    let pretty = items(&crate::generate_pretty(input, &Default::default())?)?;
+
+    let mut keys: Vec<_> = generated.keys().chain(pretty.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    Ok(keys
+        .into_iter()
+        .filter(|key| generated.get(*key) != pretty.get(*key))
+        .map(|key| ItemDiff { item: key.clone(), generated: generated.get(key).cloned(), pretty: pretty.get(key).cloned() })
+        .collect())
+}
+
+#[test]
+fn test_items() {
+    let code = "pub struct foo { pub a: i32 } impl foo { fn x() {} } impl foo { fn y() {} } \
+                impl<Out: xdr_codec::Write> xdr_codec::Pack<Out> for foo { fn pack(&self, __xdr_out: &mut Out) -> xdr_codec::Result<usize> { Ok(0) } }";
+    let found = items(code).unwrap();
+
+    assert_eq!(
+        found.keys().collect::<Vec<_>>(),
+        ["impl foo", "impl foo #2", "impl xdr_codec :: Pack < Out > for foo", "struct foo"]
+    );
+    assert!(found["impl foo #2"].contains("fn y()"));
+    // Formatting doesn't matter
+    assert_eq!(items(&code.replace(" {", "{\n")).unwrap(), found);
+}
@@ -0,0 +1,79 @@
+//! Matching definition names against the patterns given to `exclude_defs`, `impls_only` and
+//! `only`.
+//!
+//! A pattern is a definition's name, a glob such as `nfs3_*`, where `*` stands for any run of
+//! characters and `?` for any one, or a regex between slashes such as `/^(nfs|mount)3_/`. Globs
+//! match whole names, regexes anywhere in them unless anchored.
+
+use std::collections::HashSet;
+
+use regex::Regex;
+
+use crate::{Error, Result};
+
+#[derive(Debug, Default)]
+pub(crate) struct NamePatterns {
+    names: HashSet<String>,
+    patterns: Vec<Regex>,
+}
+
+impl NamePatterns {
+    pub fn new(patterns: &[&str]) -> Result<NamePatterns> {
+        let mut res = NamePatterns::default();
+        for &pattern in patterns {
+            match pattern_regex(pattern) {
+                Some(re) => {
+                    let re = Regex::new(&re).map_err(|err| Error::InvalidPattern { pattern: pattern.to_string(), problem: err.to_string() })?;
+                    res.patterns.push(re);
+                }
+                None => {
+                    res.names.insert(pattern.to_string());
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.names.contains(name) || self.patterns.iter().any(|re| re.is_match(name))
+    }
+}
+
+/// The names which `patterns` stand for, out of `names`; plain names are kept as they are. A glob
+/// or regex which matches none of them is an error, as a misspelt name would be.
+pub(crate) fn expand<'a>(patterns: &[&'a str], names: &[&'a str]) -> Result<Vec<&'a str>> {
+    let mut res = Vec::new();
+    for &pattern in patterns {
+        if pattern_regex(pattern).is_none() {
+            res.push(pattern);
+            continue;
+        }
+        let matcher = NamePatterns::new(&[pattern])?;
+        let before = res.len();
+        res.extend(names.iter().copied().filter(|name| matcher.matches(name)));
+        if res.len() == before {
+            return Err(Error::UnknownDefinition { name: pattern.to_string() });
+        }
+    }
+    Ok(res)
+}
+
+// The regex for a glob or regex pattern, or None for a plain name
+fn pattern_regex(pattern: &str) -> Option<String> {
+    if let Some(re) = pattern.strip_prefix('/').and_then(|re| re.strip_suffix('/')) {
+        return Some(re.to_string());
+    }
+    if !pattern.contains(['*', '?']) {
+        return None;
+    }
+    let mut re = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    Some(re)
+}
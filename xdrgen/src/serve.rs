@@ -0,0 +1,319 @@
+//! Generation server for build systems, as `xdrgen serve`.
+//!
+//! Each connection carries one request: the specification's file name on a line of its own,
+//! used in messages, then the specification up to the end of the client's half of the stream.
+//! The reply is an `ok` line followed by the generated code, or an `error` line followed by
+//! rustc-style diagnostics, and then the server closes the connection.
+
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::diagnostic;
+use crate::{Error, GenerateOptions, Result};
+
+/// Options for `serve_with_options`.
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    /// Where files named by `#include "file"` directives are found, and which they must be
+    /// within. Without it, specifications which include files are refused, as any client could
+    /// otherwise read whatever the server can.
+    pub include_root: Option<PathBuf>,
+    /// Largest request accepted, in bytes
+    pub max_request: u64,
+    /// How long to wait for a client to send or take more of a request or reply before giving
+    /// up on it, if at all
+    pub timeout: Option<Duration>,
+    /// How long a client may take to send its whole request, if there's a limit, so that one
+    /// which keeps sending a little at a time can't hold a connection for long
+    pub request_timeout: Option<Duration>,
+    /// Most connections answered at once; further clients wait to be accepted
+    pub max_connections: usize,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            include_root: None,
+            max_request: 1 << 20,
+            timeout: Some(Duration::from_secs(30)),
+            request_timeout: Some(Duration::from_secs(60)),
+            max_connections: 16,
+        }
+    }
+}
+
+// How many more connections may be answered at once
+struct Slots {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+// A connection being answered, until it's dropped
+struct Slot(Arc<Slots>);
+
+impl Slots {
+    fn take(self: &Arc<Self>) -> Slot {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.freed.wait(free).unwrap();
+        }
+        *free -= 1;
+        Slot(self.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Answer requests on `listener`, each connection on a thread of its own, until accepting a
+/// connection fails.
+pub fn serve(listener: TcpListener) -> Result<()> {
+    serve_with_options(listener, ServeOptions::default())
+}
+
+/// Answer requests on `listener` like `serve`, with options.
+pub fn serve_with_options(listener: TcpListener, options: ServeOptions) -> Result<()> {
+    accept(listener.incoming(), options)
+}
+
+/// Answer requests on a Unix socket like `serve_with_options`, so that only users who can open
+/// it can send them.
+#[cfg(unix)]
+pub fn serve_unix(listener: UnixListener, options: ServeOptions) -> Result<()> {
+    accept(listener.incoming(), options)
+}
+
+// Connections whose reads and writes can time out
+trait Timeouts {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Timeouts for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Timeouts for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+// Reads a connection until a deadline, waiting no longer than what's left of it for each read
+struct Deadline<'a, S> {
+    stream: &'a S,
+    timeout: Option<Duration>,
+    end: Option<Instant>,
+}
+
+impl<'a, S: Timeouts> Read for Deadline<'a, S>
+where
+    &'a S: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(end) = self.end {
+            let left = end.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "client took too long to send its request"));
+            }
+            self.stream.set_read_timeout(Some(self.timeout.map_or(left, |timeout| timeout.min(left))))?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+// Answer each connection from `incoming` on a thread of its own, with at most
+// `max_connections` of them at once.
+fn accept<S>(mut incoming: impl Iterator<Item = io::Result<S>>, options: ServeOptions) -> Result<()>
+where
+    S: Timeouts + Send + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    let options = Arc::new(options);
+    let slots = Arc::new(Slots { free: Mutex::new(options.max_connections.max(1)), freed: Condvar::new() });
+    loop {
+        // Taken before accepting, so that further clients wait in the listen backlog
+        let slot = slots.take();
+        let stream = match incoming.next() {
+            Some(stream) => stream?,
+            None => return Ok(()),
+        };
+        let options = options.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            let input = Deadline { stream: &stream, timeout: options.timeout, end: options.request_timeout.map(|limit| Instant::now() + limit) };
+            let res = stream
+                .set_read_timeout(options.timeout)
+                .and_then(|()| stream.set_write_timeout(options.timeout))
+                .map_err(Error::from)
+                .and_then(|()| handle(input, &stream, &options));
+            if let Err(err) = res {
+                warn!("xdrgen serve: {}", err);
+            }
+        });
+    }
+}
+
+fn handle(input: impl Read, mut output: impl Write, options: &ServeOptions) -> Result<()> {
+    // One byte over, to tell a request of the greatest size from a longer one
+    let mut input = BufReader::new(input.take(options.max_request + 1));
+    let mut request = Vec::new();
+    input.read_to_end(&mut request)?;
+    if request.len() as u64 > options.max_request {
+        output.write_all(format!("error\nerror: request is longer than {} bytes\n", options.max_request).as_bytes())?;
+        // Closing with the rest unread would reset the connection before the client reads the
+        // reply, so some more is read, but no more than that before giving up on the client
+        io::copy(&mut input.into_inner().into_inner().take(options.max_request), &mut io::sink())?;
+        return Ok(());
+    }
+
+    let mut request = &request[..];
+    let mut infile = String::new();
+    request.read_line(&mut infile)?;
+    // Normalized here too, so error locations refer to the text generate parsed
+    let source = crate::spec::read_source(request)?;
+    let infile = infile.trim_end_matches(&['\r', '\n'][..]);
+
+    let root = options.include_root.as_deref();
+    let res = if root.is_none() && !crate::spec::includes(&source).is_empty() {
+        Err(Error::IncludeNotAllowed)
+    } else {
+        let mut code = Vec::new();
+        let options = GenerateOptions { include_dir: root, include_root: root, ..Default::default() };
+        crate::generate_with_options(infile, Cursor::new(source.as_bytes()), &mut code, &options).map(|_| code)
+    };
+    match res {
+        Ok(code) => {
+            output.write_all(b"ok\n")?;
+            output.write_all(&code)?;
+        }
+        Err(err) => {
+            output.write_all(b"error\n")?;
+            output.write_all(diagnostic::render_error(infile, &source, &err).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serve() {
+        use std::net::{Shutdown, TcpStream};
+
+        let dir = tempdir::TempDir::new("serve").unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("common.x"), "typedef int id;\n").unwrap();
+        std::fs::write(dir.path().join("secret.x"), "not a spec\n").unwrap();
+
+        let start = |options| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            thread::spawn(move || serve_with_options(listener, options));
+            move |spec: &str| {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                write!(stream, "foo.x\n{}", spec).unwrap();
+                stream.shutdown(Shutdown::Write).unwrap();
+                let mut reply = String::new();
+                stream.read_to_string(&mut reply).unwrap();
+                reply
+            }
+        };
+
+        let request = start(ServeOptions { max_request: 64, ..Default::default() });
+        let reply = request("struct point { int x; };");
+        assert!(reply.starts_with("ok\n"));
+        assert!(reply.contains("pub struct point"));
+
+        let reply = request("struct point { int x; }");
+        assert!(reply.starts_with("error\nerror: 1:1: expected"));
+        assert!(reply.contains("--> foo.x:1:"));
+
+        // Clients choose what's included, so without a root it's refused
+        let secret = dir.path().join("secret.x");
+        let reply = request(&format!("#include \"{}\"\n", secret.display()));
+        assert!(reply.starts_with("error\nerror: #include isn't allowed here"), "{}", reply);
+        assert!(!reply.contains("not a spec"), "{}", reply);
+
+        let reply = request(&format!("struct big {{ int x; }}; /* {} */", "x".repeat(64)));
+        assert_eq!(reply, "error\nerror: request is longer than 64 bytes\n");
+
+        let request = start(ServeOptions { include_root: Some(root.clone()), ..Default::default() });
+        let reply = request("#include \"common.x\"\nstruct point { id x; };");
+        assert!(reply.starts_with("ok\n") && reply.contains("pub type id"), "{}", reply);
+        // Nor whether a file exists
+        for path in ["../secret.x".to_string(), secret.display().to_string(), "missing.x".to_string()] {
+            let reply = request(&format!("#include \"{}\"\n", path));
+            assert!(reply.starts_with("error\n") && reply.contains("must be within"), "{}", reply);
+            assert!(!reply.contains("not a spec"), "{}", reply);
+        }
+
+        // A client which sends nothing is given up on, rather than keeping others waiting
+        let options = ServeOptions { timeout: Some(Duration::from_millis(100)), max_connections: 1, ..Default::default() };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_with_options(listener, options));
+        let idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "foo.x\nstruct point {{ int x; }};").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("ok\n"), "{}", reply);
+        drop(idle);
+
+        // Nor is one which sends a little at a time, each in less than the timeout
+        let options = ServeOptions {
+            timeout: Some(Duration::from_millis(200)),
+            request_timeout: Some(Duration::from_millis(300)),
+            max_connections: 1,
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_with_options(listener, options));
+        let mut slow = TcpStream::connect(addr).unwrap();
+        let trickle = thread::spawn(move || {
+            for _ in 0..40 {
+                if slow.write_all(b" ").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "foo.x\nstruct point {{ int x; }};").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("ok\n"), "{}", reply);
+        assert!(start.elapsed() < Duration::from_millis(1500), "{:?}", start.elapsed());
+        trickle.join().unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+//! Maps from generated code back to the specification it was generated from.
+
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+/// What a generated item is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ItemKind {
+    /// A constant
+    Const,
+    /// A type, with any inherent impls and helpers generated alongside it
+    Type,
+    /// A type's `Pack` impl
+    Pack,
+    /// A type's `Unpack` impl
+    Unpack,
+    /// A namespace's module
+    Module,
+}
+
+impl ItemKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ItemKind::Const => "const",
+            ItemKind::Type => "type",
+            ItemKind::Pack => "pack",
+            ItemKind::Unpack => "unpack",
+            ItemKind::Module => "module",
+        }
+    }
+}
+
+/// A generated item, and where in the specification it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMapItem {
+    /// The name of the constant or type, or for impls the type they're for
+    pub name: String,
+    pub kind: ItemKind,
+    /// Lines of the generated code, 1-based and inclusive
+    pub generated: RangeInclusive<usize>,
+    /// Lines of the definition in the specification, 1-based and inclusive. Anonymous types are
+    /// attributed to the definition they were declared in.
+    pub spec: RangeInclusive<usize>,
+}
+
+/// Where each item of generated code came from in the specification, for tools such as editors
+/// to go from one to the other.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// The specification, as named to the generator
+    pub spec_file: String,
+    /// Items in the order they were generated. Code generated from the specification as a whole,
+    /// such as the `consts_module` feature's module, isn't included.
+    pub items: Vec<SourceMapItem>,
+}
+
+impl SourceMap {
+    /// The map as JSON, one item per line:
+    ///
+    /// ```json
+    /// {
+    ///   "spec": "simple.x",
+    ///   "items": [
+    ///     {"name": "point", "kind": "type", "generated": [8, 8], "spec": [3, 6]}
+    ///   ]
+    /// }
+    /// ```
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "{{\n  \"spec\": {},\n  \"items\": [", json_string(&self.spec_file));
+        for (idx, item) in self.items.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "    {{\"name\": {}, \"kind\": \"{}\", \"generated\": [{}, {}], \"spec\": [{}, {}]}}{}",
+                json_string(&item.name),
+                item.kind.as_str(),
+                item.generated.start(),
+                item.generated.end(),
+                item.spec.start(),
+                item.spec.end(),
+                if idx + 1 < self.items.len() { "," } else { "" },
+            );
+        }
+        out.push_str("  ]\n}\n");
+
+        out
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+#[test]
+fn test_to_json() {
+    let map = SourceMap {
+        spec_file: "dir\\\"odd\".x".into(),
+        items: vec![
+            SourceMapItem { name: "A".into(), kind: ItemKind::Const, generated: 8..=8, spec: 1..=1 },
+            SourceMapItem { name: "point".into(), kind: ItemKind::Pack, generated: 12..=12, spec: 3..=6 },
+        ],
+    };
+
+    assert_eq!(
+        map.to_json(),
+        "{\n  \"spec\": \"dir\\\\\\\"odd\\\".x\",\n  \"items\": [\n    \
+         {\"name\": \"A\", \"kind\": \"const\", \"generated\": [8, 8], \"spec\": [1, 1]},\n    \
+         {\"name\": \"point\", \"kind\": \"pack\", \"generated\": [12, 12], \"spec\": [3, 6]}\n  ]\n}\n"
+    );
+}
@@ -0,0 +1,318 @@
+//! Interpreting encoded data with a specification, rather than generated code
+//!
+//! Each primitive value is flattened to its path from the top-level type, such as
+//! `msg.items[2].name`, and a rendering of it, so that two interpretations of the same bytes
+//! can be compared field by field.
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+
+use super::{Decl, EnumDefn, EnumWidth, Symtab, Type, Value};
+
+// Nesting of types beyond which data is assumed to be garbage, rather than exhaust the stack.
+const MAX_DEPTH: usize = 2048;
+
+/// An interpretation of encoded data as a type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoded {
+    /// Each primitive value with its path, in encoding order. Decoding stops at the first
+    /// problem, which is the last entry, as `error: problem`.
+    pub fields: Vec<(String, String)>,
+    /// Bytes left over after the value.
+    pub unconsumed: usize,
+}
+
+/// A field two interpretations disagree on; `None` where one of them has no such field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub path: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let side = |val: &Option<String>| val.clone().unwrap_or_else(|| "-".to_string());
+        write!(f, "{}: {} => {}", self.path, side(&self.old), side(&self.new))
+    }
+}
+
+// Where decoding stopped, and why.
+type Failure = (String, String);
+
+struct Decoder<'a, M> {
+    symtab: &'a Symtab<M>,
+    input: &'a [u8],
+    fields: Vec<(String, String)>,
+    depth: usize,
+}
+
+impl<'a, M> Decoder<'a, M> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.input.len() < len {
+            return Err(format!("needs {} more bytes, but only {} are left", len, self.input.len()));
+        }
+        let (head, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(head)
+    }
+
+    fn word(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn hyper(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // Opaque data, padded to a multiple of 4 bytes.
+    fn padded(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let data = self.take(len)?;
+        self.take((4 - len % 4) % 4)?;
+        Ok(data)
+    }
+
+    fn length(&mut self, bound: &Option<Value>) -> Result<usize, String> {
+        let len = self.word()?;
+        match bound.as_ref().and_then(|bound| self.symtab.eval(bound)) {
+            Some(max) if i64::from(len) > max => Err(format!("length {} is over the maximum {}", len, max)),
+            _ => Ok(len as usize),
+        }
+    }
+
+    fn size(&self, size: &Value) -> Result<usize, String> {
+        self.symtab.eval(size).map(|size| size as usize).ok_or_else(|| format!("unknown size {:?}", size))
+    }
+
+    fn push(&mut self, path: &str, val: String) {
+        self.fields.push((path.to_string(), val))
+    }
+
+    fn member(&self, edefs: &[EnumDefn], val: i64) -> Result<String, String> {
+        edefs
+            .iter()
+            .find(|edef| self.symtab.getconst(&edef.0).map(|(v, _)| v) == Some(val))
+            .map(|edef| format!("{} ({})", edef.0, val))
+            .ok_or_else(|| format!("{} isn't a member of the enum", val))
+    }
+
+    // Decode a union's discriminant, returning its value.
+    fn discriminant(&mut self, path: &str, ty: &Type) -> Result<i64, Failure> {
+        use super::Type::*;
+
+        let fail = |problem: std::string::String| (path.to_string(), problem);
+        let (val, shown) = match ty {
+            Int => self.word().map(|v| v as i32 as i64).map(|v| (v, v.to_string())),
+            UInt => self.word().map(|v| v as i64).map(|v| (v, v.to_string())),
+            Hyper => self.hyper().map(|v| v as i64).map(|v| (v, v.to_string())),
+            Bool => self.word().map(|v| (v as i64, (v != 0).to_string())),
+            Enum(edefs, width) => {
+                let val = match width {
+                    EnumWidth::Int => self.word().map(|v| v as i32 as i64),
+                    EnumWidth::Hyper => self.hyper().map(|v| v as i64),
+                };
+                val.and_then(|val| self.member(edefs, val).map(|shown| (val, shown)))
+            }
+            Ident(name, _) => match self.symtab.typespec(name) {
+                Some(ty) => return self.discriminant(path, ty),
+                None => Err(format!("unknown type {}", name)),
+            },
+            _ => Err(format!("{:?} can't be a discriminant", ty)),
+        }
+        .map_err(fail)?;
+
+        self.push(path, shown);
+        Ok(val)
+    }
+
+    fn decl(&mut self, path: &str, decl: &Decl) -> Result<(), Failure> {
+        match decl {
+            Decl::Named(name, ty, ..) => self.ty(&format!("{}.{}", path, name), ty),
+            Decl::Void => Ok(()),
+        }
+    }
+
+    fn ty(&mut self, path: &str, ty: &Type) -> Result<(), Failure> {
+        self.depth += 1;
+        let res = if self.depth > MAX_DEPTH {
+            Err((path.to_string(), "types nested too deeply".to_string()))
+        } else {
+            self.nested(path, ty)
+        };
+        self.depth -= 1;
+        res
+    }
+
+    fn nested(&mut self, path: &str, ty: &Type) -> Result<(), Failure> {
+        use super::Type::*;
+
+        let fail = |problem: std::string::String| (path.to_string(), problem);
+        let shown = match ty {
+            Int => (self.word().map_err(fail)? as i32).to_string(),
+            UInt => self.word().map_err(fail)?.to_string(),
+            Hyper => (self.hyper().map_err(fail)? as i64).to_string(),
+            UHyper => self.hyper().map_err(fail)?.to_string(),
+            Float => f32::from_bits(self.word().map_err(fail)?).to_string(),
+            Double => f64::from_bits(self.hyper().map_err(fail)?).to_string(),
+            Bool => match self.word().map_err(fail)? {
+                0 => "false".to_string(),
+                1 => "true".to_string(),
+                v => return Err(fail(format!("{} isn't a bool", v))),
+            },
+            Enum(edefs, width) => {
+                let val = match width {
+                    EnumWidth::Int => self.word().map_err(fail)? as i32 as i64,
+                    EnumWidth::Hyper => self.hyper().map_err(fail)? as i64,
+                };
+                self.member(edefs, val).map_err(fail)?
+            }
+
+            Struct(decls) => return decls.iter().try_for_each(|decl| self.decl(path, decl)),
+            Union(selector, cases, default) => {
+                let disc = match selector.as_ref() {
+                    Decl::Named(name, ty, ..) => self.discriminant(&format!("{}.{}", path, name), ty)?,
+                    Decl::Void => return Err(fail("union has no discriminant".to_string())),
+                };
+                let arm = cases
+                    .iter()
+                    .find(|case| match selector.is_bool(self.symtab) {
+                        true => case.bool_label(self.symtab) == Some(disc != 0),
+                        false => self.symtab.eval(&case.0) == Some(disc),
+                    })
+                    .map(|case| &case.1)
+                    .or(default.as_deref());
+                return match arm {
+                    Some(decl) => self.decl(path, decl),
+                    None => Err(fail(format!("no case for discriminant {}", disc))),
+                };
+            }
+
+            Option(ty) => match self.word().map_err(fail)? {
+                0 => "none".to_string(),
+                1 => return self.ty(path, ty),
+                v => return Err(fail(format!("{} isn't an optional data marker", v))),
+            },
+            Array(ty, size) => {
+                let size = self.size(size).map_err(fail)?;
+                match ty.as_ref() {
+                    Opaque | String => hex(self.padded(size).map_err(fail)?),
+                    _ => return (0..size).try_for_each(|idx| self.ty(&format!("{}[{}]", path, idx), ty)),
+                }
+            }
+            Flex(ty, bound) | Map(ty, bound, _) => {
+                let len = self.length(bound).map_err(fail)?;
+                match ty.as_ref() {
+                    Opaque => hex(self.padded(len).map_err(fail)?),
+                    String => format!("{:?}", std::string::String::from_utf8_lossy(self.padded(len).map_err(fail)?)),
+                    _ => {
+                        self.push(path, format!("len {}", len));
+                        return (0..len).try_for_each(|idx| self.ty(&format!("{}[{}]", path, idx), ty));
+                    }
+                }
+            }
+            Boxed(ty) => return self.ty(path, ty),
+            Ident(name, _) => match self.symtab.typespec(name) {
+                Some(ty) => return self.ty(path, ty),
+                None => return Err(fail(format!("unknown type {}", name))),
+            },
+
+            Quadruple | Opaque | String => return Err(fail(format!("can't decode {:?}", ty))),
+        };
+
+        self.push(path, shown);
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode `data` as the type named `name`.
+pub fn decode<M>(symtab: &Symtab<M>, name: &str, data: &[u8]) -> Decoded {
+    let mut decoder = Decoder { symtab, input: data, fields: Vec::new(), depth: 0 };
+    let res = match symtab.typespec(&name.to_string()) {
+        Some(ty) => decoder.ty(name, ty),
+        None => Err((name.to_string(), "no such type".to_string())),
+    };
+    if let Err((path, problem)) = res {
+        decoder.fields.push((path, format!("error: {}", problem)));
+    }
+
+    Decoded { fields: decoder.fields, unconsumed: decoder.input.len() }
+}
+
+/// The fields which differ between two interpretations of the same data, in the order the old
+/// one has them, then those only the new one has.
+pub fn diff(old: &Decoded, new: &Decoded) -> Vec<FieldDiff> {
+    let old_fields: HashMap<_, _> = old.fields.iter().map(|(path, val)| (path, val)).collect();
+    let new_fields: HashMap<_, _> = new.fields.iter().map(|(path, val)| (path, val)).collect();
+
+    let mut diffs: Vec<_> = old
+        .fields
+        .iter()
+        .filter(|(path, val)| new_fields.get(path) != Some(&val))
+        .map(|(path, val)| FieldDiff { path: path.clone(), old: Some(val.clone()), new: new_fields.get(path).map(|val| val.to_string()) })
+        .collect();
+    diffs.extend(
+        new.fields
+            .iter()
+            .filter(|(path, _)| !old_fields.contains_key(path))
+            .map(|(path, val)| FieldDiff { path: path.clone(), old: None, new: Some(val.clone()) }),
+    );
+    if old.unconsumed != new.unconsumed {
+        diffs.push(FieldDiff {
+            path: "(unconsumed bytes)".to_string(),
+            old: Some(old.unconsumed.to_string()),
+            new: Some(new.unconsumed.to_string()),
+        });
+    }
+
+    diffs
+}
+
+#[test]
+fn test_decode_diff() {
+    let symtab = |spec: &str| {
+        let mut symtab = Symtab::new();
+        symtab.update_consts(&super::specification(spec).unwrap(), &());
+        symtab
+    };
+    let old = symtab(
+        "enum kind { A = 1, B = 2 };
+         union body switch (kind k) { case A: int a; case B: string b<8>; };
+         struct msg { unsigned id; body bodies<2>; opaque tag[3]; };",
+    );
+    let new = symtab(
+        "enum kind { A = 1, B = 2 };
+         union body switch (kind k) { case A: hyper a; case B: string b<8>; };
+         struct msg { unsigned id; body bodies<2>; opaque tag[3]; };",
+    );
+
+    let data = [
+        0, 0, 0, 7, // id
+        0, 0, 0, 1, // bodies len
+        0, 0, 0, 1, 0, 0, 0, 5, // A: 5
+        0, 0, 0, 0, 1, 2, 3, 0, // tag, then one word left over
+    ];
+    let old = decode(&old, "msg", &data);
+    assert_eq!(
+        old.fields,
+        [("msg.id", "7"), ("msg.bodies", "len 1"), ("msg.bodies[0].k", "A (1)"), ("msg.bodies[0].a", "5"), ("msg.tag", "000000")]
+            .iter()
+            .map(|(path, val)| (path.to_string(), val.to_string()))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(old.unconsumed, 4);
+
+    let new = decode(&new, "msg", &data);
+    let diffs: Vec<_> = diff(&old, &new).iter().map(FieldDiff::to_string).collect();
+    assert_eq!(
+        diffs,
+        [
+            "msg.bodies[0].a: 5 => 21474836480",
+            "msg.tag: 000000 => 010203",
+            "(unconsumed bytes): 4 => 0",
+        ]
+    );
+}
@@ -0,0 +1,234 @@
+//! House style checks for specifications
+//!
+//! Each rule has a level, set by a `LintConfig`. Lints at `Allow` aren't reported.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use super::{Decl, Defn, Type};
+use crate::error::Error;
+use crate::Result;
+
+/// A style rule `lint` checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LintRule {
+    /// Variable length arrays, strings and opaques must have a maximum size
+    UnboundedFlex,
+    /// Enum members must be given explicit values, rather than counting on from the last one
+    ImplicitEnumValue,
+    /// Structs, unions and enums must be named definitions, not declared inline in another type
+    AnonymousType,
+}
+
+impl LintRule {
+    pub const ALL: &'static [LintRule] = &[LintRule::UnboundedFlex, LintRule::ImplicitEnumValue, LintRule::AnonymousType];
+
+    /// The rule's name in config files and reports.
+    pub fn name(self) -> &'static str {
+        match self {
+            LintRule::UnboundedFlex => "unbounded-flex",
+            LintRule::ImplicitEnumValue => "implicit-enum-value",
+            LintRule::AnonymousType => "anonymous-type",
+        }
+    }
+}
+
+impl fmt::Display for LintRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for LintRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LintRule::ALL.iter().copied().find(|rule| rule.name() == s).ok_or_else(|| format!("unknown rule {:?}", s))
+    }
+}
+
+/// How a rule is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LintLevel {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl fmt::Display for LintLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LintLevel::Allow => "allow",
+            LintLevel::Warn => "warning",
+            LintLevel::Deny => "error",
+        })
+    }
+}
+
+impl FromStr for LintLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(LintLevel::Allow),
+            "warn" => Ok(LintLevel::Warn),
+            "deny" => Ok(LintLevel::Deny),
+            _ => Err(format!("unknown level {:?}, expected allow, warn or deny", s)),
+        }
+    }
+}
+
+/// The level of each lint rule; rules which aren't set are warnings.
+///
+/// The config file has a `rule = level` line for each rule to set, with `#` comments:
+///
+/// ```text
+/// # House style
+/// unbounded-flex = deny
+/// anonymous-type = allow
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LintConfig(BTreeMap<LintRule, LintLevel>);
+
+impl LintConfig {
+    /// Name of the config file `xdrgen lint` looks for next to a specification.
+    pub const FILE_NAME: &'static str = "xdrgen-lint.conf";
+
+    /// Parse the text of a config file.
+    pub fn parse(text: &str) -> Result<LintConfig> {
+        let mut config = LintConfig::default();
+
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |problem: String| Error::InvalidLintConfig { line: idx + 1, problem };
+            let (rule, level) = line.split_once('=').ok_or_else(|| invalid("expected rule = level".to_string()))?;
+            config.set(rule.trim().parse().map_err(invalid)?, level.trim().parse().map_err(invalid)?);
+        }
+
+        Ok(config)
+    }
+
+    pub fn set(&mut self, rule: LintRule, level: LintLevel) -> &mut Self {
+        self.0.insert(rule, level);
+        self
+    }
+
+    pub fn level(&self, rule: LintRule) -> LintLevel {
+        self.0.get(&rule).copied().unwrap_or(LintLevel::Warn)
+    }
+}
+
+/// A rule broken by part of a specification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub rule: LintRule,
+    pub level: LintLevel,
+    /// The offending definition, field or enum member, as `defn.field.field`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]: {}: {}", self.level, self.rule, self.path, self.message)
+    }
+}
+
+struct Linter<'a> {
+    config: &'a LintConfig,
+    lints: Vec<Lint>,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, rule: LintRule, path: &str, message: &str) {
+        let level = self.config.level(rule);
+        if level != LintLevel::Allow {
+            self.lints.push(Lint { rule, level, path: path.to_string(), message: message.to_string() });
+        }
+    }
+
+    fn defn(&mut self, scope: &str, defn: &Defn) {
+        let path = format!("{}{}", scope, defn.name());
+        match defn {
+            // Compound types are named by their definition
+            Defn::Typespec(_, ty) | Defn::Typesyn(_, ty) => self.ty(&path, ty, true),
+            Defn::Const(..) => (),
+            Defn::Namespace(_, defns) => {
+                let scope = format!("{}::", path);
+                defns.iter().for_each(|defn| self.defn(&scope, defn));
+            }
+            Defn::Program(_, versions, _) => {
+                for version in versions {
+                    for proc in &version.1 {
+                        let path = format!("{}.{}.{}", path, version.0, proc.0);
+                        proc.types().for_each(|ty| self.ty(&path, ty, false));
+                    }
+                }
+            }
+        }
+    }
+
+    fn decl(&mut self, path: &str, decl: &Decl) {
+        if let Decl::Named(name, ty, ..) = decl {
+            self.ty(&format!("{}.{}", path, name), ty, false)
+        }
+    }
+
+    fn ty(&mut self, path: &str, ty: &Type, named: bool) {
+        use super::Type::*;
+
+        if !named && matches!(ty, Enum(..) | Struct(..) | Union(..)) {
+            self.report(LintRule::AnonymousType, path, "type should be a named definition");
+        }
+
+        match ty {
+            Enum(edefs, _) => {
+                for edef in edefs.iter().filter(|edef| edef.1.is_none()) {
+                    self.report(LintRule::ImplicitEnumValue, &format!("{}.{}", path, edef.0), "enum member has no explicit value");
+                }
+            }
+            Struct(decls) => decls.iter().for_each(|decl| self.decl(path, decl)),
+            Union(selector, cases, default) => {
+                self.decl(path, selector);
+                cases.iter().for_each(|case| self.decl(path, &case.1));
+                if let Some(default) = default {
+                    self.decl(path, default);
+                }
+            }
+            Flex(ty, bound) | Map(ty, bound, _) => {
+                if bound.is_none() {
+                    self.report(LintRule::UnboundedFlex, path, "variable length type has no maximum size");
+                }
+                self.ty(path, ty, false)
+            }
+            Option(ty) | Array(ty, _) | Boxed(ty) => self.ty(path, ty, false),
+            _ => (),
+        }
+    }
+}
+
+/// Check `defns` against the rules in `config`, in definition order.
+pub fn lint(defns: &[Defn], config: &LintConfig) -> Vec<Lint> {
+    let mut linter = Linter { config, lints: Vec::new() };
+    defns.iter().for_each(|defn| linter.defn("", defn));
+    linter.lints
+}
+
+#[test]
+fn test_config() {
+    let config = LintConfig::parse("# house style\n\nunbounded-flex = deny\nanonymous-type=allow # for now\n").unwrap();
+    assert_eq!(config.level(LintRule::UnboundedFlex), LintLevel::Deny);
+    assert_eq!(config.level(LintRule::AnonymousType), LintLevel::Allow);
+    assert_eq!(config.level(LintRule::ImplicitEnumValue), LintLevel::Warn);
+
+    let err = |text| match LintConfig::parse(text) {
+        Err(Error::InvalidLintConfig { line, .. }) => line,
+        res => panic!("unexpected {:?}", res),
+    };
+    assert_eq!(err("unbounded-flex = deny\nno-such-rule = warn\n"), 2);
+    assert_eq!(err("anonymous-type = forbid\n"), 1);
+    assert_eq!(err("\nanonymous-type\n"), 2);
+}
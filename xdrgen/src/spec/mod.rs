@@ -1,17 +1,58 @@
+use std::borrow::Cow;
 use std::collections::btree_map::{BTreeMap, Iter};
-use std::collections::{HashMap, HashSet};
-use std::io::{Write, stderr};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
-use proc_macro2::{Ident, Span, TokenStream};
+use proc_macro2::{Ident, Literal, Span, TokenStream};
+use sha2::{Digest, Sha256};
 use quote::{self, ToTokens};
 
-use crate::{Result, Error};
+use crate::{Result, Error, Expected, Location, ParseError};
 
 mod xdr_nom;
+mod preprocess;
+mod quirks;
+mod normalize;
+mod lint;
+mod decode;
 
 pub type Comment = String;
 
-pub use self::xdr_nom::specification;
+pub(crate) use self::xdr_nom::includes;
+pub use self::xdr_nom::{constant_expr, specification, specification_source, specification_spans, specification_with_comments, SpecPart, SpecSource, SpecSpan, Spanned};
+pub use self::preprocess::preprocess;
+pub use self::quirks::rpcgen_quirks;
+pub use self::normalize::normalize_source;
+pub(crate) use self::normalize::read_source;
+pub use self::lint::{lint, Lint, LintConfig, LintLevel, LintRule};
+pub use self::decode::{decode, diff, FieldDiff};
+
+/// Whether a comment was written `/* ... */` or `// ...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommentKind {
+    Block,
+    Line,
+}
+
+/// A comment from the specification source.
+///
+/// `text` is the comment body without its delimiters, untrimmed, and `location` is the position
+/// of the opening delimiter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpecComment {
+    pub location: Location,
+    pub kind: CommentKind,
+    pub text: String,
+}
+
+/// A parsed specification, including the comments the grammar otherwise discards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Specification {
+    pub defns: Vec<Defn>,
+    pub comments: Vec<SpecComment>,
+}
 
 #[cfg(not(feature="derive_strum_enum_string"))]
 bitflags! {
@@ -21,6 +62,7 @@ bitflags! {
         const DEBUG = 1 << 2;
         const EQ = 1 << 3;
         const PARTIALEQ = 1 << 4;
+        const DEFAULT = 1 << 6;
     }
 }
 
@@ -33,20 +75,27 @@ bitflags! {
             const EQ = 1 << 3;
             const PARTIALEQ = 1 << 4;
             const ENUM_STRING = 1 << 5;
+            const DEFAULT = 1 << 6;
         }
     }
 
-impl ToTokens for Derives {
-    fn to_tokens(&self, toks: &mut TokenStream) {
-        if self.is_empty() {
-            return;
-        }
-
-        let mut tokens = toks.to_string();
-
-        #[cfg(feature="reprc")]
-        tokens.push_str("#[repr(C)]");
+/// What kind of Rust item a definition is generated as, for `Symtab::set_item_attrs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RustItem {
+    /// A `pub const`, for a constant, a program's numbers or, with the `enum_member_consts`
+    /// feature, an enum member
+    Const,
+    /// A struct, or the newtype wrapping an array
+    Struct,
+    /// An enum, or the Rust enum for a union
+    Enum,
+    /// A `pub type` alias, for a typedef of another type
+    TypeAlias,
+}
 
+impl Derives {
+    // The traits derived, including those every generated type gets from cargo features
+    fn names(&self) -> Vec<&'static str> {
         let mut der = Vec::<&str>::new();
 
         if self.contains(Derives::COPY) {
@@ -64,6 +113,9 @@ impl ToTokens for Derives {
         if self.contains(Derives::PARTIALEQ) {
             der.push("PartialEq")
         }
+        if self.contains(Derives::DEFAULT) {
+            der.push("Default")
+        }
 
         #[cfg(feature="derive_strum_enum_string")]
         if self.contains(Derives::ENUM_STRING) {
@@ -78,9 +130,22 @@ impl ToTokens for Derives {
         #[cfg(feature="derive_json_schema")] {
             der.push("JsonSchema");
         }
-        
-        tokens.push_str(&format!("#[derive({})]", der.join(",")));
-        *toks = tokens.parse().unwrap()
+
+        der
+    }
+}
+
+impl ToTokens for Derives {
+    fn to_tokens(&self, toks: &mut TokenStream) {
+        if self.is_empty() {
+            return;
+        }
+
+        #[cfg(feature="reprc")]
+        toks.extend(quote!(#[repr(C)]));
+
+        let der = self.names().into_iter().map(|name| Ident::new(name, Span::call_site()));
+        toks.extend(quote!(#[derive(#(#der),*)]));
     }
 }
 
@@ -105,19 +170,181 @@ lazy_static! {
 }
 
 pub(crate) fn quote_ident<S: AsRef<str>>(id: S) -> Ident {
-    let id = id.as_ref();
+    escape_ident(id.as_ref(), KeywordEscape::Underscore)
+}
+
+// An identifier for `id`, escaped as `escape` has it if it's a keyword.
+fn escape_ident(id: &str, escape: KeywordEscape) -> Ident {
+    if !(*KEYWORDS).contains(id) {
+        return Ident::new(id, Span::call_site());
+    }
+    match escape {
+        KeywordEscape::Raw if !matches!(id, "self" | "Self" | "super" | "crate") => Ident::new_raw(id, Span::call_site()),
+        _ => Ident::new(&format!("{}_", id), Span::call_site()),
+    }
+}
+
+// An identifier's name without any `r#`, to build other names from.
+fn unraw(ident: &Ident) -> String {
+    let name = ident.to_string();
+    match name.strip_prefix("r#") {
+        Some(name) => name.to_string(),
+        None => name,
+    }
+}
+
+// Whether a name can be used as a Rust identifier, other than being a keyword.
+fn is_ident(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Split `text` at each `sep` outside braces.
+fn split_outside_braces<'a>(text: &'a str, sep: &'a str) -> Vec<&'a str> {
+    let (mut parts, mut depth, mut start) = (Vec::new(), 0usize, 0);
+    for (idx, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            _ if depth == 0 && idx >= start && text[idx..].starts_with(sep) => {
+                parts.push(&text[start..idx]);
+                start = idx + sep.len();
+            }
+            _ => (),
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Whether `path` can follow `use`, such as `std::io`, `crate::types::*`, `std::fmt::{self,
+/// Write as _}` or `x::y as z`.
+pub fn is_use_tree(path: &str) -> bool {
+    let path = path.trim();
+    use_tree(path.strip_prefix("::").unwrap_or(path), false)
+}
 
-    if (*KEYWORDS).contains(id) {
-        Ident::new(&format!("{}_", id), Span::call_site())
-    } else {
-        Ident::new(id, Span::call_site())
+// A use tree without a leading `::`, which one nested in braces can't have; only one nested can
+// be a bare `*`.
+fn use_tree(path: &str, nested: bool) -> bool {
+    let segments = split_outside_braces(path, "::");
+    let (last, prefix) = segments.split_last().expect("split always has a part");
+    if !prefix.iter().all(|segment| is_ident(segment.trim())) {
+        return false;
+    }
+    let last = last.trim();
+    if let Some(inner) = last.strip_prefix('{').and_then(|last| last.strip_suffix('}')) {
+        let mut items = split_outside_braces(inner, ",");
+        if items.last().is_some_and(|item| item.trim().is_empty()) {
+            items.pop();
+        }
+        return items.iter().all(|item| use_tree(item.trim(), true));
+    }
+    match last.split_whitespace().collect::<Vec<_>>()[..] {
+        ["*"] => nested || !prefix.is_empty(),
+        [name] => is_ident(name),
+        [name, "as", alias] => is_ident(name) && is_ident(alias),
+        _ => false,
     }
 }
 
+// Generated code's locals all have this prefix, so a constant or type the spec defines can't be
+// taken for one, and user code wrapping generated fragments can use any other name.
+const LOCAL_PREFIX: &str = "__xdr_";
+
+// The unqualified part of a name.
+fn base_name(name: &str) -> &str {
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+// Unpack a struct field or union arm. With the `decode_context` feature, errors record the field
+// and how much had been decoded before it, so callers can tell where a message went bad.
+#[cfg(not(feature = "decode_context"))]
+fn field_unpacker(_name: &str, unpack: TokenStream) -> TokenStream {
+    unpack
+}
+
+#[cfg(feature = "decode_context")]
+fn field_unpacker(name: &str, unpack: TokenStream) -> TokenStream {
+    quote!(xdr_codec::unpack_field(#name, __xdr_sz, || Ok(#unpack))?)
+}
+
+/// A constant: an array size, enum value or union case label.
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 pub enum Value {
+    /// A named constant or enum member, qualified with its namespaces if need be
     Ident(String),
     Const(i64),
+    /// Dialect extension: constant expression, only accepted in union case labels
+    Binary(Box<(BinOp, Value, Value)>),
+}
+
+/// Binary operators allowed in constant expressions, with C semantics.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
+pub enum BinOp {
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinOp {
+    fn name(self) -> &'static str {
+        use self::BinOp::*;
+
+        match self {
+            Or => "or",
+            Xor => "xor",
+            And => "and",
+            Shl => "shl",
+            Shr => "shr",
+            Add => "plus",
+            Sub => "minus",
+            Mul => "times",
+            Div => "div",
+            Rem => "rem",
+        }
+    }
+
+    fn as_token(self) -> TokenStream {
+        use self::BinOp::*;
+
+        match self {
+            Or => quote!(|),
+            Xor => quote!(^),
+            And => quote!(&),
+            Shl => quote!(<<),
+            Shr => quote!(>>),
+            Add => quote!(+),
+            Sub => quote!(-),
+            Mul => quote!(*),
+            Div => quote!(/),
+            Rem => quote!(%),
+        }
+    }
+
+    /// Evaluate, returning `None` on overflow, division by zero or out of range shifts.
+    fn apply(self, l: i64, r: i64) -> Option<i64> {
+        use self::BinOp::*;
+
+        match self {
+            Or => Some(l | r),
+            Xor => Some(l ^ r),
+            And => Some(l & r),
+            Shl => u32::try_from(r).ok().and_then(|r| l.checked_shl(r)),
+            Shr => u32::try_from(r).ok().and_then(|r| l.checked_shr(r)),
+            Add => l.checked_add(r),
+            Sub => l.checked_sub(r),
+            Mul => l.checked_mul(r),
+            Div => l.checked_div(r),
+            Rem => l.checked_rem(r),
+        }
+    }
 }
 
 impl Value {
@@ -125,9 +352,21 @@ impl Value {
         Value::Ident(id.as_ref().to_string())
     }
 
-    fn as_ident(&self) -> Ident {
+    fn binary(op: BinOp, lhs: Value, rhs: Value) -> Value {
+        Value::Binary(Box::new((op, lhs, rhs)))
+    }
+
+    // Depth of the expression tree.
+    fn depth(&self) -> usize {
+        match self {
+            Value::Binary(expr) => 1 + expr.1.depth().max(expr.2.depth()),
+            _ => 0,
+        }
+    }
+
+    fn as_ident<M>(&self, symtab: &Symtab<M>) -> Ident {
         match self {
-            &Value::Ident(ref id) => quote_ident(id),
+            Value::Ident(id) => symtab.ident(base_name(id), NameKind::Variant),
             &Value::Const(val) => {
                 Ident::new(&format!(
                     "Const{}{}",
@@ -135,30 +374,105 @@ impl Value {
                     val.abs()
                 ), Span::call_site())
             }
+            Value::Binary(expr) => {
+                let (op, lhs, rhs) = &**expr;
+                Ident::new(&format!("{}_{}_{}", unraw(&lhs.as_ident(symtab)), op.name(), unraw(&rhs.as_ident(symtab))), Span::call_site())
+            }
+        }
+    }
+
+    fn references<'a>(&'a self, refs: &mut Vec<&'a str>) {
+        match self {
+            Value::Ident(id) => refs.push(id),
+            Value::Const(_) => (),
+            Value::Binary(expr) => {
+                expr.1.references(refs);
+                expr.2.references(refs);
+            }
+        }
+    }
+
+    // Canonical text for fingerprinting; see `fingerprint`.
+    fn canonical(&self) -> String {
+        match self {
+            Value::Ident(id) => id.clone(),
+            Value::Const(val) => val.to_string(),
+            Value::Binary(expr) => format!("({} {} {})", expr.0.name(), expr.1.canonical(), expr.2.canonical()),
+        }
+    }
+
+    fn rename(&mut self, f: &dyn Fn(&str) -> Option<String>) {
+        match self {
+            Value::Ident(id) => {
+                if let Some(new) = f(id) {
+                    *id = new
+                }
+            }
+            Value::Const(_) => (),
+            Value::Binary(expr) => {
+                expr.1.rename(f);
+                expr.2.rename(f);
+            }
         }
     }
 
     fn as_i64<M>(&self, symtab: &Symtab<M>) -> Option<i64> {
-        symtab.value(self)
+        symtab.eval(self)
+    }
+
+    // The bool this is, read as `Symtab::set_bool_labels` says for union case labels.
+    fn as_bool<M>(&self, symtab: &Symtab<M>) -> Option<bool> {
+        let spelt = match self {
+            Value::Ident(id) if id == "TRUE" => Some(true),
+            Value::Ident(id) if id == "FALSE" => Some(false),
+            _ if symtab.bool_labels == BoolLabels::Strict => None,
+            Value::Ident(id) if id == "true" => Some(true),
+            Value::Ident(id) if id == "false" => Some(false),
+            Value::Const(1) => Some(true),
+            Value::Const(0) => Some(false),
+            _ => None,
+        };
+        match (spelt, symtab.bool_labels) {
+            (None, BoolLabels::Constants) => match symtab.eval(self) {
+                Some(1) => Some(true),
+                Some(0) => Some(false),
+                _ => None,
+            },
+            _ => spelt,
+        }
     }
 
     fn as_token<M>(&self, symtab: &Symtab<M>) -> TokenStream {
         match self {
             &Value::Const(c) => quote!(#c),
+            Value::Binary(expr) => {
+                if let Some(v) = symtab.eval(self) {
+                    quote!(#v)
+                } else {
+                    let (op, lhs, rhs) = &**expr;
+                    let (lhs, rhs, op) = (lhs.as_token(symtab), rhs.as_token(symtab), op.as_token());
+                    quote!(((#lhs as i64) #op (#rhs as i64)))
+                }
+            }
             &Value::Ident(ref id) => {
-                let tok = quote_ident(id.as_str());
                 if let Some((_, Some(ref scope))) = symtab.getconst(id) {
-                    let scope = quote_ident(scope);
-                    quote!(#scope :: #tok)
+                    let (scope, tok) = (symtab.path(scope, NameKind::Type), symtab.ident(base_name(id), NameKind::Variant));
+                    if symtab.lossless_enums {
+                        quote!(#scope :: #tok .0)
+                    } else {
+                        quote!(#scope :: #tok)
+                    }
                 } else {
-                    quote!(#tok)
+                    symtab.path(id, NameKind::Const)
                 }
             }
         }
     }
 }
 
+/// An XDR type, as written or as the generator refines it.
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+#[non_exhaustive]
 pub enum Type {
     UInt,
     Int,
@@ -170,22 +484,67 @@ pub enum Type {
     Bool,
 
     // Special array elements
-    Opaque, // binary
-    String, // text
+    /// Bytes, as the element of an `opaque` array
+    Opaque,
+    /// Text, as the element of a `string` array
+    String,
 
     // Compound types
-    Enum(Vec<EnumDefn>),
+    Enum(Vec<EnumDefn>, EnumWidth),
     Struct(Vec<Decl>),
     Union(Box<Decl>, Vec<UnionCase>, Option<Box<Decl>>),
 
+    /// Optional data, `type *name`
     Option(Box<Type>),
+    /// Fixed length array, `type name[size]`
     Array(Box<Type>, Value),
+    /// Variable length array, `type name<max>`
     Flex(Box<Type>, Option<Value>),
+    /// Flex array of key/value entry structs, annotated as a map
+    Map(Box<Type>, Option<Value>, MapKind),
+    /// Heap allocated, annotated as @boxed
+    Boxed(Box<Type>),
 
-    // Type reference (may be external)
+    /// Type reference (may be external)
     Ident(String, Option<Derives>),
 }
 
+/// Encoded width of an enum's discriminant. Standard XDR is always `Int`; `@hyper` on an enum
+/// definition selects a 64-bit encoding for peers which use one.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
+pub enum EnumWidth {
+    Int,
+    Hyper,
+}
+
+impl EnumWidth {
+    fn as_token(self) -> TokenStream {
+        match self {
+            EnumWidth::Int => quote!(i32),
+            EnumWidth::Hyper => quote!(i64),
+        }
+    }
+}
+
+/// Rust map type used for a `@map` or `@hashmap` annotated flex array.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
+pub enum MapKind {
+    BTree,
+    Hash,
+}
+
+impl MapKind {
+    fn as_token<M>(self, symtab: &Symtab<M>) -> std::option::Option<TokenStream> {
+        let alloc = symtab.alloc_path();
+        match self {
+            MapKind::BTree => Some(quote!(#alloc::collections::BTreeMap)),
+            // `alloc` has no hash maps
+            MapKind::Hash if symtab.no_std => None,
+            MapKind::Hash => Some(quote!(::std::collections::HashMap)),
+        }
+    }
+}
+
 impl Type {
     fn array(ty: Type, sz: Value) -> Type {
         Type::Array(Box::new(ty), sz)
@@ -211,12 +570,361 @@ impl Type {
         Type::Ident(id.as_ref().to_string(), Some(derives))
     }
 
+    // Apply an annotation which affects the type's representation.
+    fn annotate(&mut self, annotation: &str) {
+        match (annotation, &mut *self) {
+            ("map", Type::Flex(entry, sz)) => *self = Type::Map(entry.clone(), sz.clone(), MapKind::BTree),
+            ("hashmap", Type::Flex(entry, sz)) => *self = Type::Map(entry.clone(), sz.clone(), MapKind::Hash),
+            ("int", Type::Enum(_, width)) => *width = EnumWidth::Int,
+            ("hyper", Type::Enum(_, width)) => *width = EnumWidth::Hyper,
+            ("boxed", ty) if !matches!(ty, Type::Enum(..) | Type::Struct(_) | Type::Union(..) | Type::Boxed(_)) => {
+                let ty = ty.clone();
+                *self = Type::Boxed(Box::new(ty))
+            }
+            // On a definition rather than its type; see `message_envelope`
+            (annotation, _) if annotation == "message" || annotation.starts_with("message=") => (),
+            // Likewise; see `codec_annotations`
+            ("pack_only", _) | ("unpack_only", _) => (),
+            // Likewise; see `timestamp_annotations`
+            ("chrono", _) | ("time", _) => (),
+            _ => warn!("ignoring annotation @{} on {:?}", annotation, self),
+        }
+    }
+
+    // Apply the annotations leading a trailing comment, returning the rest of it, as written, as
+    // documentation, and the expression of a `@default(value)` annotation, which is on the
+    // declaration rather than its type.
+    fn annotate_comment(&mut self, comment: Option<&[u8]>) -> (Option<Comment>, Option<String>) {
+        let text = match comment {
+            Some(comment) => String::from_utf8_lossy(comment),
+            None => return (None, None),
+        };
+        let (annotations, doc) = leading_annotations(&text);
+
+        let mut default = None;
+        for annotation in annotations {
+            match annotation.strip_prefix("@default(").and_then(|arg| arg.strip_suffix(')')) {
+                Some(expr) => default = Some(expr.trim().to_string()),
+                None => self.annotate(&annotation[1..]),
+            }
+        }
+        let doc = if doc.is_empty() { None } else { into_comment(Some(doc.as_bytes())) };
+        (doc, default)
+    }
+
+    // Constants named in the sizes of this type's arrays, including those of types nested in it.
+    #[cfg(feature = "pretty")]
+    fn size_constants<'a>(&'a self, refs: &mut Vec<&'a str>) {
+        use self::Type::*;
+
+        match self {
+            Array(ty, sz) => {
+                sz.references(refs);
+                ty.size_constants(refs)
+            }
+            Flex(ty, sz) | Map(ty, sz, _) => {
+                if let Some(sz) = sz {
+                    sz.references(refs)
+                }
+                ty.size_constants(refs)
+            }
+            Option(ty) | Boxed(ty) => ty.size_constants(refs),
+            Struct(decls) => decls.iter().for_each(|decl| decl.size_constants(refs)),
+            Union(selector, cases, default) => {
+                selector.size_constants(refs);
+                cases.iter().for_each(|case| case.1.size_constants(refs));
+                if let Some(default) = default {
+                    default.size_constants(refs)
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Discriminant width if this is, or names, an enum.
+    fn enum_width<M>(&self, symtab: &Symtab<M>) -> Option<EnumWidth> {
+        match self {
+            Type::Enum(_, width) => Some(*width),
+            Type::Ident(name, _) => symtab.typespec(name).and_then(|ty| ty.enum_width(symtab)),
+            _ => None,
+        }
+    }
+
+    // Names of the types and constants this type refers to.
+    fn references<'a>(&'a self, refs: &mut Vec<&'a str>) {
+        use self::Type::*;
+
+        match self {
+            Ident(name, _) => refs.push(name),
+            Array(ty, sz) => {
+                ty.references(refs);
+                sz.references(refs);
+            }
+            Flex(ty, sz) | Map(ty, sz, _) => {
+                ty.references(refs);
+                sz.iter().for_each(|sz| sz.references(refs));
+            }
+            Option(ty) | Boxed(ty) => ty.references(refs),
+            Enum(edefs, _) => edefs.iter().flat_map(|e| &e.1).for_each(|v| v.references(refs)),
+            Struct(decls) => decls.iter().for_each(|d| d.references(refs)),
+            Union(sel, cases, defl) => {
+                sel.references(refs);
+                for UnionCase(val, decl, _) in cases {
+                    val.references(refs);
+                    decl.references(refs);
+                }
+                defl.iter().for_each(|d| d.references(refs));
+            }
+            _ => (),
+        }
+    }
+
+    // Canonical text for fingerprinting. Annotations which don't change the encoding, such as
+    // @boxed and @map, aren't included.
+    fn canonical(&self) -> String {
+        use self::Type::*;
+
+        let bound = |sz: &std::option::Option<Value>| sz.as_ref().map(Value::canonical).unwrap_or_default();
+        match self {
+            UInt => "unsigned int".to_string(),
+            Int => "int".to_string(),
+            UHyper => "unsigned hyper".to_string(),
+            Hyper => "hyper".to_string(),
+            Float => "float".to_string(),
+            Double => "double".to_string(),
+            Quadruple => "quadruple".to_string(),
+            Bool => "bool".to_string(),
+            Opaque => "opaque".to_string(),
+            String => "string".to_string(),
+            Enum(edefs, width) => {
+                let members: Vec<_> = edefs
+                    .iter()
+                    .map(|EnumDefn(name, val, _)| match val {
+                        Some(val) => format!("{} = {}", name, val.canonical()),
+                        None => name.clone(),
+                    })
+                    .collect();
+                let width = match width {
+                    EnumWidth::Int => "",
+                    EnumWidth::Hyper => " hyper",
+                };
+                format!("enum{} {{ {} }}", width, members.join(", "))
+            }
+            Struct(decls) => {
+                let decls: Vec<_> = decls.iter().map(|d| d.canonical() + "; ").collect();
+                format!("struct {{ {}}}", decls.concat())
+            }
+            Union(sel, cases, defl) => {
+                let cases: Vec<_> = cases
+                    .iter()
+                    .map(|UnionCase(val, decl, _)| format!("case {}: {}; ", val.canonical(), decl.canonical()))
+                    .collect();
+                let defl = defl.as_ref().map(|d| format!("default: {}; ", d.canonical())).unwrap_or_default();
+                format!("union switch ({}) {{ {}{}}}", sel.canonical(), cases.concat(), defl)
+            }
+            Option(ty) => format!("{}*", ty.canonical()),
+            Array(ty, sz) => format!("{}[{}]", ty.canonical(), sz.canonical()),
+            Flex(ty, sz) | Map(ty, sz, _) => format!("{}<{}>", ty.canonical(), bound(sz)),
+            Boxed(ty) => ty.canonical(),
+            Ident(name, _) => name.clone(),
+        }
+    }
+
+    // Replace references to names which aren't `defined` but are known `aliases`.
+    fn resolve_aliases(&mut self, aliases: &TypeAliases, defined: &HashSet<String>) {
+        use self::Type::*;
+
+        match self {
+            Ident(name, _) => {
+                if let Some(ty) = aliases.get(name).filter(|_| !defined.contains(name)) {
+                    *self = ty.clone()
+                }
+            }
+            Array(ty, _) | Flex(ty, _) | Map(ty, ..) | Option(ty) | Boxed(ty) => ty.resolve_aliases(aliases, defined),
+            Struct(decls) => decls.iter_mut().for_each(|d| d.resolve_aliases(aliases, defined)),
+            Union(sel, cases, defl) => {
+                sel.resolve_aliases(aliases, defined);
+                cases.iter_mut().for_each(|UnionCase(_, decl, _)| decl.resolve_aliases(aliases, defined));
+                defl.iter_mut().for_each(|d| d.resolve_aliases(aliases, defined));
+            }
+            _ => (),
+        }
+    }
+
+    // Move anonymous structs, unions and enums declared in this type's fields out to `lifted`,
+    // named after `scope` and the field, nested ones first; see `lift_anonymous`.
+    fn lift_anonymous(&mut self, scope: &str, defined: &mut HashSet<String>, lifted: &mut Vec<Defn>) -> Result<()> {
+        use self::Type::*;
+
+        match self {
+            Struct(decls) => decls.iter_mut().try_for_each(|decl| decl.lift_anonymous(scope, defined, lifted)),
+            Union(selector, cases, default) => {
+                selector.lift_anonymous(scope, defined, lifted)?;
+                for case in cases {
+                    case.1.lift_anonymous(scope, defined, lifted)?;
+                }
+                match default {
+                    Some(default) => default.lift_anonymous(scope, defined, lifted),
+                    None => Ok(()),
+                }
+            }
+            Option(ty) | Array(ty, _) | Flex(ty, _) | Boxed(ty) => ty.lift_anonymous(scope, defined, lifted),
+            _ => Ok(()),
+        }
+    }
+
+    // The `T` of `union switch (bool present) { case TRUE: T val; case FALSE: void; }`, which
+    // has the same encoding as `T *`.
+    fn bool_optional(&self) -> std::option::Option<&Type> {
+        let (selector, cases) = match self {
+            Type::Union(selector, cases, None) => (selector, cases),
+            _ => return None,
+        };
+        if !matches!(selector.as_ref(), Decl::Named(_, Type::Bool, ..)) {
+            return None;
+        }
+
+        // However the cases are spelt; see `BoolLabels::Spellings`
+        let label = |case: &UnionCase| match &case.0 {
+            Value::Ident(id) if id == "TRUE" || id == "true" => Some(true),
+            Value::Ident(id) if id == "FALSE" || id == "false" => Some(false),
+            Value::Const(1) => Some(true),
+            Value::Const(0) => Some(false),
+            _ => None,
+        };
+        match &cases[..] {
+            [a, b] => {
+                let (present, absent) = if label(a) == Some(true) { (a, b) } else { (b, a) };
+                match (label(present), &present.1, label(absent), &absent.1) {
+                    (Some(true), Decl::Named(_, ty, ..), Some(false), Decl::Void) => Some(ty),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Replace bool-discriminated optional unions in this type, and nested in it, with `T *`.
+    fn optional_unions(&mut self) {
+        use self::Type::*;
+
+        match self {
+            Struct(decls) => decls.iter_mut().for_each(Decl::optional_unions),
+            Union(selector, cases, default) => {
+                selector.optional_unions();
+                cases.iter_mut().for_each(|case| case.1.optional_unions());
+                default.iter_mut().for_each(|default| default.optional_unions());
+            }
+            Option(ty) | Array(ty, _) | Flex(ty, _) | Map(ty, ..) | Boxed(ty) => ty.optional_unions(),
+            _ => (),
+        }
+        if let Some(ty) = self.bool_optional() {
+            *self = Option(Box::new(ty.clone()));
+        }
+    }
+
+    // Rewrite the names this type refers to, so it can be used from another scope.
+    fn rename(&mut self, f: &dyn Fn(&str) -> Option<String>) {
+        use self::Type::*;
+
+        match self {
+            Ident(name, _) => {
+                if let Some(new) = f(name) {
+                    *name = new
+                }
+            }
+            Array(ty, sz) => {
+                ty.rename(f);
+                sz.rename(f);
+            }
+            Flex(ty, sz) | Map(ty, sz, _) => {
+                ty.rename(f);
+                sz.iter_mut().for_each(|sz| sz.rename(f));
+            }
+            Option(ty) | Boxed(ty) => ty.rename(f),
+            Enum(edefs, _) => edefs.iter_mut().flat_map(|e| &mut e.1).for_each(|v| v.rename(f)),
+            Struct(decls) => decls.iter_mut().for_each(|d| d.rename(f)),
+            Union(sel, cases, defl) => {
+                sel.rename(f);
+                for UnionCase(val, decl, _) in cases {
+                    val.rename(f);
+                    decl.rename(f);
+                }
+                defl.iter_mut().for_each(|d| d.rename(f));
+            }
+            _ => (),
+        }
+    }
+
+    // Names of the types this one holds inline, in order, not looking inside anything which is
+    // already on the heap. These are what could make a type contain itself.
+    fn inline_refs<M>(&self, symtab: &Symtab<M>, refs: &mut Vec<std::string::String>) {
+        use self::Type::*;
+
+        let decl_refs = |decl: &Decl, refs: &mut Vec<_>| {
+            if let Decl::Named(_, ty, ..) = decl {
+                ty.inline_refs(symtab, refs)
+            }
+        };
+        match self {
+            Ident(name, _) => refs.push(name.clone()),
+            Struct(decls) => decls.iter().for_each(|decl| decl_refs(decl, refs)),
+            Union(_, cases, default) => {
+                cases.iter().for_each(|case| decl_refs(&case.1, refs));
+                default.iter().for_each(|default| decl_refs(default, refs));
+            }
+            Array(ty, _) => ty.inline_refs(symtab, refs),
+            Option(ty) if !ty.is_boxed(symtab) => ty.inline_refs(symtab, refs),
+            _ => (),
+        }
+    }
+
+    // Box the references `inline_refs` finds, in the same order, for which `boxed` is true.
+    fn box_refs<M>(&mut self, symtab: &Symtab<M>, boxed: &mut dyn Iterator<Item = bool>) {
+        use self::Type::*;
+
+        let decl_refs = |decl: &mut Decl, boxed: &mut dyn Iterator<Item = bool>| {
+            if let Decl::Named(_, ty, ..) = decl {
+                ty.box_refs(symtab, boxed)
+            }
+        };
+        match self {
+            Ident(..) if boxed.next() == Some(true) => *self = Boxed(Box::new(self.clone())),
+            Struct(decls) => decls.iter_mut().for_each(|decl| decl_refs(decl, boxed)),
+            Union(_, cases, default) => {
+                cases.iter_mut().for_each(|case| decl_refs(&mut case.1, boxed));
+                default.iter_mut().for_each(|default| decl_refs(default, boxed));
+            }
+            Array(ty, _) => ty.box_refs(symtab, boxed),
+            Option(ty) if !ty.is_boxed(symtab) => ty.box_refs(symtab, boxed),
+            _ => (),
+        }
+    }
+
+    // Key and value types for a map's entry struct.
+    fn map_entry<'a, M>(entry: &'a Type, symtab: &'a Symtab<M>) -> Result<(&'a Type, &'a Type)> {
+        let invalid = || Error::InvalidMapEntry { ty: entry.clone() };
+
+        let fields = match entry {
+            Type::Ident(name, _) => match symtab.typespec(name) {
+                Some(Type::Struct(fields)) => fields,
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+
+        match fields.as_slice() {
+            [Decl::Named(_, k, ..), Decl::Named(_, v, ..)] => Ok((k, v)),
+            _ => Err(invalid()),
+        }
+    }
+
     fn is_boxed<M>(&self, symtab: &Symtab<M>) -> bool {
         use self::Type::*;
 
         match self {
             _ if self.is_prim(symtab) => false,
-            &Array(_, _) | &Flex(_, _) | &Option(_) => false,
+            &Array(_, _) | &Flex(_, _) | &Map(..) | &Option(_) | &Boxed(_) => false,
             &Ident(ref name, _) => {
                 if let Some(ty) = symtab.typespec(name) {
                     ty.is_boxed(symtab)
@@ -258,6 +966,10 @@ impl Type {
             return *res;
         }
 
+        if let Some(prim) = symtab.primitives.get(self) {
+            return prim.derives;
+        }
+
         // No derives unless we can prove we have some
         memo.insert(self.clone(), Derives::empty());
 
@@ -266,26 +978,32 @@ impl Type {
             &Array(ref ty, ref len) => {
                 let ty = ty.as_ref();
                 let set = match ty {
-                    &Opaque | &String => Derives::EQ | Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG,
+                    &Opaque | &String => Derives::EQ | Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG | Derives::DEFAULT,
                     ref ty => ty.derivable(symtab, Some(memo)),
                 };
                 match len.as_i64(symtab) {
-                    Some(v) if v <= 32 => set,
+                    Some(v) if (1..=32).contains(&v) => set,
+                    // Arrays of any length have the std traits, but not `Default`, or serde's and
+                    // schemars'
+                    Some(v) if v > 32 && symtab.large_array_derives => set & !Derives::DEFAULT,
                     _ => Derives::empty(),   // no #[derive] for arrays > 32
                 }
             }
-            &Flex(ref ty, ..) => {
+            &Flex(ref ty, ..) | &Map(ref ty, ..) => {
                 let set = ty.derivable(symtab, Some(memo));
-                set & !Derives::COPY // no Copy, everything else OK
+                (set & !Derives::COPY) | Derives::DEFAULT // no Copy, and empty by default
             }
-            &Enum(_) => {
+            &Enum(..) => {
                 #[allow(unused_mut)]
-                let mut ders = Derives::EQ | Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG;
+                let mut ders = Derives::EQ | Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG | Derives::DEFAULT;
                 #[cfg(feature="derive_strum_enum_string")]
+                if !symtab.lossless_enums {
                     ders.insert(Derives::ENUM_STRING);
+                }
                 ders
             },
-            &Option(ref ty) => ty.derivable(symtab, Some(memo)) & !Derives::COPY,
+            &Option(ref ty) => (ty.derivable(symtab, Some(memo)) & !Derives::COPY) | Derives::DEFAULT,
+            &Boxed(ref ty) => ty.derivable(symtab, Some(memo)) & !Derives::COPY,
             &Struct(ref fields) => {
                 fields.iter().fold(Derives::all(), |a, f| {
                     a & f.derivable(symtab, memo)
@@ -299,7 +1017,8 @@ impl Type {
                     defl.as_ref().map_or(
                         Derives::all(),
                         |d| d.derivable(symtab, memo),
-                    )
+                    ) & !Derives::DEFAULT // no variant to default to
+                    & if defl.is_none() && symtab.unknown_union_arms { !Derives::COPY } else { Derives::all() }
             }
 
             &Ident(_, Some(derives)) => derives,
@@ -311,14 +1030,14 @@ impl Type {
                 }
             }
 
-            &Float | &Double => Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG,
+            &Float | &Double => Derives::PARTIALEQ | Derives::COPY | Derives::CLONE | Derives::DEBUG | Derives::DEFAULT,
             ty if ty.is_prim(symtab) => Derives::all(),
 
             _ => Derives::all() & !Derives::COPY,
         };
 
         #[cfg(feature="derive_strum_enum_string")]
-        if let Enum(_) = self {} else {
+        if let Enum(..) = self {} else {
             set.remove(Derives::ENUM_STRING);
         }
         memo.insert(self.clone(), set);
@@ -329,8 +1048,16 @@ impl Type {
     fn packer<M>(&self, val: TokenStream, symtab: &Symtab<M>) -> Result<TokenStream> {
         use self::Type::*;
 
+        if let Some((prim, ty)) = symtab.codec_primitive(self) {
+            return Ok(prim.packer(val, ty.max_token(symtab)).expect("checked by Primitives::add"));
+        }
+
         let res = match self {
-            &Enum(_) => quote!((*#val as i32).pack(out)?),
+            &Enum(..) if symtab.lossless_enums => quote!(#val.0.pack(__xdr_out)?),
+            &Enum(_, width) => {
+                let wire = width.as_token();
+                quote!((*#val as #wire).pack(__xdr_out)?)
+            }
 
             &Flex(ref ty, ref maxsz) => {
                 let ty = ty.as_ref();
@@ -342,29 +1069,246 @@ impl Type {
                     }
                 };
                 match ty {
-                    &Opaque => quote!(xdr_codec::pack_opaque_flex(&#val, #maxsz, out)?),
-                    &String => quote!(xdr_codec::pack_string(&#val, #maxsz, out)?),
-                    _ => quote!(xdr_codec::pack_flex(&#val, #maxsz, out)?),
+                    &Opaque => quote!(xdr_codec::pack_opaque_flex(&#val, #maxsz, __xdr_out)?),
+                    &String => quote!(xdr_codec::pack_string(&#val, #maxsz, __xdr_out)?),
+                    _ => quote!(xdr_codec::pack_flex(&#val, #maxsz, __xdr_out)?),
                 }
             }
 
-            &Array(ref ty, _) => {
-                let ty = ty.as_ref();
-                match ty {
-                    &Opaque | &String => {
-                        quote!(xdr_codec::pack_opaque_array(&#val[..], #val.len(), out)?)
-                    }
-                    _ => quote!(xdr_codec::pack_array(&#val[..], #val.len(), out, None)?),
+            Map(entry, maxsz, _) => {
+                let (kty, vty) = Type::map_entry(entry, symtab)?;
+                let maxsz = match maxsz {
+                    None => quote!(None),
+                    Some(mx) => {
+                        let mx = mx.as_token(symtab);
+                        quote!(Some(#mx as usize))
+                    }
+                };
+                let kpack = kty.packer(quote!(__xdr_k), symtab)?;
+                let vpack = vty.packer(quote!(__xdr_v), symtab)?;
+
+                quote!(xdr_codec::pack_map_with(#val.iter(), #maxsz, __xdr_out, |__xdr_k, __xdr_v, __xdr_out| Ok(#kpack + #vpack))?)
+            }
+
+            Boxed(ty) => ty.packer(quote!((*#val)), symtab)?,
+
+            &Array(ref ty, _) => {
+                let ty = ty.as_ref();
+                match ty {
+                    &Opaque | &String => {
+                        quote!(xdr_codec::pack_opaque_array(&#val[..], #val.len(), __xdr_out)?)
+                    }
+                    _ => quote!(xdr_codec::pack_array(&#val[..], #val.len(), __xdr_out, None)?),
                 }
             }
 
-            _ => quote!(#val.pack(out)?),
+            _ => quote!(#val.pack(__xdr_out)?),
         };
 
         trace!("packed {:?} val {:?} => {:?}", self, val, res);
         Ok(res)
     }
 
+    // Statement checking `val` against this type's spec constraints, for `Validate` impls. `name`
+    // identifies the value in errors.
+    #[cfg(feature="try_pack")]
+    fn validator<M>(&self, val: TokenStream, name: &str, symtab: &Symtab<M>) -> TokenStream {
+        use self::Type::*;
+
+        let maxsz = |maxsz: &std::option::Option<Value>| match maxsz {
+            None => quote!(None),
+            Some(mx) => {
+                let mx = mx.as_token(symtab);
+                quote!(Some(#mx as usize))
+            }
+        };
+
+        match self {
+            // Custom packing is trusted to check its own limits
+            _ if symtab.codec_primitive(self).is_some_and(|(prim, _)| prim.pack.is_some()) => quote!(),
+            Flex(_, sz) => {
+                let maxsz = maxsz(sz);
+                quote!(xdr_codec::validate_len(#name, #val.len(), #maxsz)?; xdr_codec::Validate::validate(&#val)?;)
+            }
+            Map(_, sz, _) => {
+                let maxsz = maxsz(sz);
+                quote! {
+                    xdr_codec::validate_len(#name, #val.len(), #maxsz)?;
+                    for (__xdr_k, __xdr_v) in #val.iter() {
+                        xdr_codec::Validate::validate(__xdr_k)?;
+                        xdr_codec::Validate::validate(__xdr_v)?;
+                    }
+                }
+            }
+            Boxed(ty) => ty.validator(quote!((*#val)), name, symtab),
+            _ => quote!(xdr_codec::Validate::validate(&#val)?;),
+        }
+    }
+
+    // The size limit of a variable length type, as an `Option<usize>`. A named type has the
+    // limit it's defined with, if it's defined as one.
+    fn max_token<M>(&self, symtab: &Symtab<M>) -> TokenStream {
+        match self {
+            Type::Flex(_, Some(mx)) => {
+                let mx = mx.as_token(symtab);
+                quote!(Some(#mx as usize))
+            }
+            Type::Ident(name, _) => match symtab.typespec(name) {
+                Some(ty @ Type::Flex(..)) => ty.max_token(symtab),
+                _ => quote!(None),
+            },
+            _ => quote!(None),
+        }
+    }
+
+    // Least and greatest length of this type's standard encoding. The greatest is `None` if there's
+    // no limit, or it can't be known, as for external types and custom primitives. `visiting`
+    // has the named types being measured, as one which contains itself has no limit.
+    fn packed_sizes<M>(&self, symtab: &Symtab<M>, visiting: &mut Vec<std::string::String>) -> (u64, Option<u64>) {
+        use self::Type::*;
+
+        const UNKNOWN: (u64, std::option::Option<u64>) = (0, None);
+        let fixed = |sz: u64| (sz, Some(sz));
+        let bound = |sz: &Value| symtab.eval(sz).and_then(|sz| u64::try_from(sz).ok());
+        let padded = |len: u64| len.checked_add(3).map(|len| len & !3);
+        // `count` elements, or up to that many after a length
+        let repeat = |(min, max): (u64, std::option::Option<u64>), count: std::option::Option<u64>, flex: bool| {
+            let max = max.zip(count).and_then(|(max, count)| max.checked_mul(count));
+            match flex {
+                false => (min.saturating_mul(count.unwrap_or(0)), max),
+                true => (4, max.and_then(|max| max.checked_add(4))),
+            }
+        };
+        let decl = |decl: &Decl, visiting: &mut Vec<std::string::String>| match decl {
+            Decl::Void => fixed(0),
+            Decl::Named(_, ty, ..) => ty.packed_sizes(symtab, visiting),
+        };
+
+        if symtab.codec_primitive(self).is_some() {
+            return UNKNOWN;
+        }
+        match self {
+            Int | UInt | Float | Bool => fixed(4),
+            Hyper | UHyper | Double => fixed(8),
+            Quadruple => fixed(16),
+            Enum(_, EnumWidth::Int) => fixed(4),
+            Enum(_, EnumWidth::Hyper) => fixed(8),
+            // Only array elements
+            Opaque | String => UNKNOWN,
+
+            Struct(decls) => decls.iter().fold(fixed(0), |(min, max), d| {
+                let (dmin, dmax) = decl(d, visiting);
+                (min.saturating_add(dmin), max.zip(dmax).and_then(|(max, dmax)| max.checked_add(dmax)))
+            }),
+
+            Union(sel, cases, defl) => {
+                let mut arms: Vec<_> = cases.iter().map(|UnionCase(_, d, _)| decl(d, visiting)).collect();
+                match defl {
+                    Some(d) => arms.push(decl(d, visiting)),
+                    // The rest of the input, however long
+                    None if symtab.unknown_union_arms => arms.push((0, None)),
+                    None => (),
+                }
+                let disc = match sel.selector_width(symtab) {
+                    EnumWidth::Int => 4,
+                    EnumWidth::Hyper => 8,
+                };
+                let min = arms.iter().map(|&(min, _)| min).min().unwrap_or(0);
+                let max = arms.iter().try_fold(0, |acc, &(_, max)| max.map(|max| acc.max(max)));
+                (min.saturating_add(disc), max.and_then(|max| max.checked_add(disc)))
+            }
+
+            Option(ty) => {
+                let (_, max) = ty.packed_sizes(symtab, visiting);
+                (4, max.and_then(|max| max.checked_add(4)))
+            }
+            Boxed(ty) => ty.packed_sizes(symtab, visiting),
+
+            Array(ty, sz) => match ty.as_ref() {
+                Opaque | String => bound(sz).and_then(padded).map_or(UNKNOWN, fixed),
+                ty => repeat(ty.packed_sizes(symtab, visiting), bound(sz), false),
+            },
+            Flex(ty, sz) => match ty.as_ref() {
+                Opaque | String => repeat(fixed(1), sz.as_ref().and_then(bound).and_then(padded), true),
+                ty => repeat(ty.packed_sizes(symtab, visiting), sz.as_ref().and_then(bound), true),
+            },
+            Map(entry, sz, _) => repeat(entry.packed_sizes(symtab, visiting), sz.as_ref().and_then(bound), true),
+
+            Ident(name, _) => match symtab.typespec(name) {
+                _ if visiting.contains(name) => UNKNOWN,
+                Some(ty) => {
+                    visiting.push(name.clone());
+                    let sizes = ty.packed_sizes(symtab, visiting);
+                    visiting.pop();
+                    sizes
+                }
+                // Built in for `char` and `unsigned char`
+                None if name == "u8" || name == "i8" => fixed(4),
+                None => UNKNOWN,
+            },
+        }
+    }
+
+    // Rough size of this type in memory, to find union arms worth boxing. Vecs, strings and maps
+    // count as what they keep inline, boxes as a pointer, and external types and custom
+    // primitives as a `Vec`. `visiting` has the named types being measured; one which contains
+    // itself is boxed there, so counts as a pointer.
+    fn mem_size<M>(&self, symtab: &Symtab<M>, visiting: &mut Vec<std::string::String>) -> u64 {
+        use self::Type::*;
+
+        const PTR: u64 = 8;
+        const VEC: u64 = 3 * PTR;
+        let bound = |sz: &Value| symtab.eval(sz).and_then(|sz| u64::try_from(sz).ok()).unwrap_or(0);
+        let decl = |decl: &Decl, visiting: &mut Vec<std::string::String>| match decl {
+            Decl::Void => 0,
+            Decl::Named(_, ty, ..) => ty.mem_size(symtab, visiting),
+        };
+
+        if symtab.primitives.get(self).is_some() {
+            return VEC;
+        }
+        match self {
+            Bool => 1,
+            Int | UInt | Float | Enum(_, EnumWidth::Int) => 4,
+            Hyper | UHyper | Double | Enum(_, EnumWidth::Hyper) => 8,
+            Quadruple => 16,
+            Opaque | String | Flex(..) | Map(..) => VEC,
+
+            Struct(decls) => decls.iter().fold(0, |acc, d| acc.saturating_add(decl(d, visiting))),
+            Union(sel, cases, defl) => {
+                let arms = cases.iter().map(|UnionCase(_, d, _)| d).chain(defl.as_deref());
+                let data = arms.fold(0, |acc, d| acc.max(decl(d, visiting)));
+                let disc = match sel.selector_width(symtab) {
+                    EnumWidth::Int => 4,
+                    EnumWidth::Hyper => 8,
+                };
+                data.saturating_add(disc)
+            }
+
+            Option(ty) if ty.is_boxed(symtab) => PTR,
+            Option(ty) => ty.mem_size(symtab, visiting).saturating_add(PTR),
+            Boxed(_) => PTR,
+
+            Array(ty, sz) => match ty.as_ref() {
+                Opaque | String => bound(sz),
+                ty => ty.mem_size(symtab, visiting).saturating_mul(bound(sz)),
+            },
+
+            Ident(name, _) => match symtab.typespec(name) {
+                _ if visiting.contains(name) => PTR,
+                Some(ty) => {
+                    visiting.push(name.clone());
+                    let size = ty.mem_size(symtab, visiting);
+                    visiting.pop();
+                    size
+                }
+                // Built in for `char` and `unsigned char`
+                None if name == "u8" || name == "i8" => 1,
+                None => VEC,
+            },
+        }
+    }
+
     fn is_syn(&self) -> bool {
         use self::Type::*;
 
@@ -378,6 +1322,10 @@ impl Type {
     fn unpacker<M>(&self, symtab: &Symtab<M>) -> TokenStream {
         use self::Type::*;
 
+        if let Some((prim, ty)) = symtab.codec_primitive(self) {
+            return prim.unpacker(ty.max_token(symtab)).expect("checked by Primitives::add");
+        }
+
         match self {
             &Array(ref ty, ref value) => {
                 let ty = ty.as_ref();
@@ -386,9 +1334,28 @@ impl Type {
                 match ty {
                     &Opaque | &String => {
                         quote!({
-                            let mut buf: [u8; #value as usize] = [0; #value as usize];
-                            let sz = xdr_codec::unpack_opaque_array(input, &mut buf[..], #value as usize)?;
-                            (buf, sz)
+                            let mut __xdr_buf: [u8; #value as usize] = [0; #value as usize];
+                            let __xdr_sz = xdr_codec::try_unpack_opaque_array(__xdr_input, &mut __xdr_buf[..], #value as usize)?;
+                            (__xdr_buf, __xdr_sz)
+                        })
+                    }
+                    ty if symtab.safe_arrays => {
+                        let ty = ty.as_token(symtab).unwrap();
+                        let core = symtab.core_path();
+                        let vec = symtab.alloc_type("vec", "Vec");
+                        quote!({
+                            let mut __xdr_vec: #vec<#ty> = #vec::with_capacity(#value as usize);
+                            let mut __xdr_sz = 0;
+                            for _ in 0..#value as usize {
+                                let (__xdr_v, __xdr_vsz) = xdr_codec::Unpack::unpack(__xdr_input)?;
+                                __xdr_vec.push(__xdr_v);
+                                __xdr_sz += __xdr_vsz;
+                            }
+                            let __xdr_buf: [#ty; #value as usize] = match #core::convert::TryFrom::try_from(__xdr_vec) {
+                                Ok(__xdr_buf) => __xdr_buf,
+                                Err(_) => unreachable!("unpacked every element"),
+                            };
+                            (__xdr_buf, __xdr_sz)
                         })
                     }
                     ty => {
@@ -412,24 +1379,25 @@ impl Type {
                         //
                         // We also need to catch panics to make sure the buf is forgotten. It may be partially initialized then
                         // it may leak, but that's better than calling Drop on uninitialized elements.
+                        let core = symtab.core_path();
                         quote!({
                             #[inline]
-                            fn uninit_ptr_setter<T>(p: &mut ::std::mem::MaybeUninit<T>, v: T) {
-                                p.write(v);
+                            fn uninit_ptr_setter<T>(__xdr_p: &mut #core::mem::MaybeUninit<T>, __xdr_v: T) {
+                                __xdr_p.write(__xdr_v);
                             }
                             #[inline]
-                            fn uninit_ptr_dropper<T>(p: &mut ::std::mem::MaybeUninit<T>) {
-                                unsafe { p.assume_init_drop(); }
+                            fn uninit_ptr_dropper<T>(__xdr_p: &mut #core::mem::MaybeUninit<T>) {
+                                unsafe { __xdr_p.assume_init_drop(); }
                             }
-                            let mut buf: [::std::mem::MaybeUninit<#ty>; #value as usize] = unsafe { ::std::mem::MaybeUninit::uninit().assume_init() };
-                            let res = xdr_codec::unpack_array_with(input, &mut buf[..], #value as usize, uninit_ptr_setter, uninit_ptr_dropper, None);
+                            let mut __xdr_buf: [#core::mem::MaybeUninit<#ty>; #value as usize] = unsafe { #core::mem::MaybeUninit::uninit().assume_init() };
+                            let __xdr_res = xdr_codec::unpack_array_with(__xdr_input, &mut __xdr_buf[..], #value as usize, uninit_ptr_setter, uninit_ptr_dropper, None);
 
-                            match res {
-                                Ok(sz) => {
-                                    let buf: [#ty; #value as usize] = unsafe { ::std::mem::transmute(buf) };
-                                    (buf, sz)
+                            match __xdr_res {
+                                Ok(__xdr_sz) => {
+                                    let __xdr_buf: [#ty; #value as usize] = unsafe { #core::mem::transmute(__xdr_buf) };
+                                    (__xdr_buf, __xdr_sz)
                                 }
-                                Err(err) => { return Err(err); }
+                                Err(__xdr_err) => { return Err(__xdr_err); }
                             }
                         })
                     }
@@ -447,44 +1415,101 @@ impl Type {
                 };
 
                 match ty {
-                    &String => quote!(xdr_codec::unpack_string(input, #maxsz)?),
-                    &Opaque => quote!(xdr_codec::unpack_opaque_flex(input, #maxsz)?),
-                    _ => quote!(xdr_codec::unpack_flex(input, #maxsz)?),
+                    &String => quote!(xdr_codec::unpack_string(__xdr_input, #maxsz)?),
+                    &Opaque => quote!(xdr_codec::unpack_opaque_flex(__xdr_input, #maxsz)?),
+                    _ => quote!(xdr_codec::unpack_flex(__xdr_input, #maxsz)?),
                 }
             }
 
-            _ => quote!(xdr_codec::Unpack::unpack(input)?),
+            Map(entry, maxsz, _) => {
+                let maxsz = match maxsz {
+                    None => quote!(None),
+                    Some(mx) => {
+                        let mx = mx.as_token(symtab);
+                        quote!(Some(#mx as usize))
+                    }
+                };
+                // An invalid entry type has already failed in `as_token`
+                let (kunpack, vunpack) = match Type::map_entry(entry, symtab) {
+                    Ok((kty, vty)) => (kty.unpacker(symtab), vty.unpacker(symtab)),
+                    Err(err) => {
+                        let err = err.to_string();
+                        return quote!(compile_error!(#err));
+                    }
+                };
+
+                quote!(xdr_codec::unpack_map_with(__xdr_input, #maxsz, |__xdr_input| {
+                    let mut __xdr_sz = 0;
+                    let __xdr_k = { let (__xdr_v, __xdr_fsz) = #kunpack; __xdr_sz += __xdr_fsz; __xdr_v };
+                    let __xdr_v = { let (__xdr_v, __xdr_fsz) = #vunpack; __xdr_sz += __xdr_fsz; __xdr_v };
+                    Ok(((__xdr_k, __xdr_v), __xdr_sz))
+                })?)
+            }
+
+            Boxed(ty) => {
+                let unpack = ty.unpacker(symtab);
+                let boxed = symtab.alloc_type("boxed", "Box");
+                quote!({ let (__xdr_v, __xdr_sz) = #unpack; (#boxed::new(__xdr_v), __xdr_sz) })
+            }
+
+            _ => quote!(xdr_codec::Unpack::unpack(__xdr_input)?),
         }
     }
 
     fn as_token<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream> {
         use self::Type::*;
 
+        if let Some(prim) = symtab.primitives.get(self) {
+            return Ok(prim.rust_type().expect("checked by Primitives::add"));
+        }
+
         let ret = match self {
             &Int => quote!(i32),
             &UInt => quote!(u32),
             &Hyper => quote!(i64),
             &UHyper => quote!(u64),
+            #[cfg(feature = "float")]
             &Float => quote!(f32),
+            #[cfg(feature = "float")]
             &Double => quote!(f64),
+            #[cfg(feature = "float")]
             &Quadruple => quote!(f128),
+            #[cfg(not(feature = "float"))]
+            &Float | &Double | &Quadruple => return Err(Error::FloatDisabled { ty: self.clone() }),
             &Bool => quote!(bool),
 
-            &String => quote!(String),
-            &Opaque => quote!(Vec<u8>),
+            &String => symtab.alloc_type("string", "String"),
+            &Opaque => {
+                let vec = symtab.alloc_type("vec", "Vec");
+                quote!(#vec<u8>)
+            }
 
             &Option(ref ty) => {
                 let ty = ty.as_ref();
                 let tok = ty.as_token(symtab)?;
                 if ty.is_boxed(symtab) {
-                    quote!(Option<Box<#tok>>)
+                    let boxed = symtab.alloc_type("boxed", "Box");
+                    quote!(Option<#boxed<#tok>>)
                 } else {
                     quote!(Option<#tok>)
                 }
             }
 
+            Boxed(ty) => {
+                let tok = ty.as_token(symtab)?;
+                let boxed = symtab.alloc_type("boxed", "Box");
+                quote!(#boxed<#tok>)
+            }
+
             &Array(ref ty, ref sz) => {
                 let ty = ty.as_ref();
+                // Sizes are sent as an unsigned int, and an empty fixed array is a mistake
+                match sz.as_i64(symtab) {
+                    Some(size) if size < 1 || size > i64::from(u32::MAX) => {
+                        return Err(Error::InvalidArraySize { ty: ty.clone(), bound: sz.clone(), size })
+                    }
+                    _ => (),
+                }
                 match ty {
                     &String | &Opaque => {
                         let sztok = sz.as_token(symtab);
@@ -500,27 +1525,33 @@ impl Type {
 
             &Flex(ref ty, _) => {
                 let ty = ty.as_ref();
+                let vec = symtab.alloc_type("vec", "Vec");
                 match ty {
-                    &String => quote!(String),
-                    &Opaque => quote!(Vec<u8>),
+                    &String => symtab.alloc_type("string", "String"),
+                    &Opaque => quote!(#vec<u8>),
                     ref ty => {
                         let tok = ty.as_token(symtab)?;
-                        quote!(Vec<#tok>)
+                        quote!(#vec<#tok>)
                     }
                 }
             }
 
-            &Ident(ref name, _) => {
-                let id = quote_ident(name.as_str());
-                quote!(#id)
+            Map(entry, _, kind) => {
+                let (kty, vty) = Type::map_entry(entry, symtab)?;
+                let map = kind.as_token(symtab).ok_or_else(|| Error::NoStdHashMap { ty: self.clone() })?;
+                let (ktok, vtok) = (kty.as_token(symtab)?, vty.as_token(symtab)?);
+                quote!(#map<#ktok, #vtok>)
             }
 
+            &Ident(ref name, _) => symtab.path(name, NameKind::Type),
+
             Enum(..) | Struct(..) | Union(..) => return Err(Error::UnnamedType(self.clone())),
         };
         Ok(ret)
     }
 }
 
+/// An enum member: its name, value if given, and trailing comment.
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 pub struct EnumDefn(pub String, pub Option<Value>, pub Option<Comment>);
 
@@ -530,13 +1561,49 @@ impl EnumDefn {
     }
 }
 
+/// A union arm for one case label: the label, the arm's declaration, and its comment. An arm
+/// with several labels has one of these for each.
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
-pub struct UnionCase(Value, Decl);
+#[non_exhaustive]
+pub struct UnionCase(pub Value, pub Decl, pub Option<Comment>);
+
+impl UnionCase {
+    // Which bool this case of a union switching on a `bool` is for, with its label read as
+    // `Symtab::set_bool_labels` says.
+    fn bool_label<M>(&self, symtab: &Symtab<M>) -> Option<bool> {
+        self.0.as_bool(symtab)
+    }
+
+    // The variant for this case of a union switching on `selector`. The cases of a `bool` are
+    // `TRUE` and `FALSE`, however they're spelt.
+    fn variant<M>(&self, selector: &Decl, symtab: &Symtab<M>) -> Ident {
+        match selector.is_bool(symtab).then(|| self.bool_label(symtab)).flatten() {
+            Some(true) => symtab.ident("TRUE", NameKind::Variant),
+            Some(false) => symtab.ident("FALSE", NameKind::Variant),
+            None => self.0.as_ident(symtab),
+        }
+    }
+
+    // This case's discriminant as a Rust expression of type `wire`, the selector's on the wire.
+    fn disc<M>(&self, selector: &Decl, wire: &TokenStream, symtab: &Symtab<M>) -> Result<TokenStream> {
+        if selector.is_bool(symtab) {
+            return match self.bool_label(symtab) {
+                Some(disc) => Ok(quote!(#disc)),
+                None => Err(Error::IncompatSelector { selector: Box::new(selector.clone()), value: self.0.clone() }),
+            };
+        }
+        let disc = self.0.as_token(symtab);
+        Ok(quote!((#disc as #wire)))
+    }
+}
 
+/// A declaration, as of a struct field or union arm.
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+#[non_exhaustive]
 pub enum Decl {
     Void,
-    Named(String, Type, Option<Comment>),
+    /// Name, type, trailing comment, and the expression of its `@default` annotation, if any
+    Named(String, Type, Option<Comment>, Option<String>),
 }
 
 fn into_comment(comment: Option<&[u8]>) -> Option<Comment> {
@@ -555,40 +1622,179 @@ fn comment_stream(comment: &Option<Comment>) -> TokenStream {
 
 impl Decl {
     fn named<S: AsRef<str>>(id: S, ty: Type) -> Decl {
-        Decl::Named(id.as_ref().to_string(), ty, None)
+        Decl::Named(id.as_ref().to_string(), ty, None, None)
+    }
+
+    fn references<'a>(&'a self, refs: &mut Vec<&'a str>) {
+        if let Decl::Named(_, ty, ..) = self {
+            ty.references(refs)
+        }
+    }
+
+    fn canonical(&self) -> String {
+        match self {
+            Decl::Void => "void".to_string(),
+            Decl::Named(name, ty, ..) => format!("{} {}", ty.canonical(), name),
+        }
+    }
+
+    fn rename(&mut self, f: &dyn Fn(&str) -> Option<String>) {
+        if let Decl::Named(_, ty, ..) = self {
+            ty.rename(f)
+        }
+    }
+
+    fn optional_unions(&mut self) {
+        if let Decl::Named(_, ty, ..) = self {
+            ty.optional_unions()
+        }
+    }
+
+    fn resolve_aliases(&mut self, aliases: &TypeAliases, defined: &HashSet<String>) {
+        if let Decl::Named(_, ty, ..) = self {
+            ty.resolve_aliases(aliases, defined)
+        }
+    }
+
+    fn lift_anonymous(&mut self, scope: &str, defined: &mut HashSet<String>, lifted: &mut Vec<Defn>) -> Result<()> {
+        use self::Type::*;
+
+        // The anonymous type may be behind a pointer or in an array
+        fn innermost(ty: &mut Type) -> &mut Type {
+            match ty {
+                Option(ty) | Array(ty, _) | Flex(ty, _) | Boxed(ty) => innermost(ty),
+                ty => ty,
+            }
+        }
+
+        if let Decl::Named(field, ty, ..) = self {
+            let ty = innermost(ty);
+            if matches!(ty, Enum(..) | Struct(..) | Union(..)) {
+                let name = format!("{}{}", scope, camel_case(field));
+                if !defined.insert(name.clone()) {
+                    return Err(Error::AnonymousTypeName { field: field.clone(), name });
+                }
+                ty.lift_anonymous(&name, defined, lifted)?;
+                let anon = std::mem::replace(ty, Type::ident(&name));
+                lifted.push(Defn::Typespec(name, anon));
+            }
+        }
+        Ok(())
+    }
+
+    // Width of a union selector's discriminant as sent on the wire.
+    fn selector_width<M>(&self, symtab: &Symtab<M>) -> EnumWidth {
+        let width = match self {
+            Decl::Named(_, ty, ..) => ty.enum_width(symtab),
+            Decl::Void => None,
+        };
+        width.unwrap_or(EnumWidth::Int)
+    }
+
+    // Whether this is a union selector of type `bool`.
+    fn is_bool<M>(&self, symtab: &Symtab<M>) -> bool {
+        matches!(self, Decl::Named(_, ty, ..) if *symtab.resolve_syns(ty) == Type::Bool)
+    }
+
+    // Rust type of a union selector's discriminant as sent on the wire.
+    fn selector_wire<M>(&self, symtab: &Symtab<M>) -> TokenStream {
+        match self.is_bool(symtab) {
+            true => quote!(bool),
+            false => self.selector_width(symtab).as_token(),
+        }
     }
 
+    // The data of the arm for discriminants a union without a `default` arm doesn't know, which
+    // is the rest of the input; see `Symtab::set_unknown_union_arms`. Like a `default` arm, it
+    // also holds the discriminant.
+    fn unknown_arm() -> Decl {
+        Decl::named("unknown", Type::Flex(Box::new(Type::Opaque), None))
+    }
+
+    // Attach the declaration's trailing comment. Words starting with `@` at its start are
+    // annotations on the declaration rather than documentation.
     fn with_comment(mut self, new_comment: Option<&[u8]>) -> Decl {
-        match &mut self {
-            Decl::Named(_id, _ty, comment) => *comment = into_comment(new_comment),
-            _ => {}
+        if let Decl::Named(_id, ty, comment, default) = &mut self {
+            (*comment, *default) = ty.annotate_comment(new_comment);
         }
         self
     }
 
-    fn name_as_ident(&self) -> Option<(Ident, &Type)> {
-        use self::Decl::*;
-        match self {
-            &Void => None,
-            &Named(ref name, ref ty, ..) => Some((quote_ident(name), ty)),
+    // The field's `@default` value for the generated `Default` impl of the struct `name`, as
+    // Rust code for its type.
+    fn default_value<M>(&self, name: &str, symtab: &Symtab<M>) -> Result<Option<TokenStream>> {
+        let (field, ty, expr) = match self {
+            Decl::Named(field, ty, _, Some(expr)) => (field, ty, expr),
+            _ => return Ok(None),
+        };
+        let invalid = |problem: String| Error::InvalidAnnotation { name: format!("{}.{}", name, field), problem };
+        let val = constant_expr(expr).map_err(|err| invalid(format!("@default({}): {}", expr, err)))?;
+        let undefined = || invalid(format!("@default({}) isn't a value of the field's type", expr));
+
+        let ty = symtab.resolve_syns(ty);
+        if symtab.codec_primitive(ty).is_some() {
+            return Err(invalid("fields with a custom Rust type can't have a @default".to_string()));
         }
+        let tok = match ty {
+            Type::Bool => match val.as_bool(symtab) {
+                Some(b) => quote!(#b),
+                None => return Err(undefined()),
+            },
+            Type::Int | Type::UInt | Type::Hyper | Type::UHyper => {
+                let v = symtab.eval(&val).ok_or_else(undefined)?;
+                let fits = match ty {
+                    Type::Int => i32::try_from(v).is_ok(),
+                    Type::UInt => u32::try_from(v).is_ok(),
+                    Type::Hyper => true,
+                    _ => v >= 0,
+                };
+                if !fits {
+                    return Err(undefined());
+                }
+                Literal::i64_unsuffixed(v).into_token_stream()
+            }
+            Type::Float | Type::Double => {
+                let v = symtab.eval(&val).ok_or_else(undefined)?;
+                Literal::f64_unsuffixed(v as f64).into_token_stream()
+            }
+            Type::Ident(enum_name, _) if matches!(symtab.typespec(enum_name), Some(Type::Enum(..))) => match &val {
+                Value::Ident(id) if symtab.getconst(id).is_some_and(|(_, scope)| scope.as_ref() == Some(enum_name)) => {
+                    let (scope, tok) = (symtab.path(enum_name, NameKind::Type), symtab.ident(base_name(id), NameKind::Variant));
+                    quote!(#scope :: #tok)
+                }
+                _ => return Err(undefined()),
+            },
+            _ => return Err(invalid("only bool, number and enum fields can have a @default".to_string())),
+        };
+        Ok(Some(tok))
     }
 
-    fn as_token<M>(&self, symtab: &Symtab<M>) -> Result<Option<(Ident, TokenStream, TokenStream)>> {
+    // The field's name, type and attributes, including its doc comment. `serde` is whether the
+    // struct derives serde's traits, so can have their attributes.
+    fn as_token<M>(&self, symtab: &Symtab<M>, serde: bool) -> Result<Option<(Ident, TokenStream, TokenStream)>> {
         use self::Decl::*;
         match self {
             &Void => Ok(None),
-            &Named(ref name, ref ty, ref comment) => {
-                let nametok = quote_ident(name.as_str());
+            &Named(ref name, ref ty, ref comment, _) => {
+                let nametok = symtab.ident(name, NameKind::Field);
                 let mut tok = ty.as_token(symtab)?;
                 if false && ty.is_boxed(symtab) {
                     tok = quote!(Box<#tok>)
                 };
-                Ok(Some((nametok, tok, comment_stream(comment))))
+                let comment = comment_stream(comment);
+                let alias = symtab.alias_attrs(name, NameKind::Field, serde);
+                Ok(Some((nametok, tok, quote!(#comment #alias))))
             }
         }
     }
 
+    #[cfg(feature = "pretty")]
+    fn size_constants<'a>(&'a self, refs: &mut Vec<&'a str>) {
+        if let Decl::Named(_, ty, ..) = self {
+            ty.size_constants(refs)
+        }
+    }
+
     fn derivable<M>(&self, symtab: &Symtab<M>, memo: &mut HashMap<Type, Derives>) -> Derives {
         use self::Decl::*;
         match self {
@@ -609,11 +1815,58 @@ pub struct Typesyn(pub String, pub Type);
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 pub struct Const(pub String, pub i64);
 
+/// A version of an RPC program: its name, procedures and number.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+pub struct Version(pub String, pub Vec<Procedure>, pub i64);
+
+impl Version {
+    fn new<S: AsRef<str>>(id: S, procs: Vec<Procedure>, num: i64) -> Version {
+        Version(id.as_ref().to_string(), procs, num)
+    }
+}
+
+/// An RPC procedure: its name, result and argument types, and number. A `void` result is `None`,
+/// and a `void` argument list is empty.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+pub struct Procedure(pub String, pub Option<Type>, pub Vec<Type>, pub i64);
+
+impl Procedure {
+    fn new<S: AsRef<str>>(id: S, ret: Option<Type>, args: Vec<Type>, num: i64) -> Procedure {
+        Procedure(id.as_ref().to_string(), ret, args, num)
+    }
+
+    fn types(&self) -> impl Iterator<Item = &Type> {
+        self.1.iter().chain(&self.2)
+    }
+
+    fn types_mut(&mut self) -> impl Iterator<Item = &mut Type> {
+        self.1.iter_mut().chain(&mut self.2)
+    }
+
+    fn canonical(&self) -> String {
+        let ty = |ty: Option<&Type>| ty.map_or("void".to_string(), Type::canonical);
+        let args = match &self.2[..] {
+            [] => "void".to_string(),
+            args => args.iter().map(Type::canonical).collect::<Vec<_>>().join(", "),
+        };
+        format!("{} {}({}) = {};", ty(self.1.as_ref()), self.0, args, self.3)
+    }
+}
+
+/// A top level definition.
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
+#[non_exhaustive]
 pub enum Defn {
+    /// A type of its own: a struct, union or enum, or a typedef of an array
     Typespec(String, Type),
+    /// A typedef of a primitive, optional or named type, which is another name for it
     Typesyn(String, Type),
-    Const(String, i64),
+    /// A number, or another constant, which the symbol table resolves
+    Const(String, Value),
+    /// Dialect extension: definitions generated into a nested module
+    Namespace(String, Vec<Defn>),
+    /// RPC program, which only defines constants for its numbers
+    Program(String, Vec<Version>, i64),
 }
 
 impl Defn {
@@ -625,93 +1878,1527 @@ impl Defn {
         Defn::Typesyn(id.as_ref().to_string(), ty)
     }
 
+    #[cfg(test)]
     fn constant<S: AsRef<str>>(id: S, v: i64) -> Defn {
-        Defn::Const(id.as_ref().to_string(), v)
+        Defn::Const(id.as_ref().to_string(), Value::Const(v))
     }
-}
 
-pub trait Emit {
-    fn define<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream>;
-}
+    fn namespace<S: AsRef<str>>(id: S, defns: Vec<Defn>) -> Defn {
+        Defn::Namespace(id.as_ref().to_string(), defns)
+    }
 
-pub trait Emitpack: Emit {
-    fn pack<M>(&self, symtab: &Symtab<M>) -> Result<Option<TokenStream>>;
-    fn unpack<M>(&self, symtab: &Symtab<M>) -> Result<Option<TokenStream>>;
-}
+    fn program<S: AsRef<str>>(id: S, versions: Vec<Version>, num: i64) -> Defn {
+        Defn::Program(id.as_ref().to_string(), versions, num)
+    }
 
-impl Emit for Const {
-    fn define<M>(&self, _: &Symtab<M>) -> Result<TokenStream> {
-        let name = quote_ident(&self.0);
-        let val = &self.1;
+    pub fn name(&self) -> &str {
+        match self {
+            Defn::Typespec(name, _)
+            | Defn::Typesyn(name, _)
+            | Defn::Const(name, _)
+            | Defn::Namespace(name, _)
+            | Defn::Program(name, ..) => name,
+        }
+    }
 
-        Ok(quote!(pub const #name: i64 = #val;))
+    // Constants for the numbers of a program, its versions and their procedures, named after
+    // them as rpcgen does.
+    fn program_consts(&self) -> Vec<(&str, i64)> {
+        match self {
+            Defn::Program(name, versions, num) => std::iter::once((name.as_str(), *num))
+                .chain(versions.iter().flat_map(|Version(name, procs, num)| {
+                    std::iter::once((name.as_str(), *num)).chain(procs.iter().map(|proc| (proc.0.as_str(), proc.3)))
+                }))
+                .collect(),
+            _ => Vec::new(),
+        }
     }
-}
 
-impl Emit for Typesyn {
-    fn define<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream> {
-        let ty = &self.1;
-        let name = quote_ident(&self.0);
-        let tok = ty.as_token(symtab)?;
-        Ok(quote!(pub type #name = #tok;))
+    // Names of the types and constants this definition refers to.
+    pub(crate) fn references(&self) -> Vec<&str> {
+        let mut refs = Vec::new();
+        match self {
+            Defn::Typespec(_, ty) | Defn::Typesyn(_, ty) => ty.references(&mut refs),
+            Defn::Const(_, val) => val.references(&mut refs),
+            Defn::Namespace(_, defns) => refs.extend(defns.iter().flat_map(Defn::references)),
+            Defn::Program(_, versions, _) => versions
+                .iter()
+                .flat_map(|version| &version.1)
+                .flat_map(Procedure::types)
+                .for_each(|ty| ty.references(&mut refs)),
+        }
+        refs
     }
-}
 
-impl Emit for Typespec {
-    fn define<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream> {
-        use self::Type::*;
+    // Canonical text for fingerprinting. The order of definitions doesn't matter, so they're
+    // sorted.
+    fn canonical(defns: &[Defn]) -> String {
+        let mut defns: Vec<_> = defns
+            .iter()
+            .map(|defn| match defn {
+                Defn::Typespec(name, ty) | Defn::Typesyn(name, ty) => format!("typedef {} {};\n", ty.canonical(), name),
+                Defn::Const(name, val) => format!("const {} = {};\n", name, val.canonical()),
+                Defn::Namespace(name, defns) => format!("namespace {} {{\n{}}}\n", name, Defn::canonical(defns)),
+                Defn::Program(name, versions, num) => {
+                    let versions = versions.iter().map(|Version(name, procs, num)| {
+                        let procs: String = procs.iter().map(|proc| proc.canonical() + "\n").collect();
+                        format!("version {} {{\n{}}} = {};\n", name, procs, num)
+                    });
+                    format!("program {} {{\n{}}} = {};\n", name, versions.collect::<String>(), num)
+                }
+            })
+            .collect();
+        defns.sort();
+        defns.concat()
+    }
 
-        let name = quote_ident(&self.0);
-        let ty = &self.1;
+    // Names this definition adds to its scope, including enum members, program constants, and the
+    // contents of a namespace qualified by its name.
+    pub(crate) fn scope_names(&self) -> Vec<String> {
+        match self {
+            Defn::Program(..) => self.program_consts().into_iter().map(|(name, _)| name.to_string()).collect(),
+            Defn::Typespec(name, Type::Enum(edefs, _)) => {
+                std::iter::once(name.clone()).chain(edefs.iter().map(|e| e.0.clone())).collect()
+            }
+            Defn::Namespace(ns, defns) => defns
+                .iter()
+                .flat_map(Defn::scope_names)
+                .map(|name| format!("{}::{}", ns, name))
+                .collect(),
+            _ => vec![self.name().to_string()],
+        }
+    }
+}
 
-        let ret = match ty {
-            &Enum(ref edefs) => {
-                let defs: Vec<_> = edefs
-                    .iter()
-                    .filter_map(|&EnumDefn(ref field, _, ref comment)| if let Some((val, Some(_))) =
-                        symtab.getconst(field)
-                    {
-                        Some((quote_ident(field), val as isize, comment_stream(comment)))
-                    } else {
-                        None
-                    })
-                    .map(|(field, val, comment)| quote!(#comment #field = #val,))
-                    .collect();
+/// Type names which legacy specs use without defining, and the XDR types they stand for.
+///
+/// The default table has the Sun RPC spellings, like `u_int`, `u_char` and `caddr_t`. A
+/// definition of the same name in a spec takes precedence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeAliases(BTreeMap<String, Type>);
+
+impl TypeAliases {
+    const SUN: &'static [(&'static str, &'static str)] = &[
+        ("bool_t", "bool"),
+        ("enum_t", "int"),
+        // Sun's xdr_u_char sends each one as a whole unsigned int
+        ("u_char", "unsigned int"),
+        ("u_short", "unsigned short"),
+        ("u_int", "unsigned int"),
+        ("u_long", "unsigned long"),
+        ("int32_t", "int"),
+        ("uint32_t", "unsigned int"),
+        ("u_int32_t", "unsigned int"),
+        ("int64_t", "hyper"),
+        ("uint64_t", "unsigned hyper"),
+        ("u_int64_t", "unsigned hyper"),
+        ("longlong_t", "hyper"),
+        ("u_longlong_t", "unsigned hyper"),
+        ("quad_t", "hyper"),
+        ("u_quad_t", "unsigned hyper"),
+        ("caddr_t", "opaque<>"),
+    ];
+
+    /// No aliases at all.
+    pub fn none() -> TypeAliases {
+        TypeAliases(BTreeMap::new())
+    }
 
-                let derive = ty.derivable(symtab, None);
-                quote!(#derive pub enum #name { #(#defs)* })
+    /// Make `name` stand for `xdr_type`, which is spelled as in a declaration without the
+    /// declared name, for example `unsigned hyper`, `opaque<>` or `string<64>`.
+    pub fn add(&mut self, name: &str, xdr_type: &str) -> Result<()> {
+        let split = xdr_type.find(['<', '[']).unwrap_or(xdr_type.len());
+        let typedef = format!("typedef {} alias {};", &xdr_type[..split], &xdr_type[split..]);
+
+        match xdr_nom::specification(&typedef)?.as_slice() {
+            [Defn::Typespec(_, ty)] | [Defn::Typesyn(_, ty)] => {
+                self.0.insert(name.to_string(), ty.clone());
+                Ok(())
             }
+            _ => Err(ParseError::new(xdr_type, 0, Expected::Other).into()),
+        }
+    }
 
-            &Struct(ref decls) => {
-                let decls: Vec<_> = decls
-                    .iter()
-                    .filter_map(|decl| decl.as_token(symtab).transpose())
-                    .map(|res| res.map(|(field, ty, comment)| quote!(#comment pub #field: #ty,)))
-                    .collect::<Result<Vec<_>>>()?;
+    fn get(&self, name: &str) -> Option<&Type> {
+        self.0.get(name)
+    }
 
-                let derive = ty.derivable(symtab, None);
-                quote! {
-                    #derive
-                    pub struct #name { #(#decls)* }
+    /// Resolve the aliases in `defns` which they don't define themselves.
+    pub fn resolve(&self, defns: &mut [Defn]) {
+        let defined = defns.iter().flat_map(Defn::scope_names).collect();
+        self.resolve_in(defns, &defined);
+    }
+
+    fn resolve_in(&self, defns: &mut [Defn], defined: &HashSet<String>) {
+        for defn in defns {
+            match defn {
+                Defn::Typespec(_, ty) | Defn::Typesyn(_, ty) => ty.resolve_aliases(self, defined),
+                Defn::Const(..) => (),
+                Defn::Program(_, versions, _) => versions
+                    .iter_mut()
+                    .flat_map(|version| &mut version.1)
+                    .flat_map(Procedure::types_mut)
+                    .for_each(|ty| ty.resolve_aliases(self, defined)),
+                Defn::Namespace(_, defns) => {
+                    let mut defined = defined.clone();
+                    defined.extend(defns.iter().flat_map(Defn::scope_names));
+                    self.resolve_in(defns, &defined)
                 }
             }
+        }
+    }
+}
 
-            &Union(ref selector, ref cases, ref defl) => {
-                let selector = selector.as_ref();
-                use self::Decl::*;
-                use self::Value::*;
+impl Default for TypeAliases {
+    fn default() -> TypeAliases {
+        let mut aliases = TypeAliases::none();
+        for (name, ty) in TypeAliases::SUN {
+            aliases.add(name, ty).expect("builtin alias");
+        }
+        aliases
+    }
+}
 
-                let labelfields = false; // true - include label in enum branch
+/// A Rust type to generate for an XDR type, and how to pack and unpack it; see `Primitives`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Primitive {
+    /// The Rust type, for example `bytes::Bytes`
+    pub rust_type: String,
+    /// What the Rust type implements, so types containing it can derive them too
+    pub derives: Derives,
+    /// Expression packing `{value}` into the writer `{out}`, of type `xdr_codec::Result<usize>`.
+    /// `{max}` is the XDR type's size limit, as an `Option<usize>`. If not set, the Rust type's
+    /// own `Pack` impl is used.
+    pub pack: Option<String>,
+    /// Expression unpacking from the reader `{input}`, of type `xdr_codec::Result<(T, usize)>`,
+    /// with `{max}` as for `pack`. If not set, the Rust type's own `Unpack` impl is used.
+    pub unpack: Option<String>,
+}
 
-                // return true if case is compatible with the selector
-                let compatcase = |case: &Value| {
-                    let seltype = match selector {
-                        &Void => return false,
-                        &Named(_, ref ty, ..) => ty,
-                    };
+impl Primitive {
+    /// `rust_type`, with no derives and its own `Pack` and `Unpack` impls.
+    pub fn new(rust_type: &str) -> Primitive {
+        Primitive { rust_type: rust_type.to_string(), derives: Derives::empty(), pack: None, unpack: None }
+    }
 
-                    match case {
-                        &Const(val) if val < 0 => {
+    /// `xdr_codec::XdrString`, for `string` or types defined as one, whose values needn't be
+    /// UTF-8. It keeps a string's bytes as they were sent, rather than failing to unpack it.
+    pub fn xdr_string() -> Primitive {
+        Primitive {
+            rust_type: "xdr_codec::XdrString".to_string(),
+            derives: Derives::CLONE | Derives::DEBUG | Derives::EQ | Derives::PARTIALEQ | Derives::DEFAULT,
+            pack: Some("xdr_codec::pack_opaque_flex(&{value}, {max}, {out})".to_string()),
+            unpack: Some("xdr_codec::unpack_xdr_string({input}, {max})".to_string()),
+        }
+    }
+
+    // The template with its placeholders replaced by `args`, as code.
+    fn expand(template: &str, args: &[(&str, &TokenStream)]) -> std::result::Result<TokenStream, String> {
+        let code = args
+            .iter()
+            .fold(template.to_string(), |code, (name, tok)| code.replace(name, &format!("({})", tok)));
+        code.parse().map_err(|err: proc_macro2::LexError| format!("{} in {:?}", err, template))
+    }
+
+    fn rust_type(&self) -> std::result::Result<TokenStream, String> {
+        self.rust_type.parse().map_err(|err: proc_macro2::LexError| format!("{} in {:?}", err, self.rust_type))
+    }
+
+    fn packer(&self, val: TokenStream, maxsz: TokenStream) -> std::result::Result<TokenStream, String> {
+        match &self.pack {
+            None => Ok(quote!(xdr_codec::Pack::pack(&#val, __xdr_out)?)),
+            Some(pack) => {
+                let pack = Primitive::expand(pack, &[("{value}", &val), ("{out}", &quote!(__xdr_out)), ("{max}", &maxsz)])?;
+                Ok(quote!((#pack)?))
+            }
+        }
+    }
+
+    fn unpacker(&self, maxsz: TokenStream) -> std::result::Result<TokenStream, String> {
+        match &self.unpack {
+            None => Ok(quote!(xdr_codec::Unpack::unpack(__xdr_input)?)),
+            Some(unpack) => {
+                let unpack = Primitive::expand(unpack, &[("{input}", &quote!(__xdr_input)), ("{max}", &maxsz)])?;
+                Ok(quote!((#unpack)?))
+            }
+        }
+    }
+}
+
+/// Custom Rust types for XDR types, in place of those the generator would use.
+///
+/// An XDR type is either a primitive, spelled `int`, `unsigned int`, `hyper`, `unsigned hyper`,
+/// `float`, `double`, `quadruple`, `bool`, `string` or `opaque`, or the name of a type, which
+/// needn't be defined in the spec. `string` and `opaque` are their variable length forms, of any
+/// size limit. Types defined as synonyms of a mapped type are packed and unpacked the same way.
+///
+/// Array elements and optional data are packed and unpacked with the Rust type's own impls.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Primitives(BTreeMap<String, Primitive>);
+
+impl Primitives {
+    const SPELLINGS: &'static [&'static str] = &[
+        "int", "unsigned int", "hyper", "unsigned hyper", "float", "double", "quadruple", "bool", "string", "opaque",
+    ];
+
+    /// Generate `xdr_type` as `primitive`, replacing any mapping it already had.
+    pub fn add(&mut self, xdr_type: &str, primitive: Primitive) -> Result<()> {
+        let invalid = |problem: String| Error::InvalidPrimitive { xdr_type: xdr_type.to_string(), problem };
+
+        let is_name = !xdr_type.is_empty()
+            && xdr_type.split("::").all(|part| {
+                part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+        if !is_name && !Primitives::SPELLINGS.contains(&xdr_type) {
+            return Err(invalid("not a primitive type or a type name".into()));
+        }
+        // Tokenize the code now, so generating with it can't fail
+        primitive.rust_type().map_err(invalid)?;
+        primitive.packer(quote!(__xdr_v), quote!(None)).map_err(invalid)?;
+        primitive.unpacker(quote!(None)).map_err(invalid)?;
+
+        self.0.insert(xdr_type.to_string(), primitive);
+        Ok(())
+    }
+
+    fn get(&self, ty: &Type) -> Option<&Primitive> {
+        use self::Type::*;
+
+        let key = match ty {
+            Int => "int",
+            UInt => "unsigned int",
+            Hyper => "hyper",
+            UHyper => "unsigned hyper",
+            Float => "float",
+            Double => "double",
+            Quadruple => "quadruple",
+            Bool => "bool",
+            Flex(elem, _) if **elem == String => "string",
+            Flex(elem, _) if **elem == Opaque => "opaque",
+            Ident(name, _) => name,
+            _ => return None,
+        };
+        self.0.get(key)
+    }
+}
+
+/// Names of the definitions `roots` need, including themselves, in `defns` order.
+///
+/// A reference to an enum member is a dependency on the enum.
+pub fn dependencies<'a>(defns: &'a [Defn], roots: &[&str]) -> Result<Vec<&'a str>> {
+    let by_name: HashMap<&str, &Defn> = defns.iter().map(|d| (d.name(), d)).collect();
+    let members: HashMap<&str, &str> = defns
+        .iter()
+        .filter_map(|d| match d {
+            Defn::Typespec(name, Type::Enum(edefs, _)) => Some(edefs.iter().map(move |e| (e.0.as_str(), name.as_str()))),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+
+    if let Some(root) = roots.iter().find(|root| !by_name.contains_key(*root)) {
+        return Err(Error::UnknownDefinition { name: root.to_string() });
+    }
+
+    let graph = TypeGraph::build(defns.iter().map(|defn| (defn.name(), defn.references())), |name| {
+        // Something in a namespace needs the whole namespace
+        let name = name.split("::").next().unwrap_or(name);
+        match by_name.get(name) {
+            Some(defn) => Some(defn.name()),
+            // Builtin, like TRUE, or undefined; either way not ours to emit
+            None => members.get(name).copied(),
+        }
+    });
+    let needed = graph.reachable(roots.iter().copied());
+
+    Ok(defns.iter().map(Defn::name).filter(|name| needed.contains(name)).collect())
+}
+
+/// How directives in a specification's source are handled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Directives<'a> {
+    /// Where files named by `#include "file"` directives are found. If not set, such directives
+    /// are ignored.
+    pub include_dir: Option<&'a Path>,
+    /// Directory included files must be within, if not `include_dir`. Set for specifications
+    /// from untrusted sources, whose includes which can't be found are reported as outside it,
+    /// so they can't tell which paths exist.
+    pub include_root: Option<&'a Path>,
+    /// Evaluate conditionals and `#define`s, starting with these definitions; see `preprocess`.
+    /// If not set, they're ignored.
+    pub defines: Option<&'a BTreeMap<String, String>>,
+    /// Tolerate rpcgen's quirks, with a warning for each; see `rpcgen_quirks`. Redundant
+    /// `typedef`s are dropped.
+    pub quirks: bool,
+}
+
+/// Parse a specification, acting on its directives as set by `directives`.
+///
+/// Included files are found relative to `include_dir` for `input`, and relative to their own
+/// directory for those they include in turn. Their definitions come before those of the file
+/// including them, and each file is only included once, so include cycles are harmless. Each
+/// file is preprocessed separately, starting from the same `defines`.
+///
+/// Included files must be named by relative paths without `..`, and be within `include_root`,
+/// or `include_dir` if it isn't set, once symbolic links are followed.
+pub fn specification_with_directives(input: &str, directives: &Directives) -> Result<Vec<Defn>> {
+    // The directory included files must be within, as given and as found
+    struct Root<'a> {
+        path: &'a Path,
+        canonical: PathBuf,
+    }
+
+    impl Root<'_> {
+        fn outside(&self) -> Error {
+            Error::IncludeOutsideRoot { root: self.path.to_path_buf() }
+        }
+    }
+
+    fn parse(input: &str, dir: Option<(&Path, &Root)>, directives: &Directives, seen: &mut HashSet<PathBuf>, defns: &mut Vec<Defn>) -> Result<()> {
+        let input = match directives.defines {
+            Some(defines) => Cow::Owned(preprocess(input, defines)?),
+            None => Cow::Borrowed(input),
+        };
+        let input = match directives.quirks {
+            true => Cow::Owned(rpcgen_quirks(&input).into_owned()),
+            false => input,
+        };
+
+        if let Some((dir, root)) = dir {
+            for file in xdr_nom::includes(&input) {
+                let path = dir.join(&file);
+                let relative = Path::new(&file).components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+                let res = match relative {
+                    true => include(&path, root, directives, seen, defns),
+                    false => Err(root.outside()),
+                };
+                res.map_err(|err| Error::Include { path, source: Box::new(err) })?;
+            }
+        }
+
+        defns.extend(specification(&input)?);
+        Ok(())
+    }
+
+    fn include(path: &Path, root: &Root, directives: &Directives, seen: &mut HashSet<PathBuf>, defns: &mut Vec<Defn>) -> Result<()> {
+        let canonical = match fs::canonicalize(path) {
+            Ok(canonical) => canonical,
+            Err(_) if directives.include_root.is_some() => return Err(root.outside()),
+            Err(err) => return Err(err.into()),
+        };
+        if !canonical.starts_with(&root.canonical) {
+            return Err(root.outside());
+        }
+        if !seen.insert(canonical) {
+            return Ok(());
+        }
+        let source = read_source(fs::File::open(path)?)?;
+        parse(&source, Some((path.parent().unwrap_or(Path::new(".")), root)), directives, seen, defns)
+    }
+
+    let root = match directives.include_dir {
+        Some(dir) => {
+            let path = directives.include_root.unwrap_or(dir);
+            Some(Root { path, canonical: fs::canonicalize(path)? })
+        }
+        None => None,
+    };
+    let mut defns = Vec::new();
+    let dir = directives.include_dir.zip(root.as_ref());
+    parse(input, dir, directives, &mut HashSet::new(), &mut defns)?;
+    if directives.quirks {
+        quirks::drop_redefinitions(&mut defns);
+    }
+    Ok(defns)
+}
+
+/// SHA-256 of the canonical form of `defns`, which is the same for any specifications with the
+/// same definitions, regardless of comments, layout or the order of the definitions.
+pub fn fingerprint(defns: &[Defn]) -> [u8; 32] {
+    Sha256::digest(Defn::canonical(defns).as_bytes()).into()
+}
+
+#[cfg(feature="fingerprint_const")]
+pub fn fingerprint_const<M>(symtab: &Symtab<M>, fingerprint: &[u8; 32]) -> TokenStream {
+    let vis = symtab.vis();
+    quote!(#vis const PROTOCOL_FINGERPRINT: [u8; 32] = [#(#fingerprint),*];)
+}
+
+/// The dialect the spec's peers use, to pack and unpack its types with.
+#[cfg(feature="dialect")]
+pub fn dialect_const<M>(symtab: &Symtab<M>, dialect: &xdr_codec::Dialect) -> TokenStream {
+    let xdr_codec::Dialect { pad_byte, align_hyper } = *dialect;
+    let vis = symtab.vis();
+    quote!(#vis const XDR_DIALECT: xdr_codec::Dialect = xdr_codec::Dialect { pad_byte: #pad_byte, align_hyper: #align_hyper };)
+}
+
+/// Name of the generated message envelope union, and of the enum of its discriminants.
+pub const MESSAGE_ENVELOPE: &str = "Message";
+pub const MESSAGE_KIND: &str = "MessageKind";
+
+// Whether `comment` is the last thing in a definition's text, after its closing `;`.
+fn is_trailing(text: &str, comment: &SpecComment) -> bool {
+    let start = comment.location.offset;
+    let end = match comment.kind {
+        CommentKind::Block => start + 2 + comment.text.len() + 2,
+        CommentKind::Line => text.len(),
+    };
+    text[..start].trim_end().ends_with(';') && text.get(end..).is_none_or(|rest| rest.trim().is_empty())
+}
+
+// The words starting with `@` which a comment's text starts with, and the rest of it. Later
+// words are prose, which may mention `@` for other reasons. An annotation's argument in
+// parentheses, as in `@default(A + 1)`, may have spaces.
+fn leading_annotations(text: &str) -> (Vec<&str>, &str) {
+    let mut annotations = Vec::new();
+    let mut rest = text.trim_start();
+    while rest.starts_with('@') {
+        let word = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let end = match (rest[..word].find('('), rest.find(')')) {
+            (Some(open), Some(close)) if close > open => close + 1,
+            _ => word,
+        };
+        annotations.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    (annotations, rest)
+}
+
+// The annotations in the trailing comment of a definition's text.
+fn trailing_annotations(text: &str) -> Vec<String> {
+    match xdr_nom::comments(text).last() {
+        Some(comment) if is_trailing(text, comment) => {
+            leading_annotations(&comment.text).0.into_iter().map(String::from).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Definitions for a message envelope (dialect extension), if any types are marked as messages.
+///
+/// A type is a message if the trailing comment of its definition has a `@message` annotation,
+/// optionally with its discriminant as `@message=N`; otherwise the discriminant follows on from
+/// the previous message's, starting from 0. The envelope is `union Message switch (MessageKind
+/// kind)`, with a case for each message named after its type.
+pub fn message_envelope(spec: &SpecSource) -> Result<Vec<Defn>> {
+    let invalid = |name: &str, problem: String| Error::InvalidMessage { name: name.to_string(), problem };
+
+    let mut kinds = Vec::new();
+    let mut cases = Vec::new();
+    let mut seen = HashMap::new();
+    let mut next = 0;
+    // Only a clash if there's an envelope to generate
+    let mut clash = None;
+
+    for (defn, text) in &spec.defns {
+        let name = defn.name();
+        if name == MESSAGE_ENVELOPE || name == MESSAGE_KIND {
+            clash.get_or_insert(name);
+        }
+
+        let annotations = trailing_annotations(text);
+        let annotations: Vec<_> = annotations
+            .iter()
+            .map(String::as_str)
+            .filter(|word| *word == "@message" || word.starts_with("@message="))
+            .collect();
+        let disc = match annotations[..] {
+            [] => continue,
+            [annotation] => match annotation.strip_prefix("@message=") {
+                None => next,
+                Some(val) => {
+                    let parsed = match val.strip_prefix("0x") {
+                        Some(hex) => i32::from_str_radix(hex, 16),
+                        None => val.parse(),
+                    };
+                    parsed.map_err(|_| invalid(name, format!("bad discriminant {:?}", val)))?
+                }
+            },
+            _ => return Err(invalid(name, "more than one @message annotation".to_string())),
+        };
+
+        match defn {
+            Defn::Typespec(..) | Defn::Typesyn(..) => (),
+            _ => return Err(invalid(name, "only types can be messages".to_string())),
+        }
+        if let Some(other) = seen.insert(disc, name) {
+            return Err(invalid(name, format!("discriminant {} is already used by {}", disc, other)));
+        }
+        next = disc.wrapping_add(1);
+
+        kinds.push(EnumDefn::new(name, Some(Value::Const(disc as i64)), None));
+        cases.push(UnionCase(Value::ident(name), Decl::named(name, Type::ident(name)), None));
+    }
+
+    if cases.is_empty() {
+        return Ok(vec![]);
+    }
+    if let Some(name) = clash {
+        return Err(invalid(name, "name is needed for the message envelope".to_string()));
+    }
+
+    let selector = Decl::named("kind", Type::ident(MESSAGE_KIND));
+    Ok(vec![
+        Defn::typespec(MESSAGE_KIND, Type::Enum(kinds, EnumWidth::Int)),
+        Defn::typespec(MESSAGE_ENVELOPE, Type::Union(Box::new(selector), cases, None)),
+    ])
+}
+
+// Number of single character insertions, deletions and substitutions to turn `a` into `b`.
+#[cfg(feature = "pretty")]
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (diag + (ca != cb) as usize).min(row[j] + 1).min(row[j + 1] + 1);
+            diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// Check that the constants `defns` use as array sizes are defined, either in `symtab` or among
+/// `external` names such as consts hand-written Rust defines or imports.
+///
+/// An undefined constant is usually a typo, which would otherwise only show up when the generated
+/// code fails to compile. The error suggests a similarly named constant, saying where it came
+/// from: `origin` describes the source of a symbol table entry from its metadata. Names within
+/// namespaces resolve relative to them, so they aren't checked.
+#[cfg(feature = "pretty")]
+pub fn check_size_constants<M>(
+    defns: &[Defn],
+    symtab: &Symtab<M>,
+    external: &HashSet<String>,
+    origin: impl Fn(&M) -> &'static str,
+) -> Result<()> {
+    let mut errors = Vec::new();
+
+    for defn in defns {
+        let mut refs = Vec::new();
+        if let Defn::Typespec(_, ty) | Defn::Typesyn(_, ty) = defn {
+            ty.size_constants(&mut refs)
+        }
+
+        for name in refs {
+            if symtab.consts.contains_key(name) || external.contains(name) {
+                continue;
+            }
+            let known = symtab
+                .consts
+                .iter()
+                .map(|(known, def)| (known.as_str(), origin(&def.meta)))
+                .chain(external.iter().map(|known| (known.as_str(), "the Rust headers")));
+            let suggestion = known
+                .map(|(known, origin)| (edit_distance(name, known), known, origin))
+                .filter(|&(dist, known, _)| dist <= 2 || known.eq_ignore_ascii_case(name))
+                .min()
+                .map(|(_, known, origin)| format!("{} from {}", known, origin));
+            errors.push(Error::UndefinedConstant { defn: defn.name().to_string(), name: name.to_string(), suggestion });
+        }
+    }
+
+    Error::from_many(errors).map_or(Ok(()), Err)
+}
+
+/// Which of the `Pack` and `Unpack` impls to generate for a type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codecs {
+    #[default]
+    Both,
+    /// Just `Pack`, for code which only ever encodes
+    PackOnly,
+    /// Just `Unpack`, for code which only ever decodes
+    UnpackOnly,
+    /// Neither, for types encoded some other way; see `Symtab::set_types_only`
+    None,
+}
+
+impl Codecs {
+    pub fn pack(self) -> bool {
+        matches!(self, Codecs::Both | Codecs::PackOnly)
+    }
+
+    pub fn unpack(self) -> bool {
+        matches!(self, Codecs::Both | Codecs::UnpackOnly)
+    }
+}
+
+// `snake_case` or `camelCase` as `CamelCase`.
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .flat_map(|part| {
+            let mut chars = part.chars();
+            chars.next().map(|first| first.to_uppercase().chain(chars))
+        })
+        .flatten()
+        .collect()
+}
+
+/// Replace unions which are only a bool and, when it's TRUE, a value, with optional data of the
+/// value's type, as if they had been written `T *`; the encoding is the same. A definition of
+/// such a union becomes a typedef, so it's generated as `Option<T>`. The generator only does this
+/// with `GenerateOptions::optional_unions`, as it changes the types' API.
+pub fn optional_unions(defns: &mut [Defn]) {
+    for defn in defns {
+        match defn {
+            Defn::Typespec(name, ty) => {
+                ty.optional_unions();
+                if let Type::Option(_) = ty {
+                    *defn = Defn::Typesyn(name.clone(), ty.clone());
+                }
+            }
+            Defn::Typesyn(_, ty) => ty.optional_unions(),
+            Defn::Namespace(_, defns) => optional_unions(defns),
+            Defn::Const(..) | Defn::Program(..) => (),
+        }
+    }
+}
+
+/// Lift anonymous structs, unions and enums declared in the fields of other types out to
+/// definitions of their own, just before the definition they were in. Each is named after its
+/// enclosing type and field, so `struct { int x; } point;` in `parent` becomes `ParentPoint`.
+///
+/// Returns the top-level definition each lifted type came from, so it can be selected and
+/// annotated along with it.
+pub fn lift_anonymous(defns: &mut Vec<Defn>) -> Result<HashMap<String, String>> {
+    let mut defined: HashSet<String> = defns.iter().flat_map(Defn::scope_names).collect();
+    let mut owners = HashMap::new();
+    let mut result = Vec::with_capacity(defns.len());
+
+    for mut defn in defns.drain(..) {
+        match &mut defn {
+            Defn::Typespec(name, ty) | Defn::Typesyn(name, ty) => {
+                let mut lifted = Vec::new();
+                ty.lift_anonymous(&camel_case(name), &mut defined, &mut lifted)?;
+                owners.extend(lifted.iter().map(|lifted| (lifted.name().to_string(), name.clone())));
+                result.extend(lifted);
+            }
+            Defn::Namespace(_, defns) => {
+                lift_anonymous(defns)?;
+            }
+            Defn::Const(..) | Defn::Program(..) => (),
+        }
+        result.push(defn);
+    }
+
+    *defns = result;
+    Ok(owners)
+}
+
+/// How namespace blocks (dialect extension), as used by Stellar's specs, are generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Namespaces {
+    /// Each namespace is a Rust module of the same name.
+    #[default]
+    Modules,
+    /// Namespaces' contents are moved to the top level, and names qualified by them lose the
+    /// qualification.
+    Flatten,
+}
+
+/// How the spec's names are spelled in Rust; see `Symtab::set_naming`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Naming {
+    /// As the spec has them.
+    #[default]
+    Spec,
+    /// By Rust's conventions: types and enum variants in PascalCase, fields and modules in
+    /// snake_case and constants in SCREAMING_SNAKE_CASE, so `struct nfs_fh4` becomes `NfsFh4`
+    /// and an enum member `NF4REG` becomes the variant `Nf4reg`. Items whose name changes get a
+    /// `#[doc(alias)]` with the spec's name, and with the `derive_serde` feature types, fields
+    /// and variants get a `#[serde(rename)]` too, so they serialize as before. It's an error for
+    /// two names, such as `foo_bar` and `fooBar`, to be spelled the same where they'd clash.
+    Rust,
+}
+
+/// How names which are Rust keywords are made identifiers; see `Symtab::set_keyword_escape`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeywordEscape {
+    /// With an `_` appended, so a field `type` becomes `type_`, and serializes as that with the
+    /// `derive_serde` feature.
+    #[default]
+    Underscore,
+    /// As raw identifiers, so a field `type` becomes `r#type`, and keeps its name with serde.
+    /// `self`, `Self`, `super` and `crate` can't be raw identifiers, so still get an `_`.
+    Raw,
+}
+
+/// Which code can use the generated items; see `Symtab::set_visibility`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Visibility {
+    /// `pub`, so they're part of the crate's API if the module they're generated into is.
+    #[default]
+    Public,
+    /// `pub(crate)`, so the rest of the crate can use them but other crates can't.
+    Crate,
+    /// Private to the module they're generated into. Items in the modules generated for
+    /// namespaces and sections can be used from there too.
+    Private,
+}
+
+/// How the case labels of unions switching on a `bool` are read; see `Symtab::set_bool_labels`.
+/// Either way, their variants are `TRUE` and `FALSE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BoolLabels {
+    /// `TRUE` and `FALSE`, as rpcgen spells them, or `true` and `false`, or `1` and `0`.
+    #[default]
+    Spellings,
+    /// Any of the `Spellings`, or else a constant whose value is 1 or 0.
+    Constants,
+    /// Only `TRUE` and `FALSE`.
+    Strict,
+}
+
+/// Which prefix, if any, is left off an enum's members to name its variants, such as `OP_` for
+/// `enum nfs_opnum4 { OP_ACCESS = 3, OP_CLOSE = 4, ... }`; see `Symtab::set_variant_prefixes`.
+///
+/// A prefix is only left off if every member of the enum has it and what's left of each starts
+/// with a letter or underscore. Union arms with an enum's members as labels are named the same
+/// way. Constants for the members keep their names.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum VariantPrefixes {
+    /// Variants are named as the members are.
+    #[default]
+    Keep,
+    /// The longest prefix ending with `_` which all of an enum's members share, for enums with
+    /// more than one member.
+    Auto,
+    /// The prefix given for each enum, by name. Other enums' variants keep their names.
+    Given(HashMap<String, String>),
+}
+
+impl VariantPrefixes {
+    // The prefix to leave off the enum's members, if any
+    fn prefix<'a>(&'a self, name: &str, members: &[&'a str]) -> Option<&'a str> {
+        let prefix = match self {
+            VariantPrefixes::Keep => return None,
+            VariantPrefixes::Auto if members.len() < 2 => return None,
+            VariantPrefixes::Auto => {
+                let first = members[0];
+                let common = members[1..].iter().fold(first.len(), |len, member| {
+                    first.bytes().zip(member.bytes()).take(len).take_while(|(a, b)| a == b).count()
+                });
+                &first[..first[..common].rfind('_')? + 1]
+            }
+            VariantPrefixes::Given(prefixes) => prefixes.get(name)?.as_str(),
+        };
+        let strips = |member: &str| {
+            member.strip_prefix(prefix).is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+        };
+        if prefix.is_empty() || !members.iter().all(|member| strips(member)) {
+            return None;
+        }
+        Some(prefix)
+    }
+}
+
+/// What a name is in Rust, for how it's spelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NameKind {
+    Type,
+    Variant,
+    Field,
+    Module,
+    Const,
+}
+
+impl NameKind {
+    // `name` in this kind's case. Words are split at underscores and before an upper case letter
+    // following a lower case one.
+    fn spell(self, name: &str) -> String {
+        let mut words: Vec<String> = Vec::new();
+        let mut prev: Option<char> = None;
+        for c in name.chars() {
+            if c == '_' {
+                prev = None;
+                continue;
+            }
+            let boundary = match prev {
+                None => true,
+                Some(p) => c.is_ascii_uppercase() && p.is_ascii_lowercase(),
+            };
+            if boundary {
+                words.push(String::new());
+            }
+            words.last_mut().expect("a word").push(c);
+            prev = Some(c);
+        }
+        if words.is_empty() {
+            return name.to_string();
+        }
+
+        let capitalize = |word: &String| {
+            let word = word.to_ascii_lowercase();
+            word[..1].to_ascii_uppercase() + &word[1..]
+        };
+        match self {
+            NameKind::Type | NameKind::Variant => words.iter().map(capitalize).collect(),
+            NameKind::Field | NameKind::Module => words.iter().map(|word| word.to_ascii_lowercase()).collect::<Vec<_>>().join("_"),
+            NameKind::Const => words.iter().map(|word| word.to_ascii_uppercase()).collect::<Vec<_>>().join("_"),
+        }
+    }
+}
+
+/// Move the contents of namespaces out to where the namespace was, dropping qualifications by
+/// them from the names definitions refer to. It's an error for a name to be defined twice.
+///
+/// Returns the top-level namespace each moved definition came from, so it can be selected and
+/// annotated along with it.
+pub fn flatten_namespaces(defns: &mut Vec<Defn>) -> Result<HashMap<String, String>> {
+    // Every way a namespace can be named: from the top level, and from each enclosing namespace
+    fn namespace_paths(defns: &[Defn], outer: &[String], found: &mut HashSet<String>) {
+        for defn in defns {
+            if let Defn::Namespace(ns, defns) = defn {
+                let paths: Vec<_> = outer.iter().map(|outer| format!("{}::{}", outer, ns)).chain([ns.clone()]).collect();
+                found.extend(paths.iter().cloned());
+                namespace_paths(defns, &paths, found);
+            }
+        }
+    }
+
+    fn flatten(ns: &str, defns: Vec<Defn>, out: &mut Vec<(String, Defn)>) {
+        for defn in defns {
+            match defn {
+                Defn::Namespace(_, inner) => flatten(ns, inner, out),
+                defn => out.push((ns.to_string(), defn)),
+            }
+        }
+    }
+
+    let mut namespaces = HashSet::new();
+    namespace_paths(defns, &[], &mut namespaces);
+    let unqualify = |name: &str| match name.rsplit_once("::") {
+        Some((ns, base)) if namespaces.contains(ns) => Some(base.to_string()),
+        _ => None,
+    };
+
+    let mut defined: HashSet<String> = defns
+        .iter()
+        .filter(|defn| !matches!(defn, Defn::Namespace(..)))
+        .flat_map(Defn::scope_names)
+        .collect();
+    let mut owners = HashMap::new();
+    let mut result = Vec::with_capacity(defns.len());
+
+    for defn in defns.drain(..) {
+        let moved = match defn {
+            Defn::Namespace(ns, inner) => {
+                let mut moved = Vec::new();
+                flatten(&ns, inner, &mut moved);
+                moved
+            }
+            defn => {
+                result.push(defn);
+                continue;
+            }
+        };
+        for (ns, defn) in moved {
+            for name in defn.scope_names() {
+                if !defined.insert(name.clone()) {
+                    return Err(Error::NamespaceClash { namespace: ns, name });
+                }
+            }
+            owners.insert(defn.name().to_string(), ns);
+            result.push(defn);
+        }
+    }
+
+    for defn in &mut result {
+        if let Defn::Typespec(_, ty) | Defn::Typesyn(_, ty) = defn {
+            ty.rename(&unqualify);
+        }
+    }
+
+    *defns = result;
+    Ok(owners)
+}
+
+/// Documentation for type definitions, from the comments leading up to them with no blank line
+/// in between. A block comment's lines lose any leading `*`.
+pub fn leading_docs(spec: &SpecSource) -> Result<HashMap<String, Comment>> {
+    let mut docs = HashMap::new();
+
+    for (defn, text) in &spec.defns {
+        if !matches!(defn, Defn::Typespec(..) | Defn::Typesyn(..)) {
+            continue;
+        }
+
+        let mut leading = Vec::new();
+        let mut pos = 0;
+        for comment in xdr_nom::comments(text) {
+            let gap = &text[pos..comment.location.offset];
+            if !gap.trim().is_empty() {
+                break;
+            }
+            if gap.matches('\n').count() > 1 {
+                leading.clear();
+            }
+            let delimiters = match comment.kind {
+                CommentKind::Block => 4,
+                CommentKind::Line => 2,
+            };
+            pos = (comment.location.offset + comment.text.len() + delimiters).min(text.len());
+            // A section marker isn't documentation, and ends any before it
+            match module_marker(&comment.text) {
+                Some(_) => leading.clear(),
+                None => leading.push(comment.text),
+            }
+        }
+        let rest = &text[pos..];
+        if rest[..rest.len() - rest.trim_start().len()].matches('\n').count() > 1 {
+            leading.clear();
+        }
+
+        let lines: Vec<_> = leading
+            .iter()
+            .flat_map(|text| text.lines())
+            .map(|line| line.trim().trim_start_matches('*').trim())
+            .collect();
+        let start = lines.iter().position(|line| !line.is_empty());
+        let end = lines.iter().rposition(|line| !line.is_empty());
+        if let (Some(start), Some(end)) = (start, end) {
+            let doc: Vec<_> = lines[start..=end]
+                .iter()
+                .map(|line| if line.is_empty() { String::new() } else { format!(" {}", line) })
+                .collect();
+            docs.insert(defn.name().to_string(), doc.join("\n"));
+        }
+    }
+
+    Ok(docs)
+}
+
+// The words of a `@module` comment after `@module`, if it is one
+fn module_marker(comment: &str) -> Option<Vec<&str>> {
+    let mut words = comment.split_whitespace();
+    match words.next() {
+        Some("@module") => Some(words.collect()),
+        _ => None,
+    }
+}
+
+/// Sections of the main input (dialect extension): the module each definition following a
+/// `/* @module name */` comment is generated into, and the modules in the order they first
+/// appear. A `/* @module */` comment puts the definitions following it back at the top level.
+///
+/// Each section becomes a `pub mod` which sees the rest of the generated code through `use
+/// super::*`, and whose contents the top level imports, so definitions can refer to each other
+/// across sections as the spec has them.
+pub fn section_modules(spec: &SpecSource) -> Result<(HashMap<String, String>, Vec<String>)> {
+    let mut modules = HashMap::new();
+    let mut order: Vec<String> = Vec::new();
+    let mut current: Option<String> = None;
+
+    for (idx, (defn, text)) in spec.defns.iter().enumerate() {
+        let preamble = if idx == 0 { spec.preamble } else { "" };
+        let leading = preamble.to_string() + &text[..xdr_nom::token_start(text)];
+        let invalid = |problem: String| Error::InvalidAnnotation { name: defn.name().to_string(), problem };
+
+        for comment in xdr_nom::comments(&leading) {
+            current = match module_marker(&comment.text).as_deref() {
+                None => continue,
+                Some([]) => None,
+                Some([name]) if !is_ident(name) => return Err(invalid(format!("module name {} isn't an identifier", name))),
+                Some([name]) => Some(name.to_string()),
+                Some(_) => return Err(invalid("@module takes at most one name".to_string())),
+            };
+        }
+        if let Some(module) = &current {
+            if !order.contains(module) {
+                order.push(module.clone());
+            }
+            modules.insert(defn.name().to_string(), module.clone());
+        }
+    }
+
+    let names: HashSet<_> = spec.defns.iter().map(|(defn, _)| defn.name()).collect();
+    if let Some(module) = order.iter().find(|module| names.contains(module.as_str())) {
+        return Err(Error::InvalidAnnotation { name: module.clone(), problem: "a section module can't have the name of a definition".to_string() });
+    }
+    Ok((modules, order))
+}
+
+/// Import of a section module's contents into the top level; see `section_modules`.
+pub fn section_import(module: &str) -> TokenStream {
+    let module = quote_ident(module);
+    quote!(#[allow(unused_imports)] use self::#module::*;)
+}
+
+/// A section module with `items`; see `section_modules`.
+#[cfg(feature = "pretty")]
+pub fn section_module<M>(symtab: &Symtab<M>, module: &str, items: impl IntoIterator<Item = TokenStream>) -> TokenStream {
+    let module = quote_ident(module);
+    let items = items.into_iter();
+    let vis = symtab.vis();
+    quote! {
+        #vis mod #module {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #(#items)*
+        }
+    }
+}
+
+/// `item` with `doc`, from `leading_docs`, as its doc comment.
+pub fn with_doc(doc: Option<&Comment>, item: TokenStream) -> TokenStream {
+    let lines = doc.into_iter().flat_map(|doc| doc.split('\n'));
+    quote!(#(#[doc = #lines])* #item)
+}
+
+/// The codecs for definitions annotated with `@pack_only` or `@unpack_only` in their trailing
+/// comment. On a namespace, the annotation applies to everything in it.
+///
+/// A type's impls use those of the types it contains, so they must have the codecs it does.
+pub fn codec_annotations(spec: &SpecSource) -> Result<HashMap<String, Codecs>> {
+    let mut codecs = HashMap::new();
+
+    for (defn, text) in &spec.defns {
+        let annotations = trailing_annotations(text);
+        let annotated: Vec<_> = annotations
+            .iter()
+            .filter_map(|word| match word.as_str() {
+                "@pack_only" => Some(Codecs::PackOnly),
+                "@unpack_only" => Some(Codecs::UnpackOnly),
+                _ => None,
+            })
+            .collect();
+
+        let invalid = |problem: &str| Error::InvalidAnnotation { name: defn.name().to_string(), problem: problem.to_string() };
+        match (&annotated[..], &defn) {
+            ([], _) => (),
+            ([_], Defn::Const(..)) | ([_], Defn::Program(..)) => return Err(invalid("constants have no codecs")),
+            (&[choice], _) => {
+                codecs.insert(defn.name().to_string(), choice);
+            }
+            _ => return Err(invalid("more than one of @pack_only and @unpack_only")),
+        }
+    }
+
+    Ok(codecs)
+}
+
+/// Replace timestamp structs annotated with `@chrono` or `@time` in their trailing comment with
+/// typedefs of `xdr_codec::timestamp::ChronoDateTime` or `TimeDateTime`, which have the same
+/// encoding with xdr-codec's feature of the same name. The struct must be a `hyper` of seconds
+/// followed by an `unsigned int` of nanoseconds.
+pub fn timestamp_annotations(spec: &SpecSource, defns: &mut [Defn]) -> Result<()> {
+    for (defn, text) in &spec.defns {
+        let annotations = trailing_annotations(text);
+        let annotated: Vec<_> = annotations
+            .iter()
+            .filter_map(|word| match word.as_str() {
+                "@chrono" => Some("xdr_codec::timestamp::ChronoDateTime"),
+                "@time" => Some("xdr_codec::timestamp::TimeDateTime"),
+                _ => None,
+            })
+            .collect();
+
+        let name = defn.name();
+        let invalid = |problem: &str| Error::InvalidAnnotation { name: name.to_string(), problem: problem.to_string() };
+        let rust_type = match annotated[..] {
+            [] => continue,
+            [rust_type] => rust_type,
+            _ => return Err(invalid("more than one of @chrono and @time")),
+        };
+
+        // Checked as generated, with type aliases resolved
+        let timestamp = |defn: &Defn| match defn {
+            Defn::Typespec(_, Type::Struct(decls)) => {
+                matches!(decls[..], [Decl::Named(_, Type::Hyper, ..), Decl::Named(_, Type::UInt, ..)])
+            }
+            _ => false,
+        };
+        match defns.iter_mut().find(|defn| defn.name() == name) {
+            Some(defn) if timestamp(defn) => {
+                let derives = Derives::COPY | Derives::CLONE | Derives::DEBUG | Derives::EQ | Derives::PARTIALEQ;
+                *defn = Defn::Typesyn(name.to_string(), Type::ident_with_derives(rust_type, derives));
+            }
+            _ => return Err(invalid("timestamps must be a struct of hyper seconds and unsigned int nanoseconds")),
+        }
+    }
+
+    Ok(())
+}
+
+pub trait Emit {
+    fn define<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream>;
+}
+
+pub trait Emitpack: Emit {
+    fn pack<M>(&self, symtab: &Symtab<M>) -> Result<Option<TokenStream>>;
+    fn unpack<M>(&self, symtab: &Symtab<M>) -> Result<Option<TokenStream>>;
+}
+
+impl Emit for Const {
+    fn define<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream> {
+        // As the constant it's defined as, not an enum member
+        let item = const_item(symtab, (&self.0, &(self.1, None)));
+        Ok(item.map_or_else(TokenStream::new, |(_, item)| item))
+    }
+}
+
+/// The top level item for a constant, with the name of the definition it belongs to.
+///
+/// Constants defined with `const` are emitted as they are. Enum members are reached through
+/// their enum, so they only get one with the `enum_member_consts` feature, as `EnumName_MEMBER`
+/// to match C code.
+pub fn const_item<M>(symtab: &Symtab<M>, (name, (val, scope)): (&String, &(i64, Option<String>))) -> Option<(String, TokenStream)> {
+    let vis = symtab.vis();
+    match scope {
+        None => {
+            let ident = symtab.ident(name, NameKind::Const);
+            let alias = symtab.alias_attrs(name, NameKind::Const, false);
+            let attrs = symtab.item_attrs(name, RustItem::Const);
+            Some((name.clone(), quote!(#alias #attrs #vis const #ident: i64 = #val;)))
+        }
+        #[cfg(feature = "enum_member_consts")]
+        Some(scope) => {
+            let ident = quote_ident(format!("{}_{}", unraw(&symtab.ident(base_name(scope), NameKind::Const)), unraw(&symtab.ident(base_name(name), NameKind::Const))));
+            let attrs = symtab.item_attrs(name, RustItem::Const);
+            Some((scope.clone(), quote!(#[allow(non_upper_case_globals)] #attrs #vis const #ident: i64 = #val;)))
+        }
+        #[cfg(not(feature = "enum_member_consts"))]
+        Some(_) => None,
+    }
+}
+
+/// Parse and generate code for arbitrary bytes, for fuzzing.
+///
+/// Invalid input is an error like any other; it must never panic, overflow the stack or
+/// allocate without bound.
+pub fn parse_fuzz(data: &[u8]) -> Result<()> {
+    let source = std::str::from_utf8(data).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    specification_with_comments(source)?;
+    crate::generate_source("fuzz", source, std::io::sink(), &Default::default(), &Directives::default()).map(|_| ())
+}
+
+/// Whether a symbol table entry is defined in the top level scope, rather than in a namespace
+/// whose module generates it.
+pub fn is_local<V>((name, _): &(&String, V)) -> bool {
+    !name.contains("::")
+}
+
+/// Rust module for a namespace's definitions (dialect extension).
+///
+/// `parent` is the enclosing scope, which the module's code reaches through `use super::*`.
+pub fn namespace_module<M: Clone>(name: &str, defns: &[Defn], parent: &Symtab<M>, meta: &M, codecs: Codecs) -> Result<TokenStream> {
+    let mut xdr = parent.in_module();
+    xdr.update_consts(defns, meta);
+
+    // As the symbol table has them, with recursive references boxed
+    let resolved = |name: &String, ty: &Type| xdr.typespec(name).unwrap_or(ty).clone();
+
+    let mut errors = Vec::new();
+    let mut items = Vec::new();
+    for defn in defns {
+        let res = match defn {
+            Defn::Const(name, val) => xdr
+                .eval(val)
+                .ok_or_else(|| Error::UnknownConstValue { name: name.clone(), value: val.clone() })
+                .and_then(|val| Const(name.clone(), val).define(&xdr))
+                .map(|c| vec![c]),
+            Defn::Typesyn(name, ty) => Typesyn(name.clone(), resolved(name, ty)).define(&xdr).map(|c| vec![c]),
+            Defn::Typespec(name, ty) => {
+                let spec = Typespec(name.clone(), resolved(name, ty));
+                let pack = if codecs.pack() { spec.pack(&xdr) } else { Ok(None) };
+                let unpack = if codecs.unpack() { spec.unpack(&xdr) } else { Ok(None) };
+                vec![spec.define(&xdr).map(Some), pack, unpack]
+                    .into_iter()
+                    .filter_map(Result::transpose)
+                    .collect()
+            }
+            Defn::Namespace(name, defns) => namespace_module(name, defns, &xdr, meta, codecs).map(|m| vec![m]),
+            Defn::Program(..) => defn
+                .program_consts()
+                .into_iter()
+                .map(|(name, val)| Const(name.to_string(), val).define(&xdr))
+                .collect(),
+        };
+        match res {
+            Ok(toks) => items.extend(toks),
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if let Some(err) = Error::from_many(errors) {
+        return Err(err);
+    }
+
+    let name = parent.ident(name, NameKind::Module);
+    let vis = parent.vis();
+    Ok(quote! {
+        #vis mod #name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #(#items)*
+        }
+    })
+}
+
+/// `pub mod consts` holding every constant from the spec with its raw value. Enum members are
+/// in a submodule named after their enum.
+#[cfg(feature="consts_module")]
+pub fn consts_module<'a, M: Clone>(symtab: &Symtab<M>, consts: impl IntoIterator<Item = (&'a String, &'a (i64, Option<String>))>) -> TokenStream {
+    let (global_vis, scoped_vis) = {
+        let inner = symtab.in_module();
+        (inner.vis(), inner.in_module().vis())
+    };
+    let mut global = Vec::new();
+    let mut scoped: BTreeMap<&str, Vec<TokenStream>> = BTreeMap::new();
+
+    for (name, (val, scope)) in consts {
+        let item = {
+            let name = symtab.ident(name, NameKind::Const);
+            let vis = if scope.is_some() { &scoped_vis } else { &global_vis };
+            quote!(#vis const #name: i64 = #val;)
+        };
+        match scope {
+            None => global.push(item),
+            Some(scope) => scoped.entry(scope.as_str()).or_default().push(item),
+        }
+    }
+
+    let scoped = scoped.into_iter().map(|(scope, items)| {
+        let scope = symtab.ident(scope, NameKind::Type);
+        quote! {
+            #[allow(non_snake_case)]
+            #global_vis mod #scope {
+                #(#items)*
+            }
+        }
+    });
+
+    let vis = symtab.vis();
+    quote! {
+        #[allow(non_upper_case_globals)]
+        #vis mod consts {
+            #(#global)*
+            #(#scoped)*
+        }
+    }
+}
+
+impl Emit for Typesyn {
+    fn define<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream> {
+        let ty = &self.1;
+        let name = symtab.ident(&self.0, NameKind::Type);
+        let tok = ty.as_token(symtab)?;
+        let alias = symtab.alias_attrs(&self.0, NameKind::Type, false);
+        let attrs = symtab.item_attrs(&self.0, RustItem::TypeAlias);
+        let vis = symtab.vis();
+        Ok(quote!(#alias #attrs #vis type #name = #tok;))
+    }
+}
+
+impl Typespec {
+    /// Check union case values against the range of the selector type.
+    ///
+    /// Discriminants are sent as 32-bit values, so a case value outside the selector's range is
+    /// truncated and may end up indistinguishable from another case on the wire. These aren't
+    /// errors, since the spec may be relying on the truncation, but they are worth a warning.
+    pub fn selector_warnings<M>(&self, symtab: &Symtab<M>) -> Vec<String> {
+        use self::Type::*;
+
+        let (selector, cases) = match &self.1 {
+            Union(selector, cases, _) => (selector.as_ref(), cases),
+            _ => return Vec::new(),
+        };
+        let (selname, unsigned) = match selector {
+            Decl::Named(name, ty, ..) => (name, matches!(symtab.resolve_syns(ty), UInt | UHyper)),
+            Decl::Void => return Vec::new(),
+        };
+        let (min, max) = if unsigned {
+            (0, u32::MAX as i64)
+        } else {
+            (i32::MIN as i64, i32::MAX as i64)
+        };
+
+        let mut warnings = Vec::new();
+        let mut wire: BTreeMap<i32, &Value> = BTreeMap::new();
+
+        for UnionCase(val, ..) in cases {
+            let v = match symtab.eval(val) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            if v < 0 && unsigned {
+                warnings.push(format!(
+                    "union {}: case {:?} is negative but selector {} is unsigned",
+                    self.0, val, selname
+                ));
+            } else if v < min || v > max {
+                warnings.push(format!(
+                    "union {}: case {:?} ({}) is out of range for selector {}, and is sent as {}",
+                    self.0, val, v, selname, v as i32
+                ));
+            }
+
+            match wire.get(&(v as i32)) {
+                Some(&prev) if symtab.eval(prev) != Some(v) => warnings.push(format!(
+                    "union {}: cases {:?} and {:?} are both sent as {}",
+                    self.0, prev, val, v as i32
+                )),
+                Some(_) => (),
+                None => {
+                    wire.insert(v as i32, val);
+                }
+            }
+        }
+
+        warnings
+    }
+}
+
+impl Emit for Typespec {
+    fn define<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream> {
+        use self::Type::*;
+
+        let name = symtab.ident(&self.0, NameKind::Type);
+        let vis = symtab.vis();
+        let ty = &self.1;
+
+        // Whether the type derives serde's traits, so it and its fields and variants can have
+        // their attributes
+        let serde = cfg!(feature = "derive_serde") && !(ty.derivable(symtab, None) & symtab.derives).is_empty();
+        let alias = symtab.alias_attrs(&self.0, NameKind::Type, serde);
+        // Aliases can't have associated constants
+        let sizes = match ty {
+            Enum(..) | Struct(..) | Union(..) | Flex(..) | Array(..) => self.size_consts(symtab),
+            _ => quote!(),
+        };
+
+        let ret = match ty {
+            &Enum(ref edefs, width) => {
+                let members: Vec<_> = edefs
+                    .iter()
+                    .filter_map(|&EnumDefn(ref field, _, ref comment)| if let Some((val, Some(_))) =
+                        symtab.getconst(field)
+                    {
+                        let comment = comment_stream(comment);
+                        // Associated constants can't have serde's attributes
+                        let alias = symtab.alias_attrs(field, NameKind::Variant, serde && !symtab.lossless_enums);
+                        Some((symtab.ident(field, NameKind::Variant), val, quote!(#comment #alias)))
+                    } else {
+                        None
+                    })
+                    .collect();
+                if symtab.lossless_enums {
+                    let lossless = self.lossless_enum(symtab, &members, width, alias)?;
+                    return Ok(quote!(#lossless #sizes));
+                }
+                // A derived `Default` is the zero member, or else the first
+                let default = if symtab.derives_default(&self.0, ty.derivable(symtab, None)) {
+                    members.iter().position(|&(_, val, _)| val == 0).or(Some(0))
+                } else {
+                    None
+                };
+                let defs = members.iter().enumerate().map(|(idx, (field, val, comment))| {
+                    let default = if default == Some(idx) { quote!(#[default]) } else { quote!() };
+                    let val = Literal::i64_unsuffixed(*val);
+                    quote!(#comment #default #field = #val,)
+                });
+
+                // The discriminants are their wire values, unless `reprc` has made the enum
+                // `repr(C)` already
+                let wire = width.as_token();
+                let repr = if cfg!(feature = "reprc") { quote!() } else { quote!(#[repr(#wire)]) };
+                let derive = symtab.derive_attr(&self.0, ty.derivable(symtab, None))?;
+                let attrs = symtab.item_attrs(&self.0, RustItem::Enum);
+                let conversions = self.conversions(symtab);
+                quote! {
+                    #derive #repr #alias #attrs #vis enum #name { #(#defs)* }
+
+                    #conversions
+                }
+            }
+
+            &Struct(ref decls) => {
+                // Fields annotated with `@default(value)` start as that value, so `Default` is
+                // implemented rather than derived
+                let mut derivable = ty.derivable(symtab, None);
+                let defaults = decls
+                    .iter()
+                    .map(|decl| decl.default_value(&self.0, symtab))
+                    .collect::<Result<Vec<_>>>()?;
+                let default = if defaults.iter().any(|default| default.is_some()) {
+                    if !(derivable & symtab.derives).contains(Derives::DEFAULT) {
+                        let problem = "@default needs the struct to derive Default, with Default in the derives".to_string();
+                        return Err(Error::InvalidAnnotation { name: self.0.clone(), problem });
+                    }
+                    derivable &= !Derives::DEFAULT;
+                    let fields = decls.iter().zip(defaults).filter_map(|(decl, default)| match decl {
+                        Decl::Named(field, ..) => {
+                            let field = symtab.ident(field, NameKind::Field);
+                            let value = default.unwrap_or_else(|| quote!(Default::default()));
+                            Some(quote!(#field: #value,))
+                        }
+                        Decl::Void => None,
+                    });
+                    quote! {
+                        impl Default for #name {
+                            fn default() -> Self {
+                                #name { #(#fields)* }
+                            }
+                        }
+                    }
+                } else {
+                    quote!()
+                };
+
+                let decls: Vec<_> = decls
+                    .iter()
+                    .filter_map(|decl| decl.as_token(symtab, serde).transpose())
+                    .map(|res| res.map(|(field, ty, comment)| quote!(#comment #vis #field: #ty,)))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let derive = symtab.derive_attr(&self.0, derivable)?;
+                let attrs = symtab.item_attrs(&self.0, RustItem::Struct);
+                quote! {
+                    #derive
+                    #alias
+                    #attrs
+                    #vis struct #name { #(#decls)* }
+
+                    #default
+                }
+            }
+
+            &Union(ref selector, ref cases, ref defl) => {
+                let selector = selector.as_ref();
+                use self::Decl::*;
+                use self::Value::*;
+
+                let arms = std::iter::once(selector).chain(cases.iter().map(|case| &case.1)).chain(defl.as_deref());
+                for arm in arms {
+                    if let Named(field, _, _, Some(_)) = arm {
+                        let problem = "only struct fields can have a @default".to_string();
+                        return Err(Error::InvalidAnnotation { name: format!("{}.{}", self.0, field), problem });
+                    }
+                }
+
+                let labelfields = false; // true - include label in enum branch
+
+                // return true if case is compatible with the selector
+                let compatcase = |case: &UnionCase| {
+                    let seltype = match selector {
+                        &Void => return false,
+                        &Named(_, ref ty, ..) => symtab.resolve_syns(ty),
+                    };
+                    if *seltype == Bool {
+                        return case.bool_label(symtab).is_some();
+                    }
+
+                    match &case.0 {
+                        &Const(val) if val < 0 => {
                             match seltype {
                                 &Int | &Hyper => true,
                                 _ => false,
@@ -725,18 +3412,22 @@ impl Emit for Typespec {
                             }
                         }
 
+                        case @ &Binary(..) => {
+                            match symtab.eval(case) {
+                                Some(val) if val < 0 => matches!(seltype, &Int | &Hyper),
+                                Some(_) => matches!(seltype, &Int | &Hyper | &UInt | &UHyper),
+                                None => false,
+                            }
+                        }
+
                         &Ident(ref id) => {
-                            if *seltype == Bool {
-                                id == "TRUE" || id == "FALSE"
-                            } else {
-                                if let &Type::Ident(ref selname, _) = seltype {
-                                    match symtab.getconst(id) {
-                                        Some((_, Some(ref scope))) => scope == selname,
-                                        _ => false,
-                                    }
-                                } else {
-                                    false
+                            if let &Type::Ident(ref selname, _) = seltype {
+                                match symtab.getconst(id) {
+                                    Some((_, Some(ref scope))) => scope == selname,
+                                    _ => false,
                                 }
+                            } else {
+                                false
                             }
                         }
                     }
@@ -744,89 +3435,510 @@ impl Emit for Typespec {
 
                 let mut cases: Vec<_> = cases
                     .iter()
-                    .map(|&UnionCase(ref val, ref decl)| {
-                        if !compatcase(val) {
-                            return Err(Error::IncompatSelector{selector: selector.clone(), value: val.clone()});
+                    .map(|case| {
+                        let UnionCase(val, decl, case_comment) = case;
+                        if !compatcase(case) {
+                            return Err(Error::IncompatSelector{selector: Box::new(selector.clone()), value: val.clone()});
                         }
 
-                        let label = val.as_ident();
+                        let label = case.variant(selector, symtab);
+                        let case_comment = comment_stream(case_comment);
+                        let spec_name = match (selector.is_bool(symtab), val) {
+                            (true, _) => Some(if case.bool_label(symtab) == Some(true) { "TRUE" } else { "FALSE" }),
+                            (false, Value::Ident(id)) => Some(base_name(id)),
+                            (false, _) => None,
+                        };
+                        let case_comment = match spec_name {
+                            Some(spec_name) => {
+                                let alias = symtab.alias_attrs(spec_name, NameKind::Variant, serde);
+                                quote!(#case_comment #alias)
+                            }
+                            None => case_comment,
+                        };
 
                         match decl {
-                            &Void => Ok(quote!(#label,)),
-                            &Named(ref name, ref ty, ref comment) => {
+                            &Void => Ok(quote!(#case_comment #label,)),
+                            &Named(ref name, ref ty, ref comment, _) => {
                                 let mut tok = ty.as_token(symtab)?;
                                 if false && ty.is_boxed(symtab) {
                                     tok = quote!(Box<#tok>)
                                 };
                                 let comment = comment_stream(comment);
                                 if labelfields {
-                                    let name = quote_ident(name);
-                                    Ok(quote!(#comment #label { #name : #tok },))
+                                    let name = symtab.ident(name, NameKind::Field);
+                                    Ok(quote!(#case_comment #comment #label { #name : #tok },))
                                 } else {
-                                    Ok(quote!(#comment #label(#tok),))
+                                    Ok(quote!(#case_comment #comment #label(#tok),))
                                 }
                             }
                         }
                     })
                     .collect::<Result<Vec<_>>>()?;
 
+                // The default arm keeps its discriminant, so it can be packed again
                 if let &Some(ref def_val) = defl {
                     let def_val = def_val.as_ref();
+                    let wire = selector.selector_wire(symtab);
                     match def_val {
-                        &Named(ref name, ref ty, ref comment) => {
+                        &Named(ref name, ref ty, ref comment, _) => {
                             let mut tok = ty.as_token(symtab)?;
                             if ty.is_boxed(symtab) {
-                                tok = quote!(Box<#tok>)
+                                let boxed = symtab.alloc_type("boxed", "Box");
+                                tok = quote!(#boxed<#tok>)
                             };
                             let comment = comment_stream(comment);
                             if labelfields {
-                                let name = quote_ident(name);
-                                cases.push(quote!(#comment Default { #name: #tok },
+                                let name = symtab.ident(name, NameKind::Field);
+                                cases.push(quote!(#comment Default { disc: #wire, #name: #tok },
                                 ))
                             } else {
-                                cases.push(quote!(#comment Default(#tok),))
+                                cases.push(quote!(#comment Default(#wire, #tok),))
                             }
                         }
-                        &Void => cases.push(quote!(Default,)),
+                        &Void => cases.push(quote!(Default(#wire),)),
                     }
+                } else if symtab.unknown_union_arms {
+                    let wire = selector.selector_wire(symtab);
+                    let vec = symtab.alloc_type("vec", "Vec");
+                    cases.push(quote!(Unknown(#wire, #vec<u8>),))
+                }
+
+                for warning in self.selector_warnings(symtab) {
+                    warn!("{}", warning);
                 }
 
-                let derive = ty.derivable(symtab, None);
+                let helpers = self.union_helpers(symtab)?;
+
+                let derive = symtab.derive_attr(&self.0, ty.derivable(symtab, None))?;
+                let attrs = symtab.item_attrs(&self.0, RustItem::Enum);
                 quote! {
                     #derive
-                    pub enum #name { #(#cases)* }
+                    #alias
+                    #attrs
+                    #vis enum #name { #(#cases)* }
+
+                    #helpers
                 }
             }
 
             &Flex(..) | &Array(..) => {
                 let tok = ty.as_token(symtab)?;
-                let derive = ty.derivable(symtab, None);
+                let derive = symtab.derive_attr(&self.0, ty.derivable(symtab, None))?;
+                let attrs = symtab.item_attrs(&self.0, RustItem::Struct);
                 quote! {
                     #derive
-                    pub struct #name(pub #tok);
+                    #alias
+                    #attrs
+                    #vis struct #name(#vis #tok);
+                }
+            }
+
+            _ => {
+                let tok = ty.as_token(symtab)?;
+                let attrs = symtab.item_attrs(&self.0, RustItem::TypeAlias);
+                quote!(#alias #attrs #vis type #name = #tok;)
+            }
+        };
+        Ok(quote!(#ret #sizes))
+    }
+}
+
+impl Typespec {
+    /// An enum's conversions from and to its wire value: `TryFrom`, which its `Unpack` impl uses,
+    /// unless the symbol table is for types only, and `From`. These come with the enum's
+    /// definition, so are only wanted separately for an enum whose definition isn't generated.
+    /// Other types, and lossless enums, have none.
+    pub fn conversions<M>(&self, symtab: &Symtab<M>) -> Option<TokenStream> {
+        let (edefs, width) = match &self.1 {
+            Type::Enum(edefs, width) if !symtab.lossless_enums => (edefs, width),
+            _ => return None,
+        };
+        let name = symtab.ident(&self.0, NameKind::Type);
+        let arms = edefs.iter().filter_map(|EnumDefn(field, ..)| match symtab.getconst(field) {
+            Some((val, Some(_))) => {
+                let field = symtab.ident(field, NameKind::Variant);
+                let val = Literal::i64_unsuffixed(val);
+                Some(quote!(#val => Ok(#name::#field),))
+            }
+            _ => None,
+        });
+        let wire = width.as_token();
+        let core = symtab.core_path();
+        // Its error is xdr-codec's
+        let try_from = if symtab.types_only {
+            quote!()
+        } else {
+            quote! {
+                impl #core::convert::TryFrom<#wire> for #name {
+                    type Error = xdr_codec::Error;
+
+                    fn try_from(__xdr_v: #wire) -> #core::result::Result<Self, xdr_codec::Error> {
+                        match __xdr_v {
+                            #(#arms)*
+                            __xdr_v => Err(xdr_codec::Error::invalid_named_enum(stringify!(#name), __xdr_v as i32)),
+                        }
+                    }
+                }
+            }
+        };
+
+        Some(quote! {
+            #try_from
+
+            impl From<#name> for #wire {
+                #[inline]
+                fn from(__xdr_v: #name) -> #wire {
+                    __xdr_v as #wire
+                }
+            }
+        })
+    }
+
+    // `is_<case>` tests for a union's variants, and `new_<case>` constructors and `as_<case>` and
+    // `into_<case>` accessors for its data-carrying ones, named with the symbol table's helper
+    // prefix. The default arm's constructor also takes its discriminant.
+    fn union_helpers<M>(&self, symtab: &Symtab<M>) -> Result<TokenStream> {
+        let (sel, cases, defl) = match &self.1 {
+            Type::Union(sel, cases, defl) => (sel, cases, defl),
+            _ => return Ok(quote!()),
+        };
+        let name = symtab.ident(&self.0, NameKind::Type);
+        let vis = symtab.vis();
+        let core = symtab.core_path();
+        let unknown = match defl {
+            None if symtab.unknown_union_arms => Some(Decl::unknown_arm()),
+            _ => None,
+        };
+
+        let variants = cases
+            .iter()
+            .map(|case| (case.variant(sel, symtab), &case.1, false))
+            .chain(defl.iter().map(|decl| (quote_ident("Default"), decl.as_ref(), true)))
+            .chain(unknown.iter().map(|decl| (quote_ident("Unknown"), decl, true)));
+
+        let mut seen = HashSet::new();
+        let mut helpers = Vec::new();
+
+        let wire = sel.selector_wire(symtab);
+        for (label, decl, default) in variants {
+            let method = unraw(&label).to_lowercase();
+            if !seen.insert(method.clone()) {
+                warn!("union {}: no helpers for {}, as they would clash with another case", self.0, label);
+                continue;
+            }
+            let is = quote_ident(format!("{}is_{}", symtab.helper_prefix, method));
+
+            let ty = match decl {
+                Decl::Named(_, ty, ..) => ty,
+                Decl::Void => {
+                    let pat = if default { quote!(#name::#label(..)) } else { quote!(#name::#label) };
+                    helpers.push(quote! {
+                        #vis fn #is(&self) -> bool {
+                            matches!(self, #pat)
+                        }
+                    });
+                    continue;
+                }
+            };
+
+            let mut tok = ty.as_token(symtab)?;
+            if default && ty.is_boxed(symtab) {
+                let boxed = symtab.alloc_type("boxed", "Box");
+                tok = quote!(#boxed<#tok>)
+            }
+            let (params, ctor, pat) = if default {
+                (
+                    quote!(__xdr_disc: #wire, __xdr_val: #tok),
+                    quote!(#name::#label(__xdr_disc, __xdr_val)),
+                    quote!(#name::#label(_, __xdr_val)),
+                )
+            } else {
+                (quote!(__xdr_val: #tok), quote!(#name::#label(__xdr_val)), quote!(#name::#label(__xdr_val)))
+            };
+            let new = quote_ident(format!("{}new_{}", symtab.helper_prefix, method));
+            let as_ = quote_ident(format!("{}as_{}", symtab.helper_prefix, method));
+            let into = quote_ident(format!("{}into_{}", symtab.helper_prefix, method));
+
+            helpers.push(quote! {
+                #vis fn #is(&self) -> bool {
+                    matches!(self, #name::#label(..))
+                }
+
+                #vis fn #new(#params) -> Self {
+                    #ctor
+                }
+
+                #vis fn #as_(&self) -> Option<&#tok> {
+                    match self {
+                        #pat => Some(__xdr_val),
+                        #[allow(unreachable_patterns)]
+                        _ => None,
+                    }
+                }
+
+                #vis fn #into(self) -> #core::result::Result<#tok, Self> {
+                    match self {
+                        #pat => Ok(__xdr_val),
+                        #[allow(unreachable_patterns)]
+                        __xdr_other => Err(__xdr_other),
+                    }
+                }
+            });
+        }
+
+        if helpers.is_empty() {
+            return Ok(quote!());
+        }
+
+        Ok(quote! {
+            impl #name {
+                #(#helpers)*
+            }
+        })
+    }
+
+    // An enum as a newtype of its wire value, with its `members` as associated constants; see
+    // `Symtab::set_lossless_enums`.
+    fn lossless_enum<M>(
+        &self,
+        symtab: &Symtab<M>,
+        members: &[(Ident, i64, TokenStream)],
+        width: EnumWidth,
+        alias: TokenStream,
+    ) -> Result<TokenStream> {
+        let name = symtab.ident(&self.0, NameKind::Type);
+        let wire = width.as_token();
+        let derivable = self.1.derivable(symtab, None);
+        let vis = symtab.vis();
+
+        let consts = members.iter().map(|(field, val, comment)| {
+            let val = Literal::i64_unsuffixed(*val);
+            quote!(#comment #vis const #field: #name = #name(#val);)
+        });
+        let vals: Vec<_> = members.iter().map(|&(_, val, _)| Literal::i64_unsuffixed(val)).collect();
+        let known = if vals.is_empty() { quote!(false) } else { quote!(matches!(self.0, #(#vals)|*)) };
+        let is_known = quote_ident(format!("{}is_known", symtab.helper_prefix));
+
+        // A derived `Default` would be 0, which needn't be a member, so it's the zero member or
+        // else the first, as for Rust enums
+        let default = match members.iter().find(|&&(_, val, _)| val == 0).or(members.first()) {
+            Some((field, ..)) if (derivable & symtab.derives).contains(Derives::DEFAULT) => quote! {
+                impl Default for #name {
+                    fn default() -> Self {
+                        #name::#field
+                    }
+                }
+            },
+            _ => quote!(),
+        };
+
+        let derive = symtab.derive_attr(&self.0, derivable & !Derives::DEFAULT)?;
+        let attrs = symtab.item_attrs(&self.0, RustItem::Struct);
+        Ok(quote! {
+            #derive #alias #attrs #vis struct #name(#vis #wire);
+
+            #[allow(non_upper_case_globals)]
+            impl #name {
+                #(#consts)*
+
+                #vis fn #is_known(&self) -> bool {
+                    #known
+                }
+            }
+
+            impl From<#wire> for #name {
+                #[inline]
+                fn from(__xdr_v: #wire) -> #name {
+                    #name(__xdr_v)
+                }
+            }
+
+            impl From<#name> for #wire {
+                #[inline]
+                fn from(__xdr_v: #name) -> #wire {
+                    __xdr_v.0
+                }
+            }
+
+            #default
+        })
+    }
+
+    // The length of the type's standard encoding, if it always has the same length, and its
+    // greatest length, if it has one. Lengths which wouldn't fit a 32-bit `usize` are left out.
+    fn size_bounds<M>(&self, symtab: &Symtab<M>) -> Option<(Option<u64>, u64)> {
+        let (min, max) = self.1.packed_sizes(symtab, &mut vec![self.0.clone()]);
+        match max {
+            Some(max) if max <= u64::from(u32::MAX) => Some((if min == max { Some(max) } else { None }, max)),
+            _ => None,
+        }
+    }
+
+    // `PACKED_SIZE` and `MAX_PACKED_SIZE` constants for the type's `size_bounds`.
+    fn size_consts<M>(&self, symtab: &Symtab<M>) -> TokenStream {
+        let name = symtab.ident(&self.0, NameKind::Type);
+        let vis = symtab.vis();
+        let (fixed, max) = match self.size_bounds(symtab) {
+            Some(bounds) => bounds,
+            None => return quote!(),
+        };
+        let fixed = match fixed {
+            Some(sz) => {
+                let sz = Literal::u64_unsuffixed(sz);
+                quote!(#vis const PACKED_SIZE: usize = #sz;)
+            }
+            None => quote!(),
+        };
+        let max = Literal::u64_unsuffixed(max);
+        quote! {
+            impl #name {
+                #fixed
+                #vis const MAX_PACKED_SIZE: usize = #max;
+            }
+        }
+    }
+
+    // `PackedSize` impl to go alongside the type's `Pack` impl. Types with a `PACKED_SIZE` give it
+    // rather than measuring, unless a dialect makes their encoding longer.
+    fn packed_size<M>(&self, symtab: &Symtab<M>) -> TokenStream {
+        use self::Type::*;
+
+        let name = symtab.ident(&self.0, NameKind::Type);
+        // As in `define`, only these types have associated constants
+        let fixed = match self.1 {
+            Enum(..) | Struct(..) | Union(..) | Flex(..) | Array(..) => {
+                matches!(self.size_bounds(symtab), Some((Some(_), _)))
+            }
+            _ => false,
+        };
+
+        if fixed {
+            quote! {
+                impl xdr_codec::PackedSize for #name {
+                    #[inline]
+                    fn packed_size(&self) -> xdr_codec::Result<usize> {
+                        if xdr_codec::standard_sizes() {
+                            Ok(Self::PACKED_SIZE)
+                        } else {
+                            xdr_codec::measure(self)
+                        }
+                    }
+                }
+            }
+        } else {
+            quote!(impl xdr_codec::PackedSize for #name {})
+        }
+    }
+
+    // `Validate` impl to go alongside the type's `Pack` impl.
+    #[cfg(feature="try_pack")]
+    fn validate<M>(&self, symtab: &Symtab<M>) -> TokenStream {
+        use self::Type::*;
+        use self::Decl::*;
+
+        let name = symtab.ident(&self.0, NameKind::Type);
+        let ty = &self.1;
+
+        let body = match ty {
+            Enum(..) => quote!(),
+
+            Struct(decls) => {
+                let checks = decls.iter().filter_map(|decl| match decl {
+                    Void => None,
+                    Named(field, ty, ..) => {
+                        let fieldid = symtab.ident(field, NameKind::Field);
+                        Some(ty.validator(quote!(self.#fieldid), &format!("{}.{}", self.0, field), symtab))
+                    }
+                });
+                quote!(#(#checks)*)
+            }
+
+            Union(sel, cases, defl) => {
+                let mut matches: Vec<_> = cases
+                    .iter()
+                    .map(|case| {
+                        let label = case.variant(sel, symtab);
+                        match &case.1 {
+                            Void => quote!(#name::#label => (),),
+                            Named(field, ty, ..) => {
+                                let check = ty.validator(quote!((*__xdr_val)), &format!("{}.{}", self.0, field), symtab);
+                                quote!(#name::#label(__xdr_val) => { #check })
+                            }
+                        }
+                    })
+                    .collect();
+
+                let known = known_disc_check(&name, sel, cases, symtab);
+                if let Some(decl) = defl {
+                    matches.push(match decl.as_ref() {
+                        Void => quote!(&#name::Default(__xdr_disc) => { #known }),
+                        Named(field, ty, ..) => {
+                            let check = ty.validator(quote!((*__xdr_val)), &format!("{}.{}", self.0, field), symtab);
+                            quote!(&#name::Default(__xdr_disc, ref __xdr_val) => { #known #check })
+                        }
+                    });
+                } else if symtab.unknown_union_arms {
+                    matches.push(quote!(&#name::Unknown(__xdr_disc, _) => { #known }));
+                }
+
+                quote!(match self { #(#matches)* })
+            }
+
+            Flex(..) | Array(..) => ty.validator(quote!(self.0), &self.0, symtab),
+
+            _ => ty.validator(quote!((*self)), &self.0, symtab),
+        };
+
+        quote! {
+            impl xdr_codec::Validate for #name {
+                fn validate(&self) -> xdr_codec::Result<()> {
+                    #body
+                    Ok(())
                 }
             }
+        }
+    }
+}
 
-            _ => {
-                let tok = ty.as_token(symtab)?;
-                quote!(pub type #name = #tok;)
-            }
-        };
-        Ok(ret)
+// Fail a union's `Default` or `Unknown` variant with a discriminant, `__xdr_disc`, which is one
+// of its cases', as it would unpack as that case rather than as it was packed
+fn known_disc_check<M>(name: &Ident, sel: &Decl, cases: &[UnionCase], symtab: &Symtab<M>) -> TokenStream {
+    let wire = sel.selector_wire(symtab);
+    let discs: Vec<_> = cases.iter().filter_map(|case| case.disc(sel, &wire, symtab).ok()).collect();
+    if discs.is_empty() {
+        return quote!();
+    }
+    quote! {
+        if [#(#discs),*].contains(&__xdr_disc) {
+            return Err(xdr_codec::Error::invalid_named_case(stringify!(#name), __xdr_disc as i32));
+        }
     }
 }
 
+// A `tracing` span for the rest of a Pack or Unpack impl, compiled in by the `tracing` feature
+// of the crate the generated code is in.
+#[cfg(feature="tracing_spans")]
+fn trace_span(op: &str, name: &str) -> TokenStream {
+    let span = format!("{} {}", op, name);
+    quote!(#[cfg(feature = "tracing")] let __xdr_span = tracing::trace_span!(#span).entered();)
+}
+
+#[cfg(not(feature="tracing_spans"))]
+fn trace_span(_: &str, _: &str) -> TokenStream {
+    quote!()
+}
+
 impl Emitpack for Typespec {
     fn pack<M>(&self, symtab: &Symtab<M>) -> Result<Option<TokenStream>> {
         use self::Type::*;
         use self::Decl::*;
 
-        let name = quote_ident(&self.0);
+        let name = symtab.ident(&self.0, NameKind::Type);
         let ty = &self.1;
         let mut directive = quote!();
 
         let body: TokenStream = match ty {
-            &Enum(_) => {
+            &Enum(..) => {
                 directive = quote!(#[inline]);
                 ty.packer(quote!(self), symtab)?
             }
@@ -835,54 +3947,56 @@ impl Emitpack for Typespec {
                 let decls: Vec<_> = decl.iter()
                     .filter_map(|d| match d {
                         &Void => None,
-                        &Named(ref name, ref ty, ..) => Some((quote_ident(name), ty)),
+                        &Named(ref name, ref ty, ..) => Some((symtab.ident(name, NameKind::Field), ty)),
                     })
                     .map(|(field, ty)| {
-                        let p = ty.packer(quote!(self.#field), symtab).unwrap();
-                        quote!(#p + )
+                        let p = ty.packer(quote!(self.#field), symtab)?;
+                        Ok(quote!(#p + ))
                     })
-                    .collect();
+                    .collect::<Result<_>>()?;
                 quote!(#(#decls)* 0)
             }
 
-            &Union(_, ref cases, ref defl) => {
+            &Union(ref sel, ref cases, ref defl) => {
+                let wire = sel.selector_wire(symtab);
                 let mut matches: Vec<_> = cases
                     .iter()
-                    .filter_map(|&UnionCase(ref val, ref decl)| {
-                        let label = val.as_ident();
-                        let disc = val.as_token(symtab);
+                    .filter_map(|case| {
+                        let label = case.variant(sel, symtab);
+                        let disc = case.disc(sel, &wire, symtab).ok()?;
 
-                        let ret = match decl {
-                            &Void => quote!(&#name::#label => (#disc as i32).pack(out)?,),
+                        let ret = match &case.1 {
+                            &Void => quote!(&#name::#label => #disc.pack(__xdr_out)?,),
                             &Named(_, ref ty, ..) => {
-                                let pack = match ty.packer(quote!(val), symtab) {
+                                let pack = match ty.packer(quote!(__xdr_val), symtab) {
                                     Err(_) => return None,
                                     Ok(p) => p,
                                 };
-                                quote!(&#name::#label(ref val) => (#disc as i32).pack(out)? + #pack,)
+                                quote!(&#name::#label(ref __xdr_val) => #disc.pack(__xdr_out)? + #pack,)
                             }
                         };
                         Some(ret)
                     })
                     .collect();
 
+                // Their discriminants mustn't be one of the cases'
+                let known = known_disc_check(&name, sel, cases, symtab);
                 if let &Some(ref decl) = defl {
                     let decl = decl.as_ref();
-                    // Can't cast a value-carrying enum to i32
                     let default = match decl {
-                        &Void => {
-                            quote! {
-                                &#name::Default => return Err(xdr_codec::Error::invalid_case(-1)),
-                            }
-                        }
-                        &Named(..) => {
-                            quote! {
-                                &#name::Default(_) => return Err(xdr_codec::Error::invalid_case(-1)),
-                            }
+                        &Void => quote!(&#name::Default(__xdr_disc) => { #known __xdr_disc.pack(__xdr_out)? }),
+                        &Named(_, ref ty, ..) => {
+                            let pack = ty.packer(quote!(__xdr_val), symtab)?;
+                            quote!(&#name::Default(__xdr_disc, ref __xdr_val) => { #known __xdr_disc.pack(__xdr_out)? + #pack })
                         }
                     };
 
                     matches.push(default)
+                } else if symtab.unknown_union_arms {
+                    matches.push(quote!(&#name::Unknown(__xdr_disc, ref __xdr_rest) => {
+                        #known
+                        __xdr_disc.pack(__xdr_out)? + xdr_codec::pack_rest(__xdr_rest, __xdr_out)?
+                    }));
                 }
 
                 quote!(match self { #(#matches)* })
@@ -904,13 +4018,25 @@ impl Emitpack for Typespec {
 
         trace!("body {:?}", body);
 
+        #[cfg(not(feature="try_pack"))]
+        let validate = quote!();
+        #[cfg(feature="try_pack")]
+        let validate = self.validate(symtab);
+        let packed_size = self.packed_size(symtab);
+        let trace = trace_span("pack", &self.0);
+
         Ok(Some(quote! {
             impl<Out: xdr_codec::Write> xdr_codec::Pack<Out> for #name {
                 #directive
-                    fn pack(&self, out: &mut Out) -> xdr_codec::Result<usize> {
+                    fn pack(&self, __xdr_out: &mut Out) -> xdr_codec::Result<usize> {
+                        #trace
                         Ok(#body)
                     }
             }
+
+            #packed_size
+
+            #validate
         }))
     }
 
@@ -918,50 +4044,37 @@ impl Emitpack for Typespec {
         use self::Type::*;
         use self::Decl::*;
 
-        let self_name = quote_ident(&self.0);
+        let self_name = symtab.ident(&self.0, NameKind::Type);
         let ty = &self.1;
         let mut directive = quote!();
 
         let body = match ty {
-            &Enum(ref defs) => {
+            &Enum(_, width) => {
                 directive = quote!(#[inline]);
-                let matchdefs: Vec<_> = defs.iter()
-                    .filter_map(|&EnumDefn(ref name, ..)| {
-                        let tok = quote_ident(name);
-                        if let Some((ref _val, ref scope)) = symtab.getconst(name) {
-                            // let val = *val as i32;
-                            if let &Some(ref _scope) = scope {
-                                // let scope = quote_ident(scope);
-                                // Some(quote!(#val => #scope :: #tok,))
-                                Some(quote!(x if x == #self_name :: #tok as i32 => #self_name :: #tok,))
-                            } else {
-                                // Some(quote!(#val => #tok,))
-                                Some(quote!(x if x == #tok as i32 => #tok,))
-                            }
-                        } else {
-                            println!("unknown ident {}", name);
-                            None
-                        }
-                    })
-                    .collect();
-
+                let wire = width.as_token();
+                let value = if symtab.lossless_enums {
+                    quote!(#self_name(__xdr_e))
+                } else {
+                    let core = symtab.core_path();
+                    quote!(<#self_name as #core::convert::TryFrom<#wire>>::try_from(__xdr_e)?)
+                };
                 quote!({
-                    let (e, esz): (i32, _) = xdr_codec::Unpack::unpack(input)?;
-                    sz += esz;
-                    match e {
-                        #(#matchdefs)*
-                        e => return Err(xdr_codec::Error::invalid_named_enum(stringify!(#self_name), e))
-                    }
+                    let (__xdr_e, __xdr_esz): (#wire, _) = xdr_codec::Unpack::unpack(__xdr_input)?;
+                    __xdr_sz += __xdr_esz;
+                    #value
                 })
             }
 
             &Struct(ref decls) => {
                 let decls: Vec<_> = decls
                     .iter()
-                    .filter_map(|decl| decl.name_as_ident())
-                    .map(|(field, ty)| {
-                        let unpack = ty.unpacker(symtab);
-                        quote!(#field: { let (v, fsz) = #unpack; sz += fsz; v },)
+                    .filter_map(|decl| match decl {
+                        Decl::Named(name, ty, ..) => Some((name, symtab.ident(name, NameKind::Field), ty)),
+                        Decl::Void => None,
+                    })
+                    .map(|(name, field, ty)| {
+                        let unpack = field_unpacker(name, ty.unpacker(symtab));
+                        quote!(#field: { let (__xdr_v, __xdr_fsz) = #unpack; __xdr_sz += __xdr_fsz; __xdr_v },)
                     })
                     .collect();
 
@@ -969,62 +4082,79 @@ impl Emitpack for Typespec {
             }
 
             &Union(ref sel, ref cases, ref defl) => {
+                let wire = sel.selector_wire(symtab);
                 let sel = sel.as_ref();
                 let mut matches: Vec<_> =
                     cases.iter()
-                        .map(|&UnionCase(ref val, ref decl)| {
-                            let label = val.as_ident();
-                            let disc = match val.as_i64(symtab) {
-                                Some(v) => v as i32,
-                                None => return Err(Error::DiscriminantValueUnknown { value: val.clone() }),
+                        .map(|case| {
+                            let UnionCase(val, decl, _) = case;
+                            let label = case.variant(sel, symtab);
+                            // A bool's cases are matched as `true` and `false`
+                            let pat = if sel.is_bool(symtab) {
+                                case.disc(sel, &wire, symtab)?
+                            } else {
+                                let disc = match val.as_i64(symtab) {
+                                    Some(v) => v as i32,
+                                    None => return Err(Error::DiscriminantValueUnknown { value: val.clone() }),
+                                };
+                                quote!(__xdr_x if __xdr_x == (#disc as #wire))
                             };
 
                             let ret = match decl {
-                                //&Void => quote!(#disc => #name::#label,),
-                                &Void => quote!(x if x == (#disc as i32) => #self_name::#label,),
-                                &Named(_, ref ty, ..) => {
-                                    let unpack = ty.unpacker(symtab);
-                                    //quote!(#disc => #name::#label({ let (v, fsz) = #unpack; sz += fsz; v }),)
-                                    quote!(x if x == (#disc as i32) => #self_name::#label({ let (v, fsz) = #unpack; sz += fsz; v }),)
+                                &Void => quote!(#pat => #self_name::#label,),
+                                &Named(ref field, ref ty, ..) => {
+                                    let unpack = field_unpacker(field, ty.unpacker(symtab));
+                                    quote!(#pat => #self_name::#label({ let (__xdr_v, __xdr_fsz) = #unpack; __xdr_sz += __xdr_fsz; __xdr_v }),)
                                 },
                             };
                             Ok(ret)
                         })
                         .collect::<Result<Vec<_>>>()?;
+                // Both of a bool's cases leave nothing for another arm
+                let labels: HashSet<_> = cases.iter().filter_map(|case| case.bool_label(symtab)).collect();
+                let exhaustive = sel.is_bool(symtab) && labels.len() == 2;
 
-                if let &Some(ref decl) = defl {
+                if exhaustive {
+                    // Nothing else to unpack
+                } else if let &Some(ref decl) = defl {
                     let decl = decl.as_ref();
                     let defl = match decl {
-                        &Void => quote!(_ => #self_name::Default),
-                        &Named(_, ref ty, ..) => {
-                            let unpack = ty.unpacker(symtab);
-                            quote!(_ => #self_name::Default({
-                                let (v, csz) = #unpack;
-                                sz += csz;
-                                v
+                        &Void => quote!(__xdr_v => #self_name::Default(__xdr_v)),
+                        &Named(ref field, ref ty, ..) => {
+                            let unpack = field_unpacker(field, ty.unpacker(symtab));
+                            quote!(__xdr_v => #self_name::Default(__xdr_v, {
+                                let (__xdr_v, __xdr_csz) = #unpack;
+                                __xdr_sz += __xdr_csz;
+                                __xdr_v
                             }))
                         }
                     };
 
                     matches.push(defl);
+                } else if symtab.unknown_union_arms {
+                    matches.push(quote!(__xdr_v => #self_name::Unknown(__xdr_v, {
+                        let (__xdr_v, __xdr_rsz) = xdr_codec::unpack_rest(__xdr_input)?;
+                        __xdr_sz += __xdr_rsz;
+                        __xdr_v
+                    })));
                 } else {
-                    let defl = quote!(v => return Err(xdr_codec::Error::invalid_named_case(stringify!(#self_name), v as i32)));
+                    let defl = quote!(__xdr_v => return Err(xdr_codec::Error::invalid_named_case(stringify!(#self_name), __xdr_v as i32)));
                     matches.push(defl);
                 }
 
                 let selunpack = match sel {
-                    &Void => panic!("void switch selector?"),
+                    &Void => unreachable!("parser rejects void switch selectors"),
                     &Named(_, ref ty, ..) => ty.unpacker(symtab),
                 };
 
-                quote!(match { let (v, dsz): (i32, _) = #selunpack; sz += dsz; v } { #(#matches)* })
+                quote!(match { let (__xdr_v, __xdr_dsz): (#wire, _) = #selunpack; __xdr_sz += __xdr_dsz; __xdr_v } { #(#matches)* })
             }
 
             &Option(_) => ty.unpacker(symtab),
 
             &Flex(_, _) | &Array(_, _) => {
                 let unpk = ty.unpacker(symtab);
-                quote!({ let (v, usz) = #unpk; sz = usz; #self_name(v) })
+                quote!({ let (__xdr_v, __xdr_usz) = #unpk; __xdr_sz = __xdr_usz; #self_name(__xdr_v) })
             }
 
             &Ident(_, _) => return Ok(None),
@@ -1033,24 +4163,151 @@ impl Emitpack for Typespec {
             _ => return Err(Error::UnimplementedType { ty: ty.clone() }),
         };
 
+        #[cfg(not(feature="instrument"))]
+        let (span, result) = (quote!(), quote!((#body, __xdr_sz)));
+        #[cfg(feature="instrument")]
+        let (span, result) = (
+            quote!(let __xdr_span = xdr_codec::instrument::DecodeSpan::enter(stringify!(#self_name));),
+            quote!(__xdr_span.done((#body, __xdr_sz))),
+        );
+        let trace = trace_span("unpack", &self.0);
+
         Ok(Some(quote! {
             impl<In: xdr_codec::Read> xdr_codec::Unpack<In> for #self_name {
                 #directive
-                    fn unpack(input: &mut In) -> xdr_codec::Result<(#self_name, usize)> {
+                    fn unpack(__xdr_input: &mut In) -> xdr_codec::Result<(#self_name, usize)> {
+                        #trace
+                        #span
                         #[allow(unused_assignments)]
-                        let mut sz = 0;
-                        Ok((#body, sz))
+                        let mut __xdr_sz = 0;
+                        Ok(#result)
                     }
             }
         }))
     }
 }
 
+/// What a specification defines, with constants evaluated: the table code is generated from.
+///
+/// Besides generating code, it answers questions about the protocol, such as the value of a
+/// constant or the members of an enum, for build scripts; see `Symtab::from_spec` and
+/// `generate_pretty_with_symtab`. `M` is what each definition is annotated with, which is `()`
+/// outside the pretty generator.
 #[derive(Debug, Clone)]
 pub struct Symtab<M> {
     consts: BTreeMap<String, SymDef<(i64, Option<String>), M>>,
     typespecs: BTreeMap<String, SymDef<Type, M>>,
     typesyns: BTreeMap<String, SymDef<Type, M>>,
+    helper_prefix: String,
+    primitives: Primitives,
+    // Which of the derives generated types could have they're given
+    derives: Derives,
+    // Further derives for each generated type, by name
+    extra_derives: Option<fn(&str) -> Vec<String>>,
+    // Further attributes for each generated item, by name and kind
+    item_attrs: Option<fn(&str, RustItem) -> Vec<TokenStream>>,
+    // Rust identifiers for some spec names
+    rename: HashMap<String, String>,
+    // How other spec names are spelled in Rust
+    naming: Naming,
+    // How names which are keywords are escaped
+    keyword_escape: KeywordEscape,
+    // What's left off enum members to name variants
+    variant_prefixes: VariantPrefixes,
+    // Whether enums are open newtypes rather than Rust enums
+    lossless_enums: bool,
+    // Whether unions without a `default` arm get one for discriminants they don't know
+    unknown_union_arms: bool,
+    // How the cases of unions switching on a `bool` are labelled
+    bool_labels: BoolLabels,
+    // Whether generated code uses `core` and `alloc` rather than `std`
+    no_std: bool,
+    // Whether fixed arrays are unpacked without `unsafe`
+    safe_arrays: bool,
+    // Union arms estimated to take more than this many bytes are boxed
+    box_large_arms: Option<u64>,
+    // Whether only types are generated, with nothing needing xdr-codec
+    types_only: bool,
+    // Whether types with fixed arrays longer than 32 elements still derive traits
+    large_array_derives: bool,
+    // Which code can use generated items
+    visibility: Visibility,
+    // How many generated modules deep the code being generated is
+    module_depth: usize,
+    // Constants, including enum members, whose values couldn't be evaluated yet, as (enum if
+    // any, name, value, meta)
+    unresolved: Vec<(std::option::Option<String>, String, Value, M)>,
+    // Names defined more than once by one RPC program, as (program, name)
+    duplicates: Vec<(String, String)>,
+}
+
+impl<M> Default for Symtab<M> {
+    fn default() -> Self {
+        Symtab::new()
+    }
+}
+
+impl Symtab<()> {
+    /// The constants defined by a specification, including enum members, so build scripts
+    /// can size buffers and the like from the protocol.
+    pub fn from_spec(source: &str) -> Result<Self> {
+        let defns = specification(source)?;
+        let mut symtab = Symtab::new();
+        symtab.update_consts(&defns, &());
+        match Error::from_many(symtab.errors()) {
+            Some(err) => Err(err),
+            None => Ok(symtab),
+        }
+    }
+}
+
+/// Which types refer to which, from `Symtab::dependencies`.
+///
+/// A type refers to the types of its fields, union selector and arms, array elements and
+/// optional data, and to the type a typedef names. It also refers to the enums whose members it
+/// uses, as array sizes or case labels. Names the specification doesn't define, like external
+/// types, aren't included. The generator's `only_with_deps` follows the same references.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TypeGraph {
+    refs: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl TypeGraph {
+    // The graph of `defs`, each with the names it refers to, which `node` gives the definition
+    // of, if it's one of `defs`
+    fn build<'a, 'b>(defs: impl Iterator<Item = (&'a str, Vec<&'b str>)>, node: impl Fn(&str) -> Option<&'a str>) -> TypeGraph {
+        let refs = defs
+            .map(|(name, refs)| (name.to_string(), refs.into_iter().filter_map(&node).map(String::from).collect()))
+            .collect();
+        TypeGraph { refs }
+    }
+
+    /// Every type, in name order.
+    pub fn types(&self) -> impl Iterator<Item = &str> {
+        self.refs.keys().map(String::as_str)
+    }
+
+    /// The types `name` refers to directly, in name order.
+    pub fn references(&self, name: &str) -> impl Iterator<Item = &str> {
+        self.refs.get(name).into_iter().flatten().map(String::as_str)
+    }
+
+    /// The types which refer to `name` directly, in name order.
+    pub fn referrers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.refs.iter().filter(move |(_, refs)| refs.contains(name)).map(|(ty, _)| ty.as_str())
+    }
+
+    /// `roots` and every type reachable from them, such as all the types a message needs.
+    pub fn reachable<'a>(&'a self, roots: impl IntoIterator<Item = &'a str>) -> BTreeSet<&'a str> {
+        let mut reached = BTreeSet::new();
+        let mut stack: Vec<_> = roots.into_iter().filter_map(|root| self.refs.get_key_value(root)).map(|(ty, _)| ty.as_str()).collect();
+        while let Some(ty) = stack.pop() {
+            if reached.insert(ty) {
+                stack.extend(self.references(ty));
+            }
+        }
+        reached
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1070,6 +4327,27 @@ impl<M> Symtab<M> {
             consts: BTreeMap::new(),
             typespecs: BTreeMap::new(),
             typesyns: BTreeMap::new(),
+            helper_prefix: String::new(),
+            primitives: Primitives::default(),
+            derives: Derives::all(),
+            extra_derives: None,
+            item_attrs: None,
+            rename: HashMap::new(),
+            naming: Naming::Spec,
+            keyword_escape: KeywordEscape::Underscore,
+            variant_prefixes: VariantPrefixes::Keep,
+            lossless_enums: false,
+            unknown_union_arms: false,
+            bool_labels: BoolLabels::Spellings,
+            no_std: false,
+            safe_arrays: false,
+            box_large_arms: None,
+            types_only: false,
+            large_array_derives: false,
+            visibility: Visibility::Public,
+            module_depth: 0,
+            unresolved: Vec::new(),
+            duplicates: Vec::new(),
         }
     }
 
@@ -1081,38 +4359,204 @@ impl<M> Symtab<M> {
                     self.update_enum_consts(name, ty, meta);
                 }
 
-                &Defn::Const(ref name, val) => self.defconst(name, val, None, meta.clone()),
+                Defn::Const(name, val) => match self.eval(val) {
+                    Some(v) => self.defconst(name, v, None, meta.clone()),
+                    None => self.unresolved.push((None, name.clone(), val.clone(), meta.clone())),
+                },
 
                 &Defn::Typesyn(ref name, ref ty) => {
                     self.deftypesyn(name, ty, meta.clone());
                 }
+
+                &Defn::Namespace(ref ns, ref defns) => self.update_namespace(ns, defns, meta),
+
+                Defn::Program(program, ..) => {
+                    let mut seen = HashSet::new();
+                    for (name, val) in defn.program_consts() {
+                        if !seen.insert(name) {
+                            self.duplicates.push((program.clone(), name.to_string()));
+                        }
+                        self.defconst(name, val, None, meta.clone());
+                    }
+                }
             }
         }
+
+        self.resolve_consts();
+        self.box_recursive();
+        self.box_large_arms();
     }
 
-    fn update_enum_consts(&mut self, scope: &String, ty: &Type, meta: &M) where M: Clone {
-        let mut err = stderr();
-        let mut prev = -1;
+    // Box the references which would make types contain themselves, such as a union with a case
+    // of its own type, or two structs which hold each other. Searching from each type in name
+    // order, the references which close a cycle are boxed. `T *` is already boxed.
+    fn box_recursive(&mut self) {
+        let names: BTreeSet<String> = self.typespecs.keys().chain(self.typesyns.keys()).cloned().collect();
+        let refs: HashMap<&str, Vec<String>> = names
+            .iter()
+            .filter_map(|name| self.typespec(name).map(|ty| (name.as_str(), ty)))
+            .map(|(name, ty)| {
+                let mut refs = Vec::new();
+                ty.inline_refs(self, &mut refs);
+                (name, refs)
+            })
+            .collect();
+
+        // Whether each of a type's references is to be boxed, once the search is done with it
+        fn visit<'a>(name: &'a str, refs: &'a HashMap<&str, Vec<String>>, boxed: &mut HashMap<&'a str, std::option::Option<Vec<bool>>>) {
+            boxed.insert(name, None);
+            let flags = refs[name]
+                .iter()
+                .map(|target| match boxed.get(target.as_str()) {
+                    // Still being searched from, so this closes a cycle
+                    Some(None) => true,
+                    Some(Some(_)) => false,
+                    None => {
+                        if refs.contains_key(target.as_str()) {
+                            visit(target, refs, boxed);
+                        }
+                        false
+                    }
+                })
+                .collect();
+            boxed.insert(name, Some(flags));
+        }
 
-        if let &Type::Enum(ref edefn) = ty {
-            for &EnumDefn(ref name, ref maybeval, ..) in edefn {
-                let v = match maybeval {
-                    &None => prev + 1,
-                    &Some(ref val) => {
-                        match self.value(val) {
-                            Some(c) => c,
-                            None => {
-                                let _ = writeln!(&mut err, "Unknown value {:?}", val);
-                                continue;
-                            }
+        let mut boxed = HashMap::new();
+        for name in &names {
+            if !boxed.contains_key(name.as_str()) && refs.contains_key(name.as_str()) {
+                visit(name, &refs, &mut boxed);
+            }
+        }
+
+        let boxed: Vec<(String, Vec<bool>)> = boxed
+            .into_iter()
+            .filter_map(|(name, flags)| flags.filter(|flags| flags.contains(&true)).map(|flags| (name.to_string(), flags)))
+            .collect();
+        for (name, flags) in boxed {
+            let mut ty = self.typespec(&name).cloned().expect("searched type");
+            ty.box_refs(self, &mut flags.into_iter());
+            match self.typespecs.get_mut(&name) {
+                Some(def) => def.value = ty,
+                None => self.typesyns.get_mut(&name).expect("searched type").value = ty,
+            }
+        }
+    }
+
+    // Box the union arms estimated to take more than `set_box_large_arms`'s threshold, so the
+    // union is only as big as its other arms.
+    fn box_large_arms(&mut self) {
+        let threshold = match self.box_large_arms {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let names: Vec<String> = self.typespecs.keys().chain(self.typesyns.keys()).cloned().collect();
+        let mut boxed = Vec::new();
+        for name in names {
+            let mut ty = match self.typespec(&name) {
+                Some(ty @ Type::Union(..)) => ty.clone(),
+                _ => continue,
+            };
+            let mut changed = false;
+            if let Type::Union(_, cases, defl) = &mut ty {
+                let arms = cases.iter_mut().map(|UnionCase(_, decl, _)| decl).chain(defl.as_deref_mut());
+                for arm in arms {
+                    if let Decl::Named(field, arm_ty, ..) = arm {
+                        if !matches!(arm_ty, Type::Boxed(_)) && arm_ty.mem_size(self, &mut vec![name.clone()]) > threshold {
+                            debug!("union {}: boxing arm {}", name, field);
+                            *arm_ty = Type::Boxed(Box::new(arm_ty.clone()));
+                            changed = true;
                         }
                     }
-                };
+                }
+            }
+            if changed {
+                boxed.push((name, ty));
+            }
+        }
+        for (name, ty) in boxed {
+            match self.typespecs.get_mut(&name) {
+                Some(def) => def.value = ty,
+                None => self.typesyns.get_mut(&name).expect("union type").value = ty,
+            }
+        }
+    }
+
+    // Evaluate the constants and enum members which refer to constants defined after them,
+    // including enums' members, as far as they can be.
+    fn resolve_consts(&mut self) {
+        loop {
+            let pending = std::mem::take(&mut self.unresolved);
+            let before = pending.len();
+            for (scope, name, val, meta) in pending {
+                match self.eval(&val) {
+                    Some(v) => self.defconst(name, v, scope, meta),
+                    None => self.unresolved.push((scope, name, val, meta)),
+                }
+            }
+            if self.unresolved.len() == before {
+                break;
+            }
+        }
+    }
+
+    // Resolve a namespace's definitions in its own scope, then make them visible in this one by
+    // qualified name.
+    fn update_namespace(&mut self, ns: &str, defns: &[Defn], meta: &M) where M: Clone {
+        let mut scope = self.clone();
+        scope.unresolved.clear();
+        scope.duplicates.clear();
+        scope.update_consts(defns, meta);
+        self.duplicates.extend(scope.duplicates.drain(..).map(|(program, name)| (format!("{}::{}", ns, program), name)));
+
+        let own: HashSet<String> = defns.iter().flat_map(Defn::scope_names).collect();
+        let qualify = |name: &str| own.get(name).map(|name| format!("{}::{}", ns, name));
+
+        // Left to be resolved with what's defined after the namespace
+        for (name, member, mut val, meta) in scope.unresolved.drain(..) {
+            val.rename(&qualify);
+            let name = name.map(|name| format!("{}::{}", ns, name));
+            self.unresolved.push((name, format!("{}::{}", ns, member), val, meta));
+        }
+
+        for name in &own {
+            let qualified = format!("{}::{}", ns, name);
+
+            if let Some(SymDef { value: (val, scope), meta }) = scope.consts.get(name) {
+                let scope = scope.as_ref().map(|scope| qualify(scope).unwrap_or_else(|| scope.clone()));
+                self.defconst(&qualified, *val, scope, meta.clone());
+            }
+            if let Some(SymDef { value, meta }) = scope.typespecs.get(name) {
+                let mut ty = value.clone();
+                ty.rename(&qualify);
+                self.deftype(&qualified, &ty, meta.clone());
+            }
+            if let Some(SymDef { value, meta }) = scope.typesyns.get(name) {
+                let mut ty = value.clone();
+                ty.rename(&qualify);
+                self.deftypesyn(&qualified, &ty, meta.clone());
+            }
+        }
+    }
 
-                prev = v;
+    fn update_enum_consts(&mut self, scope: &String, ty: &Type, meta: &M) where M: Clone {
+        let mut prev: std::option::Option<&String> = None;
+
+        if let &Type::Enum(ref edefn, _) = ty {
+            for &EnumDefn(ref name, ref maybeval, ..) in edefn {
+                // A member without a value follows on from the one before, whether or not that
+                // can be evaluated yet
+                let val = match (maybeval, prev) {
+                    (Some(val), _) => val.clone(),
+                    (None, Some(prev)) => Value::binary(BinOp::Add, Value::ident(prev), Value::Const(1)),
+                    (None, None) => Value::Const(0),
+                };
+                match self.eval(&val) {
+                    Some(v) => self.defconst(name, v, Some(scope.clone()), meta.clone()),
+                    None => self.unresolved.push((Some(scope.clone()), name.clone(), val, meta.clone())),
+                }
 
-                // println!("enum {} -> {}", name, v);
-                self.defconst(name, v, Some(scope.clone()), meta.clone());
+                prev = Some(name);
             }
         }
     }
@@ -1129,6 +4573,340 @@ impl<M> Symtab<M> {
         self.typesyns.insert(From::from(name.as_ref()), SymDef{ value: ty.clone(), meta});
     }
 
+    /// Prefix for the names of generated inherent helpers, such as unions' `new_<case>`
+    /// constructors, to keep them clear of methods on the types in hand-written code.
+    pub fn set_helper_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.helper_prefix = prefix.to_string();
+        self
+    }
+
+    /// Only give generated types those of `derives` they could have, such as to leave out `Copy`
+    /// so large structs aren't copied by accident. They're given all they could have by default.
+    pub fn set_derives(&mut self, derives: Derives) -> &mut Self {
+        self.derives = derives;
+        self
+    }
+
+    /// Give some generated types further derives, such as `Hash` on key types or
+    /// `serde::Serialize` only on API types. `extra` is called with each struct, union, enum and
+    /// array wrapper's name, and returns the paths of the traits to derive for it besides those
+    /// from `set_derives` and cargo features; any already derived are skipped.
+    pub fn set_extra_derives(&mut self, extra: fn(&str) -> Vec<String>) -> &mut Self {
+        self.extra_derives = Some(extra);
+        self
+    }
+
+    /// Attach further attributes to some generated items, such as
+    /// `#[serde(deny_unknown_fields)]` or `#[cfg_attr(...)]`, rather than editing the generated
+    /// file afterwards. `attrs` is called with the name of each constant and type and what kind of
+    /// item it's generated as, and returns the attributes to put on it, after any derives.
+    pub fn set_item_attrs(&mut self, attrs: fn(&str, RustItem) -> Vec<TokenStream>) -> &mut Self {
+        self.item_attrs = Some(attrs);
+        self
+    }
+
+    /// Generate some of the spec's names as other Rust identifiers, such as to tidy up names like
+    /// `nfs_fh4` or avoid a clash with hand-written code. Types, constants, enum members, struct
+    /// fields, union arms and namespaces with a name in `rename` get the name it maps to; names
+    /// are unqualified, as the spec defines them. What's sent on the wire doesn't change, and
+    /// renamed items keep the spec's name as an alias, as with `Naming::Rust`.
+    ///
+    /// It's an error for a new name not to be an identifier.
+    pub fn set_rename(&mut self, rename: &HashMap<String, String>) -> Result<&mut Self> {
+        if let Some((name, rust_name)) = rename.iter().find(|(_, rust_name)| !is_ident(rust_name)) {
+            return Err(Error::InvalidRename { name: name.clone(), rust_name: rust_name.clone() });
+        }
+        self.rename = rename.clone();
+        Ok(self)
+    }
+
+    /// Spell the spec's names in Rust as `naming` has them; see `Naming`. Names in the map given
+    /// to `set_rename` are spelled as it has them.
+    pub fn set_naming(&mut self, naming: Naming) -> &mut Self {
+        self.naming = naming;
+        self
+    }
+
+    /// Escape names which are Rust keywords as `escape` has it; see `KeywordEscape`. By default
+    /// they get an `_` appended.
+    pub fn set_keyword_escape(&mut self, escape: KeywordEscape) -> &mut Self {
+        self.keyword_escape = escape;
+        self
+    }
+
+    /// Leave a prefix off enums' members to name their variants; see `VariantPrefixes`.
+    pub fn set_variant_prefixes(&mut self, prefixes: VariantPrefixes) -> &mut Self {
+        self.variant_prefixes = prefixes;
+        self
+    }
+
+    /// Generate enums as newtypes of their wire value, such as `pub struct color(pub i32);`,
+    /// with an associated constant for each member, rather than as Rust enums. Unpacking then
+    /// keeps values the spec doesn't know, as from a newer version of the protocol, and packing
+    /// sends them back unchanged.
+    pub fn set_lossless_enums(&mut self, lossless: bool) -> &mut Self {
+        self.lossless_enums = lossless;
+        self
+    }
+
+    /// Give unions without a `default` arm an `Unknown` variant holding any discriminant they
+    /// don't know, rather than failing to unpack it, and pack it back as it was. An arm's data
+    /// doesn't record its length, so the variant also holds the rest of the input, as raw bytes.
+    /// That's only right for unions which end their message: anything after one then fails to
+    /// unpack, as the input has run out.
+    pub fn set_unknown_union_arms(&mut self, unknown: bool) -> &mut Self {
+        self.unknown_union_arms = unknown;
+        self
+    }
+
+    /// How to read the case labels of unions switching on a `bool`. Specs spell them `TRUE` and
+    /// `FALSE`, `true` and `false`, or `1` and `0`, all of which are accepted by default; other
+    /// labels are errors unless `BoolLabels::Constants` falls back to their value.
+    pub fn set_bool_labels(&mut self, labels: BoolLabels) -> &mut Self {
+        self.bool_labels = labels;
+        self
+    }
+
+    /// Generate code for `no_std` crates, with paths to `core` and `alloc` rather than `std`, to
+    /// use with xdr-codec without its `std` feature. The crate must have `extern crate alloc;`.
+    /// `@hashmap` flex arrays are rejected, as `alloc` has no `HashMap`.
+    pub fn set_no_std(&mut self, no_std: bool) -> &mut Self {
+        self.no_std = no_std;
+        self
+    }
+
+    /// Unpack fixed arrays of non-opaque types without `unsafe`, for crates which
+    /// `#![forbid(unsafe_code)]`. The elements are collected into a `Vec` which is then converted
+    /// to the array, rather than unpacked in place, so each unpack allocates.
+    pub fn set_safe_arrays(&mut self, safe: bool) -> &mut Self {
+        self.safe_arrays = safe;
+        self
+    }
+
+    /// Generate types for use without xdr-codec, such as with serde or another codec: enums don't
+    /// get the `TryFrom` conversion from their wire value, whose error is xdr-codec's. Their
+    /// `Pack` and `Unpack` impls are left out as for `Codecs::None`, by whatever emits them.
+    pub fn set_types_only(&mut self, types_only: bool) -> &mut Self {
+        self.types_only = types_only;
+        self
+    }
+
+    /// Box union arms whose data is estimated to take more than `threshold` bytes in memory, such
+    /// as a large fixed `opaque` array, so the union is only as big as its other arms. The
+    /// estimate is rough: it takes no account of alignment, and counts external types as a
+    /// `Vec`. Must be set before `update_consts`.
+    pub fn set_box_large_arms(&mut self, threshold: Option<u64>) -> &mut Self {
+        self.box_large_arms = threshold;
+        self
+    }
+
+    /// Derive `Debug`, `Clone`, `Copy`, `PartialEq` and `Eq` for types with fixed arrays longer
+    /// than 32 elements, which Rust implements for arrays of any length since 1.47. Without it
+    /// such types derive nothing, for older compilers. They still don't derive `Default`, which
+    /// arrays only have up to 32. serde's and schemars' traits also stop there, so the generator
+    /// refuses this with the `derive_serde` or `derive_json_schema` features, whose derives every
+    /// type gets.
+    pub fn set_large_array_derives(&mut self, large: bool) -> &mut Self {
+        self.large_array_derives = large;
+        self
+    }
+
+    /// Generate types, constants, fields, helper methods and modules with `visibility` rather
+    /// than `pub`, so the code can be used within a crate without being part of its API.
+    pub fn set_visibility(&mut self, visibility: Visibility) -> &mut Self {
+        self.visibility = visibility;
+        self
+    }
+
+    // Visibility of items, fields and modules generated at this depth. Private ones inside
+    // generated modules reach up to where the code was generated, as the glob imports of
+    // section modules and references into namespaces need.
+    pub(crate) fn vis(&self) -> TokenStream {
+        match (self.visibility, self.module_depth) {
+            (Visibility::Public, _) => quote!(pub),
+            (Visibility::Crate, _) => quote!(pub(crate)),
+            (Visibility::Private, 0) => quote!(),
+            (Visibility::Private, 1) => quote!(pub(super)),
+            (Visibility::Private, depth) => {
+                let supers = std::iter::repeat_n(quote!(super), depth);
+                quote!(pub(in #(#supers)::*))
+            }
+        }
+    }
+
+    // This table for generating the contents of a module
+    pub(crate) fn in_module(&self) -> Self
+    where
+        M: Clone,
+    {
+        Symtab { module_depth: self.module_depth + 1, ..self.clone() }
+    }
+
+    // Path to `core` in generated code
+    fn core_path(&self) -> TokenStream {
+        if self.no_std { quote!(::core) } else { quote!(::std) }
+    }
+
+    // Path to `alloc` in generated code
+    fn alloc_path(&self) -> TokenStream {
+        if self.no_std { quote!(::alloc) } else { quote!(::std) }
+    }
+
+    // `Vec`, `String` or `Box` in generated code, from `alloc`'s `module` if they're not in the
+    // prelude
+    fn alloc_type(&self, module: &str, name: &str) -> TokenStream {
+        let name = Ident::new(name, Span::call_site());
+        if self.no_std {
+            let module = Ident::new(module, Span::call_site());
+            quote!(::alloc::#module::#name)
+        } else {
+            quote!(#name)
+        }
+    }
+
+    // `name` without the prefix left off it as a variant of its enum, if it's an enum member
+    fn variant_name<'a>(&self, name: &'a str) -> &'a str {
+        let scope = match self.consts.get(name) {
+            Some(SymDef { value: (_, Some(scope)), .. }) => scope,
+            _ => return name,
+        };
+        let members: Vec<&str> = match self.typespecs.get(scope).map(|def| &def.value) {
+            Some(Type::Enum(members, _)) => members.iter().map(|EnumDefn(member, ..)| member.as_str()).collect(),
+            _ => return name,
+        };
+        match self.variant_prefixes.prefix(scope, &members) {
+            Some(prefix) => base_name(name).strip_prefix(prefix).unwrap_or(name),
+            None => name,
+        }
+    }
+
+    // What a spec name, of something which is a `kind` in Rust, is called in Rust
+    fn rust_name<'a>(&'a self, name: &'a str, kind: NameKind) -> Cow<'a, str> {
+        if let Some(rust_name) = self.rename.get(name) {
+            return Cow::Borrowed(rust_name);
+        }
+        let name = match kind {
+            NameKind::Variant => self.variant_name(name),
+            _ => name,
+        };
+        match self.naming {
+            Naming::Spec => Cow::Borrowed(name),
+            Naming::Rust => Cow::Owned(kind.spell(name)),
+        }
+    }
+
+    // The Rust identifier for a spec name
+    pub(crate) fn ident(&self, name: &str, kind: NameKind) -> Ident {
+        escape_ident(&self.rust_name(name, kind), self.keyword_escape)
+    }
+
+    // A name which may be qualified by namespaces, as a Rust path.
+    fn path(&self, name: &str, kind: NameKind) -> TokenStream {
+        let mut segs: Vec<_> = name.split("::").collect();
+        let last = segs.pop().map(|seg| self.ident(seg, kind));
+        let segs = segs.into_iter().map(|seg| self.ident(seg, NameKind::Module)).chain(last);
+        quote!(#(#segs)::*)
+    }
+
+    // Attributes keeping the spec's name for a renamed item: a doc alias, so it can be searched
+    // for, and with `serde` a serde rename, so it serializes as before
+    fn alias_attrs(&self, name: &str, kind: NameKind, serde: bool) -> TokenStream {
+        if self.rust_name(name, kind) == name {
+            return TokenStream::new();
+        }
+        if serde {
+            quote!(#[doc(alias = #name)] #[serde(rename = #name)])
+        } else {
+            quote!(#[doc(alias = #name)])
+        }
+    }
+
+    // The extra attributes for the item generated for `name`
+    fn item_attrs(&self, name: &str, kind: RustItem) -> TokenStream {
+        self.item_attrs.map_or_else(TokenStream::new, |attrs| attrs(name, kind).into_iter().collect())
+    }
+
+    // Whether the type `name`, which could derive `derivable`, derives `Default`
+    fn derives_default(&self, name: &str, derivable: Derives) -> bool {
+        (derivable & self.derives).contains(Derives::DEFAULT)
+            || self.extra_derives.is_some_and(|extra| extra(name).iter().any(|path| path == "Default"))
+    }
+
+    // The `derive` attribute for the type `name`, which could derive `derivable`
+    fn derive_attr(&self, name: &str, derivable: Derives) -> Result<TokenStream> {
+        let derive = derivable & self.derives;
+        let extra = self.extra_derives.map_or_else(Vec::new, |extra| extra(name));
+        if extra.is_empty() {
+            return Ok(derive.into_token_stream());
+        }
+
+        let mut names: Vec<String> = derive.names().into_iter().map(String::from).collect();
+        if derive.is_empty() {
+            names.clear();
+        }
+        for path in extra {
+            if !names.contains(&path) {
+                names.push(path);
+            }
+        }
+        let paths = names
+            .iter()
+            .map(|path| {
+                let invalid = |problem: String| Error::InvalidDerive { name: name.to_string(), derive: path.clone(), problem };
+                match path.parse::<TokenStream>() {
+                    Ok(toks) if toks.is_empty() => Err(invalid("empty path".to_string())),
+                    Ok(toks) => Ok(toks),
+                    Err(err) => Err(invalid(err.to_string())),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut toks = TokenStream::new();
+        #[cfg(feature="reprc")]
+        if !derive.is_empty() {
+            toks.extend(quote!(#[repr(C)]));
+        }
+        toks.extend(quote!(#[derive(#(#paths),*)]));
+        Ok(toks)
+    }
+
+    /// Generate custom Rust types, with their own packing and unpacking, for some XDR types; see
+    /// `Primitives`.
+    pub fn set_primitives(&mut self, primitives: &Primitives) -> &mut Self {
+        self.primitives = primitives.clone();
+        self
+    }
+
+    // The type `ty` is a synonym of, through any number of typedefs, or `ty` itself.
+    fn resolve_syns<'a>(&'a self, ty: &'a Type) -> &'a Type {
+        let mut ty = ty;
+        // Bounded, in case of synonyms for each other
+        for _ in 0..=self.typesyns.len() {
+            match ty {
+                Type::Ident(name, _) if self.typesyns.contains_key(name) => ty = &self.typesyns[name].value,
+                _ => break,
+            }
+        }
+        ty
+    }
+
+    // The custom Rust type for `ty`, or for the type it's a synonym of, to pack and unpack it, and
+    // the XDR type it's for.
+    fn codec_primitive<'a>(&'a self, ty: &'a Type) -> Option<(&'a Primitive, &'a Type)> {
+        let mut ty = ty;
+        // Bounded, in case of synonyms for each other
+        for _ in 0..=self.typesyns.len() {
+            if let Some(prim) = self.primitives.get(ty) {
+                return Some((prim, ty));
+            }
+            ty = match ty {
+                Type::Ident(name, _) => &self.typesyns.get(name)?.value,
+                _ => return None,
+            };
+        }
+        None
+    }
+
     pub fn getconst(&self, name: &String) -> Option<(i64, Option<String>)> {
         match self.consts.get(name) {
             None => None,
@@ -1136,13 +4914,24 @@ impl<M> Symtab<M> {
         }
     }
 
-    pub fn value(&self, val: &Value) -> Option<i64> {
+    /// The value of a constant expression, or `None` if it names an undefined constant or
+    /// overflows.
+    pub fn eval(&self, val: &Value) -> Option<i64> {
         match val {
             &Value::Const(c) => Some(c),
             &Value::Ident(ref id) => self.getconst(id).map(|(v, _)| v),
+            Value::Binary(expr) => {
+                let (op, lhs, rhs) = &**expr;
+                op.apply(self.eval(lhs)?, self.eval(rhs)?)
+            }
         }
     }
 
+    /// Parse and evaluate a constant expression, such as `MAXNAME * 2`.
+    pub fn eval_expr(&self, expr: &str) -> Result<Option<i64>> {
+        constant_expr(expr).map(|val| self.eval(&val))
+    }
+
     pub fn typespec(&self, name: &String) -> Option<&Type> {
         match self.typespecs.get(name) {
             None => {
@@ -1155,6 +4944,61 @@ impl<M> Symtab<M> {
         }
     }
 
+    /// The value of constant or enum member `name`.
+    pub fn constant(&self, name: &str) -> Option<i64> {
+        self.consts.get(name).map(|def| def.value.0)
+    }
+
+    /// The enum `name` is a member of, if it's an enum member.
+    pub fn enum_of(&self, name: &str) -> Option<&str> {
+        self.consts.get(name).and_then(|def| def.value.1.as_deref())
+    }
+
+    /// The members of enum `name` with their values, in the order they're declared.
+    pub fn enum_members(&self, name: &str) -> Option<Vec<(&str, i64)>> {
+        let members = match self.typespecs.get(name).map(|def| &def.value) {
+            Some(Type::Enum(members, _)) => members,
+            _ => return None,
+        };
+        // Members of an enum in a namespace are qualified as it is
+        let ns = name.rfind("::").map_or("", |idx| &name[..idx + 2]);
+        let members = members.iter().filter_map(|EnumDefn(member, ..)| {
+            let (qualified, def) = self.consts.get_key_value(&format!("{}{}", ns, member))?;
+            Some((qualified.as_str(), def.value.0))
+        });
+        Some(members.collect())
+    }
+
+    /// The type `name` stands for, following typedefs of one name to another to the struct,
+    /// union, enum, array or primitive type at the end. `None` if a name on the way isn't
+    /// defined, as with external types.
+    pub fn resolve_type(&self, name: &str) -> Option<&Type> {
+        let ty = match self.typesyns.get(name) {
+            Some(def) => self.resolve_syns(&def.value),
+            None => return self.typespecs.get(name).map(|def| &def.value),
+        };
+        // A name typedefs lead to which isn't a typedef is a struct, union, enum or array, if
+        // it's defined at all
+        match ty {
+            Type::Ident(name, _) => self.typespecs.get(name).map(|def| &def.value),
+            ty => Some(ty),
+        }
+    }
+
+    /// Which types refer to which; see `TypeGraph`.
+    pub fn dependencies(&self) -> TypeGraph {
+        let types = self.typespecs.iter().chain(&self.typesyns).map(|(name, def)| {
+            let mut refs = Vec::new();
+            def.value.references(&mut refs);
+            (name.as_str(), refs)
+        });
+        TypeGraph::build(types, |name| match self.typespecs.get_key_value(name).or_else(|| self.typesyns.get_key_value(name)) {
+            Some((name, _)) => Some(name.as_str()),
+            // An enum member is its enum's
+            None => self.enum_of(name),
+        })
+    }
+
     pub fn constants(&self) -> Iter<String, SymDef<(i64, Option<String>), M>> {
         self.consts.iter()
     }
@@ -1166,6 +5010,128 @@ impl<M> Symtab<M> {
     pub fn typesyns(&self) -> Iter<String, SymDef<Type, M>> {
         self.typesyns.iter()
     }
+
+    /// Non-fatal issues with the definitions, such as those from `Typespec::selector_warnings`.
+    pub fn warnings(&self) -> Vec<String> {
+        self.typespecs
+            .iter()
+            .flat_map(|(name, def)| Typespec(name.clone(), def.value.clone()).selector_warnings(self))
+            .collect()
+    }
+
+    /// Constants and enum members whose values name undefined constants, which are left out of
+    /// the table, enum members which overflow, names which could be taken for the locals of
+    /// generated code, and names an RPC program defines more than once.
+    pub fn errors(&self) -> Vec<Error> {
+        let reserved = self
+            .consts
+            .keys()
+            .chain(self.typespecs.keys())
+            .chain(self.typesyns.keys())
+            .filter(|name| base_name(name).starts_with(LOCAL_PREFIX))
+            .map(|name| Error::ReservedName { name: name.clone() });
+
+        // Members without a value of their own follow on from the one before, which is reported
+        // instead. Those are the only enum values which are expressions.
+        let unknown = self.unresolved.iter().filter(|(_, _, value, _)| !matches!(value, Value::Binary(..)));
+
+        // Standard enums are sent as an int, which an out of range value would be truncated to
+        let out_of_range = self.consts.iter().filter_map(|(member, def)| {
+            let (value, scope) = &def.value;
+            let name = scope.as_ref()?;
+            match self.typespecs.get(name)?.value {
+                Type::Enum(_, EnumWidth::Int) if i32::try_from(*value).is_err() => Some(Error::EnumValueRange {
+                    name: name.clone(),
+                    member: base_name(member).to_string(),
+                    value: *value,
+                }),
+                _ => None,
+            }
+        });
+
+        unknown
+            .map(|(scope, member, value, _)| match scope {
+                Some(name) => Error::UnknownEnumValue {
+                    name: name.clone(),
+                    member: base_name(member).to_string(),
+                    value: value.clone(),
+                },
+                None => Error::UnknownConstValue { name: member.clone(), value: value.clone() },
+            })
+            .chain(out_of_range)
+            .chain(reserved)
+            .chain(self.duplicates.iter().map(|(program, name)| Error::DuplicateProgramName { program: program.clone(), name: name.clone() }))
+            .chain(self.name_clashes())
+            .collect()
+    }
+
+    // Spec names which are different but are spelled the same in Rust, such as `foo_bar` and
+    // `fooBar` with `Naming::Rust`, where they'd be in the same Rust namespace: types and
+    // modules, or constants, in the same module, the fields of a struct, or an enum's variants.
+    fn name_clashes(&self) -> Vec<Error> {
+        let scope = |name: &str| name.rsplit_once("::").map_or("", |(scope, _)| scope).to_string();
+        let qualified = |scope: &str, name: &str| if scope.is_empty() { name.to_string() } else { format!("{}::{}", scope, name) };
+        // (group, spec name) of each name, with what it is in Rust
+        let mut names: Vec<((String, String), String)> = Vec::new();
+
+        for name in self.typespecs.keys().chain(self.typesyns.keys()) {
+            names.push(((format!("type {}", scope(name)), name.clone()), self.ident(base_name(name), NameKind::Type).to_string()));
+            let mut prefix = String::new();
+            for module in name.split("::").collect::<Vec<_>>().split_last().map_or(&[][..], |(_, modules)| modules) {
+                names.push(((format!("type {}", prefix), qualified(&prefix, module)), self.ident(module, NameKind::Module).to_string()));
+                prefix = qualified(&prefix, module);
+            }
+        }
+        for name in self.consts.keys() {
+            names.push(((format!("const {}", scope(name)), name.clone()), self.ident(base_name(name), NameKind::Const).to_string()));
+        }
+        for (name, def) in &self.typespecs {
+            match &def.value {
+                Type::Struct(decls) => names.extend(decls.iter().filter_map(|decl| match decl {
+                    Decl::Named(field, ..) => Some(((format!("struct {}", name), format!("{}.{}", name, field)), self.ident(field, NameKind::Field).to_string())),
+                    Decl::Void => None,
+                })),
+                Type::Enum(members, _) => names.extend(members.iter().map(|EnumDefn(member, ..)| {
+                    ((format!("enum {}", name), format!("{}::{}", name, base_name(member))), self.ident(member, NameKind::Variant).to_string())
+                })),
+                // The variants generated for a `default` arm, or discriminants it doesn't know,
+                // mustn't be a case's too
+                Type::Union(sel, cases, defl) => {
+                    let group = format!("union {}", name);
+                    names.extend(cases.iter().filter_map(|case| match &case.0 {
+                        Value::Ident(id) => Some(((group.clone(), format!("{}::{}", name, base_name(id))), case.variant(sel, self).to_string())),
+                        _ => None,
+                    }));
+                    let generated = match defl {
+                        Some(_) => Some(("default", "Default")),
+                        None if self.unknown_union_arms => Some(("unknown", "Unknown")),
+                        None => None,
+                    };
+                    if let Some((arm, variant)) = generated {
+                        names.push(((group, format!("{}'s {} arm", name, arm)), variant.to_string()));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let mut seen: BTreeMap<(&str, &str), &str> = BTreeMap::new();
+        let mut clashes = Vec::new();
+        for ((group, name), rust) in &names {
+            match seen.get(&(group.as_str(), rust.as_str())) {
+                Some(first) if first != name => clashes.push(Error::NameClash {
+                    first: first.to_string(),
+                    second: name.clone(),
+                    rust: rust.clone(),
+                }),
+                Some(_) => (),
+                None => {
+                    seen.insert((group, rust), name);
+                }
+            }
+        }
+        clashes
+    }
 }
 
 
@@ -0,0 +1,69 @@
+//! Specifications saved by other platforms' tools
+//!
+//! Windows editors and exporters often start a file with a UTF-8 byte order mark, end lines with
+//! CRLF (or, from older Mac tools, a bare CR), write comments in a legacy code page rather than
+//! UTF-8, and paste in non-breaking spaces. None of that means anything to XDR, so it's
+//! normalized away before parsing.
+use std::borrow::Cow;
+use std::io::Read;
+
+use super::Result;
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+// Whitespace the grammar doesn't know, other than the Unicode line separators it does
+fn is_foreign_space(c: char) -> bool {
+    !c.is_ascii() && c.is_whitespace() && c != '\u{2028}' && c != '\u{2029}'
+}
+
+/// Normalize the text of a specification for parsing.
+///
+/// A leading byte order mark is dropped, CRLF and bare CR line endings become LF, and non-ASCII
+/// spaces become ASCII spaces. Bytes which aren't UTF-8, typically from a legacy code page in
+/// comments, are replaced with U+FFFD with a warning. Lines, and columns within them, are
+/// unchanged, so error locations still refer to the original text.
+pub fn normalize_source(input: &[u8]) -> Cow<'_, str> {
+    let input = input.strip_prefix(BOM).unwrap_or(input);
+    let text = match std::str::from_utf8(input) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(err) => {
+            let line = input[..err.valid_up_to()].iter().filter(|&&c| c == b'\n').count() + 1;
+            warn!("line {}: replacing bytes which aren't UTF-8", line);
+            String::from_utf8_lossy(input)
+        }
+    };
+
+    if !text.contains(|c| c == '\r' || is_foreign_space(c)) {
+        return text;
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\r' => out.push('\n'),
+            c if is_foreign_space(c) => out.push(' '),
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Read a specification, normalized for parsing.
+pub(crate) fn read_source<In: Read>(mut input: In) -> Result<String> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    Ok(normalize_source(&bytes).into_owned())
+}
+
+#[test]
+fn test_normalize_source() {
+    assert_eq!(
+        normalize_source(b"\xef\xbb\xbf/* caf\xe9 */\r\nconst A = 1;\rconst\xc2\xa0B = 2;\r\n"),
+        "/* caf\u{fffd} */\nconst A = 1;\nconst B = 2;\n"
+    );
+    // Only a leading byte order mark is dropped, and line separators are left to the grammar
+    assert_eq!(normalize_source("a\u{feff}\u{2028}".as_bytes()), "a\u{feff}\u{2028}");
+
+    assert!(matches!(normalize_source(b"const A = 1;\n"), Cow::Borrowed(_)));
+}
@@ -0,0 +1,394 @@
+//! C preprocessor directives
+//!
+//! Specifications written for rpcgen are run through cpp, so they may use `#define` for
+//! constants and conditionals to select definitions. `preprocess` handles that subset of cpp
+//! itself, so they can be used without an external cpp step.
+use std::collections::BTreeMap;
+
+use super::xdr_nom::{block_comments, comments};
+use super::CommentKind;
+use crate::error::{Expected, ParseError};
+use crate::Result;
+
+// An open `#if`, `#ifdef` or `#ifndef`.
+struct Cond {
+    // Whether the enclosing region is included
+    outer: bool,
+    // Whether a branch has been included yet
+    taken: bool,
+    // Whether `#else` has been seen
+    done: bool,
+}
+
+/// Evaluate `#define`, `#undef`, `#if`, `#ifdef`, `#ifndef`, `#elif`, `#else` and `#endif`
+/// directives, starting with `defines`.
+///
+/// Excluded lines and the directives themselves are blanked out, leaving everything else in
+/// place so that error locations still refer to the original text. A `#define` of a name to an
+/// integer, such as `#define MAXNAME 255`, becomes an XDR constant. Other directives, such as
+/// `#include`, are left for the parser.
+///
+/// `#if` and `#elif` take C expressions over integers, `defined`, `!`, comparisons, `&&` and
+/// `||`; names which aren't defined as integers count as 0. There's no macro expansion.
+pub fn preprocess(input: &str, defines: &BTreeMap<String, String>) -> Result<String> {
+    let mut defines = defines.clone();
+    let blocks = block_comments(input);
+    let invalid = |offset: usize, expected: Expected| ParseError::new(input, offset, expected).into();
+
+    let mut conds: Vec<Cond> = Vec::new();
+    let mut active = true;
+    let mut out = String::with_capacity(input.len());
+    let mut offset = 0;
+    // In a block comment a directive line opened, and whether its opener was carried into the
+    // output, so that the rest of it is kept; otherwise it's blanked out
+    let mut open_comment: Option<bool> = None;
+
+    for line in input.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+
+        // cpp removes comments before it looks for directives, so the rest of one is never code
+        if let Some(kept) = open_comment {
+            let blank = |text: &str| text.chars().map(|c| if c.is_whitespace() { c } else { ' ' }).collect::<String>();
+            let end = line.find("*/").map(|end| end + 2);
+            let (comment, rest) = line.split_at(end.unwrap_or(line.len()));
+            out.push_str(&if kept { comment.to_string() } else { blank(comment) });
+            if end.is_some() {
+                open_comment = None;
+            }
+            out.push_str(&if active { rest.to_string() } else { blank(rest) });
+            continue;
+        }
+
+        // Where in the line a block comment which goes on past it starts, if one does
+        let mut open = None;
+        let directive = Some(line.trim_start())
+            .filter(|_| !blocks.iter().any(|range| range.contains(&start)))
+            .and_then(|line| line.strip_prefix('#'))
+            .map(|d| {
+                let d = d.trim_start();
+                let end = d.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(d.len());
+                let (arg, opened) = strip_comment(&d[end..]);
+                // The argument runs to the end of the line
+                open = opened.map(|at| line.len() - d[end..].len() + at);
+                (&d[..end], arg.trim())
+            });
+
+        let replacement = match directive {
+            Some((kind @ "ifdef", name)) | Some((kind @ "ifndef", name)) => {
+                let cond = defines.contains_key(name) == (kind == "ifdef");
+                conds.push(Cond { outer: active, taken: active && cond, done: false });
+                active = active && cond;
+                None
+            }
+            Some(("if", expr)) => {
+                let cond = active && eval(expr, &defines).ok_or_else(|| invalid(start, Expected::Directive))?;
+                conds.push(Cond { outer: active, taken: cond, done: false });
+                active = cond;
+                None
+            }
+            Some(("elif", expr)) => {
+                let cond = match conds.last_mut() {
+                    Some(cond) if !cond.done => cond,
+                    _ => return Err(invalid(start, Expected::Directive)),
+                };
+                active = cond.outer && !cond.taken && eval(expr, &defines).ok_or_else(|| invalid(start, Expected::Directive))?;
+                cond.taken |= active;
+                None
+            }
+            Some(("else", _)) => {
+                let cond = match conds.last_mut() {
+                    Some(cond) if !cond.done => cond,
+                    _ => return Err(invalid(start, Expected::Directive)),
+                };
+                active = cond.outer && !cond.taken;
+                cond.taken = true;
+                cond.done = true;
+                None
+            }
+            Some(("endif", _)) => {
+                active = match conds.pop() {
+                    Some(cond) => cond.outer,
+                    None => return Err(invalid(start, Expected::Directive)),
+                };
+                None
+            }
+            Some(("define", def)) if active => {
+                let (name, val) = def.split_at(def.find(char::is_whitespace).unwrap_or(def.len()));
+                if name.is_empty() || name.contains('(') {
+                    return Err(invalid(start, Expected::Directive));
+                }
+                let val = val.trim();
+                defines.insert(name.to_string(), val.to_string());
+                parse_int(val).map(|val| format!("const {} = {};", name, val))
+            }
+            Some(("undef", name)) if active => {
+                defines.remove(name);
+                None
+            }
+            Some(("define", _)) | Some(("undef", _)) => None,
+            // Not ours; the parser skips directives, comment and all
+            _ if active => {
+                // Without the comment, which would otherwise end in the lines after
+                let (directive, comment) = line.split_at(open.unwrap_or(line.len()));
+                out.push_str(directive);
+                out.extend(comment.chars().map(|c| if c.is_whitespace() { c } else { ' ' }));
+                if open.is_some() {
+                    open_comment = Some(false);
+                }
+                continue;
+            }
+            _ => None,
+        };
+
+        // Keep the line structure, and any comments which are only partly excluded
+        match replacement {
+            Some(text) => out.push_str(&text),
+            None => out.extend(line.char_indices().map(|(idx, c)| {
+                let keep = c.is_whitespace() || blocks.iter().any(|range| range.contains(&(start + idx)));
+                if keep { c } else { ' ' }
+            })),
+        }
+        if !out.ends_with('\n') && line.ends_with('\n') {
+            out.push('\n');
+        }
+        // Carried over, so the comment goes on over the same lines
+        if open.is_some() {
+            let newline = out.ends_with('\n');
+            if newline {
+                out.pop();
+            }
+            out.push_str(" /*");
+            if newline {
+                out.push('\n');
+            }
+            open_comment = Some(true);
+        }
+    }
+
+    match conds.is_empty() {
+        true => Ok(out),
+        false => Err(invalid(input.len(), Expected::Endif)),
+    }
+}
+
+// Drop a trailing comment from a directive's argument, found as the parser finds comments, and
+// give where it starts if it's a block comment which goes on past the end of the argument.
+fn strip_comment(arg: &str) -> (&str, Option<usize>) {
+    let comments = comments(arg);
+    let open = comments
+        .last()
+        .filter(|c| c.kind == CommentKind::Block && c.location.offset + 2 + c.text.len() == arg.len())
+        .map(|c| c.location.offset);
+    match comments.first() {
+        Some(c) => (&arg[..c.location.offset], open),
+        None => (arg, None),
+    }
+}
+
+// A C integer literal, as XDR allows; `u` and `l` suffixes are ignored.
+fn parse_int(s: &str) -> Option<i64> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let s = s.trim_end_matches(['u', 'U', 'l', 'L']);
+    let val = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if s.len() > 1 && s.starts_with('0') {
+        i64::from_str_radix(&s[1..], 8).ok()?
+    } else if !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit()) {
+        s.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if neg { -val } else { val })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok<'a> {
+    Num(i64),
+    Name(&'a str),
+    Op(&'static str),
+}
+
+fn tokenize(expr: &str) -> Option<Vec<Tok<'_>>> {
+    const OPS: &[&str] = &["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")"];
+
+    let mut toks = Vec::new();
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        let word = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+        if word > 0 {
+            let (tok, tail) = rest.split_at(word);
+            toks.push(match tok.as_bytes()[0] {
+                b'0'..=b'9' => Tok::Num(parse_int(tok)?),
+                _ => Tok::Name(tok),
+            });
+            rest = tail;
+        } else {
+            let op = OPS.iter().find(|op| rest.starts_with(**op))?;
+            toks.push(Tok::Op(op));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Some(toks)
+}
+
+// Nesting of parentheses and `!` beyond which an expression is taken as malformed, rather than
+// exhaust the stack.
+const MAX_DEPTH: usize = 256;
+
+// Evaluate a `#if` expression, or `None` if it's malformed.
+fn eval(expr: &str, defines: &BTreeMap<String, String>) -> Option<bool> {
+    struct Parser<'a, 'd> {
+        toks: Vec<Tok<'a>>,
+        pos: usize,
+        depth: usize,
+        defines: &'d BTreeMap<String, String>,
+    }
+
+    impl<'a, 'd> Parser<'a, 'd> {
+        fn next(&mut self) -> Option<Tok<'a>> {
+            let tok = self.toks.get(self.pos).cloned();
+            self.pos += 1;
+            tok
+        }
+
+        fn eat(&mut self, op: &str) -> bool {
+            let found = matches!(self.toks.get(self.pos), Some(Tok::Op(tok)) if *tok == op);
+            self.pos += found as usize;
+            found
+        }
+
+        fn or(&mut self) -> Option<i64> {
+            let mut val = self.and()?;
+            while self.eat("||") {
+                let rhs = self.and()?;
+                val = (val != 0 || rhs != 0) as i64;
+            }
+            Some(val)
+        }
+
+        fn and(&mut self) -> Option<i64> {
+            let mut val = self.compare()?;
+            while self.eat("&&") {
+                let rhs = self.compare()?;
+                val = (val != 0 && rhs != 0) as i64;
+            }
+            Some(val)
+        }
+
+        fn compare(&mut self) -> Option<i64> {
+            let lhs = self.unary()?;
+            let op = match self.toks.get(self.pos) {
+                Some(Tok::Op(op)) if ["==", "!=", "<", ">", "<=", ">="].contains(op) => *op,
+                _ => return Some(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.unary()?;
+            Some(match op {
+                "==" => lhs == rhs,
+                "!=" => lhs != rhs,
+                "<" => lhs < rhs,
+                ">" => lhs > rhs,
+                "<=" => lhs <= rhs,
+                _ => lhs >= rhs,
+            } as i64)
+        }
+
+        fn unary(&mut self) -> Option<i64> {
+            match self.next()? {
+                Tok::Op("!") => self.nested(|parser| parser.unary().map(|v| (v == 0) as i64)),
+                Tok::Op("(") => {
+                    let val = self.nested(Self::or)?;
+                    self.eat(")").then_some(val)
+                }
+                Tok::Num(val) => Some(val),
+                Tok::Name("defined") => {
+                    let paren = self.eat("(");
+                    let name = match self.next()? {
+                        Tok::Name(name) => name,
+                        _ => return None,
+                    };
+                    (!paren || self.eat(")")).then(|| self.defines.contains_key(name) as i64)
+                }
+                Tok::Name(name) => Some(self.defines.get(name).and_then(|val| parse_int(val)).unwrap_or(0)),
+                Tok::Op(_) => None,
+            }
+        }
+
+        fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Option<i64>) -> Option<i64> {
+            self.depth += 1;
+            let val = if self.depth > MAX_DEPTH { None } else { parse(self) };
+            self.depth -= 1;
+            val
+        }
+    }
+
+    let mut parser = Parser { toks: tokenize(expr)?, pos: 0, depth: 0, defines };
+    let val = parser.or()?;
+    (parser.pos == parser.toks.len()).then_some(val != 0)
+}
+
+#[test]
+fn test_eval() {
+    let defines: BTreeMap<_, _> = vec![("A", ""), ("B", "2"), ("C", "0x10")]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let eval = |expr| eval(expr, &defines);
+
+    assert_eq!(eval("defined(A)"), Some(true));
+    assert_eq!(eval("defined A && !defined(Z)"), Some(true));
+    assert_eq!(eval("B == 2 && C > 15"), Some(true));
+    assert_eq!(eval("Z || 0"), Some(false));
+    assert_eq!(eval("(B < 1) || (C >= 16)"), Some(true));
+    assert_eq!(eval("1"), Some(true));
+    assert_eq!(eval("!(1)"), Some(false));
+
+    assert_eq!(eval("defined("), None);
+    assert_eq!(eval("B +"), None);
+    assert_eq!(eval("(1"), None);
+    assert_eq!(eval("1 2"), None);
+
+    let deep = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+    assert_eq!(eval(&deep), None);
+    let nots = "!".repeat(100_000) + "1";
+    assert_eq!(eval(&nots), None);
+    let shallow = format!("{}1{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+    assert_eq!(eval(&shallow), Some(true));
+}
+
+#[test]
+fn test_preprocess() {
+    let defines: BTreeMap<_, _> = vec![("LINUX".to_string(), String::new())].into_iter().collect();
+    let src = "#ifndef GUARD_X\n#define GUARD_X\n#define MAXNAME 0x20 /* bytes */\n#ifdef LINUX\nconst os = 1;\n#else\nconst os = 2; /* not\n#endif\nlinux */\n#endif\n#if MAXNAME > 16 && defined(GUARD_X)\nconst big = 1;\n#elif 1\nconst big = 0;\n#endif\n#include \"x.x\"\n#endif\n";
+    let out = preprocess(src, &defines).unwrap();
+    assert_eq!(out.lines().count(), src.lines().count());
+    assert_eq!(
+        out.lines().map(str::trim).filter(|l| !l.is_empty()).collect::<Vec<_>>(),
+        vec!["const MAXNAME = 32;", "const os = 1;", "/* not", "#endif", "linux */", "const big = 1;", "#include \"x.x\""]
+    );
+    // Idempotent
+    assert_eq!(preprocess(&out, &defines).unwrap(), out);
+
+    let err = |src| match preprocess(src, &BTreeMap::new()) {
+        Err(crate::Error::Parse(err)) => (err.expected, err.location.line),
+        res => panic!("unexpected {:?}", res),
+    };
+    assert_eq!(err("#ifdef X\nconst a = 1;\n"), (Expected::Endif, 3));
+    assert_eq!(err("const a = 1;\n#endif\n"), (Expected::Directive, 2));
+    assert_eq!(err("#if 1\n#else\n#else\n#endif\n"), (Expected::Directive, 3));
+    assert_eq!(err("#if defined(\n#endif\n"), (Expected::Directive, 1));
+    assert_eq!(err("#define F(x) x\n"), (Expected::Directive, 1));
+
+    // A comment which goes on past a directive stays a comment
+    let src = "#define MAX 4 /* the most\n we allow */ const a = MAX;\n#ifdef X /* not\nthis */\n#endif\n#include \"x.x\" /*\nhere */\n#define S 1 // why\n";
+    let out = preprocess(src, &BTreeMap::new()).unwrap();
+    assert_eq!(out, "const MAX = 4; /*\n we allow */ const a = MAX;\n                /*\nthis */\n      \n#include \"x.x\"   \n       \nconst S = 1;\n");
+    assert_eq!(preprocess(&out, &BTreeMap::new()).unwrap(), out);
+    assert_eq!(strip_comment(" 1 /*/\n"), (" 1 ", Some(3)));
+    assert_eq!(strip_comment(" 1 /* x */\n"), (" 1 ", None));
+}
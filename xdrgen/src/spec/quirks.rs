@@ -0,0 +1,119 @@
+//! Tolerating rpcgen's quirks
+//!
+//! Specifications written for Sun rpcgen often have stray semicolons, C spellings of types such
+//! as `long int`, and `typedef`s of names which are already defined, as in `typedef struct foo
+//! foo;`. rpcgen lets them through to the C compiler, which doesn't mind. In quirks mode, so does
+//! xdrgen, logging a warning for each rather than failing or generating code which won't build.
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use super::{Defn, Type};
+use crate::Location;
+
+fn is_word(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Blank out stray semicolons, and the redundant `int` of `long int`, `short int` and their
+/// `unsigned` forms, logging a warning for each.
+///
+/// Everything else is left in place, so error locations still refer to the original text. Like
+/// the grammar, `#` and `%` lines are directives, which are left alone.
+pub fn rpcgen_quirks(input: &str) -> Cow<'_, str> {
+    let src = input.as_bytes();
+    let find = |from: usize, pat: &[u8]| src[from..].windows(pat.len()).position(|w| w == pat).map(|p| from + p);
+
+    let mut blanks = Vec::new();
+    // The token before, if any: a word or a punctuation character
+    let mut prev: Option<&[u8]> = None;
+    let mut idx = 0;
+    let mut line_start = true;
+
+    while idx < src.len() {
+        let rest = &src[idx..];
+
+        if line_start {
+            let indent = rest.iter().take_while(|c| **c == b' ' || **c == b'\t').count();
+            if let Some(b'#') | Some(b'%') = rest.get(indent) {
+                idx = find(idx, b"\n").unwrap_or(src.len());
+                continue;
+            }
+        }
+        line_start = false;
+
+        if rest.starts_with(b"/*") {
+            idx = find(idx + 2, b"*/").map_or(src.len(), |end| end + 2);
+            continue;
+        }
+        if rest.starts_with(b"//") {
+            idx = find(idx, b"\n").unwrap_or(src.len());
+            continue;
+        }
+        if rest[0].is_ascii_whitespace() {
+            line_start = rest[0] == b'\n';
+            idx += 1;
+            continue;
+        }
+
+        let len = if is_word(rest[0]) { rest.iter().take_while(|c| is_word(**c)).count() } else { 1 };
+        let token = &rest[..len];
+        match (prev, token) {
+            (None, b";") | (Some(b";"), b";") | (Some(b"{"), b";") => {
+                warn!("{}: ignoring stray semicolon", Location::from_offset(input, idx));
+                blanks.push(idx);
+            }
+            (Some(b"long"), b"int") | (Some(b"short"), b"int") => {
+                warn!("{}: ignoring redundant int", Location::from_offset(input, idx));
+                blanks.extend(idx..idx + len);
+            }
+            _ => prev = Some(token),
+        }
+        idx += len;
+    }
+
+    if blanks.is_empty() {
+        return Cow::Borrowed(input);
+    }
+    let mut out = src.to_vec();
+    for idx in blanks {
+        out[idx] = b' ';
+    }
+    // Only ASCII was replaced, with ASCII
+    Cow::Owned(String::from_utf8(out).expect("still UTF-8"))
+}
+
+/// Drop `typedef`s of names to themselves, and of names which are already defined, logging a
+/// warning for each. Definitions in a namespace are checked against the others in it.
+pub(super) fn drop_redefinitions(defns: &mut Vec<Defn>) {
+    let mut seen = HashSet::new();
+
+    defns.retain_mut(|defn| match defn {
+        Defn::Typesyn(name, Type::Ident(target, _)) if target == name => {
+            warn!("ignoring typedef of {} to itself", name);
+            false
+        }
+        Defn::Typesyn(name, _) if seen.contains(name) => {
+            warn!("ignoring typedef of {}, which is already defined", name);
+            false
+        }
+        defn => {
+            if let Defn::Namespace(_, inner) = defn {
+                drop_redefinitions(inner);
+            }
+            seen.insert(defn.name().to_string());
+            true
+        }
+    });
+}
+
+#[test]
+fn test_rpcgen_quirks() {
+    let input = ";\nconst A = 1;;\n%passthrough;\n;struct s { unsigned long int a;; short  int b; /* ;; */ };\n";
+    assert_eq!(
+        rpcgen_quirks(input),
+        " \nconst A = 1; \n%passthrough;\n struct s { unsigned long     a;  short      b; /* ;; */ };\n"
+    );
+
+    let clean = "struct s { unsigned int a; int b; };\n";
+    assert!(matches!(rpcgen_quirks(clean), Cow::Borrowed(_)));
+}
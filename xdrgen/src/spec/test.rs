@@ -1,7 +1,14 @@
-use super::specification;
-use super::super::generate;
+use super::{specification, specification_source};
+use super::super::{generate_with_options, GenerateOptions};
 use std::io::Cursor;
 
+// The code generated from `src` with `options`.
+fn gen_with(src: &str, options: &GenerateOptions) -> String {
+    let mut out = Vec::new();
+    generate_with_options("test", Cursor::new(src.as_bytes()), &mut out, options).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
 #[test]
 fn typedef_void() {
     let s = specification(
@@ -110,8 +117,8 @@ fn inline_struct() {
     println!("spec {:?}", s);
     assert!(s.is_ok());
 
-    let g = generate("", Cursor::new(spec.as_bytes()), Vec::new(), &[]);
-    assert!(g.is_err());
+    let g = generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default());
+    assert!(g.is_ok());
 }
 
 #[test]
@@ -126,8 +133,8 @@ fn inline_union() {
     println!("spec {:?}", s);
     assert!(s.is_ok());
 
-    let g = generate("", Cursor::new(spec.as_bytes()), Vec::new(), &[]);
-    assert!(g.is_err());
+    let g = generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default());
+    assert!(g.is_ok());
 }
 
 #[test]
@@ -142,7 +149,7 @@ fn case_type() {
         println!("spec sp \"{}\" => {:?}", sp, s);
         assert!(s.is_ok());
 
-        let g = generate("", Cursor::new(sp.as_bytes()), Vec::new(), &[]);
+        let g = generate_with_options("", Cursor::new(sp.as_bytes()), Vec::new(), &Default::default());
         assert!(g.is_ok());
     }
 }
@@ -159,7 +166,7 @@ fn case_type_mismatch() {
         println!("spec sp \"{}\" => {:?}", sp, s);
         assert!(s.is_ok());
 
-        let g = generate("", Cursor::new(sp.as_bytes()), Vec::new(), &[]);
+        let g = generate_with_options("", Cursor::new(sp.as_bytes()), Vec::new(), &Default::default());
         assert!(g.is_err());
     }
 }
@@ -183,7 +190,7 @@ fn constants() {
         println!("spec sp \"{}\" => {:?}", sp, s);
         assert!(s.is_ok());
 
-        let g = generate("", Cursor::new(sp.as_bytes()), Vec::new(), &[]);
+        let g = generate_with_options("", Cursor::new(sp.as_bytes()), Vec::new(), &Default::default());
         assert!(g.is_ok());
     }
 }
@@ -251,3 +258,2527 @@ union foo switch (int x) {
     println!("spec {:?}", s);
     assert!(s.is_ok())
 }
+
+#[test]
+fn parse_error_location() {
+    use std::error::Error as StdError;
+    use crate::{Error, Expected};
+
+    let spec = "const A = 1;\nstruct foo { int a; }\nconst B = 2;\n";
+    let err = specification(spec).unwrap_err();
+    println!("err {}", err);
+
+    let perr = err.parse_error().expect("parse error");
+    assert_eq!(perr.expected, Expected::Definition);
+    assert_eq!((perr.location.line, perr.location.column), (2, 1));
+    assert_eq!(perr.found, "struct foo { int a; }");
+
+    // Shown as the parse error itself, rather than wrapping it as its source
+    assert_eq!(err.to_string(), perr.to_string());
+    assert!(err.source().is_none());
+    assert!(matches!(err, Error::Parse(_)));
+}
+
+#[test]
+fn case_expressions() {
+    let spec = r#"
+const FLAG_A = 1;
+const FLAG_B = 2;
+union foo switch (int x) {
+  case FLAG_A | FLAG_B:
+       int both;
+  case (FLAG_B << 2) - 1:
+       void;
+};
+"#;
+    let s = specification(spec);
+    println!("spec {:?}", s);
+    assert!(s.is_ok());
+
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("FLAG_A_or_FLAG_B (i32)"));
+    assert!(out.contains("(3i64 as i32) . pack (__xdr_out)"));
+    assert!(out.contains("__xdr_x if __xdr_x == (7i32 as i32)"));
+}
+
+#[test]
+fn case_expression_unresolved() {
+    let spec = "union foo switch (int x) { case MISSING | 1: void; };";
+
+    let g = generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default());
+    assert!(g.is_err());
+}
+
+#[test]
+fn selector_range_warnings() {
+    use super::{Symtab, Typespec, Defn};
+
+    let warnings = |spec: &str| {
+        let defns = specification(spec).unwrap();
+        let mut symtab = Symtab::new();
+        symtab.update_consts(&defns, &());
+        defns.iter()
+            .flat_map(|d| match d {
+                Defn::Typespec(name, ty) => Typespec(name.clone(), ty.clone()).selector_warnings(&symtab),
+                _ => vec![],
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert!(warnings("union u switch (int x) { case -1: void; case 0x7fffffff: void; };").is_empty());
+    assert!(warnings("union u switch (unsigned int x) { case 0xffffffff: void; case 0: void; };").is_empty());
+
+    let w = warnings("union u switch (int x) { case 0x80000000: void; case -2147483648: void; };");
+    println!("{:?}", w);
+    assert_eq!(w.len(), 2);
+    assert!(w[0].contains("out of range"));
+    assert!(w[1].contains("both sent as -2147483648"));
+
+    let w = warnings("union u switch (unsigned int x) { case 0x100000001: void; case 1: void; };");
+    println!("{:?}", w);
+    assert_eq!(w.len(), 2);
+
+    let w = warnings("union u switch (unsigned int x) { case 1 - 2: void; };");
+    println!("{:?}", w);
+    assert_eq!(w.len(), 1);
+    assert!(w[0].contains("negative"));
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn pretty_spec_order() {
+    use crate::{generate_pretty, pretty::GenerateOptions};
+
+    let spec = r#"
+const ZED = 1;
+struct b { int x; };
+typedef int a_syn;
+const ALPHA = 2;
+enum a { A1, A2 };
+"#;
+    let position = |out: &str, item: &str| out.find(item).unwrap_or_else(|| panic!("{} missing", item));
+
+    let sorted = generate_pretty(spec, &GenerateOptions::default()).unwrap();
+    assert!(position(&sorted, "const ALPHA") < position(&sorted, "const ZED"));
+    assert!(position(&sorted, "enum a") < position(&sorted, "struct b"));
+
+    let ordered = generate_pretty(spec, &GenerateOptions { spec_order: true, ..Default::default() }).unwrap();
+    println!("{}", ordered);
+    let items = ["const ZED", "struct b", "type a_syn", "const ALPHA", "enum a", "Pack<Out> for b", "Unpack<In> for b", "Pack<Out> for a"];
+    for pair in items.windows(2) {
+        assert!(position(&ordered, pair[0]) < position(&ordered, pair[1]), "{} before {}", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn map_annotation() {
+    let spec = r#"
+struct entry { string key<>; hyper value; };
+struct bag {
+    entry entries<16>;  /* @map counters by name */
+    entry hashed<>;     /* @hashmap */
+};
+"#;
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("entries : :: std :: collections :: BTreeMap < String , i64 >"));
+    assert!(out.contains("hashed : :: std :: collections :: HashMap < String , i64 >"));
+    assert!(out.contains("xdr_codec :: pack_map_with"));
+    assert!(out.contains("xdr_codec :: unpack_map_with"));
+    assert!(out.contains("counters by name"));
+    assert!(!out.contains("@map"));
+
+    let bad = "struct entry { int a; }; struct bag { entry e<>; /* @map */ };";
+    let g = generate_with_options("", Cursor::new(bad.as_bytes()), Vec::new(), &Default::default());
+    assert!(g.is_err());
+}
+
+#[test]
+fn annotation_comment_text() {
+    let spec = r#"
+struct entry { string key<>; hyper value; };
+struct bag {
+    entry entries<16>;  /* @map counters
+                           by name, mail admin@example.com */
+    int plain;          /* see @boxed */
+};
+"#;
+    let out = gen_with(spec, &Default::default());
+    // Only the leading annotations are taken out, and the rest is kept as written
+    assert!(out.contains(r#"# [doc = " counters\n                           by name, mail admin@example.com"]"#), "{}", out);
+    assert!(out.contains("BTreeMap < String , i64 >"), "{}", out);
+    assert!(out.contains(r#"# [doc = " see @boxed"] pub plain : i32"#), "{}", out);
+}
+
+#[test]
+fn enum_width_annotation() {
+    let spec = r#"
+enum wide { W0, W1 = 5 }; /* @hyper sent as 64 bits */
+enum narrow { N0, N1 };
+union u switch (wide w) {
+  case W1: int val;
+  case W0: void;
+};
+"#;
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("(* self as i64) . pack (__xdr_out)"));
+    assert!(out.contains("(* self as i32) . pack (__xdr_out)"));
+    assert!(out.contains("let (__xdr_e , __xdr_esz) : (i64 , _)"));
+    assert!(out.contains("(wide :: W1 as i64) . pack (__xdr_out)"));
+    assert!(out.contains("let (__xdr_v , __xdr_dsz) : (i64 , _)"));
+}
+
+#[cfg(feature = "instrument")]
+#[test]
+fn instrumented_unpack() {
+    let spec = "struct foo { int a; }; typedef int bar<>;";
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("DecodeSpan :: enter (stringify ! (foo))"));
+    assert!(out.contains("DecodeSpan :: enter (stringify ! (bar))"));
+    assert_eq!(out.matches("span . done").count(), 2);
+}
+
+#[cfg(feature = "tracing_spans")]
+#[test]
+fn tracing_spans() {
+    let spec = "struct foo { int a; }; enum bar { B = 1 };";
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    for span in &["pack foo", "unpack foo", "pack bar", "unpack bar"] {
+        assert!(out.contains(&format!("# [cfg (feature = \"tracing\")] let __xdr_span = tracing :: trace_span ! (\"{}\") . entered () ;", span)));
+    }
+}
+
+#[cfg(feature = "try_pack")]
+#[test]
+fn validate_impls() {
+    let spec = r#"
+const MAX = 4;
+struct foo { string name<MAX>; int vals<>; };
+union bar switch (int x) { case 1: foo f; default: void; };
+typedef opaque buf<16>;
+"#;
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("impl xdr_codec :: Validate for foo"));
+    assert!(out.contains("xdr_codec :: validate_len (\"foo.name\" , self . name . len () , Some (MAX as usize)) ?"));
+    assert!(out.contains("xdr_codec :: validate_len (\"foo.vals\" , self . vals . len () , None) ?"));
+    // Nor can the default arm's discriminant be a case's
+    assert!(out.contains("& bar :: Default (__xdr_disc) => { if [(1i64 as i32)] . contains (& __xdr_disc) {"), "{}", out);
+    assert!(out.contains("xdr_codec :: validate_len (\"buf\" , self . 0 . len () , Some (16i64 as usize)) ?"));
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn pretty_impls_with_defns() {
+    use crate::{generate_pretty, pretty::GenerateOptions};
+
+    let spec = "struct b { int x; }; const C = 1; enum a { A1, A2 };";
+    let position = |out: &str, item: &str| out.find(item).unwrap_or_else(|| panic!("{} missing", item));
+
+    let grouped = generate_pretty(spec, &GenerateOptions { impls_with_defns: true, ..Default::default() }).unwrap();
+    println!("{}", grouped);
+    let items = ["const C", "enum a", "Pack<Out> for a", "Unpack<In> for a", "struct b", "Pack<Out> for b", "Unpack<In> for b"];
+    for pair in items.windows(2) {
+        assert!(position(&grouped, pair[0]) < position(&grouped, pair[1]), "{} before {}", pair[0], pair[1]);
+    }
+
+    let ordered = generate_pretty(spec, &GenerateOptions { impls_with_defns: true, spec_order: true, ..Default::default() }).unwrap();
+    let items = ["struct b", "Pack<Out> for b", "Unpack<In> for b", "const C", "enum a", "Pack<Out> for a", "Unpack<In> for a"];
+    for pair in items.windows(2) {
+        assert!(position(&ordered, pair[0]) < position(&ordered, pair[1]), "{} before {}", pair[0], pair[1]);
+    }
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn pretty_headers_and_trailer() {
+    use crate::{generate_pretty, pretty::GenerateOptions};
+
+    let options = GenerateOptions {
+        generate: crate::GenerateOptions { rust_header: "#![allow(dead_code)]\nuse std::fmt;", ..Default::default() },
+        rust_headers: &["use std::io;", "#![allow(non_camel_case_types)]\nconst H: u32 = 1;"],
+        rust_trailer: "pub use self::foo as Foo;",
+        ..Default::default()
+    };
+    let out = generate_pretty("struct foo { int x; };", &options).unwrap();
+    println!("{}", out);
+
+    let position = |item: &str| out.find(item).unwrap_or_else(|| panic!("{} missing", item));
+    let items = ["#![allow(dead_code)]", "#![allow(non_camel_case_types)]", "use std::fmt;", "use std::io;", "const H: u32 = 1;", "pub struct foo", "Unpack<In> for foo", "pub use self::foo as Foo;"];
+    for pair in items.windows(2) {
+        assert!(position(pair[0]) < position(pair[1]), "{} before {}", pair[0], pair[1]);
+    }
+
+    let bad = GenerateOptions { rust_trailer: "fn (", ..Default::default() };
+    assert!(generate_pretty("const A = 1;", &bad).is_err());
+}
+
+#[cfg(feature = "consts_module")]
+#[test]
+fn consts_module() {
+    let spec = "const MAX = 10; enum color { RED = 1, GREEN }; enum type { A };";
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("pub mod consts { pub const MAX : i64 = 10i64 ; \
+        # [allow (non_snake_case)] pub mod color { pub const GREEN : i64 = 2i64 ; pub const RED : i64 = 1i64 ; } \
+        # [allow (non_snake_case)] pub mod type_ { pub const A : i64 = 0i64 ; } }"));
+}
+
+#[test]
+fn union_helpers() {
+    let spec = r#"
+enum kind { TEXT, DATA, EXEC };
+union filetype switch (kind k) {
+  case TEXT: void;
+  case DATA: string creator<>;
+  case EXEC: int pid;
+};
+union other switch (int x) { case 1: hyper h; default: filetype f; };
+union empty switch (int x) { case 1: void; };
+"#;
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("pub fn new_data (__xdr_val : String) -> Self { filetype :: DATA (__xdr_val) }"));
+    assert!(out.contains("pub fn as_exec (& self) -> Option < & i32 >"));
+    assert!(!out.contains("new_text"));
+    assert!(out.contains("pub fn new_const1 (__xdr_val : i64) -> Self"));
+    assert!(out.contains("pub fn as_default (& self) -> Option < & Box < filetype > >"));
+    // The default arm keeps its discriminant, to pack it again
+    assert!(out.contains("pub enum other { Const1 (i64) , Default (i32 , Box < filetype >) , }"), "{}", out);
+    assert!(out.contains("pub fn new_default (__xdr_disc : i32 , __xdr_val : Box < filetype >) -> Self"), "{}", out);
+    assert!(out.contains("& other :: Default (__xdr_disc , ref __xdr_val) => { if [(1i64 as i32)] . contains (& __xdr_disc) { \
+                          return Err (xdr_codec :: Error :: invalid_named_case (stringify ! (other) , __xdr_disc as i32)) ; } \
+                          __xdr_disc . pack (__xdr_out) ? +"), "{}", out);
+    assert!(out.contains("__xdr_v => other :: Default (__xdr_v ,"), "{}", out);
+    assert!(out.contains("pub fn into_exec (self) -> :: std :: result :: Result < i32 , Self >"), "{}", out);
+    assert!(out.contains("pub fn is_text (& self) -> bool { matches ! (self , filetype :: TEXT) }"), "{}", out);
+    assert!(out.contains("pub fn is_data (& self) -> bool { matches ! (self , filetype :: DATA (..)) }"), "{}", out);
+    // Void variants only get tests
+    assert!(out.contains("impl empty { pub fn is_const1 (& self) -> bool { matches ! (self , empty :: Const1) } }"), "{}", out);
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn helper_prefix() {
+    use crate::{generate_pretty, pretty::GenerateOptions, Error};
+
+    let spec = "union reply switch (int x) { case 1: hyper data; case 2: void; };";
+    let rust_trailer = "impl reply { pub fn new_const1(val: i64) -> Self { reply::Const1(val) } pub fn len(&self) -> usize { 8 } }";
+    let err = generate_pretty(spec, &GenerateOptions { rust_trailer, ..Default::default() }).unwrap_err();
+    match err.downcast_ref::<Error>() {
+        Some(Error::HelperClash { ty, method }) => assert_eq!((ty.as_str(), method.as_str()), ("reply", "new_const1")),
+        err => panic!("unexpected {:?}", err),
+    }
+
+    let out = generate_pretty(spec, &GenerateOptions {
+        generate: crate::GenerateOptions { helper_prefix: "xdr_", ..Default::default() },
+        rust_trailer,
+        ..Default::default()
+    }).unwrap();
+    assert!(out.contains("pub fn xdr_new_const1(__xdr_val: i64) -> Self"));
+    assert!(out.contains("pub fn xdr_as_const1(&self) -> Option<&i64>"));
+}
+
+#[test]
+fn only_with_deps() {
+    let spec = r#"
+const N = 4;
+enum color { RED = 0, GREEN = N };
+typedef int ids<N>;
+struct pt { int x; ids y; };
+struct other { color c; };
+union u switch (color c) { case GREEN: pt p; default: void; };
+"#;
+    let defns = specification(spec).expect("spec");
+
+    assert_eq!(super::dependencies(&defns, &["u"]).unwrap(), vec!["N", "color", "ids", "pt", "u"]);
+    assert_eq!(super::dependencies(&defns, &["other", "ids"]).unwrap(), vec!["N", "color", "ids", "other"]);
+    assert!(super::dependencies(&defns, &["nope"]).is_err());
+
+    let options = GenerateOptions { only: Some(&["pt"]), ..Default::default() };
+    let out = gen_with(spec, &options);
+    assert!(out.contains("pub struct pt"));
+    assert!(!out.contains("struct ids"));
+    assert!(!out.contains("pub const N"));
+}
+
+#[test]
+fn hostile_specs() {
+    use super::parse_fuzz;
+    use crate::{Error, Expected};
+
+    let nested = |n: usize, open: &str, close: &str, mid: &str| format!("{}{}{}", open.repeat(n), mid, close.repeat(n));
+    let nesting = |src: &str| match parse_fuzz(src.as_bytes()) {
+        Err(Error::Parse(perr)) => perr.expected == Expected::ShallowerNesting,
+        _ => false,
+    };
+
+    // Too deep for the parser's recursion
+    assert!(nesting(&format!("struct s {{ {} }};", nested(100_000, "struct { ", " } y;", "int z;"))));
+    assert!(nesting(&format!("union u switch (int k) {{ case {}: int a; }};", nested(100_000, "(", ")", "1"))));
+    assert!(parse_fuzz(format!("union u switch (int k) {{ case 1{}: int a; }};", "+1".repeat(100_000)).as_bytes()).is_err());
+    // Brackets in comments don't count
+    assert!(parse_fuzz(format!("/* {} */ const A = 1;", "(".repeat(1000)).as_bytes()).is_ok());
+
+    // Malformed, and once exponential to reject
+    assert!(parse_fuzz(format!("struct s {{ {} }};", nested(40, "struct { ", " y; }", "int z;")).as_bytes()).is_err());
+
+    assert!(parse_fuzz(b"const A = 99999999999999999999;").is_err());
+    assert!(parse_fuzz(b"const A = 0x10000000000000000;").is_err());
+    assert!(parse_fuzz(b"const A = -01000000000000000000000000;").is_err());
+    assert!(parse_fuzz(b"union u switch (void) { case 1: int a; };").is_err());
+    assert!(matches!(parse_fuzz(b"struct s { opaque a[0x7fffffffffffffff]; };"), Err(Error::InvalidArraySize { .. })));
+    assert!(parse_fuzz(b"struct s { int b<0x7fffffffffffffff>; };").is_ok());
+    assert!(parse_fuzz(b"const A = \xff;").is_err());
+}
+
+#[test]
+fn minimize_spec() {
+    let spec = r#"/*
+ * Header
+ */
+%#include <stuff.h>
+
+/* The size */
+const N = 4;
+
+/* colours */
+enum color { RED = 0, GREEN = N };
+typedef int ids<N>; // trailing on ids
+struct pt {
+    int x;  /* x coord */
+    ids y;
+};
+struct other { color c; };
+/* end */
+"#;
+
+    let source = super::specification_source(spec).expect("spec");
+    assert_eq!(source.preamble, "/*\n * Header\n */\n%#include <stuff.h>\n");
+    assert_eq!(source.trailer, "\n/* end */\n");
+    let text: String = source.defns.iter().map(|(_, text)| *text).collect();
+    assert_eq!(format!("{}{}{}", source.preamble, text, source.trailer), spec);
+
+    // Errors are those of a normal parse, whether a definition or what follows them is bad
+    for bad in ["const N = 4;\nstruct pt { int x };\n", "const N = 4;\n}\n"] {
+        let err = super::specification_source(bad).unwrap_err().to_string();
+        assert_eq!(err, specification(bad).unwrap_err().to_string());
+    }
+
+    let mut out = Vec::new();
+    super::super::minimize(Cursor::new(spec.as_bytes()), &mut out, &["pt"]).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        r#"/*
+ * Header
+ */
+%#include <stuff.h>
+
+/* The size */
+const N = 4;
+typedef int ids<N>; // trailing on ids
+struct pt {
+    int x;  /* x coord */
+    ids y;
+};
+"#
+    );
+}
+
+#[test]
+fn type_aliases() {
+    use super::TypeAliases;
+
+    let spec = r#"
+struct legacy { u_int a; u_char b; caddr_t c; uint64_t d<>; addr e; };
+struct u_long { int own; };
+struct uses { u_long x; };
+"#;
+    let gen = |aliases: &TypeAliases| {
+        let options = GenerateOptions { type_aliases: Some(aliases), ..Default::default() };
+        gen_with(spec, &options)
+    };
+
+    let mut aliases = TypeAliases::default();
+    aliases.add("addr", "opaque[16]").unwrap();
+    let out = gen(&aliases);
+    println!("{}", out);
+    assert!(out.contains("pub a : u32"));
+    assert!(out.contains("pub b : u32"));
+    assert!(out.contains("pub c : Vec < u8 >"));
+    assert!(out.contains("pub d : Vec < u64 >"));
+    assert!(out.contains("pub e : [u8 ; 16i64 as usize]"));
+    // The spec's own definition wins
+    assert!(out.contains("pub x : u_long"));
+
+    let out = gen(&TypeAliases::none());
+    assert!(out.contains("pub a : u_int"));
+
+    assert!(aliases.add("bad", "opaque<> x; const X = 1").is_err());
+    assert!(aliases.add("bad", "struct {").is_err());
+}
+
+#[test]
+fn custom_primitives() {
+    use super::{Derives, Primitive, Primitives};
+
+    let spec = r#"
+typedef string name<32>;
+typedef ext_t ext_alias;
+struct rec { string label<16>; name n; opaque blob<>; ext_t e; ext_alias a; int plain; };
+"#;
+    let mut primitives = Primitives::default();
+    primitives
+        .add(
+            "string",
+            Primitive {
+                derives: Derives::CLONE | Derives::DEBUG | Derives::EQ | Derives::PARTIALEQ,
+                pack: Some("xdr_codec::pack_string(&{value}, {max}, {out})".into()),
+                unpack: Some("xdr_codec::unpack_string({input}, {max}).map(|(s, sz)| (s.into(), sz))".into()),
+                ..Primitive::new("std::sync::Arc<str>")
+            },
+        )
+        .unwrap();
+    primitives
+        .add(
+            "ext_t",
+            Primitive { pack: Some("{value}.encode({out})".into()), unpack: Some("crate::Ext::decode({input})".into()), ..Primitive::new("crate::Ext") },
+        )
+        .unwrap();
+
+    let options = GenerateOptions { primitives: Some(&primitives), ..Default::default() };
+    let out = gen_with(spec, &options);
+    println!("{}", out);
+    assert!(out.contains("# [derive (Clone , Debug , Eq , PartialEq"));
+    assert!(out.contains("pub struct name (pub std :: sync :: Arc < str >) ;"));
+    assert!(out.contains("pub type ext_alias = crate :: Ext ;"));
+    assert!(out.contains("pub label : std :: sync :: Arc < str > , pub n : name , pub blob : Vec < u8 > , pub e : crate :: Ext , pub a : ext_alias ,"));
+    assert!(out.contains("(xdr_codec :: pack_string (& (self . label) , (Some (16i64 as usize)) , (__xdr_out))) ?"));
+    assert!(out.contains("(xdr_codec :: pack_string (& (self . 0) , (Some (32i64 as usize)) , (__xdr_out))) ?"));
+    assert!(out.contains("xdr_codec :: unpack_string ((__xdr_input) , (Some (32i64 as usize)))"));
+    assert!(out.contains("xdr_codec :: pack_opaque_flex (& self . blob , None , __xdr_out) ?"));
+    // Through the synonym too
+    assert!(out.contains("((self . e) . encode ((__xdr_out))) ?"));
+    assert!(out.contains("((self . a) . encode ((__xdr_out))) ?"));
+    assert!(out.contains("(crate :: Ext :: decode ((__xdr_input))) ?"));
+    // No derives from the external type
+    assert!(out.contains("\npub struct rec"));
+
+    assert!(primitives.add("string<>", Primitive::new("String")).is_err());
+    assert!(primitives.add("unsigned short", Primitive::new("u16")).is_err());
+    assert!(primitives.add("int", Primitive::new("Vec<(u8>")).is_err());
+    assert!(primitives.add("int", Primitive { pack: Some("({value}".into()), ..Primitive::new("i32") }).is_err());
+}
+
+#[test]
+fn xdr_strings() {
+    use super::{Primitive, Primitives};
+
+    let spec = "typedef string filename<255>; struct entry { filename name; string owner<>; filename links<>; };";
+    let gen = |xdr_type| {
+        let mut primitives = Primitives::default();
+        primitives.add(xdr_type, Primitive::xdr_string()).unwrap();
+        let options = GenerateOptions { primitives: Some(&primitives), ..Default::default() };
+        gen_with(spec, &options)
+    };
+
+    // Every string
+    let out = gen("string");
+    assert!(out.contains("pub struct filename (pub xdr_codec :: XdrString) ;"), "{}", out);
+    assert!(out.contains("pub owner : xdr_codec :: XdrString ,"), "{}", out);
+    assert!(out.contains("xdr_codec :: unpack_xdr_string ((__xdr_input) , (Some (255i64 as usize)))"), "{}", out);
+
+    // Only one type's
+    let out = gen("filename");
+    assert!(out.contains("pub name : xdr_codec :: XdrString , pub owner : String , pub links : Vec < xdr_codec :: XdrString > ,"), "{}", out);
+    // With the limit the spec gives it
+    assert!(out.contains("xdr_codec :: pack_opaque_flex (& (self . name) , (Some (255i64 as usize)) , (__xdr_out))"), "{}", out);
+}
+
+#[test]
+fn boxed_annotation() {
+    let spec = r#"
+struct big { opaque blob[4096]; };
+struct holder {
+    big inner;          /* @boxed kept off the stack */
+    int ids<8>;         /* @boxed */
+    int plain;
+};
+union u switch (int k) { case 1: big b; /* @boxed */ case 2: int x; /* @boxed */ };
+"#;
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("pub inner : Box < big >"));
+    assert!(out.contains("pub ids : Box < Vec < i32 > >"));
+    assert!(out.contains("pub plain : i32"));
+    // Not boxed twice where it would be anyway
+    assert!(out.contains("Const1 (Box < big >)"));
+    assert!(out.contains("Const2 (Box < i32 >)"));
+    assert!(out.contains("xdr_codec :: pack_flex (& (* self . ids) , Some (8i64 as usize) , __xdr_out)"));
+    assert!(out.contains("kept off the stack"));
+    assert!(!out.contains("@boxed"));
+}
+
+#[cfg(feature = "decode_context")]
+#[test]
+fn decode_context_unpack() {
+    let spec = "struct foo { int type; }; union bar switch (int x) { case 1: foo f; default: int d; };";
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("type_ : { let (__xdr_v , __xdr_fsz) = xdr_codec :: unpack_field (\"type\" , __xdr_sz , || Ok (xdr_codec :: Unpack :: unpack (__xdr_input) ?)) ? ;"));
+    assert!(out.contains("xdr_codec :: unpack_field (\"f\" , __xdr_sz ,"));
+    assert!(out.contains("xdr_codec :: unpack_field (\"d\" , __xdr_sz ,"));
+}
+
+#[test]
+fn message_envelope() {
+    use super::{message_envelope, Defn, Type, Value};
+    use crate::Error;
+
+    let spec = r#"
+struct ping { int seq; }; /* @message=0x10 */
+struct pong { int seq; }; // @message
+struct detail { int x; /* @message */ };
+enum status { OK, FAILED }; /* @message=3 sent alone */
+typedef string note<>; /* @message=7 */
+"#;
+    let defns = message_envelope(&specification_source(spec).unwrap()).unwrap();
+    assert_eq!(defns.iter().map(Defn::name).collect::<Vec<_>>(), vec!["MessageKind", "Message"]);
+    match &defns[0] {
+        Defn::Typespec(_, Type::Enum(kinds, _)) => assert_eq!(
+            kinds.iter().map(|k| (k.0.as_str(), k.1.clone())).collect::<Vec<_>>(),
+            vec![
+                ("ping", Some(Value::Const(16))),
+                ("pong", Some(Value::Const(17))),
+                ("status", Some(Value::Const(3))),
+                ("note", Some(Value::Const(7))),
+            ]
+        ),
+        defn => panic!("unexpected {:?}", defn),
+    }
+
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("pub enum MessageKind { # [default] ping = 16 , pong = 17 , status = 3 , note = 7 , }"));
+    assert!(out.contains("pub enum Message { ping (ping) , pong (pong) , status (status) , note (note) , }"));
+
+    assert!(message_envelope(&specification_source("struct a { int x; };").unwrap()).unwrap().is_empty());
+
+    let invalid = |spec: &str| match message_envelope(&specification_source(spec).unwrap()) {
+        Err(Error::InvalidMessage { problem, .. }) => problem,
+        res => panic!("unexpected {:?}", res),
+    };
+    assert_eq!(invalid("struct a { int x; }; /* @message=1 */\nstruct b { int x; }; /* @message=1 */"),
+               "discriminant 1 is already used by a");
+    assert_eq!(invalid("struct a { int x; }; /* @message=x */"), "bad discriminant \"x\"");
+    assert_eq!(invalid("const a = 1; /* @message */"), "only types can be messages");
+    assert_eq!(invalid("struct Message { int x; };\nstruct a { int x; }; /* @message */"), "name is needed for the message envelope");
+    // Without any messages, the names are free
+    let spec = specification_source("struct Message { int x; };\nenum MessageKind { A };").unwrap();
+    assert!(message_envelope(&spec).unwrap().is_empty());
+}
+
+#[test]
+fn timestamp_annotations() {
+    use crate::Error;
+
+    let spec = r#"
+struct timestamp { int64_t seconds; unsigned int nanos; }; /* @chrono */
+struct when { hyper secs; unsigned int ns; }; // @time
+struct event { timestamp at; when *until; };
+"#;
+    let out = gen_with(spec, &Default::default());
+    assert!(out.contains("pub type timestamp = xdr_codec :: timestamp :: ChronoDateTime ;"), "{}", out);
+    assert!(out.contains("pub type when = xdr_codec :: timestamp :: TimeDateTime ;"), "{}", out);
+    assert!(out.contains("# [derive (Clone , Debug , Eq , PartialEq"), "{}", out);
+    assert!(!out.contains("for timestamp") && !out.contains("for when"), "{}", out);
+
+    let invalid = |spec: &str| match generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default()) {
+        Err(Error::InvalidAnnotation { problem, .. }) => problem,
+        res => panic!("unexpected {:?}", res),
+    };
+    assert_eq!(invalid("struct t { hyper s; int ns; }; /* @chrono */"),
+               "timestamps must be a struct of hyper seconds and unsigned int nanoseconds");
+    assert_eq!(invalid("typedef hyper t; /* @time */"),
+               "timestamps must be a struct of hyper seconds and unsigned int nanoseconds");
+    assert_eq!(invalid("struct t { hyper s; unsigned int ns; }; /* @chrono @time */"),
+               "more than one of @chrono and @time");
+}
+
+#[test]
+fn spec_fingerprint() {
+    use crate::spec_fingerprint;
+
+    let fp = |spec: &str| spec_fingerprint(Cursor::new(spec.as_bytes())).unwrap();
+    let hex = |fp: [u8; 32]| fp.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+    let base = fp("const N = 4; struct foo { int a<N>; bar *next; }; enum bar { A, B = 2 };");
+    // Layout, comments, order and representation-only annotations don't matter
+    assert_eq!(base, fp("/* header */\nenum bar {\n  A,\n  B = 2\n};\nconst N = 4;\n\nstruct foo {\n  int a<N>; /* @boxed */\n  bar *next; // list\n};\n"));
+    // Pinned, so that the canonical form doesn't change by accident
+    assert_eq!(hex(base), "7a5d3aea2be550b85983da4376cc17187905b8196262196dec30fd9d7819f64c");
+
+    // Anything which changes the encoding does
+    assert_ne!(base, fp("const N = 5; struct foo { int a<N>; bar *next; }; enum bar { A, B = 2 };"));
+    assert_ne!(base, fp("const N = 4; struct foo { int a<N>; bar *next; }; enum bar { A, B = 3 };"));
+    assert_ne!(base, fp("const N = 4; struct foo { bar *next; int a<N>; }; enum bar { A, B = 2 };"));
+    assert_ne!(base, fp("const N = 4; struct foo { int a<N>; bar *next; }; enum bar { A, B = 2 }; /* @hyper */"));
+    assert_ne!(base, fp("const N = 4; struct foo { int a<N>; bar *next; }; /* @message */\nenum bar { A, B = 2 };"));
+}
+
+#[cfg(feature = "fingerprint_const")]
+#[test]
+fn fingerprint_const() {
+    let spec = "struct foo { int a; };";
+    let fp = crate::spec_fingerprint(Cursor::new(spec.as_bytes())).unwrap();
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    let bytes: Vec<_> = fp.iter().map(|b| format!("{}u8", b)).collect();
+    assert!(out.contains(&format!("pub const PROTOCOL_FINGERPRINT : [u8 ; 32] = [{}] ;", bytes.join(" , "))));
+}
+
+#[test]
+fn includes() {
+    use std::fs;
+    use crate::Error;
+
+    let dir = tempdir::TempDir::new("includes").unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    // Includes itself, which is ignored
+    fs::write(dir.path().join("common.x"), "#include \"sub/types.x\"\n#include \"common.x\"\nconst MAXNAME = 16;\n").unwrap();
+    fs::write(dir.path().join("sub/types.x"), "typedef string name<MAXNAME>;\n").unwrap();
+
+    let directives = super::Directives { include_dir: Some(dir.path()), ..Default::default() };
+    let main = "#include \"common.x\"\n#include <rpc/types.h>\nstruct entry { name n; };\n";
+    let defns = super::specification_with_directives(main, &directives).unwrap();
+    assert_eq!(defns.iter().map(super::Defn::name).collect::<Vec<_>>(), vec!["name", "MAXNAME", "entry"]);
+
+    let infile = dir.path().join("main.x");
+    let options = GenerateOptions { include_dir: Some(dir.path()), ..Default::default() };
+    let mut out = Vec::new();
+    crate::generate_with_options(infile.to_str().unwrap(), Cursor::new(main.as_bytes()), &mut out, &options).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("pub const MAXNAME : i64 = 16i64 ;"));
+    assert!(out.contains("pub struct name"));
+    assert!(out.contains("pub struct entry"));
+
+    // Only followed when asked to, even next to the spec
+    let mut out = Vec::new();
+    let main = "#include \"common.x\"\nstruct entry { int n; };\n";
+    generate_with_options(infile.to_str().unwrap(), Cursor::new(main.as_bytes()), &mut out, &Default::default()).unwrap();
+    assert!(!String::from_utf8(out).unwrap().contains("MAXNAME"));
+
+    // Nor outside the include directory
+    let outside = tempdir::TempDir::new("outside").unwrap();
+    let secret = outside.path().join("secret.x");
+    fs::write(&secret, "const SECRET = 1;\n").unwrap();
+    let escapes = vec![secret.display().to_string(), "../secret.x".to_string(), "sub/../../secret.x".to_string()];
+    #[cfg(unix)]
+    let escapes = {
+        std::os::unix::fs::symlink(&secret, dir.path().join("link.x")).unwrap();
+        [escapes, vec!["link.x".to_string()]].concat()
+    };
+    for file in escapes {
+        match super::specification_with_directives(&format!("#include \"{}\"\n", file), &directives) {
+            Err(Error::Include { source, .. }) => assert!(matches!(*source, Error::IncludeOutsideRoot { .. }), "{}", source),
+            res => panic!("{}: unexpected {:?}", file, res),
+        }
+    }
+
+    // Errors say which file they're from
+    fs::write(dir.path().join("bad.x"), "struct {").unwrap();
+    match super::specification_with_directives("#include \"common.x\"\n#include \"bad.x\"\n", &directives) {
+        Err(Error::Include { path, source }) => {
+            assert_eq!(path, dir.path().join("bad.x"));
+            assert!(source.parse_error().is_some());
+        }
+        res => panic!("unexpected {:?}", res),
+    }
+    match super::specification_with_directives("#include \"missing.x\"\n", &directives) {
+        Err(Error::Include { source, .. }) => assert!(matches!(*source, Error::IOError(_))),
+        res => panic!("unexpected {:?}", res),
+    }
+}
+
+#[test]
+fn defines() {
+    use std::collections::BTreeMap;
+
+    let src = r#"
+#define MAXNAME 32
+#if defined(EXTENDED) && VERSION >= 2
+struct entry { string name<MAXNAME>; unsigned hyper size; };
+#else
+struct entry { string name<MAXNAME>; };
+#endif
+"#;
+    let gen = |defines: &[(&str, &str)]| {
+        let defines: BTreeMap<_, _> = defines.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        let options = GenerateOptions { defines: Some(&defines), ..Default::default() };
+        gen_with(src, &options)
+    };
+
+    let out = gen(&[("EXTENDED", ""), ("VERSION", "2")]);
+    assert!(out.contains("pub const MAXNAME : i64 = 32i64 ;"));
+    assert!(out.contains("pub size : u64"));
+
+    let out = gen(&[("EXTENDED", ""), ("VERSION", "1")]);
+    assert!(!out.contains("pub size"));
+}
+
+#[test]
+fn quirks() {
+    let src = r#"
+const A = 1;;
+struct entry { long int size;; unsigned short int mode; };
+typedef struct entry entry;
+typedef int count;
+typedef unsigned count;
+namespace ns { typedef int count; typedef int count; };
+"#;
+    assert!(generate_with_options("quirks", Cursor::new(src.as_bytes()), Vec::new(), &Default::default()).is_err());
+
+    let out = gen_with(src, &GenerateOptions { quirks: true, ..Default::default() });
+    assert!(out.contains("pub size : i32 , pub mode : u32 ,"), "{}", out);
+    assert!(!out.contains("pub type entry"));
+    // The first definition stands
+    assert_eq!(out.matches("pub type count").count(), 2);
+    assert!(out.contains("pub type count = i32 ;"));
+}
+
+#[test]
+fn generate_options() {
+    use super::super::{ast::Defn, Derives};
+    use std::collections::BTreeMap;
+
+    let src = r#"
+#ifdef WIDE
+typedef hyper count;
+#else
+typedef int count;
+#endif
+struct entry { long int size;; count n; };
+struct other { int a; };
+"#;
+    let defines = BTreeMap::from([("WIDE".to_string(), String::new())]);
+    let options = GenerateOptions {
+        rust_header: "use std::fmt;",
+        only: Some(&["entry"]),
+        only_with_deps: true,
+        derives: Some(Derives::CLONE | Derives::DEBUG),
+        defines: Some(&defines),
+        quirks: true,
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    let generated = generate_with_options("options", Cursor::new(src.as_bytes()), &mut out, &options).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(generated.defns.iter().map(Defn::name).collect::<Vec<_>>(), ["count", "entry", "other"]);
+    assert!(generated.source_map.is_none());
+
+    let header = out.find("use std::fmt;").expect("header");
+    assert!(header > out.find("// GENERATED CODE").unwrap());
+    assert!(header < out.find("pub struct entry").unwrap());
+    assert!(out.contains("pub type count = i64 ;"), "{}", out);
+    assert!(!out.contains("other"));
+    assert!(!out.contains("Copy"));
+    assert!(out.contains("Clone"));
+}
+
+#[test]
+fn extra_derives() {
+    use super::super::Derives;
+
+    fn extra(name: &str) -> Vec<String> {
+        match name {
+            "key" => vec!["Hash".to_string(), "Debug".to_string()],
+            "request" => vec!["serde::Serialize".to_string()],
+            "bad" => vec!["Hash(".to_string()],
+            _ => Vec::new(),
+        }
+    }
+    let gen = |src: &str, derives| {
+        let options = GenerateOptions { derives, extra_derives: Some(extra), ..Default::default() };
+        let mut out = Vec::new();
+        generate_with_options("extra", Cursor::new(src.as_bytes()), &mut out, &options).map(|_| String::from_utf8(out).unwrap())
+    };
+
+    // The traits in the derive attribute before `pub struct name`
+    let derived = |out: &str, name: &str| -> Vec<String> {
+        let end = out.find(&format!("pub struct {} ", name)).unwrap();
+        match out[..end].rfind("# [derive (") {
+            Some(start) if !out[start..end].contains("pub ") => out[start + 11..end]
+                .split(")]").next().unwrap().split(',').map(|t| t.trim().to_string()).collect(),
+            _ => Vec::new(),
+        }
+    };
+
+    let src = "struct key { int id; }; struct request { key k; }; struct other { int a; };";
+    let out = gen(src, Some(Derives::CLONE | Derives::DEBUG)).unwrap();
+    let key = derived(&out, "key");
+    assert!(key.starts_with(&["Clone".to_string(), "Debug".to_string()]) && key.contains(&"Hash".to_string()), "{}", out);
+    // Debug isn't derived twice
+    assert_eq!(key.iter().filter(|t| *t == "Debug").count(), 1);
+    assert!(derived(&out, "request").contains(&"serde :: Serialize".to_string()), "{}", out);
+    assert!(!derived(&out, "other").contains(&"Hash".to_string()));
+    // Extra derives are added even to types which don't get any of the usual ones
+    let out = gen(src, Some(Derives::empty())).unwrap();
+    assert_eq!(derived(&out, "key")[..2], ["Hash", "Debug"]);
+    let err = gen("struct bad { int a; };", None).unwrap_err();
+    assert!(matches!(err, crate::Error::InvalidDerive { ref name, .. } if name == "bad"), "{}", err);
+}
+
+#[test]
+fn item_attrs() {
+    use super::super::RustItem;
+    use proc_macro2::TokenStream;
+
+    fn attrs(name: &str, kind: RustItem) -> Vec<TokenStream> {
+        match (name, kind) {
+            ("request", RustItem::Struct) => vec![quote!(#[serde(deny_unknown_fields)])],
+            (_, RustItem::Enum) => vec![quote!(#[non_exhaustive])],
+            ("LIMIT", RustItem::Const) => vec![quote!(#[doc(hidden)]), quote!(#[cfg(feature = "limits")])],
+            ("shade", RustItem::TypeAlias) => vec![quote!(#[allow(dead_code)])],
+            _ => Vec::new(),
+        }
+    }
+    let src = "const LIMIT = 4; enum color { RED }; union u switch (color c) { case RED: void; };\n\
+               struct request { int ids<LIMIT>; }; struct other { int a; }; typedef color shade;";
+    let options = GenerateOptions { item_attrs: Some(attrs), ..Default::default() };
+    let out = gen_with(src, &options);
+
+    assert!(out.contains("# [serde (deny_unknown_fields)] pub struct request"), "{}", out);
+    assert!(out.contains("# [non_exhaustive] pub enum color"), "{}", out);
+    assert!(out.contains("# [non_exhaustive] pub enum u"), "{}", out);
+    assert!(out.contains("# [doc (hidden)] # [cfg (feature = \"limits\")] pub const LIMIT"), "{}", out);
+    assert!(out.contains("# [allow (dead_code)] pub type shade"), "{}", out);
+    assert_eq!(out.matches("deny_unknown_fields").count(), 1);
+}
+
+#[test]
+fn rename() {
+    use std::collections::HashMap;
+
+    let src = "const NFS4_FHSIZE = 128;\n\
+               typedef opaque nfs_fh4<NFS4_FHSIZE>;\n\
+               enum ftype4 { NF4REG = 1, NF4DIR = 2 };\n\
+               union entry switch (ftype4 type) { case NF4REG: nfs_fh4 fh; case NF4DIR: void; };\n\
+               struct lookup { nfs_fh4 dir; entry e; };";
+    let rename: HashMap<_, _> = [
+        ("nfs_fh4", "FileHandle"),
+        ("NFS4_FHSIZE", "FHSIZE"),
+        ("NF4REG", "Regular"),
+        ("dir", "parent"),
+    ]
+    .iter()
+    .map(|&(from, to)| (from.to_string(), to.to_string()))
+    .collect();
+    let gen = |rename| {
+        let options = GenerateOptions { rename: Some(rename), ..Default::default() };
+        let mut out = Vec::new();
+        generate_with_options("rename", Cursor::new(src.as_bytes()), &mut out, &options).map(|_| String::from_utf8(out).unwrap())
+    };
+    let out = gen(&rename).unwrap();
+
+    assert!(out.contains("pub const FHSIZE : i64 = 128i64 ;"), "{}", out);
+    assert!(out.contains("pub struct FileHandle"), "{}", out);
+    // Only identifiers are renamed; messages, like validation errors, and doc aliases still use
+    // the spec's names
+    assert!(!out.contains("struct nfs_fh4") && !out.contains("NFS4_FHSIZE as"), "{}", out);
+    assert!(out.contains("# [doc (alias = \"NFS4_FHSIZE\")] pub const FHSIZE"), "{}", out);
+    assert!(out.contains("Regular = 1"), "{}", out);
+    assert!(out.contains("Regular (FileHandle)"), "{}", out);
+    assert!(out.contains("pub parent : FileHandle"), "{}", out);
+    // The wire format doesn't change
+    assert!(out.contains("Some (FHSIZE as usize)"), "{}", out);
+
+    let bad = HashMap::from([("dir".to_string(), "parent dir".to_string())]);
+    assert!(matches!(gen(&bad), Err(crate::Error::InvalidRename { .. })));
+}
+
+#[test]
+fn naming() {
+    use super::super::Naming;
+    use super::NameKind;
+    use std::collections::HashMap;
+
+    assert_eq!(NameKind::Type.spell("nfs_fh4"), "NfsFh4");
+    assert_eq!(NameKind::Variant.spell("NF4_REG"), "Nf4Reg");
+    assert_eq!(NameKind::Field.spell("dirHandle"), "dir_handle");
+    assert_eq!(NameKind::Module.spell("Inner_NS"), "inner_ns");
+    assert_eq!(NameKind::Const.spell("maxNameLen"), "MAX_NAME_LEN");
+
+    let src = "const maxLen = 4;\n\
+               enum ftype { NF4REG = 1, NF4DIR = 2 };\n\
+               union file_entry switch (ftype type) { case NF4REG: int size; case NF4DIR: void; };\n\
+               struct lookup_args { opaque dirHandle<maxLen>; file_entry entry; int count; };";
+    let rename = HashMap::from([("count".to_string(), "total".to_string())]);
+    let options = GenerateOptions { naming: Naming::Rust, rename: Some(&rename), ..Default::default() };
+    let out = gen_with(src, &options);
+
+    assert!(out.contains("# [doc (alias = \"maxLen\")] pub const MAX_LEN : i64 = 4i64 ;"), "{}", out);
+    assert!(out.contains("pub struct LookupArgs"), "{}", out);
+    assert!(out.contains("pub dir_handle : Vec < u8 >"), "{}", out);
+    // Names given explicitly win, and unchanged names get no alias
+    assert!(out.contains("pub total : i32"), "{}", out);
+    assert!(out.contains(", pub entry : FileEntry"), "{}", out);
+    assert!(out.contains("pub enum Ftype { # [doc (alias = \"NF4REG\")]"), "{}", out);
+    assert!(out.contains("Nf4reg = 1"), "{}", out);
+    assert!(out.contains("Nf4reg (i32)"), "{}", out);
+    assert!(out.contains("Some (MAX_LEN as usize)"), "{}", out);
+    assert!(!out.contains("lookup_args {"));
+    if cfg!(feature = "derive_serde") {
+        assert!(out.contains("# [serde (rename = \"dirHandle\")] pub dir_handle"), "{}", out);
+    } else {
+        assert!(!out.contains("serde"));
+    }
+}
+
+#[test]
+fn naming_clashes() {
+    use super::super::Naming;
+
+    let gen = |src: &str, naming| {
+        let options = GenerateOptions { naming, ..Default::default() };
+        generate_with_options("clashes", Cursor::new(src.as_bytes()), &mut Vec::new(), &options).map_err(|err| err.to_string())
+    };
+
+    let types = "struct foo_bar { int a; }; struct fooBar { int b; };";
+    assert!(gen(types, Naming::Spec).is_ok());
+    let err = gen(types, Naming::Rust).unwrap_err();
+    assert!(err.contains("fooBar and foo_bar are both FooBar in Rust"), "{}", err);
+
+    let fields = "struct s { int x_y; int xY; };";
+    let err = gen(fields, Naming::Rust).unwrap_err();
+    assert!(err.contains("s.x_y and s.xY are both x_y in Rust"), "{}", err);
+
+    let variants = "enum e { FOO_BAR = 1, FooBar = 2 };";
+    let err = gen(variants, Naming::Rust).unwrap_err();
+    assert!(err.contains("FOO_BAR and FooBar are both"), "{}", err);
+
+    // Types and constants are in different Rust namespaces, as are fields of different structs
+    assert!(gen("const ab = 1; struct AB { int ab; }; struct s { int ab; };", Naming::Rust).is_ok());
+}
+
+#[test]
+fn variant_prefixes() {
+    use super::super::{Naming, VariantPrefixes};
+    use std::collections::HashMap;
+
+    let src = "enum nfs_opnum4 { OP_ACCESS = 3, OP_CLOSE = 4 };\n\
+               enum versions { V_1 = 1, V_2 = 2 };\n\
+               enum nfsstat4 { NFS4_OK = 0, NFS4ERR_PERM = 1 };\n\
+               union nfs_argop4 switch (nfs_opnum4 argop) { case OP_ACCESS: int access; case OP_CLOSE: void; };\n\
+               struct sized { int a[OP_CLOSE]; };";
+    let gen = |variant_prefixes, naming| {
+        let options = GenerateOptions { variant_prefixes, naming, ..Default::default() };
+        gen_with(src, &options)
+    };
+
+    let out = gen(VariantPrefixes::Auto, Naming::Spec);
+    assert!(out.contains(" ACCESS = 3"), "{}", out);
+    assert!(out.contains(" ACCESS (i32)"), "{}", out);
+    assert_eq!(out.matches("# [doc (alias = \"OP_ACCESS\")]").count(), 2);
+    assert!(out.contains("[i32 ; nfs_opnum4 :: CLOSE as usize]"), "{}", out);
+    // Digits can't start a variant, and NFS4_ and NFS4ERR_ only share NFS4
+    assert!(out.contains("V_1 = 1"), "{}", out);
+    assert!(out.contains("NFS4_OK = 0 , NFS4ERR_PERM = 1"), "{}", out);
+
+    let given = HashMap::from([("nfsstat4".to_string(), "NFS4".to_string())]);
+    let out = gen(VariantPrefixes::Given(given), Naming::Rust);
+    assert!(out.contains("pub enum Nfsstat4 {"), "{}", out);
+    assert!(out.contains("Ok = 0"), "{}", out);
+    assert!(out.contains("ErrPerm = 1"), "{}", out);
+    assert!(out.contains("OpAccess = 3"), "{}", out);
+}
+
+#[test]
+fn enum_conversions() {
+    let src = "enum status { OK = 0, ERR = -1 }; enum wide { W0, W1 = 0x100000000 }; /* @hyper */";
+    let out = gen_with(src, &Default::default());
+
+    if !cfg!(feature = "reprc") {
+        assert!(out.contains("# [repr (i32)] pub enum status { # [default] OK = 0 , ERR = - 1 , }"), "{}", out);
+        assert!(out.contains("# [repr (i64)] pub enum wide { # [default] W0 = 0 , W1 = 4294967296 , }"), "{}", out);
+    }
+    assert!(out.contains("impl :: std :: convert :: TryFrom < i32 > for status"), "{}", out);
+    assert!(out.contains("- 1 => Ok (status :: ERR) ,"), "{}", out);
+    assert!(out.contains("impl From < status > for i32"), "{}", out);
+    assert!(out.contains("impl :: std :: convert :: TryFrom < i64 > for wide"), "{}", out);
+    assert!(out.contains("impl From < wide > for i64"), "{}", out);
+    // Unpacking goes through the conversion
+    assert!(out.contains("< status as :: std :: convert :: TryFrom < i32 >> :: try_from (__xdr_e) ?"), "{}", out);
+}
+
+#[test]
+fn no_std() {
+    let src = "enum color { RED, GREEN };
+               struct node { string name<>; opaque data<>; int vals[2]; node *next; };
+               union u switch (color c) { case RED: node n; case GREEN: void; };
+               struct entry { int k; int v; };
+               struct bag { entry m<>; /* @map */ };";
+    let gen = |src: &str, no_std| {
+        let options = GenerateOptions { no_std, ..Default::default() };
+        let mut out = Vec::new();
+        generate_with_options("nostd", Cursor::new(src.as_bytes()), &mut out, &options).map(|_| String::from_utf8(out).unwrap())
+    };
+
+    let out = gen(src, true).unwrap();
+    assert!(!out.contains(":: std ::"), "{}", out);
+    assert!(out.contains("pub name : :: alloc :: string :: String , pub data : :: alloc :: vec :: Vec < u8 > ,"), "{}", out);
+    assert!(out.contains("pub next : Option < :: alloc :: boxed :: Box < node >> ,"), "{}", out);
+    assert!(out.contains("impl :: core :: convert :: TryFrom < i32 > for color"), "{}", out);
+    assert!(out.contains("-> :: core :: result :: Result < node , Self >"), "{}", out);
+    assert!(out.contains("pub m : :: alloc :: collections :: BTreeMap < i32 , i32 > ,"), "{}", out);
+
+    assert!(gen(src, false).unwrap().contains("pub name : String , pub data : Vec < u8 > ,"));
+
+    let hashmap = "struct entry { int k; int v; }; struct bag { entry m<>; /* @hashmap */ };";
+    match gen(hashmap, true) {
+        Err(crate::Error::NoStdHashMap { .. }) => (),
+        res => panic!("unexpected {:?}", res),
+    }
+}
+
+#[test]
+fn safe_arrays() {
+    let src = "struct pt { int x; }; struct s { pt pts[3]; opaque tag[4]; };";
+    let gen = |safe_arrays, no_std| {
+        let options = GenerateOptions { safe_arrays, no_std, ..Default::default() };
+        gen_with(src, &options)
+    };
+
+    assert!(gen(false, false).contains("unsafe"));
+
+    let out = gen(true, false);
+    assert!(!out.contains("unsafe"), "{}", out);
+    assert!(out.contains("let mut __xdr_vec : Vec < pt > = Vec :: with_capacity"), "{}", out);
+    assert!(out.contains("try_unpack_opaque_array"), "{}", out);
+
+    let out = gen(true, true);
+    assert!(out.contains("let mut __xdr_vec : :: alloc :: vec :: Vec < pt > = :: alloc :: vec :: Vec :: with_capacity"), "{}", out);
+    assert!(out.contains(":: core :: convert :: TryFrom :: try_from (__xdr_vec)"), "{}", out);
+}
+
+#[test]
+fn large_array_derives() {
+    let src = "struct small { int v[32]; }; struct big { int v[33]; opaque tag[64]; };";
+    let gen = |large_array_derives| {
+        let options = GenerateOptions { large_array_derives, ..Default::default() };
+        let mut out = Vec::new();
+        generate_with_options("large", Cursor::new(src.as_bytes()), &mut out, &options).map(|_| String::from_utf8(out).unwrap())
+    };
+
+    let out = gen(false).unwrap();
+    assert!(out.contains("# [derive (Copy , Clone , Debug , Eq , PartialEq , Default"), "{}", out);
+    assert!(!out.contains(")] pub struct big"), "{}", out);
+
+    if cfg!(any(feature = "derive_serde", feature = "derive_json_schema")) {
+        // Which every type would derive, but large arrays don't implement
+        let err = gen(true).unwrap_err().to_string();
+        assert!(err.contains("large_array_derives can't be used with the derive_"), "{}", err);
+    } else {
+        let out = gen(true).unwrap();
+        assert!(out.contains("# [derive (Copy , Clone , Debug , Eq , PartialEq , Default"), "{}", out);
+        assert!(out.contains("# [derive (Copy , Clone , Debug , Eq , PartialEq)] pub struct big"), "{}", out);
+    }
+}
+
+#[test]
+fn keyword_escape() {
+    use super::KeywordEscape;
+
+    let src = "enum op { match = 1, loop = 2 };
+               struct s { int type; int self; op fn; };
+               union u switch (op o) { case match: int ref; case loop: void; };";
+    let gen = |keyword_escape| {
+        let options = GenerateOptions { keyword_escape, ..Default::default() };
+        gen_with(src, &options)
+    };
+
+    let out = gen(KeywordEscape::Underscore);
+    assert!(out.contains("pub struct s { pub type_ : i32 , pub self_ : i32 , pub fn_ : op , }"), "{}", out);
+
+    let out = gen(KeywordEscape::Raw);
+    assert!(out.contains("pub struct s { pub r#type : i32 , pub self_ : i32 , pub r#fn : op , }"), "{}", out);
+    assert!(out.contains("pub enum op { # [default] r#match = 1 , r#loop = 2 , }"), "{}", out);
+    assert!(out.contains("pub enum u { r#match (i32) , r#loop , }"), "{}", out);
+    assert!(out.contains("pub fn is_match (& self)"), "{}", out);
+}
+
+#[test]
+fn box_large_arms() {
+    let src = "struct pair { hyper a; hyper b; };
+               union msg switch (int d) { case 1: int n; case 2: opaque blob[4096]; case 3: pair p; default: pair q[8]; };";
+    let gen = |box_large_arms| {
+        let options = GenerateOptions { box_large_arms, ..Default::default() };
+        gen_with(src, &options)
+    };
+
+    let out = gen(None);
+    assert!(out.contains("pub enum msg { Const1 (i32) , Const2 ([u8 ; 4096i64 as usize]) , Const3 (pair) , Default (i32 , [pair ; 8i64 as usize]) , }"), "{}", out);
+
+    let out = gen(Some(64));
+    assert!(out.contains("pub enum msg { Const1 (i32) , Const2 (Box < [u8 ; 4096i64 as usize] >) , Const3 (pair) , Default (i32 , Box < [pair ; 8i64 as usize] >) , }"), "{}", out);
+    assert!(out.contains("Box :: new (__xdr_v)"), "{}", out);
+}
+
+#[test]
+fn impls_only() {
+    let src = "enum color { RED = 1, GREEN = 2 };
+               struct point { int x; color c; struct { int y; } inner; };
+               typedef point points<>;
+               struct other { point p; };";
+    let options = GenerateOptions { impls_only: &["color", "point", "points"], ..Default::default() };
+    let out = gen_with(src, &options);
+
+    for item in ["pub enum color", "pub struct point", "pub struct PointInner", "pub type points"] {
+        assert!(!out.contains(item), "{} in {}", item, out);
+    }
+    for item in ["pub struct other", "Pack < Out > for point", "Unpack < In > for point", "Unpack < In > for PointInner", "Pack < Out > for color"] {
+        assert!(out.contains(item), "{} missing from {}", item, out);
+    }
+    assert!(out.contains("impl :: std :: convert :: TryFrom < i32 > for color"), "{}", out);
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn pretty_impls_only() {
+    use crate::{generate_pretty, pretty::GenerateOptions};
+
+    let options = GenerateOptions {
+        generate: crate::GenerateOptions { impls_only: &["a", "b"], ..Default::default() },
+        ..Default::default()
+    };
+    let out = generate_pretty("struct b { int x; }; enum a { A1, A2 }; struct c { a v; };", &options).unwrap();
+    assert!(!out.contains("struct b"), "{}", out);
+    assert!(!out.contains("enum a"), "{}", out);
+    for item in ["struct c", "Pack<Out> for b", "Unpack<In> for a", "TryFrom<i32> for a"] {
+        assert!(out.contains(item), "{} missing from {}", item, out);
+    }
+}
+
+// The fields which moved to the shared options still have to work
+#[cfg(feature = "pretty")]
+#[test]
+#[allow(deprecated)]
+fn pretty_moved_options() {
+    use crate::{generate_pretty, pretty::GenerateOptions};
+
+    let options = GenerateOptions { rust_header: "type Extra = u8;", exclude_defs: &["skipped"], ..Default::default() };
+    let out = generate_pretty("struct point { int x; }; struct skipped { int y; };", &options).unwrap();
+    assert!(out.contains("type Extra = u8;") && out.contains("pub struct point") && !out.contains("skipped"), "{}", out);
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn pretty_module() {
+    use crate::{generate_pretty, pretty::GenerateOptions};
+
+    let options = GenerateOptions {
+        generate: crate::GenerateOptions {
+            rust_header: "#![allow(dead_code)]\ntype Extra = u8;",
+            module: Some("proto"),
+            module_imports: &["std::io"],
+            ..Default::default()
+        },
+        rust_trailer: "impl point { fn zero() -> Self { point { x: 0 } } }",
+        ..Default::default()
+    };
+    let out = generate_pretty("struct point { int x; };", &options).unwrap();
+    assert!(
+        out.starts_with("pub mod proto {\n    #![allow(dead_code)]\n    type Extra = u8;\n    #[allow(unused_imports)]\n    use xdr_codec;\n    use std::io;\n\n"),
+        "{}",
+        out
+    );
+    assert!(out.contains("    pub struct point {") && out.ends_with("    }\n}\n"), "{}", out);
+
+    let generate = crate::GenerateOptions { module: Some("proto"), module_imports: &["not a path"], ..Default::default() };
+    let bad = GenerateOptions { generate, ..Default::default() };
+    assert!(generate_pretty("struct point { int x; };", &bad).is_err());
+}
+
+#[test]
+fn module_imports() {
+    use super::is_use_tree;
+
+    for path in &["std::io", "::std::io", "crate::types::*", "std::fmt::{self, Write as _}", "a::{b::{c, *}, d,}", "x::y as z"] {
+        assert!(is_use_tree(path), "{}", path);
+    }
+    for path in &["not a path", "*", "std::", "std::io;", "a::{::b}", "a as b as c", "a::{b", "std::io::*::x"] {
+        assert!(!is_use_tree(path), "{}", path);
+    }
+
+    // Checked without the pretty printer too
+    let generate = GenerateOptions { module: Some("proto"), module_imports: &["std::io", "not a path"], ..Default::default() };
+    let err = generate_with_options("imports", Cursor::new(&b"struct point { int x; };"[..]), &mut Vec::new(), &generate).unwrap_err();
+    assert_eq!(err.to_string(), "module import not a path isn't a path to use");
+}
+
+#[test]
+fn types_only() {
+    let src = "enum color { RED = 1, GREEN = 2 };
+               struct point { int x; color c; opaque tag<>; point *next; };
+               union u switch (color c) { case RED: point p; default: void; };
+               struct packed { int x; }; /* @pack_only */";
+    let options = GenerateOptions { types_only: true, ..Default::default() };
+    let out = gen_with(src, &options);
+
+    assert!(!out.contains("xdr_codec"), "{}", out);
+    for item in ["pub enum color", "impl From < color > for i32", "pub struct point", "pub enum u", "pub struct packed"] {
+        assert!(out.contains(item), "{} missing from {}", item, out);
+    }
+}
+
+#[test]
+fn exclude_defs() {
+    let src = "enum color { RED = 1, GREEN = 2 };
+               typedef int id;
+               struct foo { int x; struct { int y; } inner; };
+               struct foobar { foo f; id i; color c; };";
+    let options = GenerateOptions { exclude_defs: &["color", "id", "foo"], ..Default::default() };
+    let out = gen_with(src, &options);
+
+    for item in ["enum color", "for color", "const RED", "type id", "struct foo {", "for foo {", "FooInner"] {
+        assert!(!out.contains(item), "{} in {}", item, out);
+    }
+    for item in ["pub struct foobar", "Pack < Out > for foobar", "Unpack < In > for foobar"] {
+        assert!(out.contains(item), "{} missing from {}", item, out);
+    }
+}
+
+#[test]
+fn name_patterns() {
+    let src = "struct nfs3_fh { int h; };
+               struct nfs3_attr { nfs3_fh fh; };
+               struct mount3_res { int r; };
+               struct nlm4_lock { int l; };";
+    let gen = |options: &GenerateOptions| {
+        let mut out = Vec::new();
+        generate_with_options("patterns", Cursor::new(src.as_bytes()), &mut out, options).map(|_| String::from_utf8(out).unwrap())
+    };
+
+    let out = gen(&GenerateOptions { exclude_defs: &["nfs3_*"], ..Default::default() }).unwrap();
+    assert!(!out.contains("nfs3_") && out.contains("pub struct mount3_res"), "{}", out);
+
+    let out = gen(&GenerateOptions { exclude_defs: &["/^(nfs|mount)3_/"], ..Default::default() }).unwrap();
+    assert!(!out.contains("nfs3_") && !out.contains("mount3_") && out.contains("pub struct nlm4_lock"), "{}", out);
+
+    let out = gen(&GenerateOptions { impls_only: &["nfs3_?h"], ..Default::default() }).unwrap();
+    assert!(!out.contains("pub struct nfs3_fh") && out.contains("Pack < Out > for nfs3_fh"), "{}", out);
+
+    let out = gen(&GenerateOptions { only: Some(&["*_attr"]), only_with_deps: true, ..Default::default() }).unwrap();
+    assert!(out.contains("pub struct nfs3_attr") && out.contains("pub struct nfs3_fh"), "{}", out);
+    assert!(!out.contains("mount3_res") && !out.contains("nlm4_lock"), "{}", out);
+
+    // Like a misspelt name
+    assert!(matches!(gen(&GenerateOptions { only: Some(&["nfs4_*"]), ..Default::default() }), Err(crate::Error::UnknownDefinition { .. })));
+    assert!(matches!(gen(&GenerateOptions { exclude_defs: &["/nfs3_(/"], ..Default::default() }), Err(crate::Error::InvalidPattern { .. })));
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn pretty_name_patterns() {
+    use crate::{generate_pretty, pretty::GenerateOptions};
+
+    let src = "struct nfs3_fh { int h; }; struct nfs3_attr { nfs3_fh fh; struct { int t; } time; }; struct nlm4_lock { int l; };";
+    let generate = crate::GenerateOptions { exclude_defs: &["*_attr"], impls_only: &["/fh$/"], ..Default::default() };
+    let options = GenerateOptions { generate, ..Default::default() };
+    let out = generate_pretty(src, &options).unwrap();
+    for item in ["struct nfs3_attr", "Nfs3AttrTime", "struct nfs3_fh"] {
+        assert!(!out.contains(item), "{} in {}", item, out);
+    }
+    for item in ["struct nlm4_lock", "Pack<Out> for nfs3_fh"] {
+        assert!(out.contains(item), "{} missing from {}", item, out);
+    }
+}
+
+#[test]
+fn visibility() {
+    use super::super::Visibility;
+
+    let src = "const N = 2;
+               struct point { int x; };
+               union shape switch (int k) { case 0: point p; };
+               namespace ns { struct inner { int v; }; namespace deeper { typedef int id; }; };";
+    let gen = |visibility| {
+        let options = GenerateOptions { visibility, ..Default::default() };
+        gen_with(src, &options)
+    };
+
+    let out = gen(Visibility::Crate);
+    for item in [
+        "pub (crate) const N : i64",
+        "pub (crate) struct point { pub (crate) x : i32 , }",
+        "pub (crate) fn new_const0",
+        "pub (crate) const PACKED_SIZE",
+        "pub (crate) mod ns",
+        "pub (crate) type id",
+    ] {
+        assert!(out.contains(item), "{} missing from {}", item, out);
+    }
+
+    // Items in namespaces can still be reached from where the code is generated
+    let out = gen(Visibility::Private);
+    assert!(!out.contains("pub struct") && !out.contains("pub (crate)"), "{}", out);
+    for item in [
+        "struct point { x : i32 , }",
+        "impl shape { fn is_const0",
+        "mod ns { # [allow (unused_imports)] use super :: * ;",
+        "pub (super) struct inner { pub (super) v : i32 , }",
+        "pub (super) mod deeper",
+        "pub (in super :: super) type id",
+    ] {
+        assert!(out.contains(item), "{} missing from {}", item, out);
+    }
+}
+
+#[test]
+fn lossless_enums() {
+    let src = "enum status { OK = 1, ERR = -1 };
+               union reply switch (status s) { case OK: int n; case ERR: void; };
+               struct counts { int c[OK]; };";
+    let options = GenerateOptions { lossless_enums: true, ..Default::default() };
+    let out = gen_with(src, &options);
+
+    assert!(out.contains("pub struct status (pub i32) ;"), "{}", out);
+    assert!(out.contains("pub const OK : status = status (1) ;"), "{}", out);
+    assert!(out.contains("pub fn is_known (& self) -> bool { matches ! (self . 0 , 1 | - 1) }"), "{}", out);
+    assert!(out.contains("impl From < i32 > for status"), "{}", out);
+    assert!(out.contains("impl From < status > for i32"), "{}", out);
+    assert!(!out.contains("TryFrom"), "{}", out);
+    // Defaults to a member, rather than 0
+    assert!(out.contains("impl Default for status { fn default () -> Self { status :: OK } }"), "{}", out);
+    assert!(out.contains("self . 0 . pack (__xdr_out) ?"), "{}", out);
+    assert!(out.contains("status (__xdr_e)"), "{}", out);
+    // Members are still usable as values
+    assert!(out.contains("(status :: OK . 0 as i32) . pack (__xdr_out) ?"), "{}", out);
+    assert!(out.contains("[i32 ; status :: OK . 0 as usize]"), "{}", out);
+}
+
+#[test]
+fn unknown_union_arms() {
+    let src = "union reply switch (int s) { case 0: int n; case 1: void; };
+               enum big { B0, B1 = 0x100000000 }; /* @hyper */
+               union wide switch (big b) { case B0: void; };
+               union other switch (int s) { case 0: void; default: int d; };";
+    let gen = |unknown_union_arms| {
+        let options = GenerateOptions { unknown_union_arms, ..Default::default() };
+        gen_with(src, &options)
+    };
+
+    let out = gen(true);
+    // With the rest of the input, as the arm's length isn't known
+    assert!(out.contains("pub enum reply { Const0 (i32) , Const1 , Unknown (i32 , Vec < u8 >) , }"), "{}", out);
+    assert!(out.contains("__xdr_v => reply :: Unknown (__xdr_v , { let (__xdr_v , __xdr_rsz) = xdr_codec :: unpack_rest (__xdr_input) ? ;"), "{}", out);
+    // Packing one of the cases' discriminants would unpack as that case
+    assert!(out.contains("& reply :: Unknown (__xdr_disc , ref __xdr_rest) => { if [(0i64 as i32) , (1i64 as i32)] . contains (& __xdr_disc) { \
+                          return Err (xdr_codec :: Error :: invalid_named_case (stringify ! (reply) , __xdr_disc as i32)) ; } \
+                          __xdr_disc . pack (__xdr_out) ? + xdr_codec :: pack_rest (__xdr_rest , __xdr_out) ? }"), "{}", out);
+    assert!(out.contains("pub fn new_unknown (__xdr_disc : i32 , __xdr_val : Vec < u8 >) -> Self"), "{}", out);
+    assert!(out.contains("pub enum wide { B0 , Unknown (i64 , Vec < u8 >) , }"), "{}", out);
+    assert!(!out.contains("impl reply { pub const"), "{}", out);
+    // A union's own default arm already takes them
+    assert!(!out.contains("other :: Unknown"), "{}", out);
+
+    assert!(!gen(false).contains("Unknown"));
+
+    // Nor can a case have the variant's name
+    let src = "enum kind { Known, Unknown }; union u switch (kind k) { case Known: void; case Unknown: int x; };";
+    let options = GenerateOptions { unknown_union_arms: true, ..Default::default() };
+    let err = generate_with_options("clash", Cursor::new(src.as_bytes()), &mut Vec::new(), &options).unwrap_err().to_string();
+    assert!(err.contains("u::Unknown and u's unknown arm are both Unknown in Rust"), "{}", err);
+    let src = "enum kind { Known, Default }; union u switch (kind k) { case Default: void; default: int x; };";
+    let err = generate_with_options("clash", Cursor::new(src.as_bytes()), &mut Vec::new(), &Default::default()).unwrap_err().to_string();
+    assert!(err.contains("u::Default and u's default arm are both Default in Rust"), "{}", err);
+}
+
+#[test]
+fn size_consts() {
+    let src = "const N = 3;
+               enum color { RED, GREEN };
+               struct point { int x; hyper y; color c; opaque tag[5]; unsigned char b; };
+               struct label { string name<N>; point *at; };
+               typedef int coords<2>;
+               union shape switch (int k) { case 0: point p; case 1: void; default: coords c; };
+               struct list { int v; list *next; };
+               struct open { string s<>; };
+               struct ext { other o; };
+               typedef int count;";
+    let out = gen_with(src, &Default::default());
+
+    assert!(out.contains("impl color { pub const PACKED_SIZE : usize = 4 ; pub const MAX_PACKED_SIZE : usize = 4 ; }"), "{}", out);
+    assert!(out.contains("impl point { pub const PACKED_SIZE : usize = 28 ; pub const MAX_PACKED_SIZE : usize = 28 ; }"), "{}", out);
+    assert!(out.contains("impl label { pub const MAX_PACKED_SIZE : usize = 40 ; }"), "{}", out);
+    assert!(out.contains("impl coords { pub const MAX_PACKED_SIZE : usize = 12 ; }"), "{}", out);
+    assert!(out.contains("impl shape { pub const MAX_PACKED_SIZE : usize = 32 ; }"), "{}", out);
+    // Unbounded, or can't be known
+    for name in &["list", "open", "ext", "count"] {
+        assert!(!out.contains(&format!("impl {} {{ pub const", name)), "{}", out);
+    }
+
+    // `PackedSize` gives the fixed size rather than measuring
+    assert!(out.contains("impl xdr_codec :: PackedSize for point { # [inline] fn packed_size (& self) -> xdr_codec :: Result < usize > { if xdr_codec :: standard_sizes () { Ok (Self :: PACKED_SIZE) }"), "{}", out);
+    for name in &["label", "coords", "shape", "list", "open", "ext"] {
+        assert!(out.contains(&format!("impl xdr_codec :: PackedSize for {} {{ }}", name)), "{}", out);
+    }
+}
+
+#[test]
+fn default_impls() {
+    use super::super::Derives;
+
+    let src = "enum color { RED = 1, GREEN = 0 }; enum level { LOW = 2, HIGH = 3 };
+               union u switch (int k) { case 0: int a; default: void; };
+               struct plain { color c; int n[4]; opaque o[8]; string s<>; point *p; double d; };
+               struct point { int x; };
+               struct tagged { int a; u b; };";
+    let gen = |derives| {
+        let options = GenerateOptions { derives, ..Default::default() };
+        gen_with(src, &options)
+    };
+    // Whether `Default` is derived for the item declared as `decl`
+    let defaulted = |out: &str, decl: &str| {
+        let end = out.find(decl).unwrap();
+        let start = out[..end].rfind("# [derive (").unwrap();
+        out[start..end].split(")]").next().unwrap().contains("Default")
+    };
+
+    let out = gen(None);
+    assert!(out.contains("pub enum color { RED = 1 , # [default] GREEN = 0 , }"), "{}", out);
+    assert!(out.contains("pub enum level { # [default] LOW = 2 , HIGH = 3 , }"), "{}", out);
+    assert!(defaulted(&out, "pub struct plain "), "{}", out);
+    assert!(!defaulted(&out, "pub enum u "), "{}", out);
+    assert!(!defaulted(&out, "pub struct tagged "), "{}", out);
+
+    // Left out on request, and with it the enums' default members
+    let out = gen(Some(Derives::all() & !Derives::DEFAULT));
+    assert!(!out.contains("PartialEq , Default"), "{}", out);
+    assert!(!out.contains("# [default]"), "{}", out);
+}
+
+#[test]
+fn default_annotations() {
+    use super::super::Derives;
+
+    let gen = |src: &str, derives| {
+        let options = GenerateOptions { derives, ..Default::default() };
+        let mut out = Vec::new();
+        generate_with_options("defaults", Cursor::new(src.as_bytes()), &mut out, &options).map(|_| String::from_utf8(out).unwrap())
+    };
+
+    let src = "enum color { RED = 1, GREEN = 2 }; const LIMIT = 10; typedef int count;
+               struct s {
+                   int a; /* @default(LIMIT * 2) added in v2 */
+                   bool b; /* @default(TRUE) */
+                   color c; /* @default(GREEN) */
+                   count d; /* @default(-3) */
+                   double e; /* @default( 4 ) */
+                   string f<>;
+               };";
+    let out = gen(src, None).unwrap();
+    assert!(out.contains("impl Default for s { fn default () -> Self { s { a : 20 , b : true , c : color :: GREEN , d : - 3 , \
+                          e : 4.0 , f : Default :: default () , } } }"), "{}", out);
+    assert!(out.contains("# [doc = \" added in v2\"] pub a : i32"), "{}", out);
+    // Implemented rather than derived
+    let start = out.find("pub struct s ").unwrap();
+    assert!(!out[out[..start].rfind("# [derive (").unwrap()..start].contains("Default"), "{}", out);
+
+    // Rejected rather than ignored
+    let err = |src: &str, derives| gen(src, derives).unwrap_err().to_string();
+    let wrapped = |decl: &str| format!("enum color {{ RED = 1, GREEN = 2 }}; struct s {{ {} }};", decl);
+    assert_eq!(err(&wrapped("int a; /* @default(4294967296) */"), None),
+               "invalid annotation on s.a: @default(4294967296) isn't a value of the field's type");
+    assert_eq!(err(&wrapped("unsigned a; /* @default(-1) */"), None),
+               "invalid annotation on s.a: @default(-1) isn't a value of the field's type");
+    assert_eq!(err(&wrapped("bool a; /* @default(2) */"), None),
+               "invalid annotation on s.a: @default(2) isn't a value of the field's type");
+    assert_eq!(err(&wrapped("color a; /* @default(UNDEFINED) */"), None),
+               "invalid annotation on s.a: @default(UNDEFINED) isn't a value of the field's type");
+    assert_eq!(err(&wrapped("string a<>; /* @default(1) */"), None),
+               "invalid annotation on s.a: only bool, number and enum fields can have a @default");
+    assert!(err(&wrapped("int a; /* @default(1 +) */"), None).starts_with("invalid annotation on s.a: @default(1 +): "));
+    assert_eq!(err(&wrapped("int a; /* @default(1) */"), Some(Derives::all() & !Derives::DEFAULT)),
+               "invalid annotation on s: @default needs the struct to derive Default, with Default in the derives");
+    assert_eq!(err("union u switch (int k) { case 0: int a; /* @default(1) */ default: void; };", None),
+               "invalid annotation on u.a: only struct fields can have a @default");
+}
+
+#[test]
+fn windows_input() {
+    // A byte order mark, CRLF, a cp1252 comment and a non-breaking space
+    let src = b"\xef\xbb\xbf/* caf\xe9 */\r\nconst A = 1;\r\n// point\r\nstruct point {\xc2\xa0int x; };\r\n";
+
+    let mut out = Vec::new();
+    generate_with_options("windows", Cursor::new(&src[..]), &mut out, &Default::default()).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("pub const A : i64 = 1i64 ;"), "{}", out);
+    assert!(out.contains("# [doc = \" point\"]"), "{}", out);
+    assert!(!out.contains('\r'));
+
+    #[cfg(feature = "pretty")]
+    {
+        use crate::{generate_pretty, pretty::GenerateOptions};
+
+        let src = String::from_utf8_lossy(src);
+        let options = GenerateOptions { xdr_header: "\u{feff}const B = 2;\r\n", ..Default::default() };
+        let out = generate_pretty(&src, &options).unwrap();
+        assert!(out.contains("pub const A: i64 = 1i64;"), "{}", out);
+        assert!(out.contains("/// point\n"), "{}", out);
+    }
+}
+
+#[test]
+fn lint() {
+    use super::{LintConfig, LintLevel, LintRule};
+
+    let s = specification(
+        r#"
+enum color { RED = 1, GREEN, BLUE = 4 };
+struct entry {
+    string name<>;
+    opaque data<16>;
+    union switch (int kind) { case 1: int list<>; default: void; } u;
+};
+typedef struct { int x; } point;
+namespace ns { typedef int list<>; }
+"#,
+    ).unwrap();
+
+    let mut config = LintConfig::default();
+    config.set(LintRule::UnboundedFlex, LintLevel::Deny).set(LintRule::ImplicitEnumValue, LintLevel::Allow);
+    let lints: Vec<_> = super::lint(&s, &config).iter().map(ToString::to_string).collect();
+    assert_eq!(
+        lints,
+        vec![
+            "error[unbounded-flex]: entry.name: variable length type has no maximum size",
+            "warning[anonymous-type]: entry.u: type should be a named definition",
+            "error[unbounded-flex]: entry.u.list: variable length type has no maximum size",
+            "error[unbounded-flex]: ns::list: variable length type has no maximum size",
+        ]
+    );
+
+    let lints = super::lint(&s, &LintConfig::default());
+    assert_eq!(lints.iter().filter(|lint| lint.rule == LintRule::ImplicitEnumValue).map(|lint| &lint.path).collect::<Vec<_>>(), vec!["color.GREEN"]);
+}
+
+#[test]
+fn codecs() {
+    use super::{codec_annotations, Codecs};
+    use crate::Error;
+
+    let src = "struct a { int x; }; /* @pack_only */\nstruct b { a y; }; /* @unpack_only docs */\nstruct c { int z; };\n";
+    let annotated = codec_annotations(&specification_source(src).unwrap()).unwrap();
+    assert_eq!(annotated.get("a"), Some(&Codecs::PackOnly));
+    assert_eq!(annotated.get("b"), Some(&Codecs::UnpackOnly));
+    assert_eq!(annotated.get("c"), None);
+
+    // Annotations override the default
+    let options = GenerateOptions { codecs: Codecs::UnpackOnly, ..Default::default() };
+    let out = gen_with(src, &options);
+    assert!(out.contains("Pack < Out > for a"));
+    assert!(!out.contains("Unpack < In > for a"));
+    assert!(!out.contains("Pack < Out > for c"));
+    assert!(out.contains("Unpack < In > for c"));
+
+    let err = |src| match codec_annotations(&specification_source(src).unwrap()) {
+        Err(Error::InvalidAnnotation { name, .. }) => name,
+        res => panic!("unexpected {:?}", res),
+    };
+    assert_eq!(err("const A = 1; /* @pack_only */\n"), "A");
+    assert_eq!(err("struct a { int x; }; /* @pack_only @unpack_only */\n"), "a");
+}
+
+#[test]
+#[cfg(not(feature = "float"))]
+fn float_disabled() {
+    use crate::Error;
+
+    for src in &["struct a { int x; double y; };", "typedef float f<4>;", "union u switch (int k) { case 1: float v; };"] {
+        let mut out = Vec::new();
+        match generate_with_options("float", Cursor::new(src.as_bytes()), &mut out, &Default::default()) {
+            Err(Error::FloatDisabled { .. }) => (),
+            res => panic!("unexpected {:?} for {}", res, src),
+        }
+    }
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn pretty_size_constants() {
+    use crate::{generate_pretty, pretty::GenerateOptions, Error};
+
+    let options = GenerateOptions {
+        generate: crate::GenerateOptions { rust_header: "const RUST_MAX: usize = 4;", ..Default::default() },
+        xdr_header: "const MAXNAME = 32;",
+        ..Default::default()
+    };
+    let spec = "const LOCAL = 2; struct a { string name<MAXNAME>; int x[LOCAL]; int y<RUST_MAX>; };";
+    generate_pretty(spec, &options).unwrap();
+
+    let spec = "struct a { string name<MAXNAM>; union switch (int k) { case 1: int v<maxname>; } u; };\ntypedef opaque b[NOPE];";
+    let err = generate_pretty(spec, &options).unwrap_err();
+    let err = err.downcast_ref::<Error>().unwrap();
+    assert_eq!(
+        err.errors().iter().map(|err| err.to_string()).collect::<Vec<_>>(),
+        vec![
+            "AU uses undefined constant maxname; did you mean MAXNAME from the XDR header?",
+            "a uses undefined constant MAXNAM; did you mean MAXNAME from the XDR header?",
+            "b uses undefined constant NOPE",
+        ]
+    );
+
+    // Imported constants count, and a glob import may provide any
+    let rust_header = "use crate::limits::{MAXLEN, keys::MAXKEY as KEYMAX};";
+    let options = GenerateOptions { generate: crate::GenerateOptions { rust_header, ..Default::default() }, ..Default::default() };
+    generate_pretty("struct a { string name<MAXLEN>; opaque key[KEYMAX]; };", &options).unwrap();
+    assert!(generate_pretty("struct a { string name<NOPE>; };", &options).is_err());
+    let rust_header = "use crate::limits::*;";
+    let options = GenerateOptions { generate: crate::GenerateOptions { rust_header, ..Default::default() }, ..Default::default() };
+    generate_pretty("struct a { string name<NOPE>; };", &options).unwrap();
+}
+
+#[cfg(feature = "enum_member_consts")]
+#[test]
+fn enum_member_consts() {
+    let spec = "const MAX = 10; enum color { RED = 1, GREEN = 2 }; enum shape { SQUARE = 4 };";
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    for item in &["pub const MAX : i64 = 10i64 ;", "pub const color_RED : i64 = 1i64 ;", "pub const color_GREEN : i64 = 2i64 ;", "pub const shape_SQUARE : i64 = 4i64 ;"] {
+        assert!(out.contains(item), "missing {}", item);
+    }
+
+    // Members go with their enum
+    let options = GenerateOptions { only: Some(&["color"]), ..Default::default() };
+    let out = gen_with(spec, &options);
+    assert!(out.contains("color_RED") && !out.contains("shape_SQUARE") && !out.contains("const MAX :"));
+}
+
+#[cfg(all(feature = "enum_member_consts", feature = "pretty"))]
+#[test]
+fn pretty_enum_member_consts() {
+    use crate::{generate_pretty, pretty::GenerateOptions};
+
+    let spec = "enum color { RED = 1 }; const MAX = 10;";
+    let out = generate_pretty(spec, &GenerateOptions { spec_order: true, ..Default::default() }).unwrap();
+    println!("{}", out);
+    let position = |item: &str| out.find(item).unwrap_or_else(|| panic!("{} missing", item));
+    assert!(position("pub enum color") < position("pub const color_RED: i64 = 1i64;"));
+    assert!(position("pub const color_RED: i64 = 1i64;") < position("pub const MAX: i64 = 10i64;"));
+
+    let generate = crate::GenerateOptions { exclude_defs: &["color"], ..Default::default() };
+    let out = generate_pretty(spec, &GenerateOptions { generate, ..Default::default() }).unwrap();
+    assert!(!out.contains("color_RED"));
+
+    let generate = crate::GenerateOptions { only: Some(&["color"]), ..Default::default() };
+    let out = generate_pretty(spec, &GenerateOptions { generate, ..Default::default() }).unwrap();
+    assert!(out.contains("pub enum color") && out.contains("color_RED") && !out.contains("const MAX:"), "{}", out);
+}
+
+#[test]
+fn eval_constants() {
+    use crate::Symtab;
+
+    let symtab = Symtab::from_spec(
+        r#"
+const MAXNAME = 64;
+enum color { RED = 1, GREEN, BLUE = MAXNAME };
+namespace proto { const VERSION = 3; };
+"#,
+    ).unwrap();
+
+    assert_eq!(symtab.eval_expr("MAXNAME").unwrap(), Some(64));
+    assert_eq!(symtab.eval_expr("GREEN").unwrap(), Some(2));
+    assert_eq!(symtab.eval_expr("(MAXNAME + BLUE) * proto::VERSION").unwrap(), Some(384));
+    assert_eq!(symtab.eval_expr("MAXNAME * UNDEFINED").unwrap(), None);
+    assert!(symtab.eval_expr("MAXNAME +").is_err());
+}
+
+#[test]
+fn symtab_queries() {
+    use super::{Decl, Type};
+    use crate::Symtab;
+
+    let spec = r#"
+enum color { RED = 1, GREEN, BLUE = 8 };
+typedef color shade;
+typedef shade tint;
+typedef opaque blob<>;
+struct pair { int a; };
+typedef external ext;
+namespace ns { enum version { V1 = 1, V2 }; };
+"#;
+    let symtab = Symtab::from_spec(spec).unwrap();
+
+    assert_eq!(symtab.constant("GREEN"), Some(2));
+    assert_eq!(symtab.constant("ns::V2"), Some(2));
+    assert_eq!(symtab.constant("pair"), None);
+    assert_eq!(symtab.enum_of("BLUE"), Some("color"));
+    assert_eq!(symtab.enum_of("ns::V1"), Some("ns::version"));
+
+    assert_eq!(symtab.enum_members("color"), Some(vec![("RED", 1), ("GREEN", 2), ("BLUE", 8)]));
+    assert_eq!(symtab.enum_members("ns::version"), Some(vec![("ns::V1", 1), ("ns::V2", 2)]));
+    assert_eq!(symtab.enum_members("pair"), None);
+
+    assert!(matches!(symtab.resolve_type("tint"), Some(Type::Enum(members, _)) if members.len() == 3));
+    assert_eq!(symtab.resolve_type("pair"), Some(&Type::Struct(vec![Decl::named("a", Type::Int)])));
+    assert_eq!(symtab.resolve_type("blob"), Some(&Type::Flex(Box::new(Type::Opaque), None)));
+    assert_eq!(symtab.resolve_type("ext"), None);
+}
+
+#[test]
+fn type_dependencies() {
+    use crate::Symtab;
+
+    let spec = r#"
+enum kind { FILE, DIR };
+struct attr { hyper size; };
+typedef attr attrs<>;
+struct entry { string name<>; entry *next; };
+union node switch (kind k) { case FILE: attrs a; case DIR: entry *first; };
+struct message { node root; external e; };
+struct unrelated { int x; };
+"#;
+    let deps = Symtab::from_spec(spec).unwrap().dependencies();
+
+    assert_eq!(deps.references("message").collect::<Vec<_>>(), ["node"]);
+    assert_eq!(deps.references("node").collect::<Vec<_>>(), ["attrs", "entry", "kind"]);
+    assert_eq!(deps.references("attrs").collect::<Vec<_>>(), ["attr"]);
+    assert_eq!(deps.referrers("entry").collect::<Vec<_>>(), ["entry", "node"]);
+    assert_eq!(
+        deps.reachable(["message"]).into_iter().collect::<Vec<_>>(),
+        ["attr", "attrs", "entry", "kind", "message", "node"]
+    );
+    assert!(deps.reachable(["external"]).is_empty());
+    assert_eq!(deps.types().count(), 7);
+
+    // An enum's members need the enum, as `only_with_deps` has it
+    let spec = "enum size { SMALL = 2, LARGE = 8 }; struct block { opaque data[LARGE]; }; struct other { int x; };";
+    let deps = Symtab::from_spec(spec).unwrap().dependencies();
+    assert_eq!(deps.references("block").collect::<Vec<_>>(), ["size"]);
+    let defns = specification(spec).unwrap();
+    let needed = super::dependencies(&defns, &["block"]).unwrap();
+    assert_eq!(deps.reachable(["block"]), needed.into_iter().collect());
+}
+
+#[test]
+fn lift_anonymous() {
+    use super::{lift_anonymous, Decl, Defn, Type};
+    use crate::Error;
+
+    let mut defns = specification("struct parent { int id; struct { int x; } *next_val; };").unwrap();
+    let owners = lift_anonymous(&mut defns).unwrap();
+    assert_eq!(owners.get("ParentNextVal").map(String::as_str), Some("parent"));
+    assert_eq!(defns.iter().map(Defn::name).collect::<Vec<_>>(), ["ParentNextVal", "parent"]);
+    match &defns[1] {
+        Defn::Typespec(_, Type::Struct(decls)) => {
+            assert_eq!(decls[1], Decl::named("next_val", Type::Option(Box::new(Type::ident("ParentNextVal")))))
+        }
+        defn => panic!("unexpected {:?}", defn),
+    }
+
+    let mut defns = specification("typedef int ParentPoint; struct parent { struct { int x; } point; };").unwrap();
+    match lift_anonymous(&mut defns) {
+        Err(Error::AnonymousTypeName { field, name }) => assert_eq!((field.as_str(), name.as_str()), ("point", "ParentPoint")),
+        res => panic!("unexpected {:?}", res),
+    }
+
+    // Selecting a definition also generates the types lifted out of it
+    let spec = "struct parent { struct { int x; } point; }; struct other { int y; };";
+    let options = GenerateOptions { only: Some(&["parent"]), ..Default::default() };
+    let out = gen_with(spec, &options);
+    assert!(out.contains("pub struct ParentPoint") && !out.contains("pub struct other"));
+}
+
+#[test]
+fn optional_unions() {
+    use super::{optional_unions, Decl, Defn, Type};
+
+    let spec = r#"
+        union maybe switch (bool present) { case TRUE: int val; case FALSE: void; };
+        struct holder {
+            union switch (bool present) { case FALSE: void; case TRUE: maybe inner; } next;
+            union switch (bool present) { case TRUE: int val; default: void; } other;
+        };
+    "#;
+    let mut defns = specification(spec).unwrap();
+    optional_unions(&mut defns);
+    assert_eq!(defns[0], Defn::Typesyn("maybe".into(), Type::Option(Box::new(Type::Int))));
+    match &defns[1] {
+        Defn::Typespec(_, Type::Struct(decls)) => {
+            assert_eq!(decls[0], Decl::named("next", Type::Option(Box::new(Type::ident("maybe")))));
+            assert!(matches!(&decls[1], Decl::Named(_, Type::Union(..), ..)));
+        }
+        defn => panic!("unexpected {:?}", defn),
+    }
+
+    let spec = r#"
+        union maybe switch (bool present) { case TRUE: int val; case FALSE: void; };
+        struct holder { union switch (bool present) { case FALSE: void; case TRUE: maybe inner; } next; };
+    "#;
+    let gen = |optional_unions| {
+        let options = GenerateOptions { optional_unions, ..Default::default() };
+        gen_with(spec, &options)
+    };
+    let out = gen(true);
+    assert!(out.contains("pub type maybe = Option < i32 > ;"), "{}", out);
+    assert!(out.contains("pub next : Option < maybe > ,"), "{}", out);
+    // Only when asked for, as it changes the types
+    let out = gen(false);
+    assert!(out.contains("pub enum maybe { TRUE (i32) , FALSE , }"), "{}", out);
+    assert!(out.contains("pub next : HolderNext ,"), "{}", out);
+}
+
+#[test]
+fn bool_labels() {
+    use crate::ast::{Defn, Type, Value};
+    use crate::{generate_with_options, BoolLabels, GenerateOptions};
+
+    let gen = |spec: &str, bool_labels| {
+        let mut out = Vec::new();
+        let options = GenerateOptions { bool_labels, ..Default::default() };
+        generate_with_options("", Cursor::new(spec.as_bytes()), &mut out, &options).map(|_| String::from_utf8(out).unwrap())
+    };
+
+    // However they're spelt, the cases are TRUE and FALSE, matched as bools
+    for (yes, no) in [("TRUE", "FALSE"), ("true", "false"), ("1", "0")] {
+        let spec = format!("union u switch (bool b) {{ case {}: int a; case {}: hyper c; }};", yes, no);
+        let out = gen(&spec, BoolLabels::Spellings).unwrap();
+        assert!(out.contains("TRUE (i32) , FALSE (i64) ,"), "{}", out);
+        assert!(out.contains("& u :: TRUE (ref __xdr_val) => true . pack (__xdr_out) ?"), "{}", out);
+        assert!(out.contains("false => u :: FALSE"), "{}", out);
+        assert!(!out.contains("invalid_named_case"), "{}", out);
+    }
+    // The parsed labels are left as they were written
+    let defns = crate::ast::parse("union u switch (bool b) { case 1: int a; case false: void; };").unwrap();
+    match &defns[0] {
+        Defn::Typespec(_, Type::Union(_, cases, _)) => assert_eq!((&cases[0].0, &cases[1].0), (&Value::Const(1), &Value::ident("false"))),
+        defn => panic!("unexpected {:?}", defn),
+    }
+
+    let spec = "const YES = 1; union u switch (bool b) { case YES: int a; case 0: void; };";
+    let incompatible = |res: crate::Result<String>| res.unwrap_err().to_string().contains("incompat selector");
+    assert!(incompatible(gen(spec, BoolLabels::Spellings)));
+    let out = gen(spec, BoolLabels::Constants).unwrap();
+    assert!(out.contains("TRUE (i32) , FALSE ,"), "{}", out);
+    assert!(incompatible(gen("union u switch (bool b) { case 1: int a; };", BoolLabels::Strict)));
+}
+
+#[test]
+fn generated_defns() {
+    use crate::ast::{Defn, Type};
+
+    let spec = "struct point { int x; struct { int lo; } range; }; const MAX = 3;";
+    let mut out = Vec::new();
+    let defns = generate_with_options("", Cursor::new(spec.as_bytes()), &mut out, &Default::default()).unwrap().defns;
+    assert_eq!(defns.iter().map(Defn::name).collect::<Vec<_>>(), ["PointRange", "point", "MAX"]);
+    assert!(matches!(&defns[1], Defn::Typespec(_, Type::Struct(decls)) if decls.len() == 2));
+    assert!(String::from_utf8(out).unwrap().contains("pub struct point"));
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn generate_pretty_with_defns() {
+    use crate::{ast::Defn, generate_pretty, generate_pretty_with_defns, pretty::GenerateOptions};
+
+    let options = GenerateOptions { xdr_header: "const HDR = 1;", ..Default::default() };
+    let spec = "enum color { RED = HDR }; typedef color colors<>;";
+    let (code, defns) = generate_pretty_with_defns(spec, &options).unwrap();
+    assert_eq!(code, generate_pretty(spec, &options).unwrap());
+    assert_eq!(defns.iter().map(Defn::name).collect::<Vec<_>>(), ["color", "colors"]);
+}
+
+#[test]
+fn compile_with_options() {
+    use crate::{compile_with_options, CompileOptions, GenerateOptions, Newline};
+
+    let dir = tempdir::TempDir::new("compile").unwrap();
+    let infile = dir.path().join("my-spec.x");
+    std::fs::write(&infile, "struct point { int x; };\n").unwrap();
+    // Paths are taken as they are, not only strings
+    let infile = infile.as_path();
+
+    let options = CompileOptions { out_dir: Some(dir.path()), ..Default::default() };
+    compile_with_options(infile, &options).unwrap();
+    let lf = std::fs::read_to_string(dir.path().join("my_spec_xdr.rs")).unwrap();
+    assert!(lf.contains("pub struct point") && !lf.contains('\r'));
+
+    compile_with_options(infile, &CompileOptions { newline: Newline::CrLf, ..options }).unwrap();
+    let crlf = std::fs::read_to_string(dir.path().join("my_spec_xdr.rs")).unwrap();
+    assert_eq!(crlf, lf.replace('\n', "\r\n"));
+    assert!(!dir.path().join("my_spec_xdr.rs.map.json").exists());
+
+    let generate = GenerateOptions { source_map: true, ..Default::default() };
+    compile_with_options(infile, &CompileOptions { generate, ..options }).unwrap();
+    let map = std::fs::read_to_string(dir.path().join("my_spec_xdr.rs.map.json")).unwrap();
+    assert!(map.contains(r#"{"name": "point", "kind": "type", "generated": [8, 8], "spec": [1, 1]}"#), "{}", map);
+}
+
+#[test]
+fn compile_only() {
+    use crate::{compile_with_options, CompileOptions, GenerateOptions};
+
+    let dir = tempdir::TempDir::new("only").unwrap();
+    let infile = dir.path().join("proto.x");
+    std::fs::write(
+        &infile,
+        "typedef int id;\nstruct point { id x; };\nstruct shape { point corner; };\nstruct other { int y; };\n",
+    )
+    .unwrap();
+    let infile = infile.to_str().unwrap();
+    let outfile = dir.path().join("proto_xdr.rs");
+
+    let generate = GenerateOptions { only: Some(&["shape"]), ..Default::default() };
+    let options = CompileOptions { out_dir: Some(dir.path()), generate: generate.clone(), ..Default::default() };
+    compile_with_options(infile, &options).unwrap();
+    let code = std::fs::read_to_string(&outfile).unwrap();
+    assert!(code.contains("pub struct shape") && !code.contains("pub struct point"), "{}", code);
+
+    let with_deps = GenerateOptions { only_with_deps: true, ..generate };
+    compile_with_options(infile, &CompileOptions { generate: with_deps, ..options.clone() }).unwrap();
+    let code = std::fs::read_to_string(&outfile).unwrap();
+    assert!(code.contains("pub struct shape") && code.contains("pub struct point"), "{}", code);
+    assert!(code.contains("pub type id") && !code.contains("pub struct other"), "{}", code);
+
+    let generate = GenerateOptions { only: Some(&["nothing"]), ..Default::default() };
+    assert!(compile_with_options(infile, &CompileOptions { generate, ..options }).is_err());
+}
+
+#[test]
+fn compile_module() {
+    use crate::{compile_with_options, CompileOptions, GenerateOptions, Visibility};
+
+    let dir = tempdir::TempDir::new("module").unwrap();
+    let infile = dir.path().join("proto.x");
+    std::fs::write(&infile, "struct point { int x; };\n").unwrap();
+    let infile = infile.to_str().unwrap();
+
+    let generate = GenerateOptions {
+        module: Some("proto"),
+        module_imports: &["std::io", "crate::types::*"],
+        visibility: Visibility::Crate,
+        source_map: true,
+        ..Default::default()
+    };
+    let options = CompileOptions { out_dir: Some(dir.path()), generate: generate.clone(), ..Default::default() };
+    compile_with_options(infile, &options).unwrap();
+    let code = std::fs::read_to_string(dir.path().join("proto_xdr.rs")).unwrap();
+    assert!(
+        code.contains("pub (crate) mod proto {\n\n# [allow (unused_imports)] use xdr_codec ;\nuse std::io;\nuse crate::types::*;\n\n"),
+        "{}",
+        code
+    );
+    assert!(code.contains("pub (crate) struct point { pub (crate) x : i32 , }") && code.ends_with("}\n"), "{}", code);
+
+    // The source map allows for the lines around the module's items
+    let line = code.lines().position(|line| line.contains("struct point")).unwrap() + 1;
+    let map = std::fs::read_to_string(dir.path().join("proto_xdr.rs.map.json")).unwrap();
+    let item = format!(r#"{{"name": "point", "kind": "type", "generated": [{}, {}], "spec": [1, 1]}}"#, line, line);
+    assert!(map.contains(&item), "{} not in {}", item, map);
+
+    let generate = GenerateOptions { types_only: true, module_imports: &[], ..generate };
+    compile_with_options(infile, &CompileOptions { generate, ..options }).unwrap();
+    let code = std::fs::read_to_string(dir.path().join("proto_xdr.rs")).unwrap();
+    assert!(!code.contains("xdr_codec"), "{}", code);
+}
+
+#[cfg(feature = "dialect")]
+#[test]
+fn dialect_const() {
+    use crate::{compile_with_options, CompileOptions, Dialect, GenerateOptions};
+
+    let dir = tempdir::TempDir::new("dialect").unwrap();
+    let infile = dir.path().join("legacy.x");
+    std::fs::write(&infile, "struct stamp { int seq; hyper when; };\n").unwrap();
+    let infile = infile.to_str().unwrap();
+    let outfile = dir.path().join("legacy_xdr.rs");
+
+    let options = CompileOptions { out_dir: Some(dir.path()), ..Default::default() };
+    compile_with_options(infile, &options).unwrap();
+    assert!(!std::fs::read_to_string(&outfile).unwrap().contains("XDR_DIALECT"));
+
+    let dialect = Dialect { pad_byte: 0xff, align_hyper: true };
+    let generate = GenerateOptions { dialect: Some(dialect), ..Default::default() };
+    compile_with_options(infile, &CompileOptions { generate: generate.clone(), ..options }).unwrap();
+    let code = std::fs::read_to_string(&outfile).unwrap();
+    assert!(
+        code.replace(' ', "").contains("pubconstXDR_DIALECT:xdr_codec::Dialect=xdr_codec::Dialect{pad_byte:255u8,align_hyper:true};"),
+        "{}",
+        code
+    );
+
+    #[cfg(feature = "pretty")]
+    {
+        let options = crate::pretty::GenerateOptions { generate, ..Default::default() };
+        let code = crate::generate_pretty("const A = 1;", &options).unwrap();
+        assert!(code.contains("pub const XDR_DIALECT: xdr_codec::Dialect"), "{}", code);
+    }
+}
+
+#[test]
+fn generated_source_map() {
+    use crate::{GenerateOptions, ItemKind};
+
+    let spec = "/* header */\n\nconst A = 1;\n\n// a point\nstruct point {\n    int x;\n    struct { int lo; } range;\n};\n";
+    let mut out = Vec::new();
+    let options = GenerateOptions { source_map: true, ..Default::default() };
+    let map = generate_with_options("p.x", Cursor::new(spec.as_bytes()), &mut out, &options).unwrap().source_map.unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    assert_eq!(map.spec_file, "p.x");
+    let items: Vec<_> = map.items.iter().map(|item| (item.name.as_str(), item.kind, item.spec.clone())).collect();
+    assert_eq!(
+        items,
+        [
+            ("A", ItemKind::Const, 3..=3),
+            ("PointRange", ItemKind::Type, 6..=9),
+            ("point", ItemKind::Type, 6..=9),
+            ("PointRange", ItemKind::Pack, 6..=9),
+            ("point", ItemKind::Pack, 6..=9),
+            ("PointRange", ItemKind::Unpack, 6..=9),
+            ("point", ItemKind::Unpack, 6..=9),
+        ]
+    );
+    let lines: Vec<_> = out.lines().collect();
+    for item in &map.items {
+        let text = lines[item.generated.start() - 1..*item.generated.end()].join("\n");
+        assert!(text.contains(&format!(" {} ", item.name)), "{:?}: {}", item, text);
+    }
+}
+
+#[test]
+fn cross_enum_consts() {
+    use crate::{Error, Symtab};
+
+    let spec = r#"
+        enum c { C = D, C2, C3 };
+        enum d { D = 7 };
+        namespace ns { enum e { E = F, E2 }; enum f { F = LATER }; };
+        enum later { LATER = 9 };
+        struct s { int x[C3]; int y[ns::E2]; };
+    "#;
+    let symtab = Symtab::from_spec(spec).unwrap();
+    let consts = ["C", "C2", "C3", "ns::E", "ns::E2", "ns::F"].map(|name| symtab.getconst(&name.to_string()));
+    assert_eq!(
+        consts,
+        [
+            Some((7, Some("c".into()))),
+            Some((8, Some("c".into()))),
+            Some((9, Some("c".into()))),
+            Some((9, Some("ns::e".into()))),
+            Some((10, Some("ns::e".into()))),
+            Some((9, Some("ns::f".into()))),
+        ]
+    );
+
+    let out = gen_with(spec, &Default::default());
+    assert!(out.contains("pub enum c { # [default] C = 7 , C2 = 8 , C3 = 9 , }"), "{}", out);
+    assert!(out.contains("pub x : [i32 ; c :: C3 as usize] , pub y : [i32 ; ns :: e :: E2 as usize] ,"), "{}", out);
+
+    // Only the members which name something undefined are errors, not those following them
+    let err = Symtab::from_spec("enum x { X = Y, X2 }; enum y { Y = X }; enum z { Z = NOPE, Z2 };").unwrap_err();
+    let unknown: Vec<_> = err
+        .errors()
+        .into_iter()
+        .filter_map(|err| match err {
+            Error::UnknownEnumValue { name, member, .. } => Some((name.as_str(), member.as_str())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(unknown, [("x", "X"), ("y", "Y"), ("z", "Z")]);
+}
+
+#[test]
+fn box_recursive() {
+    let spec = r#"
+        struct entry { int v; entry *next; };
+        union expr switch (int k) { case 0: int lit; case 1: expr negated; default: void; };
+        union tree switch (int kind) { case 0: void; case 1: tree_pair pair; };
+        struct tree_pair { tree left; tree right; };
+        namespace ns { union term switch (int k) { case 0: void; case 1: term inner; }; };
+    "#;
+    let out = gen_with(spec, &Default::default());
+
+    assert!(out.contains("pub struct entry { pub v : i32 , pub next : Option < Box < entry >> , }"), "{}", out);
+    assert!(out.contains("pub enum expr { Const0 (i32) , Const1 (Box < expr >) , Default (i32) , }"), "{}", out);
+    // Only the references which close the cycle, searching from `tree`
+    assert!(out.contains("pub enum tree { Const0 , Const1 (tree_pair) , }"), "{}", out);
+    assert!(out.contains("pub struct tree_pair { pub left : Box < tree > , pub right : Box < tree > , }"), "{}", out);
+    assert!(out.contains("pub enum term { Const0 , Const1 (Box < term >) , }"), "{}", out);
+}
+
+#[test]
+fn unknown_enum_value() {
+    use crate::{Error, Symtab};
+
+    let spec = "const MAX = 2; enum color { RED = MAX, GREEN = MISSING, BLUE }; namespace ns { enum e { A = NOPE }; };";
+    let err = generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default()).unwrap_err();
+    let unknown: Vec<_> = err
+        .errors()
+        .into_iter()
+        .filter_map(|err| match err {
+            Error::UnknownEnumValue { name, member, .. } => Some((name.as_str(), member.as_str())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(unknown, [("color", "GREEN"), ("ns::e", "A")]);
+
+    assert!(Symtab::from_spec(spec).is_err());
+
+    #[cfg(feature = "pretty")]
+    {
+        let err = crate::generate_pretty(spec, &Default::default()).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>().unwrap().errors().len(), 2);
+    }
+}
+
+#[test]
+fn enum_value_range() {
+    use crate::Error;
+
+    let spec = r#"
+const BIG = 0x80000000;
+enum flags { LOW = -2147483648, HIGH = 0x7fffffff, OVER };
+enum big { B = BIG };
+namespace ns { enum e { A = -2147483649 }; };
+enum wide { W = 0x100000000 }; /* @hyper */
+"#;
+    let err = generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default()).unwrap_err();
+    let out_of_range: Vec<_> = err
+        .errors()
+        .into_iter()
+        .filter_map(|err| match err {
+            Error::EnumValueRange { name, member, value } => Some((name.as_str(), member.as_str(), *value)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(out_of_range, [("big", "B", 0x80000000), ("flags", "OVER", 0x80000000), ("ns::e", "A", -0x80000001)]);
+    assert_eq!(err.errors().len(), 3);
+}
+
+#[test]
+fn const_refs() {
+    use crate::Error;
+
+    let spec = r#"
+const A = 2;
+const B = A;
+const C = LATER;
+const LATER = GREEN;
+enum color { RED = B, GREEN };
+namespace ns { const D = A; const E = D; };
+const F = ns::E;
+struct s { int x[B]; };
+"#;
+    let out = gen_with(spec, &Default::default());
+    for item in ["pub const B : i64 = 2i64 ;", "pub const C : i64 = 3i64 ;", "pub const E : i64 = 2i64 ;", "pub const F : i64 = 2i64 ;", "pub x : [i32 ; B as usize]"] {
+        assert!(out.contains(item), "{} in {}", item, out);
+    }
+
+    let err = generate_with_options("", Cursor::new(&b"const A = B; const B = A; const C = NONE;"[..]), Vec::new(), &Default::default()).unwrap_err();
+    let unknown: Vec<_> = err
+        .errors()
+        .into_iter()
+        .map(|err| match err {
+            Error::UnknownConstValue { name, .. } => name.as_str(),
+            err => panic!("unexpected {:?}", err),
+        })
+        .collect();
+    assert_eq!(unknown, ["A", "B", "C"]);
+}
+
+#[test]
+fn typedef_selectors() {
+    let spec = r#"
+enum color { RED, GREEN = 2, BLUE };
+typedef color c1;
+typedef c1 c2;
+typedef unsigned int count;
+union u switch (c2 k) { case RED: int r; case GREEN: void; default: void; };
+union v switch (count n) { case 0: void; case 1: hyper h; case 0x80000000: void; };
+"#;
+    let out = gen_with(spec, &Default::default());
+    println!("{}", out);
+    assert!(out.contains("& u :: RED (ref __xdr_val) => (color :: RED as i32) . pack (__xdr_out) ?"));
+    assert!(out.contains("pub enum v { Const0 , Const1 (i64) , Const2147483648 , }"));
+
+    let mut symtab = super::Symtab::new();
+    symtab.update_consts(&specification(spec).unwrap(), &());
+    // In range for the unsigned int it's a synonym of
+    assert!(symtab.warnings().is_empty(), "{:?}", symtab.warnings());
+}
+
+#[test]
+fn array_sizes() {
+    use super::Type;
+    use crate::Error;
+
+    let spec = r#"
+const ZERO = 0;
+const HUGE = 0x100000000;
+struct empty { int x[ZERO]; };
+struct negative { opaque y[-1]; };
+typedef hyper big[HUGE];
+struct fine { opaque ok[0xffffffff]; int z[1]; };
+"#;
+    let err = generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default()).unwrap_err();
+    let sizes: Vec<_> = err
+        .errors()
+        .into_iter()
+        .filter_map(|err| match err {
+            Error::InvalidArraySize { ty, size, .. } => Some((ty.clone(), *size)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(sizes, [(Type::Hyper, 0x100000000), (Type::Int, 0), (Type::Opaque, -1)]);
+    assert_eq!(err.errors().len(), 3);
+    assert!(err.errors()[1].to_string().contains("Int[Ident(\"ZERO\")] has 0 elements"));
+}
+
+#[test]
+fn reserved_local_prefix() {
+    use crate::Error;
+
+    let spec = "const __xdr_sz = 1; struct ok { int __xdr_v; }; namespace ns { typedef int __xdr_out; };";
+    let err = generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default()).unwrap_err();
+    let reserved: Vec<_> = err
+        .errors()
+        .into_iter()
+        .filter_map(|err| match err {
+            Error::ReservedName { name } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(reserved, ["__xdr_sz", "ns::__xdr_out"]);
+}
+
+#[test]
+fn duplicate_program_names() {
+    use crate::Error;
+
+    let spec = "program P { version V { void F(void) = 1; void F(void) = 2; } = 1; } = 1;
+                program Q { version W { void G(void) = 1; } = 1; version X { void G(void) = 1; } = 2; } = 2;
+                namespace ns { program R { version R { void H(void) = 1; } = 1; } = 3; };
+                program S { version Y { void K(void) = 1; } = 1; } = 4;";
+    let err = generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default()).unwrap_err();
+    let duplicates: Vec<_> = err
+        .errors()
+        .into_iter()
+        .filter_map(|err| match err {
+            Error::DuplicateProgramName { program, name } => Some((program.as_str(), name.as_str())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(duplicates, [("P", "F"), ("Q", "G"), ("ns::R", "R")]);
+    assert_eq!(err.errors().len(), 3);
+}
+
+#[test]
+fn leading_docs() {
+    let spec = r#"/* File header */
+
+/*
+ * A point in the plane.
+ *
+ * Units are pixels.
+ */
+struct point { int x; int y; };
+
+// Colours
+// of things
+enum color { RED = 1 };
+
+/* Not attached */
+
+typedef int count;
+const MAX = 3; /* trailing */
+typedef point points<MAX>;
+"#;
+    let docs = super::leading_docs(&specification_source(spec).unwrap()).unwrap();
+    assert_eq!(docs.get("point").map(String::as_str), Some(" A point in the plane.\n\n Units are pixels."));
+    assert_eq!(docs.get("color").map(String::as_str), Some(" Colours\n of things"));
+    assert_eq!(docs.len(), 2);
+
+    let out = gen_with(spec, &Default::default());
+    assert!(out.contains("# [doc = \" Colours\"] # [doc = \" of things\"] #"));
+    assert!(!out.contains("File header"));
+
+    #[cfg(feature = "pretty")]
+    {
+        let out = crate::generate_pretty(spec, &Default::default()).unwrap();
+        println!("{}", out);
+        assert!(out.contains("/// A point in the plane.\n///\n/// Units are pixels.\n#["));
+    }
+}
+
+#[test]
+fn union_case_comments() {
+    use super::{Defn, Type};
+
+    let spec = r#"
+union reply switch (int status) {
+case 0: /* Found it */
+    int value;
+case 1:
+case 2:
+    /* Not there, or gone */
+    void;
+case 3: void; /* Try again */
+case 4: void;
+    /* Not for case 4 */
+default: void;
+};
+"#;
+    let defns = specification(spec).unwrap();
+    let cases = match &defns[0] {
+        Defn::Typespec(_, Type::Union(_, cases, _)) => cases,
+        defn => panic!("unexpected {:?}", defn),
+    };
+    let comments: Vec<_> = cases.iter().map(|case| case.2.as_deref()).collect();
+    assert_eq!(
+        comments,
+        [Some(" Found it"), Some(" Not there, or gone"), Some(" Not there, or gone"), Some(" Try again"), None]
+    );
+
+    let out = gen_with(spec, &Default::default());
+    assert!(out.contains("# [doc = \" Found it\"] Const0 (i32) ,"), "{}", out);
+    assert!(out.contains("# [doc = \" Try again\"] Const3 ,"), "{}", out);
+    assert!(!out.contains("Not for case 4"));
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn flatten_namespaces() {
+    use crate::{generate_pretty, pretty::GenerateOptions, Error, Namespaces};
+
+    // As Stellar's specs are laid out
+    let spec = r#"
+namespace stellar
+{
+typedef opaque Hash[32];
+typedef unsigned hyper uint64;
+
+enum CryptoKeyType
+{
+    KEY_TYPE_ED25519 = 0,
+    KEY_TYPE_MUXED_ED25519 = 0x100
+};
+
+union MuxedAccount switch (CryptoKeyType type)
+{
+case KEY_TYPE_ED25519:
+    Hash ed25519;
+case KEY_TYPE_MUXED_ED25519:
+    struct
+    {
+        uint64 id;
+        stellar::Hash ed25519;
+    } med25519;
+};
+}
+"#;
+    let modules = generate_pretty(spec, &Default::default()).unwrap();
+    assert!(modules.contains("pub mod stellar {"));
+
+    let flatten = crate::GenerateOptions { namespaces: Namespaces::Flatten, ..Default::default() };
+    let options = GenerateOptions { generate: flatten.clone(), ..Default::default() };
+    let flat = generate_pretty(spec, &options).unwrap();
+    assert!(!flat.contains("mod stellar"));
+    assert!(flat.contains("pub enum MuxedAccount {"));
+    assert!(flat.contains("pub struct MuxedAccountMed25519 {\n    pub id: uint64,\n    pub ed25519: Hash,\n}"));
+    assert!(flat.contains("KEY_TYPE_MUXED_ED25519 = 256,"));
+
+    // Excluding the namespace excludes what was in it
+    let generate = crate::GenerateOptions { exclude_defs: &["stellar"], ..flatten.clone() };
+    let flat = generate_pretty(spec, &GenerateOptions { generate, ..Default::default() }).unwrap();
+    assert!(!flat.contains("MuxedAccount"));
+
+    // The same option flattens them without the pretty printer
+    let out = gen_with(spec, &flatten);
+    assert!(!out.contains("mod stellar") && out.contains("pub enum MuxedAccount {"), "{}", out);
+
+    let err = generate_pretty("typedef int Hash; namespace stellar { typedef opaque Hash[32]; }", &options).unwrap_err();
+    match err.root_cause().downcast_ref::<Error>() {
+        Some(Error::NamespaceClash { namespace, name }) => assert_eq!((namespace.as_str(), name.as_str()), ("stellar", "Hash")),
+        err => panic!("unexpected {:?}", err),
+    }
+}
+
+#[test]
+fn section_modules() {
+    use crate::Error;
+
+    let spec = r#"
+/* @module common */
+typedef int id;
+struct point { id x; };
+
+/* @module nfs */
+/* A file */
+struct file { point p; };
+
+/* @module */
+struct top { file f; };
+"#;
+    let out = gen_with(spec, &Default::default());
+    assert!(out.contains("use self :: common :: * ;") && out.contains("use self :: nfs :: * ;"), "{}", out);
+    let common = out.find("pub mod common {").expect("common module");
+    let nfs = out.find("pub mod nfs {").expect("nfs module");
+    assert!(out.find("pub struct top").unwrap() < common);
+    assert!((common..nfs).contains(&out.find("pub struct point").unwrap()));
+    assert!(out.find("pub struct file").unwrap() > nfs);
+    // The marker isn't documentation
+    assert!(out.contains("# [doc = \" A file\"]") && !out.contains("@module"), "{}", out);
+
+    #[cfg(feature = "pretty")]
+    {
+        let code = crate::generate_pretty(spec, &Default::default()).unwrap();
+        assert!(code.contains("use self::common::*;"), "{}", code);
+        assert!(code.contains("pub mod nfs {\n    #[allow(unused_imports)]\n    use super::*;\n    /// A file\n"), "{}", code);
+    }
+
+    let invalid = |spec: &str| match generate_with_options("", Cursor::new(spec.as_bytes()), Vec::new(), &Default::default()) {
+        Err(Error::InvalidAnnotation { name, problem }) => (name, problem),
+        res => panic!("unexpected {:?}", res),
+    };
+    assert_eq!(invalid("/* @module a b */ const A = 1;").1, "@module takes at most one name");
+    assert_eq!(invalid("/* @module 9a */ const A = 1;").1, "module name 9a isn't an identifier");
+    assert_eq!(invalid("/* @module id */ typedef int id;").0, "id");
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn pretty_symtab() {
+    use crate::{generate_pretty_with_symtab, pretty::GenerateOptions};
+
+    let options = GenerateOptions { xdr_header: "const BASE = 100;", ..Default::default() };
+    let (code, symtab) = generate_pretty_with_symtab("enum proto_version { V1 = BASE, V2 };", &options).unwrap();
+    assert!(code.contains("pub enum proto_version"));
+    assert_eq!(symtab.enum_members("proto_version"), Some(vec![("V1", 100), ("V2", 101)]));
+    let header = |name: &str| symtab.constants().find(|(n, _)| *n == name).map(|(_, def)| def.meta.header);
+    assert_eq!((header("BASE"), header("V1")), (Some(true), Some(false)));
+}
+
+#[cfg(feature = "pretty")]
+#[test]
+fn modes_agree() {
+    use crate::diff_modes;
+
+    let spec = r#"
+const N = 4;
+enum color { RED, GREEN = N, BLUE };
+struct point { int x; hyper y<N>; color c; string name<>; };
+typedef point points<>;
+typedef int counts[N];
+union shape switch (color c) { case RED: point p; case GREEN: void; default: int other; };
+struct holder { struct { int a; } inner; point *next; };
+namespace ns { const M = 2; struct thing { int v[M]; }; };
+program PROG { version V1 { int PING(point) = 1; } = 1; } = 0x20000001;
+"#;
+    let diffs = diff_modes(spec).unwrap();
+    assert!(diffs.is_empty(), "{}", diffs.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"));
+}
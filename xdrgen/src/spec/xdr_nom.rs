@@ -1,10 +1,15 @@
-// Grammar for a .x file specifying XDR type codecs. Does not include any RPC syntax. Should match RFC4506.
+// Grammar for a .x file specifying XDR type codecs, and RPC program definitions. Should match RFC4506 and RFC5531.
 use nom::{Err, ErrorKind, IResult, Needed, is_digit, is_space, not_line_ending};
 use nom::IResult::*;
 
+use std::ops::RangeInclusive;
 use std::str;
 
-use super::{Decl, Defn, EnumDefn, Type, UnionCase, Value, Derives, Error, Result};
+use super::{BinOp, Decl, Defn, EnumDefn, EnumWidth, Procedure, Type, UnionCase, Value, Version, Derives, Result, Specification, SpecComment, CommentKind, into_comment};
+use crate::error::{Expected, Location, ParseError};
+
+mod spans;
+pub use self::spans::{specification_spans, SpecPart, SpecSpan, Spanned};
 
 #[inline]
 fn ignore<T>(_: T) -> () {
@@ -24,20 +29,116 @@ fn eof(input: &[u8]) -> IResult<&[u8], ()> {
     }
 }
 
+// Deepest nesting of braces and parentheses, and of operators in a constant expression. The
+// parser and code generator recurse for each level, so this bounds their stack use on hostile
+// input.
+const MAX_NESTING: usize = 64;
+
+// Offset of the first bracket nested more than MAX_NESTING deep, ignoring comments.
+fn too_deep(src: &[u8]) -> Option<usize> {
+    let find = |from: usize, pat: &[u8]| {
+        src[from..].windows(pat.len()).position(|w| w == pat).map(|p| from + p)
+    };
+    let mut depth = 0usize;
+    let mut idx = 0;
+
+    while idx < src.len() {
+        let rest = &src[idx..];
+
+        if rest.starts_with(b"/*") {
+            idx = find(idx + 2, b"*/").map_or(src.len(), |end| end + 2);
+            continue;
+        }
+        if rest.starts_with(b"//") {
+            idx = find(idx, b"\n").unwrap_or(src.len());
+            continue;
+        }
+
+        match rest[0] {
+            b'{' | b'(' => {
+                depth += 1;
+                if depth > MAX_NESTING {
+                    return Some(idx);
+                }
+            }
+            b'}' | b')' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+        idx += 1;
+    }
+    None
+}
+
 pub fn specification(input: &str) -> Result<Vec<Defn>> {
-    let parse_err = match spec(input.as_bytes()) {
+    let src = input.as_bytes();
+    if let Some(offset) = too_deep(src) {
+        return Err(ParseError::new(input, offset, Expected::ShallowerNesting).into());
+    }
+    // Error positions are subslices of the input, but not necessarily suffixes of it
+    let offset = |pos: &[u8]| (pos.as_ptr() as usize).saturating_sub(src.as_ptr() as usize);
+
+    let err = match spec(src) {
         Done(_, spec) => return Ok(spec),
-        Error(Err::Position(kind, input)) => {
-            format!(
-                "{:?}: {}",
-                kind,
-                String::from(str::from_utf8(input).unwrap())
-            )
-        }
-        Error(err) => format!("Error: {:?}", err),
-        Incomplete(need) => format!("Incomplete {:?}", need),
+        Error(Err::Position(kind, pos)) |
+        Error(Err::NodePosition(kind, pos, _)) => ParseError::new(input, offset(pos), expected(&kind)),
+        Error(Err::Code(kind)) |
+        Error(Err::Node(kind, _)) => ParseError::new(input, 0, expected(&kind)),
+        Incomplete(_) => ParseError::new(input, src.len(), Expected::MoreInput),
     };
-    Err(Error::Parse(parse_err))
+    Err(err.into())
+}
+
+/// Parse a constant expression, with the syntax of a union case label.
+pub fn constant_expr(input: &str) -> Result<Value> {
+    // A final number or identifier is only complete once something follows it
+    let terminated = format!("{}\n", input);
+    let src = terminated.as_bytes();
+    if let Some(offset) = too_deep(src) {
+        return Err(ParseError::new(input, offset, Expected::ShallowerNesting).into());
+    }
+    let offset = |pos: &[u8]| (pos.as_ptr() as usize).saturating_sub(src.as_ptr() as usize).min(input.len());
+
+    let err = match do_parse!(src, val: case_value >> spaces >> eof >> (val)) {
+        Done(_, val) => return Ok(val),
+        Error(Err::Position(ErrorKind::Eof, pos)) => ParseError::new(input, offset(pos), Expected::Token),
+        Error(Err::Position(kind, pos)) |
+        Error(Err::NodePosition(kind, pos, _)) => ParseError::new(input, offset(pos), expected(&kind)),
+        Error(Err::Code(kind)) |
+        Error(Err::Node(kind, _)) => ParseError::new(input, 0, expected(&kind)),
+        Incomplete(_) => ParseError::new(input, input.len(), Expected::MoreInput),
+    };
+    Err(err.into())
+}
+
+#[test]
+fn test_constant_expr() {
+    assert_eq!(constant_expr("MAXNAME").unwrap(), Value::ident("MAXNAME"));
+    assert_eq!(constant_expr(" (ns::A + 1) * 2 ").unwrap(),
+               Value::binary(BinOp::Mul,
+                             Value::binary(BinOp::Add, Value::ident("ns::A"), Value::Const(1)),
+                             Value::Const(2)));
+    assert_eq!(constant_expr("0x10").unwrap(), Value::Const(16));
+
+    let err = |input| match constant_expr(input) {
+        Err(crate::Error::Parse(err)) => (err.expected, err.location.offset),
+        res => panic!("unexpected {:?}", res),
+    };
+    assert_eq!(err("1 2"), (Expected::Token, 2));
+    assert_eq!(err("A +"), (Expected::MoreInput, 3));
+    assert_eq!(err(""), (Expected::MoreInput, 0));
+    assert_eq!(err(&"(".repeat(100)).0, Expected::ShallowerNesting);
+}
+
+fn expected(kind: &ErrorKind) -> Expected {
+    match kind {
+        ErrorKind::Custom(0) => Expected::Keyword,
+        ErrorKind::Custom(1) | ErrorKind::AlphaNumeric => Expected::Identifier,
+        ErrorKind::Custom(2) => Expected::ShallowerNesting,
+        ErrorKind::Digit => Expected::Number,
+        ErrorKind::Tag => Expected::Token,
+        ErrorKind::Eof => Expected::Definition,
+        _ => Expected::Other,
+    }
 }
 
 named!(spec< Vec<Defn> >,
@@ -78,12 +179,14 @@ enum bop { a = 2, b = 1 };
                          Defn::typespec("bar", Type::Struct(vec!(Decl::named("a", Type::Int),
                                                                                Decl::named("b", Type::Int)))),
                          Defn::typespec("bop", Type::Enum(vec!(EnumDefn::new("a", Some(Value::Const(2)), None),
-                                                                             EnumDefn::new("b", Some(Value::Const(1)), None)))))));
+                                                                             EnumDefn::new("b", Some(Value::Const(1)), None)), EnumWidth::Int)))));
 }
 
 named!(definition<Defn>,
        alt!(type_def => { |t| t } |
-            const_def => { |c| c }));
+            const_def => { |c| c } |
+            namespace_def => { |n| n } |
+            program_def => { |p| p }));
 
 fn is_hexdigit(ch: u8) -> bool {
     match ch as char {
@@ -129,8 +232,8 @@ named!(star,    preceded!(spaces, apply!(ctag, "*")));
 named!(hexnumber<i64>,
     do_parse!(
         apply!(ctag, "0x") >>
-        val: map_res!(apply!(digit, is_hexdigit), str::from_utf8) >>
-        (i64::from_str_radix(val, 16).unwrap())
+        val: map_res!(map_res!(apply!(digit, is_hexdigit), str::from_utf8), |val| i64::from_str_radix(val, 16)) >>
+        (val)
     )
 );
 
@@ -138,16 +241,16 @@ named!(octnumber<i64>,
     do_parse!(
         sign: opt!(apply!(ctag, "-")) >>
         apply!(ctag, "0") >>
-        val: opt!(map_res!(apply!(digit, is_octdigit), str::from_utf8)) >>
-        (i64::from_str_radix(val.unwrap_or("0"), 8).unwrap() * (if sign.is_some() { -1 } else { 1 }))
+        val: opt!(map_res!(map_res!(apply!(digit, is_octdigit), str::from_utf8), |val| i64::from_str_radix(val, 8))) >>
+        (val.unwrap_or(0) * (if sign.is_some() { -1 } else { 1 }))
     )
 );
 
 named!(decnumber<i64>,
     do_parse!(
         sign: opt!(apply!(ctag, "-")) >>
-        val: map_res!(apply!(digit, is_digit), str::from_utf8) >>
-        (i64::from_str_radix(val, 10).unwrap() * (if sign.is_some() { -1 } else { 1 }))
+        val: map_res!(map_res!(apply!(digit, is_digit), str::from_utf8), str::parse::<i64>) >>
+        (val * (if sign.is_some() { -1 } else { 1 }))
     )
 );
 
@@ -237,7 +340,9 @@ kw!(kw_float, b"float");
 kw!(kw_hyper, b"hyper");
 kw!(kw_int, b"int");
 kw!(kw_long, b"long"); // special case - part time keyword
+kw!(kw_namespace, b"namespace"); // special case - part time keyword
 kw!(kw_opaque, b"opaque");
+kw!(kw_program, b"program"); // special case - part time keyword
 kw!(kw_quadruple, b"quadruple");
 kw!(kw_short, b"short"); // special case - part time keyword
 kw!(kw_string, b"string");
@@ -246,6 +351,7 @@ kw!(kw_switch, b"switch");
 kw!(kw_typedef, b"typedef");
 kw!(kw_union, b"union");
 kw!(kw_unsigned, b"unsigned");
+kw!(kw_version, b"version"); // special case - part time keyword
 kw!(kw_void, b"void");
 
 named!(keyword<()>,
@@ -385,6 +491,225 @@ fn test_comments() {
                Done(&b"\n"[..], ()));
 }
 
+/// Collect every comment in `input`, in source order.
+///
+/// This is a separate lexical pass from the grammar, which discards most comments as whitespace.
+/// Like the grammar, it treats `#` and `%` lines as directives rather than looking for comments
+/// within them.
+pub fn comments(input: &str) -> Vec<SpecComment> {
+    let src = input.as_bytes();
+    let mut ret = Vec::new();
+    let mut idx = 0;
+    let mut line_start = true;
+
+    let find = |from: usize, pat: &[u8]| {
+        src[from..].windows(pat.len()).position(|w| w == pat).map(|p| from + p)
+    };
+    let text = |start: usize, end: usize| String::from_utf8_lossy(&src[start..end]).into_owned();
+
+    while idx < src.len() {
+        let rest = &src[idx..];
+
+        if line_start {
+            let indent = rest.iter().take_while(|c| is_space(**c)).count();
+            if let Some(b'#') | Some(b'%') = rest.get(indent) {
+                idx = find(idx, b"\n").unwrap_or(src.len());
+                continue;
+            }
+        }
+        line_start = false;
+
+        if rest.starts_with(b"/*") {
+            let end = find(idx + 2, b"*/");
+            let body_end = end.unwrap_or(src.len());
+            ret.push(SpecComment {
+                location: Location::from_offset(input, idx),
+                kind: CommentKind::Block,
+                text: text(idx + 2, body_end),
+            });
+            idx = end.map_or(src.len(), |e| e + 2);
+        } else if rest.starts_with(b"//") {
+            let end = find(idx, b"\n").unwrap_or(src.len());
+            let body_end = if end > idx + 2 && src[end - 1] == b'\r' { end - 1 } else { end };
+            ret.push(SpecComment {
+                location: Location::from_offset(input, idx),
+                kind: CommentKind::Line,
+                text: text(idx + 2, body_end),
+            });
+            idx = end;
+        } else {
+            line_start = rest[0] == b'\n';
+            idx += 1;
+        }
+    }
+
+    ret
+}
+
+/// Byte ranges of the block comments in `input`, including their delimiters.
+pub(super) fn block_comments(input: &str) -> Vec<std::ops::Range<usize>> {
+    comments(input)
+        .into_iter()
+        .filter(|c| c.kind == CommentKind::Block)
+        .map(|c| c.location.offset..(c.location.offset + c.text.len() + 4).min(input.len()))
+        .collect()
+}
+
+/// Files named by `#include "file"` directives in `input`, in source order.
+///
+/// Only the quoted form is included; `#include <file>` names a system header for C code built
+/// from the same specification, as do `%#include` lines.
+pub fn includes(input: &str) -> Vec<String> {
+    let blocks = block_comments(input);
+
+    let mut ret = Vec::new();
+    let mut offset = 0;
+    for line in input.split('\n') {
+        let start = offset;
+        offset += line.len() + 1;
+        if blocks.iter().any(|range| range.contains(&start)) {
+            continue;
+        }
+
+        let file = line
+            .trim()
+            .strip_prefix('#')
+            .and_then(|d| d.trim_start().strip_prefix("include"))
+            .and_then(|d| d.trim().strip_prefix('"'))
+            .and_then(|d| d.split('"').next().filter(|_| d.contains('"')));
+        if let Some(file) = file {
+            ret.push(file.to_string());
+        }
+    }
+
+    ret
+}
+
+#[test]
+fn test_includes() {
+    let src = "#include \"a.x\"\n# include \"sub/b.x\" // why\n#include <rpc/types.h>\n%#include \"c.h\"\n/*\n#include \"d.x\"\n*/\n#include \"e.x\r\n#define X\n  #include\t\"f.x\"\n";
+    assert_eq!(includes(src), vec!["a.x", "sub/b.x", "f.x"]);
+}
+
+/// Source text of a specification, split by definition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpecSource<'a> {
+    /// Everything up to the last blank line before the first definition; usually a file header.
+    pub preamble: &'a str,
+    /// Each definition with its text, including the comments leading up to it and a comment
+    /// trailing on its last line.
+    pub defns: Vec<(Defn, &'a str)>,
+    /// Anything after the last definition.
+    pub trailer: &'a str,
+}
+
+impl SpecSource<'_> {
+    /// The lines each definition spans, 1-based and inclusive, without the comments leading up
+    /// to it.
+    pub fn defn_lines(&self) -> Vec<(&Defn, RangeInclusive<usize>)> {
+        let mut line = self.preamble.matches('\n').count() + 1;
+
+        self.defns
+            .iter()
+            .map(|(defn, text)| {
+                let leading = token_start(text);
+                let start = line + text[..leading].matches('\n').count();
+                let end = start + text[leading..].trim_end().matches('\n').count();
+                line += text.matches('\n').count();
+                (defn, start..=end)
+            })
+            .collect()
+    }
+}
+
+/// Parse a specification, splitting its text into definitions.
+pub fn specification_source(input: &str) -> Result<SpecSource<'_>> {
+    let src = input.as_bytes();
+    if too_deep(src).is_some() {
+        specification(input)?;
+    }
+
+    let offset = |rest: &[u8]| src.len() - rest.len();
+    let mut pos = match directive(src) {
+        Done(rest, _) => offset(rest),
+        _ => 0,
+    };
+
+    let mut defns = Vec::new();
+    while let Done(rest, defn) = definition(&src[pos..]) {
+        let mut end = offset(rest);
+
+        let line_end = src[end..].iter().position(|&c| c == b'\n').map_or(src.len(), |p| end + p);
+        let line = input[end..line_end].trim_start();
+        let trailing = line.starts_with("//")
+            || (line.starts_with("/*") && line.find("*/").is_some_and(|p| line[p + 2..].trim().is_empty()));
+        if trailing {
+            end = line_end;
+        }
+
+        defns.push((defn, pos..end));
+        pos = end;
+    }
+    // The walk stops at anything it can't parse; report that the same way as a normal parse
+    if !do_parse!(&src[pos..], spaces >> eof >> (())).is_done() {
+        specification(input)?;
+    }
+
+    // The header is split off at the last blank line before the first definition
+    let preamble = defns.first().map_or(pos, |(_, range)| {
+        let mut rest = &src[range.start..];
+        let mut split = range.start;
+        let mut line_blank = range.start == 0;
+
+        while let Done(next, ()) = space_item(rest) {
+            match eol(rest) {
+                Done(after, ()) => {
+                    if line_blank {
+                        split = offset(rest);
+                    }
+                    // An end of line may take a directive line with it
+                    line_blank = after.len() == next.len();
+                }
+                _ => line_blank &= whitespace(rest).is_done(),
+            }
+            rest = next;
+        }
+        split
+    });
+    if let Some((_, range)) = defns.first_mut() {
+        range.start = preamble;
+    }
+
+    Ok(SpecSource {
+        preamble: &input[..preamble],
+        defns: defns.into_iter().map(|(defn, range)| (defn, &input[range])).collect(),
+        trailer: &input[pos..],
+    })
+}
+
+/// Parse a specification, keeping all its comments alongside the definitions.
+pub fn specification_with_comments(input: &str) -> Result<Specification> {
+    Ok(Specification {
+        defns: specification(input)?,
+        comments: comments(input),
+    })
+}
+
+#[test]
+fn test_all_comments() {
+    let cs = comments("/* head */\n#define X // not a comment\nconst a = 1; // trailing\r\nstruct b { int c; /* field */ };\n// end");
+
+    assert_eq!(
+        cs.iter().map(|c| (c.location.line, c.location.column, c.kind, c.text.as_str())).collect::<Vec<_>>(),
+        vec![
+            (1, 1, CommentKind::Block, " head "),
+            (3, 14, CommentKind::Line, " trailing"),
+            (4, 19, CommentKind::Block, " field "),
+            (5, 1, CommentKind::Line, " end"),
+        ]
+    );
+}
+
 named!(eol<()>, map!(alt!(apply!(ctag, "\n") |
                           apply!(ctag, "\r\n") |
                           apply!(ctag, "\u{2028}") |
@@ -397,19 +722,16 @@ named!(whitespace<()>,
 
 // `spaces` consumes spans of space and tab characters interpolated
 // with comments, c-preproc and passthrough lines.
-named!(spaces<()>,
-    map!(
-        many0!(
-            alt!( do_parse!(eol >> opt!(complete!(directive)) >> (()))
-                | whitespace
-                | blockcomment
-                | linecomment
-                )
-        ),
-        ignore
-    )
+named!(space_item<()>,
+    alt!( do_parse!(eol >> opt!(complete!(directive)) >> (()))
+        | whitespace
+        | blockcomment
+        | linecomment
+        )
 );
 
+named!(spaces<()>, map!(many0!(space_item), ignore));
+
 fn ws(input: &[u8]) -> &[u8] {
     match spaces(input) {
         Done(rest, _) => rest,
@@ -417,6 +739,11 @@ fn ws(input: &[u8]) -> &[u8] {
     }
 }
 
+// Offset of the first token in `text`, after any spaces, comments and directives
+pub(super) fn token_start(text: &str) -> usize {
+    text.len() - ws(text.as_bytes()).len()
+}
+
 #[test]
 fn test_spaces() {
     assert_eq!(eol(&b"\nx"[..]), Done(&b"x"[..], ()));
@@ -473,11 +800,98 @@ named!(enum_assign<EnumDefn>,
 );
 
 named!(value<Value>,
-       alt!(number => { |c| Value::Const(c) } |
-            ident => { |id| Value::ident(id) }
+       alt!(number => { Value::Const } |
+            qualified_ident => { Value::ident }
             )
        );
 
+// Case labels may be constant expressions with C operator precedence. This is a dialect
+// extension; rpcgen accepts it because cpp has expanded them into literals by then.
+fn case_value(input: &[u8]) -> IResult<&[u8], Value> {
+    expr_or(input)
+}
+
+fn binary_expr<'a>(
+    input: &'a [u8],
+    operand: fn(&[u8]) -> IResult<&[u8], Value>,
+    ops: &[(&str, BinOp)],
+) -> IResult<&'a [u8], Value> {
+    let (mut input, mut lhs) = match operand(input) {
+        Done(rest, v) => (rest, v),
+        Error(e) => return Error(e),
+        Incomplete(need) => return Incomplete(need),
+    };
+
+    loop {
+        let rest = ws(input);
+        let (tok, op) = match ops.iter().find(|&&(tok, _)| rest.starts_with(tok.as_bytes())) {
+            Some(&(tok, op)) => (tok, op),
+            None => return Done(input, lhs),
+        };
+
+        match operand(&rest[tok.len()..]) {
+            Done(rest, rhs) => {
+                lhs = Value::binary(op, lhs, rhs);
+                if lhs.depth() > MAX_NESTING {
+                    return Error(Err::Position(ErrorKind::Custom(2), input));
+                }
+                input = rest;
+            }
+            Error(e) => return Error(e),
+            Incomplete(need) => return Incomplete(need),
+        }
+    }
+}
+
+fn expr_or(input: &[u8]) -> IResult<&[u8], Value> {
+    binary_expr(input, expr_xor, &[("|", BinOp::Or)])
+}
+
+fn expr_xor(input: &[u8]) -> IResult<&[u8], Value> {
+    binary_expr(input, expr_and, &[("^", BinOp::Xor)])
+}
+
+fn expr_and(input: &[u8]) -> IResult<&[u8], Value> {
+    binary_expr(input, expr_shift, &[("&", BinOp::And)])
+}
+
+fn expr_shift(input: &[u8]) -> IResult<&[u8], Value> {
+    binary_expr(input, expr_add, &[("<<", BinOp::Shl), (">>", BinOp::Shr)])
+}
+
+fn expr_add(input: &[u8]) -> IResult<&[u8], Value> {
+    binary_expr(input, expr_mul, &[("+", BinOp::Add), ("-", BinOp::Sub)])
+}
+
+fn expr_mul(input: &[u8]) -> IResult<&[u8], Value> {
+    binary_expr(input, expr_atom, &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)])
+}
+
+named!(expr_atom<Value>,
+       alt!(delimited!(lparen, expr_or, rparen) |
+            value
+            )
+       );
+
+#[test]
+fn test_case_value() {
+    assert_eq!(case_value(&b"A:"[..]), Done(&b":"[..], Value::ident("A")));
+    assert_eq!(case_value(&b" -1 :"[..]), Done(&b" :"[..], Value::Const(-1)));
+    assert_eq!(case_value(&b"A | B:"[..]),
+               Done(&b":"[..], Value::binary(BinOp::Or, Value::ident("A"), Value::ident("B"))));
+    assert_eq!(case_value(&b"1 << 2 | 3:"[..]),
+               Done(&b":"[..], Value::binary(BinOp::Or,
+                                             Value::binary(BinOp::Shl, Value::Const(1), Value::Const(2)),
+                                             Value::Const(3))));
+    assert_eq!(case_value(&b"A - 1:"[..]),
+               Done(&b":"[..], Value::binary(BinOp::Sub, Value::ident("A"), Value::Const(1))));
+    assert_eq!(case_value(&b"(A + 1) * 2:"[..]),
+               Done(&b":"[..], Value::binary(BinOp::Mul,
+                                             Value::binary(BinOp::Add, Value::ident("A"), Value::Const(1)),
+                                             Value::Const(2))));
+    assert!(case_value(&b"A |:"[..]).is_err());
+}
+
 named!(struct_type_spec< Vec<Decl> >,
        preceded!(kw_struct, struct_body));
 
@@ -495,20 +909,26 @@ named!(union_type_spec<(Decl, Vec<UnionCase>, Option<Decl>)>,
 
 named!(union_body<(Decl, Vec<UnionCase>, Option<Decl>)>,
     do_parse!(
-        kw_switch >> lparen >> decl:declaration >> rparen >>
+        kw_switch >> lparen >> decl:nonvoid_declaration >> rparen >>
         lbrace >>
         ucss: many1!(union_case) >>
         dfl: opt!(union_default) >>
         rbrace >>
-        (decl, ucss.into_iter().flat_map(|v| v).collect(), dfl)
+        (decl, ucss.into_iter().flatten().collect(), dfl)
     )
 );
 
+// A case arm's comment follows its labels, or a `void;` arm on the same line.
 named!(union_case< Vec<UnionCase> >,
     do_parse!(
-        vs: many1!(do_parse!(kw_case >> v:value >> colon >> (v))) >>
+        vs: many1!(do_parse!(kw_case >> v:case_value >> colon >> (v))) >>
+        comment: opt!(peek!(preceded!(many0!(alt!(whitespace | eol)), blockcomment_value))) >>
         decl: declaration >> semi >>
-        (vs.into_iter().map(|v| UnionCase(v, decl.clone())).collect())
+        trailing: opt!(peek!(preceded!(many0!(whitespace), blockcomment_value))) >>
+        ({
+            let comment = into_comment(comment.or(trailing.filter(|_| decl == Decl::Void)));
+            vs.into_iter().map(|v| UnionCase(v, decl.clone(), comment.clone())).collect()
+        })
     )
 );
 
@@ -539,18 +959,24 @@ named!(declaration<Decl>,
     )
 );
 
-named!(nonvoid_declaration<Decl>,
-    alt!(
-        do_parse!(ty: array_type_spec >> id: ident >> lbrack >> sz:value >> rbrack >>
-            (Decl::named(id, Type::array(ty, sz))))
-    |   do_parse!(ty: array_type_spec >> id: ident >> lt >> sz:opt!(value) >> gt >>
-            (Decl::named(id, Type::flex(ty, sz))))
-    |   do_parse!(ty: type_spec >> star >> id: ident >>
-            (Decl::named(id, Type::option(ty))))
-    |   do_parse!(ty: type_spec >> id: ident >>
-            (Decl::named(id, ty)))
+// The type is parsed once, followed by whichever declarator fits it. Trying each whole
+// declaration in turn would re-parse inline struct and union bodies, taking time exponential in
+// how deeply they're nested.
+fn nonvoid_declaration(input: &[u8]) -> IResult<&[u8], Decl> {
+    let (rest, ty) = try_parse!(input, array_type_spec);
+    // opaque and string are only valid as arrays
+    let bytes = ty == Type::Opaque || ty == Type::String;
+
+    alt!(rest,
+        do_parse!(id: ident >> lbrack >> sz:value >> rbrack >>
+            (Decl::named(id, Type::array(ty.clone(), sz))))
+    |   do_parse!(id: ident >> lt >> sz:opt!(value) >> gt >>
+            (Decl::named(id, Type::flex(ty.clone(), sz))))
+    |   cond_reduce!(!bytes, do_parse!(star >> id: ident >>
+            (Decl::named(id, Type::option(ty.clone())))))
+    |   cond_reduce!(!bytes, map!(ident, |id| Decl::named(id, ty.clone())))
     )
-);
+}
 
 named!(array_type_spec<Type>,
        alt!(kw_opaque => { |_| Type::Opaque } |
@@ -602,13 +1028,13 @@ named!(type_spec<Type>,
             do_parse!(kw_unsigned >> kw_int >> (Type::UInt)) |
             do_parse!(kw_unsigned >> kw_long >> (Type::UInt)) |          // backwards compat with rpcgen
             do_parse!(kw_unsigned >> kw_char >>                          // backwards compat with rpcgen
-                (Type::ident_with_derives("u8", Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG))) |
+                (Type::ident_with_derives("u8", Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG | Derives::DEFAULT))) |
             do_parse!(kw_unsigned >> kw_short >> (Type::UInt)) |         // backwards compat with rpcgen
             do_parse!(kw_unsigned >> kw_hyper >> (Type::UHyper)) |
             kw_unsigned => { |_| Type::UInt } |                     // backwards compat with rpcgen
             kw_long => { |_| Type::Int } |                          // backwards compat with rpcgen
             kw_char => {                                            // backwards compat with rpcgen
-                |_| Type::ident_with_derives("i8", Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG)
+                |_| Type::ident_with_derives("i8", Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG | Derives::DEFAULT)
             } |
             kw_short => { |_| Type::Int } |                         // backwards compat with rpcgen
             kw_int => { |_| Type::Int } |
@@ -617,11 +1043,11 @@ named!(type_spec<Type>,
             kw_double => { |_| Type::Double } |
             kw_quadruple => { |_| Type::Quadruple } |
             kw_bool => { |_| Type::Bool } |
-            enum_type_spec => { |defns| Type::Enum(defns) } |
-            struct_type_spec => { |defns| Type::Struct(defns) } |
+            enum_type_spec => { |defns| Type::Enum(defns, EnumWidth::Int) } |
+            struct_type_spec => { Type::Struct } |
             do_parse!(kw_struct >> id:ident >> (Type::ident(id))) |    // backwards compat with rpcgen
-            union_type_spec => { |u| Type::union(u) } |
-            ident => { |id| Type::ident(id) }
+            union_type_spec => { Type::union } |
+            qualified_ident => { Type::ident }
         )
     )
 );
@@ -637,7 +1063,7 @@ fn test_type() {
     assert_eq!(type_spec(&b"unsigned hyper "[..]), Done(&b" "[..], Type::UHyper));
 
     assert_eq!(type_spec(&b"unsigned char "[..]), Done(&b" "[..],
-        Type::Ident("u8".into(), Some(Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG))));
+        Type::Ident("u8".into(), Some(Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG | Derives::DEFAULT))));
     assert_eq!(type_spec(&b"unsigned short "[..]), Done(&b" "[..], Type::UInt));
 
     assert_eq!(type_spec(&b" hyper "[..]), Done(&b" "[..], Type::Hyper));
@@ -646,7 +1072,7 @@ fn test_type() {
     assert_eq!(type_spec(&b"// thing\n bool "[..]), Done(&b" "[..], Type::Bool));
 
     assert_eq!(type_spec(&b"char "[..]), Done(&b" "[..],
-        Type::Ident("i8".into(), Some(Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG))));
+        Type::Ident("i8".into(), Some(Derives::COPY | Derives::CLONE | Derives::EQ | Derives::PARTIALEQ | Derives::DEBUG | Derives::DEFAULT))));
 
     assert_eq!(type_spec(&b"short "[..]), Done(&b" "[..], Type::Int));
 
@@ -659,9 +1085,16 @@ fn test_type() {
     assert_eq!(type_spec(&b"union switch (int a) { case 1: void; case 2: int a; default: void; } "[..]),
                          Done(&b" "[..],
                               Type::Union(Box::new(Decl::named("a", Type::Int)),
-                                          vec!(UnionCase(Value::Const(1), Decl::Void),
-                                               UnionCase(Value::Const(2), Decl::named("a", Type::Int))),
+                                          vec!(UnionCase(Value::Const(1), Decl::Void, None),
+                                               UnionCase(Value::Const(2), Decl::named("a", Type::Int), None)),
                                           Some(Box::new(Decl::Void)))));
+
+    assert_eq!(type_spec(&b"union switch (bool a) { case 1: int a; case false: void; } "[..]),
+                         Done(&b" "[..],
+                              Type::Union(Box::new(Decl::named("a", Type::Bool)),
+                                          vec!(UnionCase(Value::Const(1), Decl::named("a", Type::Int), None),
+                                               UnionCase(Value::ident("false"), Decl::Void, None)),
+                                          None)));
 }
 
 #[test]
@@ -670,19 +1103,19 @@ fn test_enum() {
                Done(&b" "[..],
                     Type::Enum(vec!(EnumDefn::new("a", None, None),
                                     EnumDefn::new("b", None, None),
-                                    EnumDefn::new("c", None, None)))));
+                                    EnumDefn::new("c", None, None)), EnumWidth::Int)));
 
     assert_eq!(type_spec(&b"enum { a = 1, b, c } "[..]),
                Done(&b" "[..],
                     Type::Enum(vec!(EnumDefn::new("a", Some(Value::Const(1)), None),
                                     EnumDefn::new("b", None, None),
-                                    EnumDefn::new("c", None, None)))));
+                                    EnumDefn::new("c", None, None)), EnumWidth::Int)));
 
     assert_eq!(type_spec(&b"enum { a = Bar, b, c } "[..]),
                Done(&b" "[..],
                     Type::Enum(vec!(EnumDefn::new("a", Some(Value::ident("Bar")), None),
                                     EnumDefn::new("b", None, None),
-                                    EnumDefn::new("c", None, None)))));
+                                    EnumDefn::new("c", None, None)), EnumWidth::Int)));
 
     assert_eq!(type_spec(&b"enum { } "[..]),
                Error(Err::Position(ErrorKind::Alt, &b"enum { } "[..])));
@@ -697,7 +1130,7 @@ fn test_doc_comments() {
                 EnumDefn::new("a", Some(Value::Const(1)), Some(b"a comment")),
                 EnumDefn::new("b", None, None),
                 EnumDefn::new("c", None, Some(b"c comment")),
-            ))
+            ), EnumWidth::Int)
         )
     );
 
@@ -713,15 +1146,104 @@ fn test_doc_comments() {
     );
 }
 
+// Dialect extension: definitions within a namespace are generated into a Rust module of the same
+// name. They can refer to those in enclosing namespaces directly, and to those in other
+// namespaces with qualified names.
+named!(namespace_def<Defn>,
+    do_parse!(
+        kw_namespace >> id:ident >> lbrace >>
+        defns: many0!(definition) >>
+        rbrace >> opt!(semi) >>
+        (Defn::namespace(id, defns))
+    )
+);
+
+#[test]
+fn test_namespace() {
+    assert_eq!(definition(&b"namespace a { const X = 1; struct s { b::t f; }; } "[..]),
+               Done(&b" "[..], Defn::namespace("a", vec!(
+                   Defn::constant("X", 1),
+                   Defn::typespec("s", Type::Struct(vec!(Decl::named("f", Type::ident("b::t")))))))));
+    assert_eq!(definition(&b"namespace a { namespace b { }; };"[..]),
+               Done(&b""[..], Defn::namespace("a", vec!(Defn::namespace("b", vec!())))));
+    assert_eq!(value(&b"a::b::C "[..]), Done(&b" "[..], Value::ident("a::b::C")));
+}
+
+// RPC program definitions from RFC 5531. Their numbers are constants, as with rpcgen; there's no
+// code generated for the procedures themselves.
+named!(program_def<Defn>,
+    do_parse!(
+        kw_program >> id:ident >> lbrace >>
+        versions: many1!(version_def) >>
+        rbrace >> eq >> num:number >> semi >>
+        (Defn::program(id, versions, num))
+    )
+);
+
+named!(version_def<Version>,
+    do_parse!(
+        kw_version >> id:ident >> lbrace >>
+        procs: many1!(procedure_def) >>
+        rbrace >> eq >> num:number >> semi >>
+        (Version::new(id, procs, num))
+    )
+);
+
+named!(procedure_def<Procedure>,
+    do_parse!(
+        ret: proc_type >> id:ident >> lparen >>
+        first: proc_type >>
+        rest: many0!(preceded!(comma, type_spec)) >>
+        rparen >> eq >> num:number >> semi >>
+        (Procedure::new(id, ret, first.into_iter().chain(rest).collect(), num))
+    )
+);
+
+named!(proc_type<Option<Type> >,
+    alt!(kw_void => { |_| None } |
+         type_spec => { Some })
+);
+
+#[test]
+fn test_program() {
+    assert_eq!(definition(&b"program NFS_PROGRAM { version NFS_V3 { void NFSPROC3_NULL(void) = 0; \
+                             getattr3res NFSPROC3_GETATTR(getattr3args) = 1; } = 3; } = 100003; "[..]),
+               Done(&b" "[..], Defn::program("NFS_PROGRAM", vec!(
+                   Version::new("NFS_V3", vec!(
+                       Procedure::new("NFSPROC3_NULL", None, vec!(), 0),
+                       Procedure::new("NFSPROC3_GETATTR", Some(Type::ident("getattr3res")), vec!(Type::ident("getattr3args")), 1),
+                   ), 3)), 100003)));
+    assert_eq!(procedure_def(&b"int ADD(int, unsigned hyper) = 0x10; "[..]),
+               Done(&b" "[..], Procedure::new("ADD", Some(Type::Int), vec!(Type::Int, Type::UHyper), 16)));
+
+    // Still usable as names elsewhere
+    assert_eq!(definition(&b"struct program { int version; };"[..]),
+               Done(&b""[..], Defn::typespec("program", Type::Struct(vec!(Decl::named("version", Type::Int))))));
+
+    // Programs and versions need at least one member
+    assert!(definition(&b"program P { } = 1;"[..]).is_err());
+    assert!(definition(&b"program P { version V { } = 1; } = 1;"[..]).is_err());
+}
+
+// An identifier, or with the namespace dialect extension a path of them like `ns::name`.
+named!(qualified_ident<String>,
+    do_parse!(
+        first: ident >>
+        rest: many0!(preceded!(apply!(ctag, "::"), ident)) >>
+        (rest.into_iter().fold(first.to_string(), |path, id| path + "::" + id))
+    )
+);
+
 named!(const_def<Defn>,
     do_parse!(
-        kw_const >> id:ident >> eq >> v:number >> semi >>
-            (Defn::constant(id, v)))
+        kw_const >> id:ident >> eq >> v:value >> semi >>
+            (Defn::Const(id.to_string(), v)))
 );
 
 #[test]
 fn test_const() {
     assert_eq!(const_def(&b"const foo = 123;"[..]), Done(&b""[..], Defn::constant("foo", 123)));
+    assert_eq!(const_def(&b"const bar = ns::foo;"[..]), Done(&b""[..], Defn::Const("bar".to_string(), Value::ident("ns::foo"))));
 }
 
 named!(type_def<Defn>,
@@ -740,7 +1262,18 @@ named!(type_def<Defn>,
                 }
             })
         )
-    |   do_parse!(kw_enum >> id:ident >> e:enum_body >> semi >> (Defn::typespec(id, Type::Enum(e))))
+    |   do_parse!(
+            kw_enum >> id:ident >> e:enum_body >>
+            comment: opt!(peek!(preceded!(complete!(spaced_semi), blockcomment_value))) >>
+            semi >>
+            ({
+                let mut ty = Type::Enum(e, EnumWidth::Int);
+                if let (_, Some(_)) = ty.annotate_comment(comment) {
+                    warn!("ignoring annotation @default on enum {}", id);
+                }
+                Defn::typespec(id, ty)
+            })
+        )
     |   do_parse!(kw_struct >> id:ident >> s:struct_body >> semi >> (Defn::typespec(id, Type::Struct(s))))
     |   do_parse!(kw_union >> id:ident >> u:union_body >> semi >> (Defn::typespec(id, Type::union(u))))
     )
@@ -756,7 +1289,7 @@ fn test_typedef() {
                Done(&b""[..], Defn::typespec("foo", Type::Flex(Box::new(Type::Int), None))));
 
     assert_eq!(type_def(&b"enum foo { a };"[..]),
-               Done(&b""[..], Defn::typespec("foo", Type::Enum(vec!(EnumDefn::new("a", None, None))))));
+               Done(&b""[..], Defn::typespec("foo", Type::Enum(vec!(EnumDefn::new("a", None, None)), EnumWidth::Int))));
 
     assert_eq!(type_def(&b"struct foo { int a; };"[..]),
                Done(&b""[..], Defn::typespec("foo", Type::Struct(vec!(Decl::named("a", Type::Int))))));
@@ -764,6 +1297,6 @@ fn test_typedef() {
     assert_eq!(type_def(&b"union foo switch(int a) { case 1: int a; };"[..]),
                Done(&b""[..], Defn::typespec("foo",
                                              Type::Union(Box::new(Decl::named("a", Type::Int)),
-                                                         vec!(UnionCase(Value::Const(1), Decl::named("a", Type::Int))),
+                                                         vec!(UnionCase(Value::Const(1), Decl::named("a", Type::Int), None)),
                                                          None))));
 }
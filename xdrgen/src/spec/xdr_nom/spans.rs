@@ -0,0 +1,255 @@
+//! Where each definition, and each part of one, was written
+//!
+//! The AST types are compared and hashed all through code generation, so rather than carrying
+//! positions themselves they're paired with them here, in a tree which mirrors the source: a
+//! struct's fields, a union's selector, arms and default, an enum's members, a namespace's
+//! definitions and a program's versions and their procedures. Inline struct, union and enum
+//! bodies are parts of the declaration they're written in.
+use std::ops::Range;
+
+use super::*;
+use crate::error::Location;
+
+/// A range of a specification's text.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpecSpan {
+    /// Bytes from the start of the first token to the end of the last, so without the comments
+    /// leading up to it
+    pub range: Range<usize>,
+    pub start: Location,
+    pub end: Location,
+}
+
+/// What a `Spanned` covers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SpecPart {
+    Defn(Defn),
+    /// A struct field with its `;`, or a union's selector
+    Decl(Decl),
+    /// A union arm with its `;`, which may have several labels
+    Case(Vec<UnionCase>),
+    /// A union's `default` arm with its `;`
+    Default(Decl),
+    /// An enum member, without the `,` after it
+    Member(EnumDefn),
+    Version(Version),
+    Procedure(Procedure),
+}
+
+/// SpecPart of a specification with where it was written, and the same for its own parts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Spanned {
+    pub part: SpecPart,
+    pub span: SpecSpan,
+    pub parts: Vec<Spanned>,
+}
+
+named!(field<Decl>, terminated!(declaration, semi));
+
+named!(case_labels<()>, do_parse!(many1!(do_parse!(kw_case >> case_value >> colon >> (()))) >> (())));
+
+struct Walk<'a> {
+    input: &'a str,
+    // Offset of the start of each line
+    lines: Vec<usize>,
+}
+
+impl<'a> Walk<'a> {
+    fn src(&self, at: usize) -> &'a [u8] {
+        &self.input.as_bytes()[at..]
+    }
+
+    // Parser results are suffixes of the input
+    fn offset(&self, rest: &[u8]) -> usize {
+        self.input.len() - rest.len()
+    }
+
+    // Where the next token starts
+    fn skip(&self, at: usize) -> usize {
+        self.offset(ws(self.src(at)))
+    }
+
+    fn location(&self, offset: usize) -> Location {
+        let line = self.lines.partition_point(|&start| start <= offset);
+        let column = self.input[self.lines[line - 1]..offset].chars().count() + 1;
+        Location { offset, line, column }
+    }
+
+    fn spanned(&self, part: SpecPart, range: Range<usize>, parts: Vec<Spanned>) -> Spanned {
+        let span = SpecSpan { start: self.location(range.start), end: self.location(range.end), range };
+        Spanned { part, span, parts }
+    }
+
+    // Parse as many of something as follow `at`, each with its parts, leaving `at` after them
+    fn many<T>(
+        &self,
+        at: &mut usize,
+        parse: fn(&[u8]) -> IResult<&[u8], T>,
+        part: impl Fn(T, usize) -> (SpecPart, Vec<Spanned>),
+    ) -> Vec<Spanned> {
+        let mut spanned = Vec::new();
+        loop {
+            let start = self.skip(*at);
+            match parse(self.src(start)) {
+                Done(rest, val) => {
+                    *at = self.offset(rest);
+                    let (val, parts) = part(val, start);
+                    spanned.push(self.spanned(val, start..*at, parts));
+                }
+                _ => return spanned,
+            }
+        }
+    }
+
+    fn defns(&self, mut at: usize) -> Vec<Spanned> {
+        self.many(&mut at, definition, |defn, start| {
+            let parts = self.defn_parts(&defn, start);
+            (SpecPart::Defn(defn), parts)
+        })
+    }
+
+    fn defn_parts(&self, defn: &Defn, start: usize) -> Vec<Spanned> {
+        let src = self.src(start);
+        match defn {
+            Defn::Typespec(..) | Defn::Typesyn(..) => match kw_typedef(src) {
+                Done(rest, _) => self.type_parts(self.skip(self.offset(rest))),
+                _ => self.type_parts(start),
+            },
+            Defn::Namespace(..) => match do_parse!(src, kw_namespace >> ident >> lbrace >> (())) {
+                Done(rest, _) => self.defns(self.offset(rest)),
+                _ => Vec::new(),
+            },
+            Defn::Program(..) => match do_parse!(src, kw_program >> ident >> lbrace >> (())) {
+                Done(rest, _) => self.many(&mut self.offset(rest), version_def, |version, start| {
+                    let parts = match do_parse!(self.src(start), kw_version >> ident >> lbrace >> (())) {
+                        Done(rest, _) => self.many(&mut self.offset(rest), procedure_def, |proc, _| (SpecPart::Procedure(proc), Vec::new())),
+                        _ => Vec::new(),
+                    };
+                    (SpecPart::Version(version), parts)
+                }),
+                _ => Vec::new(),
+            },
+            Defn::Const(..) => Vec::new(),
+        }
+    }
+
+    // The parts of the struct, union or enum body a type at `at` is written with, if any
+    fn type_parts(&self, at: usize) -> Vec<Spanned> {
+        let src = self.src(at);
+        if let Done(rest, _) = do_parse!(src, kw_struct >> opt!(ident) >> lbrace >> (())) {
+            return self.many(&mut self.offset(rest), field, |decl, start| {
+                (SpecPart::Decl(decl), self.type_parts(start))
+            });
+        }
+        if let Done(rest, _) = do_parse!(src, kw_enum >> opt!(ident) >> lbrace >> (())) {
+            return self.members(self.offset(rest));
+        }
+        if let Done(rest, _) = do_parse!(src, kw_union >> opt!(ident) >> kw_switch >> lparen >> (())) {
+            return self.union_parts(self.offset(rest));
+        }
+        Vec::new()
+    }
+
+    fn members(&self, mut at: usize) -> Vec<Spanned> {
+        let mut members = Vec::new();
+        loop {
+            let start = self.skip(at);
+            let (rest, member) = match enum_assign(self.src(start)) {
+                Done(rest, member) => (rest, member),
+                _ => return members,
+            };
+            members.push(self.spanned(SpecPart::Member(member), start..self.offset(rest), Vec::new()));
+            match comma(rest) {
+                Done(rest, _) => at = self.offset(rest),
+                _ => return members,
+            }
+        }
+    }
+
+    // From just inside the selector's parentheses
+    fn union_parts(&self, at: usize) -> Vec<Spanned> {
+        let start = self.skip(at);
+        let (rest, selector) = match nonvoid_declaration(self.src(start)) {
+            Done(rest, selector) => (rest, selector),
+            _ => return Vec::new(),
+        };
+        let mut parts = vec![self.spanned(SpecPart::Decl(selector), start..self.offset(rest), Vec::new())];
+
+        let mut at = match do_parse!(rest, rparen >> lbrace >> (())) {
+            Done(rest, _) => self.offset(rest),
+            _ => return parts,
+        };
+        parts.extend(self.many(&mut at, union_case, |cases, start| {
+            // The arm's declaration follows its labels
+            let decl = match case_labels(self.src(start)) {
+                Done(rest, _) => self.type_parts(self.skip(self.offset(rest))),
+                _ => Vec::new(),
+            };
+            (SpecPart::Case(cases), decl)
+        }));
+        parts.extend(self.many(&mut at, union_default, |decl, start| {
+            let parts = match do_parse!(self.src(start), kw_default >> colon >> (())) {
+                Done(rest, _) => self.type_parts(self.skip(self.offset(rest))),
+                _ => Vec::new(),
+            };
+            (SpecPart::Default(decl), parts)
+        }));
+        parts
+    }
+}
+
+/// Parse a specification, with where each definition and each part of one was written.
+///
+/// Errors are reported as by `specification`.
+pub fn specification_spans(input: &str) -> Result<Vec<Spanned>> {
+    specification(input)?;
+
+    let lines = std::iter::once(0).chain(input.match_indices('\n').map(|(idx, _)| idx + 1)).collect();
+    let walk = Walk { input, lines };
+    let at = match directive(input.as_bytes()) {
+        Done(rest, _) => walk.offset(rest),
+        _ => 0,
+    };
+    Ok(walk.defns(at))
+}
+
+#[test]
+fn test_specification_spans() {
+    let input = "const A = 1;\n\
+                 /* doc */\n\
+                 struct s {\n    \
+                     int a;\n    \
+                     union switch (bool b) { case 1: enum { X, Y = 2 } e; default: void; } u;\n\
+                 };\n\
+                 namespace n { typedef int t; };\n\
+                 program P { version V { void NULL(void) = 0; } = 1; } = 2;\n";
+    let defns = specification_spans(input).unwrap();
+    let text = |spanned: &Spanned| &input[spanned.span.range.clone()];
+
+    assert_eq!(defns.len(), 4);
+    assert_eq!(text(&defns[0]), "const A = 1;");
+    assert!(defns[0].parts.is_empty());
+
+    let s = &defns[1];
+    assert!(text(s).starts_with("struct s {") && text(s).ends_with("};"));
+    assert_eq!((s.span.start.line, s.span.start.column, s.span.end.line), (3, 1, 6));
+    assert_eq!(s.parts.iter().map(text).collect::<Vec<_>>(), [
+        "int a;",
+        "union switch (bool b) { case 1: enum { X, Y = 2 } e; default: void; } u;",
+    ]);
+    assert_eq!(s.parts[0].part, SpecPart::Decl(Decl::named("a", Type::Int)));
+    assert_eq!((s.parts[1].span.start.line, s.parts[1].span.start.column), (5, 5));
+
+    let u = &s.parts[1].parts;
+    assert_eq!(u.iter().map(text).collect::<Vec<_>>(), ["bool b", "case 1: enum { X, Y = 2 } e;", "default: void;"]);
+    assert!(matches!(&u[1].part, SpecPart::Case(cases) if cases[0].0 == Value::Const(1)));
+    assert_eq!(u[1].parts.iter().map(text).collect::<Vec<_>>(), ["X", "Y = 2"]);
+    assert_eq!(u[2].part, SpecPart::Default(Decl::Void));
+
+    assert_eq!(defns[2].parts.iter().map(text).collect::<Vec<_>>(), ["typedef int t;"]);
+    let program = &defns[3];
+    assert_eq!(program.parts.iter().map(text).collect::<Vec<_>>(), ["version V { void NULL(void) = 0; } = 1;"]);
+    assert_eq!(program.parts[0].parts.iter().map(text).collect::<Vec<_>>(), ["void NULL(void) = 0;"]);
+
+    assert!(specification_spans("struct s { int a }").is_err());
+}
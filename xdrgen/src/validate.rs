@@ -0,0 +1,167 @@
+//! Checking many specifications at once, as a repository of them would in CI.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::spec;
+use crate::{directives, generate_source, GenerateOptions};
+
+// How many names `Report`'s `Display` lists as the most referenced
+const SHOWN_REFERENCED: usize = 10;
+
+/// The result of checking one specification.
+#[derive(Debug)]
+pub struct FileReport {
+    pub path: PathBuf,
+    /// Why code can't be generated from it, if it can't
+    pub error: Option<Error>,
+    /// Non-fatal issues found while generating code from it
+    pub warnings: Vec<String>,
+    /// The names it defines itself, not counting those of files it includes
+    pub defines: Vec<String>,
+}
+
+impl FileReport {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The results of checking a set of specifications, with statistics across them.
+#[derive(Debug)]
+pub struct Report {
+    /// Each specification's results, in the order they were given
+    pub files: Vec<FileReport>,
+    /// Names defined by more than one of the specifications, with the files defining them
+    pub duplicates: BTreeMap<String, Vec<PathBuf>>,
+    /// Names with how many definitions refer to them across all the specifications, most first
+    pub most_referenced: Vec<(String, usize)>,
+}
+
+impl Report {
+    /// Whether code can be generated from every specification.
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(FileReport::is_ok)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file in &self.files {
+            let path = file.path.display();
+            match &file.error {
+                None => writeln!(f, "{}: ok", path)?,
+                Some(err) => {
+                    for err in err.errors() {
+                        writeln!(f, "{}: error: {}", path, err)?;
+                    }
+                }
+            }
+            for warning in &file.warnings {
+                writeln!(f, "{}: warning: {}", path, warning)?;
+            }
+        }
+        for (name, paths) in &self.duplicates {
+            let paths: Vec<_> = paths.iter().map(|path| path.display().to_string()).collect();
+            writeln!(f, "{} is defined by {}", name, paths.join(", "))?;
+        }
+        for (name, count) in self.most_referenced.iter().take(SHOWN_REFERENCED) {
+            let plural = if *count == 1 { "" } else { "s" };
+            writeln!(f, "{} is referred to by {} definition{}", name, count, plural)?;
+        }
+        Ok(())
+    }
+}
+
+fn check(path: &Path) -> (FileReport, Vec<spec::Defn>) {
+    let mut report = FileReport { path: path.to_path_buf(), error: None, warnings: Vec::new(), defines: Vec::new() };
+    let source = match fs::File::open(path).map_err(Error::from).and_then(spec::read_source) {
+        Ok(source) => source,
+        Err(err) => {
+            report.error = Some(err);
+            return (report, Vec::new());
+        }
+    };
+
+    // Its own definitions, so that those of shared includes aren't counted for every includer
+    let defns: Vec<_> = match spec::specification_source(&source) {
+        Ok(spec) => spec.defns.into_iter().map(|(defn, _)| defn).collect(),
+        Err(_) => Vec::new(),
+    };
+    report.defines = defns.iter().map(|defn| defn.name().to_string()).collect();
+
+    let name = path.display().to_string();
+    // With the includes next to it, which are shared between specifications
+    let options = GenerateOptions { include_dir: Some(path.parent().unwrap_or(Path::new(""))), ..Default::default() };
+    match generate_source(&name, &source, std::io::sink(), &options, &directives(&options)) {
+        Ok(generated) => report.warnings = generated.warnings,
+        Err(err) => report.error = Some(err),
+    }
+    (report, defns)
+}
+
+/// Check that code can be generated from each of the specifications in `paths`, as `compile`
+/// would with their includes found next to them, and gather statistics across them: names which more than one of them define, and which
+/// names are referred to most.
+///
+/// A specification which can't be read or generated from doesn't stop the others being checked;
+/// it's reported in its `FileReport`.
+pub fn validate_all<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Report {
+    let mut files = Vec::new();
+    let mut defined: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    let mut referenced: BTreeMap<String, usize> = BTreeMap::new();
+
+    for path in paths {
+        let (report, defns) = check(path.as_ref());
+        for defn in &defns {
+            defined.entry(defn.name().to_string()).or_default().push(report.path.clone());
+            // Once for each definition referring to it
+            for name in defn.references().into_iter().collect::<BTreeSet<_>>() {
+                *referenced.entry(name.to_string()).or_default() += 1;
+            }
+        }
+        files.push(report);
+    }
+
+    let duplicates = defined.into_iter().filter(|(_, paths)| paths.len() > 1).collect();
+    let mut most_referenced: Vec<_> = referenced.into_iter().collect();
+    // Stable, so equally referenced names stay in name order
+    most_referenced.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    Report { files, duplicates, most_referenced }
+}
+
+#[test]
+fn test_validate_all() {
+    let dir = tempdir::TempDir::new("validate").unwrap();
+    let write = |name: &str, text: &str| {
+        let path = dir.path().join(name);
+        fs::write(&path, text).unwrap();
+        path
+    };
+    let a = write("a.x", "typedef int id;\nstruct user { id uid; id gid; };\n");
+    let b = write("b.x", "typedef int id;\nstruct group { id gid; user owner; };\n");
+    let bad = write("bad.x", "struct broken { int x }\n");
+    let missing = dir.path().join("missing.x");
+
+    let report = validate_all([&a, &bad, &b, &missing]);
+    assert!(!report.is_ok());
+    let ok: Vec<_> = report.files.iter().map(FileReport::is_ok).collect();
+    assert_eq!(ok, [true, false, true, false]);
+    assert!(matches!(report.files[3].error, Some(Error::IOError(_))));
+    assert_eq!(report.files[2].defines, ["id", "group"]);
+
+    assert_eq!(report.duplicates.keys().collect::<Vec<_>>(), ["id"]);
+    assert_eq!(report.duplicates["id"], [a.clone(), b.clone()]);
+    assert_eq!(report.most_referenced, [("id".to_string(), 2), ("user".to_string(), 1)]);
+
+    let text = report.to_string();
+    assert!(text.contains(&format!("{}: ok\n", a.display())), "{}", text);
+    assert!(text.contains(&format!("{}: error: 1:1:", bad.display())), "{}", text);
+    assert!(text.contains("id is referred to by 2 definitions\nuser is referred to by 1 definition\n"), "{}", text);
+
+    assert!(validate_all([&a]).is_ok());
+}
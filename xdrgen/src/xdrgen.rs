@@ -4,25 +4,434 @@ extern crate xdrgen;
 extern crate env_logger;
 extern crate clap;
 
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 use std::io::{stderr, stdin, stdout};
+use std::net::TcpListener;
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
 
-use clap::{Command, arg};
+use clap::{Command, ArgAction, arg};
 
-use xdrgen::generate;
+use xdrgen::{decode_diff, generate_with_options, lint, minimize, serve_with_options, validate_all, BoolLabels, Codecs, GenerateOptions, KeywordEscape, LintConfig, LintLevel, Naming, Primitive, Primitives, ServeOptions, VariantPrefixes, Visibility};
+#[cfg(unix)]
+use xdrgen::serve_unix;
+
+fn define_arg() -> clap::Arg {
+    arg!(-D --define <NAME> "Define NAME, or NAME=VALUE, for preprocessor conditionals; may be repeated")
+        .action(ArgAction::Append)
+}
+
+fn codecs_args() -> [clap::Arg; 3] {
+    [
+        arg!(--"pack-only" "Only generate Pack impls, except where annotated otherwise").conflicts_with("unpack-only"),
+        arg!(--"unpack-only" "Only generate Unpack impls, except where annotated otherwise"),
+        arg!(--"types-only" "Only generate the types, without Pack or Unpack impls or anything else needing xdr-codec")
+            .conflicts_with_all(["pack-only", "unpack-only"]),
+    ]
+}
+
+fn source_map_arg() -> clap::Arg {
+    arg!(--"source-map" <PATH> "Also write a JSON map from the generated code's lines to the .x file's")
+}
+
+fn include_dir_arg() -> clap::Arg {
+    arg!(--"include-dir" <DIR> "Follow #include \"file\" directives, finding the files in DIR, which they must be within")
+}
+
+fn quirks_arg() -> clap::Arg {
+    arg!(--quirks "Tolerate rpcgen quirks such as stray semicolons, with a warning for each")
+}
+
+fn rust_names_arg() -> clap::Arg {
+    arg!(--"rust-names" "Spell names by Rust's conventions, keeping the spec's as doc aliases")
+}
+
+fn raw_identifiers_arg() -> clap::Arg {
+    arg!(--"raw-identifiers" "Make names which are Rust keywords raw identifiers, like r#type, rather than appending _")
+}
+
+fn variant_prefixes_arg() -> clap::Arg {
+    arg!(--"strip-variant-prefixes" "Leave the prefix an enum's members share off its variants")
+}
+
+fn lossless_enums_arg() -> clap::Arg {
+    arg!(--"lossless-enums" "Generate enums as newtypes which keep values the spec doesn't know")
+}
+
+fn unknown_union_arms_arg() -> clap::Arg {
+    arg!(--"unknown-union-arms" "Give unions without a default arm an Unknown variant for discriminants they don't know, holding the rest of the input")
+}
+
+fn optional_unions_arg() -> clap::Arg {
+    arg!(--"optional-unions" "Generate unions of a bool and, when it's TRUE, a value as Options of the value")
+}
+
+fn no_std_arg() -> clap::Arg {
+    arg!(--"no-std" "Generate code for no_std crates, using core and alloc rather than std")
+}
+
+fn safe_arrays_arg() -> clap::Arg {
+    arg!(--"safe-arrays" "Unpack fixed arrays without unsafe code, for crates which forbid it")
+}
+
+fn box_large_arms_arg() -> clap::Arg {
+    arg!(--"box-large-arms" <BYTES> "Box union arms estimated to take more than BYTES in memory")
+        .value_parser(clap::value_parser!(u64))
+}
+
+fn large_array_derives_arg() -> clap::Arg {
+    arg!(--"large-array-derives" "Derive traits for types with fixed arrays longer than 32 elements, which needs Rust 1.47")
+}
+
+fn bool_labels_arg() -> clap::Arg {
+    arg!(--"bool-labels" <LABELS> "Case labels of unions switching on a bool: TRUE/FALSE, true/false or 1/0 (spellings), also constants of 1 or 0 (constants), or only TRUE/FALSE (strict)")
+        .value_parser(["spellings", "constants", "strict"])
+        .default_value("spellings")
+}
+
+fn visibility_arg() -> clap::Arg {
+    arg!(--visibility <VIS> "Visibility of generated items and fields: pub, crate for pub(crate), or private")
+        .value_parser(["pub", "crate", "private"])
+        .default_value("pub")
+}
+
+fn module_args() -> [clap::Arg; 2] {
+    [
+        arg!(--module <NAME> "Wrap the generated code in a module of this name, with use xdr_codec;"),
+        arg!(--"module-import" <PATH> "Also use this path in the --module, like std::io; may be repeated")
+            .action(ArgAction::Append)
+            .requires("module"),
+    ]
+}
+
+fn impls_only_arg() -> clap::Arg {
+    arg!(--"impls-only" <TYPE> "Only generate the Pack and Unpack impls of this definition or pattern, for a type kept by hand; may be repeated")
+        .action(ArgAction::Append)
+}
+
+fn xdr_string_arg() -> clap::Arg {
+    arg!(--"xdr-string" <TYPE> "Generate this type, or every string if TYPE is `string`, as xdr_codec::XdrString, which needn't be UTF-8; may be repeated")
+        .action(ArgAction::Append)
+}
+
+fn serve_command() -> Command {
+    let command = Command::new("serve")
+        .about("Generate code for specifications sent over a local socket, one per connection")
+        .arg(arg!(--listen <ADDR> "Address to listen on").default_value("127.0.0.1:7979"))
+        .arg(arg!(--"include-root" <DIR> "Directory included files are found in and must be within; without it, #include is refused"))
+        .arg(
+            arg!(--"max-request" <BYTES> "Largest request accepted")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1048576"),
+        )
+        .arg(
+            arg!(--timeout <SECS> "Give up on a client which sends or takes nothing for this long")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("30"),
+        )
+        .arg(
+            arg!(--"request-timeout" <SECS> "Give up on a client which takes longer than this to send its whole request")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("60"),
+        )
+        .arg(
+            arg!(--"max-connections" <N> "Most connections answered at once")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("16"),
+        );
+    #[cfg(unix)]
+    let command = command.arg(arg!(--unix <PATH> "Listen on a Unix socket at PATH instead").conflicts_with("listen"));
+    command
+}
 
 fn main() {
     let _ = env_logger::init();
 
     let matches = Command::new("XDR code generator")
         .version(env!("CARGO_PKG_VERSION"))
+        .args_conflicts_with_subcommands(true)
         .arg(arg!(<FILE> "Set .x file"))
+        .arg(define_arg())
+        .args(codecs_args())
+        .arg(source_map_arg())
+        .arg(include_dir_arg())
+        .arg(quirks_arg())
+        .arg(rust_names_arg())
+        .arg(raw_identifiers_arg())
+        .arg(variant_prefixes_arg())
+        .arg(lossless_enums_arg())
+        .arg(unknown_union_arms_arg())
+        .arg(bool_labels_arg())
+        .arg(optional_unions_arg())
+        .arg(no_std_arg())
+        .arg(safe_arrays_arg())
+        .arg(box_large_arms_arg())
+        .arg(large_array_derives_arg())
+        .arg(visibility_arg())
+        .args(module_args())
+        .arg(impls_only_arg())
+        .arg(xdr_string_arg())
+        .subcommand(
+            Command::new("gen")
+                .about("Generate code, optionally for only some definitions")
+                .arg(arg!(<FILE> "Set .x file"))
+                .arg(define_arg())
+                .args(codecs_args())
+                .arg(source_map_arg())
+                .arg(include_dir_arg())
+                .arg(quirks_arg())
+                .arg(rust_names_arg())
+                .arg(raw_identifiers_arg())
+                .arg(variant_prefixes_arg())
+                .arg(lossless_enums_arg())
+                .arg(unknown_union_arms_arg())
+                .arg(bool_labels_arg())
+                .arg(optional_unions_arg())
+                .arg(no_std_arg())
+                .arg(safe_arrays_arg())
+                .arg(box_large_arms_arg())
+                .arg(large_array_derives_arg())
+                .arg(visibility_arg())
+                .args(module_args())
+                .arg(impls_only_arg())
+                .arg(xdr_string_arg())
+                .arg(
+                    arg!(--only <TYPE> "Only generate this definition, or those matching a glob such as nfs3_* or a /regex/; may be repeated")
+                        .action(ArgAction::Append),
+                )
+                .arg(arg!(--"with-deps" "Also generate everything the --only definitions depend on").requires("only")),
+        )
+        .subcommand(
+            Command::new("minimize")
+                .about("Print a .x file with only some definitions and their dependencies")
+                .arg(arg!(<FILE> "Set .x file"))
+                .arg(arg!(<ROOT>... "Definitions to keep")),
+        )
+        .subcommand(
+            Command::new("lint")
+                .about("Check a .x file against house style rules")
+                .arg(arg!(<FILE> "Set .x file"))
+                .arg(arg!(--config <PATH> "Rule levels; defaults to xdrgen-lint.conf next to the .x file, if there is one")),
+        )
+        .subcommand(
+            Command::new("validate")
+                .about("Check that code can be generated from .x files, with statistics across them")
+                .arg(arg!(<FILE>... "Set .x files")),
+        )
+        .subcommand(
+            Command::new("decode-diff")
+                .about("Show how two versions of a .x file disagree on decoding the same data")
+                .arg(arg!(<OLD> "Set old .x file"))
+                .arg(arg!(<NEW> "Set new .x file"))
+                .arg(arg!(<TYPE> "Type to decode the data as"))
+                .arg(arg!(<DATA> "File with the encoded data, or - for stdin")),
+        )
+        .subcommand(serve_command())
         .get_matches();
 
     let output = stdout();
     let mut err = stderr();
 
+    if let Some(matches) = matches.subcommand_matches("minimize") {
+        let fname = matches.get_one::<String>("FILE").unwrap();
+        let roots: Vec<&str> = matches.get_many::<String>("ROOT").unwrap().map(String::as_str).collect();
+        let res = File::open(fname)
+            .map_err(xdrgen::Error::from)
+            .and_then(|f| minimize(BufReader::new(f), output, &roots));
+        if let Err(e) = res {
+            let _ = writeln!(&mut err, "Failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("lint") {
+        let fname = matches.get_one::<String>("FILE").unwrap();
+        let config = match matches.get_one::<String>("config") {
+            Some(path) => Some(Path::new(path).to_path_buf()),
+            None => Some(Path::new(fname).with_file_name(LintConfig::FILE_NAME)).filter(|path| path.exists()),
+        };
+        let res = config
+            .map_or(Ok(LintConfig::default()), |path| {
+                std::fs::read_to_string(path).map_err(xdrgen::Error::from).and_then(|text| LintConfig::parse(&text))
+            })
+            .and_then(|config| File::open(fname).map_err(xdrgen::Error::from).and_then(|f| lint(f, &config)));
+        match res {
+            Ok(lints) => {
+                for lint in &lints {
+                    let _ = writeln!(&mut err, "{}: {}", fname, lint);
+                }
+                if lints.iter().any(|lint| lint.level == LintLevel::Deny) {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                let _ = writeln!(&mut err, "Failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        let report = validate_all(matches.get_many::<String>("FILE").unwrap());
+        let _ = write!(output.lock(), "{}", report);
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("decode-diff") {
+        let arg = |name| matches.get_one::<String>(name).unwrap();
+        let data = match arg("DATA").as_str() {
+            "-" => {
+                let mut data = Vec::new();
+                stdin().read_to_end(&mut data).map(|_| data)
+            }
+            fname => std::fs::read(fname),
+        };
+        let res = data.map_err(xdrgen::Error::from).and_then(|data| {
+            let old = File::open(arg("OLD"))?;
+            let new = File::open(arg("NEW"))?;
+            decode_diff(BufReader::new(old), BufReader::new(new), arg("TYPE"), &data)
+        });
+        match res {
+            Ok(diffs) => {
+                let mut output = output.lock();
+                if diffs.is_empty() {
+                    let _ = writeln!(output, "no differences");
+                }
+                for diff in &diffs {
+                    let _ = writeln!(output, "{}", diff);
+                }
+            }
+            Err(e) => {
+                let _ = writeln!(&mut err, "Failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("serve") {
+        let options = ServeOptions {
+            include_root: matches.get_one::<String>("include-root").map(PathBuf::from),
+            max_request: *matches.get_one::<u64>("max-request").unwrap(),
+            timeout: matches.get_one::<u64>("timeout").map(|secs| Duration::from_secs(*secs)),
+            request_timeout: matches.get_one::<u64>("request-timeout").map(|secs| Duration::from_secs(*secs)),
+            max_connections: *matches.get_one::<usize>("max-connections").unwrap(),
+        };
+        #[cfg(unix)]
+        if let Some(path) = matches.get_one::<String>("unix") {
+            let res = UnixListener::bind(path).map_err(xdrgen::Error::from).and_then(|listener| {
+                let _ = writeln!(&mut err, "Listening on {}", path);
+                serve_unix(listener, options)
+            });
+            if let Err(e) = res {
+                let _ = writeln!(&mut err, "Failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        let addr = matches.get_one::<String>("listen").unwrap();
+        let res = TcpListener::bind(addr).map_err(xdrgen::Error::from).and_then(|listener| {
+            let _ = writeln!(&mut err, "Listening on {}", addr);
+            serve_with_options(listener, options)
+        });
+        if let Err(e) = res {
+            let _ = writeln!(&mut err, "Failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let matches = matches.subcommand_matches("gen").unwrap_or(&matches);
+    let only: Vec<&str> = matches
+        .try_get_many::<String>("only")
+        .ok()
+        .flatten()
+        .map(|only| only.map(String::as_str).collect())
+        .unwrap_or_default();
+    let impls_only: Vec<&str> = matches.get_many::<String>("impls-only").into_iter().flatten().map(String::as_str).collect();
+    let module_imports: Vec<&str> = matches.get_many::<String>("module-import").into_iter().flatten().map(String::as_str).collect();
+    let with_deps = matches.try_get_one::<bool>("with-deps").ok().flatten() == Some(&true);
+    let defines: Option<BTreeMap<String, String>> = matches.get_many::<String>("define").map(|defines| {
+        defines
+            .map(|def| match def.split_once('=') {
+                Some((name, val)) => (name.to_string(), val.to_string()),
+                None => (def.to_string(), String::new()),
+            })
+            .collect()
+    });
+
+    let codecs = if matches.get_flag("pack-only") {
+        Codecs::PackOnly
+    } else if matches.get_flag("unpack-only") {
+        Codecs::UnpackOnly
+    } else {
+        Codecs::Both
+    };
+
+    let source_map = matches.get_one::<String>("source-map");
+    let quirks = matches.get_flag("quirks");
+
+    let mut primitives = Primitives::default();
+    for xdr_type in matches.get_many::<String>("xdr-string").into_iter().flatten() {
+        if let Err(e) = primitives.add(xdr_type, Primitive::xdr_string()) {
+            let _ = writeln!(&mut err, "Failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let options = GenerateOptions {
+        only: if only.is_empty() { None } else { Some(&only) },
+        only_with_deps: with_deps,
+        impls_only: &impls_only,
+        module: matches.get_one::<String>("module").map(String::as_str),
+        module_imports: &module_imports,
+        types_only: matches.get_flag("types-only"),
+        codecs,
+        include_dir: matches.get_one::<String>("include-dir").map(Path::new),
+        defines: defines.as_ref(),
+        quirks,
+        naming: if matches.get_flag("rust-names") { Naming::Rust } else { Naming::Spec },
+        keyword_escape: if matches.get_flag("raw-identifiers") { KeywordEscape::Raw } else { KeywordEscape::Underscore },
+        variant_prefixes: if matches.get_flag("strip-variant-prefixes") { VariantPrefixes::Auto } else { VariantPrefixes::Keep },
+        lossless_enums: matches.get_flag("lossless-enums"),
+        unknown_union_arms: matches.get_flag("unknown-union-arms"),
+        bool_labels: match matches.get_one::<String>("bool-labels").map(String::as_str) {
+            Some("constants") => BoolLabels::Constants,
+            Some("strict") => BoolLabels::Strict,
+            _ => BoolLabels::Spellings,
+        },
+        optional_unions: matches.get_flag("optional-unions"),
+        no_std: matches.get_flag("no-std"),
+        safe_arrays: matches.get_flag("safe-arrays"),
+        box_large_arms: matches.get_one::<u64>("box-large-arms").copied(),
+        large_array_derives: matches.get_flag("large-array-derives"),
+        visibility: match matches.get_one::<String>("visibility").map(String::as_str) {
+            Some("crate") => Visibility::Crate,
+            Some("private") => Visibility::Private,
+            _ => Visibility::Public,
+        },
+        primitives: Some(&primitives),
+        source_map: source_map.is_some(),
+        ..Default::default()
+    };
+
+    let gen = |fname: &str, input: &mut dyn BufRead, output| {
+        let generated = generate_with_options(fname, input, output, &options)?;
+        match (source_map, generated.source_map) {
+            (Some(path), Some(map)) => std::fs::write(path, map.to_json()).map_err(xdrgen::Error::from),
+            _ => Ok(()),
+        }
+    };
+
     let res = if let Some(fname) = matches.get_one::<String>("FILE") {
         let f = match File::open(fname) {
             Ok(f) => f,
@@ -31,9 +440,9 @@ fn main() {
                 std::process::exit(1);
             }
         };
-        generate(fname, BufReader::new(f), output, &[])
+        gen(fname, &mut BufReader::new(f), output)
     } else {
-        generate("stdin", BufReader::new(stdin()), output, &[])
+        gen("stdin", &mut BufReader::new(stdin()), output)
     };
 
     if let Err(e) = res {
@@ -7,7 +7,7 @@ use std::io::{Cursor, Write};
 use std::process::Command;
 
 use anyhow::{bail, Result};
-use xdrgen::generate;
+use xdrgen::generate_with_options;
 
 fn build_test(name: &str, xdr_spec: &str) -> Result<()> {
     let tempdir = tempdir::TempDir::new("build").expect("Failed to make tempdir");
@@ -71,7 +71,7 @@ fn main() {{}}
 
     {
         let test = File::create(&testfile)?;
-        generate(name, Cursor::new(xdr_spec.as_bytes()), test, &[])?;
+        generate_with_options(name, Cursor::new(xdr_spec.as_bytes()), test, &Default::default())?;
     }
 
     let compile = {
@@ -306,6 +306,7 @@ fn flex() {
 }
 
 #[test]
+#[cfg(feature = "float")]
 fn derive_float() {
     let name = "derive_float";
     let spec = r#"
@@ -316,3 +317,324 @@ fn derive_float() {
         panic!("test {} failed: {}", name, e);
     }
 }
+
+#[test]
+fn union_case_exprs() {
+    let name = "union_case_exprs";
+    let spec = r#"
+        const FLAG_A = 1;
+        const FLAG_B = 2;
+        union foo switch (unsigned int flags) {
+        case FLAG_A | FLAG_B: int both;
+        case (FLAG_B << 2) - 1: void;
+        case 0x10 * 2: hyper big;
+        };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn maps() {
+    let name = "maps";
+    let spec = r#"
+        struct entry { string key<>; hyper value; };
+        struct bag {
+            entry entries<16>;  /* @map */
+            entry hashed<>;     /* @hashmap */
+        };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn enum_hyper() {
+    let name = "enum_hyper";
+    let spec = r#"
+        enum Wide { A = 0, B = -1 }; /* @hyper */
+        struct Bar { Wide x; };
+        union foo switch (Wide w) {
+        case A: int val;
+        case B: void;
+        };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn union_helpers() {
+    let name = "union_helpers";
+    let spec = r#"
+        enum kind { TEXT, DATA };
+        union filetype switch (kind k) {
+        case TEXT: void;
+        case DATA: string creator<>;
+        };
+        union single switch (int x) { case 1: int only; };
+        union other switch (int x) { case 1: hyper h; default: other *rest; };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn namespaces() {
+    let name = "namespaces";
+    let spec = r#"
+        const MAX = 8;
+        struct id { unsigned int value; };
+
+        namespace auth {
+            enum level { GUEST, ADMIN };
+            struct id { string name<MAX>; level lvl; };
+            namespace token { typedef opaque value<MAX>; };
+        };
+
+        namespace store {
+            struct id { hyper key; };
+            struct entry {
+                id local;
+                auth::id owner;
+                auth::token::value tok;
+                auth::level perms[auth::ADMIN];
+            };
+            union access switch (auth::level l) {
+            case auth::GUEST: void;
+            case auth::ADMIN: entry e;
+            };
+        }
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn sun_type_aliases() {
+    let name = "sun_type_aliases";
+    let spec = r#"
+        struct legacy {
+            u_int a;
+            u_char b[4];
+            bool_t c;
+            caddr_t d;
+            u_quad_t e<>;
+        };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn boxed_fields() {
+    let name = "boxed_fields";
+    let spec = r#"
+        enum color { RED, GREEN };
+        struct big { opaque blob[4096]; };
+        struct holder {
+            big inner;          /* @boxed */
+            int ids<8>;         /* @boxed */
+            opaque raw[16];     /* @boxed */
+            color c;            /* @boxed */
+            holder *next;       /* @boxed */
+        };
+        union u switch (int k) { case 1: int x; /* @boxed */ default: void; };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn message_envelope() {
+    let name = "message_envelope";
+    let spec = r#"
+        struct ping { unsigned hyper seq; }; /* @message=1 */
+        struct pong { unsigned hyper seq; opaque data<>; }; /* @message */
+        enum status { OK, FAILED }; /* @message=10 */
+        typedef string note<64>; /* @message */
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn rpc_program() {
+    let name = "rpc_program";
+    let spec = r#"
+        const MAXNAME = 255;
+        typedef string name<MAXNAME>;
+        struct lookupargs { name dir; name file; };
+        program LOOKUP_PROG {
+            version LOOKUP_V1 {
+                void LOOKUPPROC_NULL(void) = 0;
+                unsigned hyper LOOKUPPROC_LOOKUP(lookupargs) = 1;
+            } = 1;
+            version LOOKUP_V2 {
+                lookupargs LOOKUPPROC_ECHO(lookupargs, int) = 1;
+            } = 2;
+        } = 0x20000010;
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn codec_annotations() {
+    let name = "codec_annotations";
+    let spec = r#"
+        struct sample { int readings<16>; unsigned hyper at; }; /* @pack_only */
+        enum command { RESET = 1, SLEEP = 2 }; /* @unpack_only */
+        union reply switch (command cmd) { case RESET: void; case SLEEP: int secs; }; /* @unpack_only */
+        namespace out { struct batch { sample samples<>; }; }; /* @pack_only */
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn nested_anonymous() {
+    let name = "nested_anonymous";
+    let spec = r#"
+        struct parent {
+            int id;
+            struct { int x; struct { hyper lo; hyper hi; } range; } point;
+            struct { int val; } *next_val;
+            union switch (enum { EMPTY = 0, FULL = 1 } kind) {
+                case EMPTY: void;
+                case FULL: struct { opaque data<16>; } contents;
+            } slot;
+        }; /* @pack_only */
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn local_names() {
+    let name = "local_names";
+    // Constants and types named like the locals generated code would otherwise have
+    let spec = r#"
+        const sz = 1;
+        const v = 2;
+        const val = 3;
+        const out = 4;
+        const input = 5;
+        enum kinds { e = 0, x = 1, k = 2 };
+        struct buf { int fsz; };
+        struct res { opaque err[sz]; buf p[v]; };
+        union dsz switch (kinds kind) { case e: res usz; case x: buf esz<out>; default: void; };
+        typedef buf *csz;
+        struct holder { int span<input>; dsz *next; csz again; };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn optional_unions() {
+    let name = "optional_unions";
+    let spec = r#"
+        union maybe_int switch (bool present) { case TRUE: int val; case FALSE: void; };
+        struct node {
+            int id;
+            union switch (bool has_next) { case FALSE: void; case TRUE: node next; } next;
+            maybe_int count;
+        };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn cross_enum_consts() {
+    let name = "cross_enum_consts";
+    let spec = r#"
+        enum c { C = D, C2 };
+        enum d { D = 7 };
+        namespace ns { enum e { E = C2, E2 }; struct t { int x[E2]; int y[C]; }; };
+        struct s { int x[ns::E2]; };
+        union u switch (c kind) { case C: int v; case C2: void; };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn recursive_types() {
+    let name = "recursive_types";
+    let spec = r#"
+        struct entry { int v; entry *next; };
+        union expr switch (int k) { case 0: int lit; case 1: expr negated; default: void; };
+        union tree switch (int kind) { case 0: void; case 1: tree_pair pair; };
+        struct tree_pair { tree left; tree right; };
+        typedef tree forest<>;
+        namespace ns { union term switch (int k) { case 0: void; case 1: term inner; }; struct holder { term t; tree u; }; };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn bool_case_spellings() {
+    let name = "bool_case_spellings";
+    let spec = r#"
+        union lower switch (bool present) { case true: int val; case false: void; };
+        union numeric switch (bool present) { case 0: void; case 1: lower val; };
+        union both switch (bool b) { case 1: int a; case 0: hyper c; };
+        union upper switch (bool b) { case TRUE: int a; case FALSE: hyper c; };
+        union one switch (bool b) { case true: int a; };
+        struct holder { lower a; numeric b; both c; upper d; one e; };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}
+
+#[test]
+fn typedef_selectors() {
+    let name = "typedef_selectors";
+    let spec = r#"
+        enum color { RED, GREEN = 2, BLUE };
+        typedef color c1;
+        typedef c1 c2;
+        typedef int level;
+        union u switch (c2 k) { case RED: int r; case GREEN: void; default: void; };
+        union v switch (level l) { case 0: void; case 1: u inner; };
+    "#;
+
+    if let Err(e) = build_test(name, spec) {
+        panic!("test {} failed: {}", name, e);
+    }
+}